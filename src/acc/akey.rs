@@ -1,43 +1,315 @@
-use openssl::ec::EcKey;
-use openssl::pkey;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{self, Id, PKey};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
 
-use crate::cert::EC_GROUP_P256;
-use crate::Result;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::cert::{check_rsa_bits, EC_GROUP_P256, EC_GROUP_P384, EC_GROUP_P521};
+use crate::jwt::Jwk;
+use crate::util::base64url;
+use crate::{Error, Result};
+
+/// Kinds of keys that can be used for the ACME account.
+///
+/// The key type is only used when creating a new account key. When an account key is read
+/// from persistence, the type is detected from the PEM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountKeyType {
+    /// Elliptic curve P-256, signing requests using `ES256`. This is the default.
+    P256,
+    /// Elliptic curve P-384, signing requests using `ES384`.
+    P384,
+    /// Elliptic curve P-521, signing requests using `ES512`.
+    P521,
+    /// RSA key with the given number of bits, signing requests using `RS256`.
+    ///
+    /// The bits must be between 2048 and 16384, or creating the key fails with
+    /// [`Error::Validation`]. For Let's Encrypt, the bits must be between 2048 and 4096.
+    ///
+    /// [`Error::Validation`]: enum.Error.html#variant.Validation
+    Rsa(u32),
+    /// Edwards curve Ed25519, signing requests using `EdDSA`.
+    Ed25519,
+}
+
+/// Parameters for an elliptic curve account key.
+pub(crate) struct EcParams {
+    pub group: &'static EcGroup,
+    /// Curve name as used in the JWK.
+    pub crv: &'static str,
+    /// Digest used for signing.
+    pub digest: MessageDigest,
+    /// Size in bytes of the curve coordinates, and of r and s in the signature.
+    pub size: usize,
+}
+
+impl AccountKeyType {
+    /// Parameters for elliptic curve keys, `None` for other kinds of keys.
+    pub(crate) fn ec_params(self) -> Option<EcParams> {
+        let (group, crv, digest, size) = match self {
+            AccountKeyType::P256 => (&*EC_GROUP_P256, "P-256", MessageDigest::sha256(), 32),
+            AccountKeyType::P384 => (&*EC_GROUP_P384, "P-384", MessageDigest::sha384(), 48),
+            AccountKeyType::P521 => (&*EC_GROUP_P521, "P-521", MessageDigest::sha512(), 66),
+            AccountKeyType::Rsa(_) | AccountKeyType::Ed25519 => return None,
+        };
+        Some(EcParams {
+            group,
+            crv,
+            digest,
+            size,
+        })
+    }
+}
+
+/// Signs requests to the ACME API using the account key.
+///
+/// By default acme-lib holds the account key in memory. Implement this trait to keep the
+/// key elsewhere, such as in an HSM or a cloud KMS, and access the account using
+/// [`Directory::account_with_signer`].
+///
+/// [`Directory::account_with_signer`]: struct.Directory.html#method.account_with_signer
+pub trait AcmeSigner: Send + Sync {
+    /// Sign the data as required by the JWS [`alg`]. For ECDSA, the signature is the
+    /// fixed size concatenation of r and s.
+    ///
+    /// [`alg`]: trait.AcmeSigner.html#tymethod.alg
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>>;
+    /// The public key of the account key.
+    fn public_jwk(&self) -> Jwk;
+    /// The JWS `alg` used when signing, such as `ES256`.
+    fn alg(&self) -> &str;
+}
+
+/// An account key held in memory.
+///
+/// The key material is owned by openssl, which clears it when the key is freed.
+#[derive(Clone)]
+struct LocalKey {
+    private_key: PKey<pkey::Private>,
+    key_type: AccountKeyType,
+}
+
+impl AcmeSigner for LocalKey {
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self.key_type {
+            AccountKeyType::Rsa(_) => {
+                let mut signer =
+                    Signer::new(MessageDigest::sha256(), &self.private_key).expect("Signer");
+                signer
+                    .sign_oneshot_to_vec(data)
+                    .expect("sign_oneshot_to_vec")
+            }
+            AccountKeyType::Ed25519 => {
+                // EdDSA signs the message as is, without a separate digest.
+                let mut signer = Signer::new_without_digest(&self.private_key).expect("Signer");
+                signer
+                    .sign_oneshot_to_vec(data)
+                    .expect("sign_oneshot_to_vec")
+            }
+            _ => {
+                let params = self.key_type.ec_params().expect("ec_params");
+                let digest = hash(params.digest, data).expect("hash");
+                let ec = self.private_key.ec_key().expect("ec_key");
+                let sig = EcdsaSig::sign(&digest, &ec).expect("EcdsaSig::sign");
+                // JWS wants the fixed size concatenation of r and s.
+                let size = params.size as i32;
+                let mut v = sig.r().to_vec_padded(size).expect("to_vec_padded");
+                v.extend_from_slice(&sig.s().to_vec_padded(size).expect("to_vec_padded"));
+                v
+            }
+        })
+    }
+
+    fn public_jwk(&self) -> Jwk {
+        if let Some(params) = self.key_type.ec_params() {
+            let mut ctx = BigNumContext::new().expect("BigNumContext");
+            let mut x = BigNum::new().expect("BigNum");
+            let mut y = BigNum::new().expect("BigNum");
+            self.private_key
+                .ec_key()
+                .expect("ec_key")
+                .public_key()
+                .affine_coordinates_gfp(params.group, &mut x, &mut y, &mut ctx)
+                .expect("affine_coordinates_gfp");
+            let size = params.size as i32;
+            Jwk {
+                alg: self.alg().into(),
+                kty: "EC".into(),
+                crv: Some(params.crv.into()),
+                _use: "sig".into(),
+                x: Some(base64url(&x.to_vec_padded(size).expect("to_vec_padded"))),
+                y: Some(base64url(&y.to_vec_padded(size).expect("to_vec_padded"))),
+                e: None,
+                n: None,
+            }
+        } else if self.key_type == AccountKeyType::Ed25519 {
+            // RFC 8037
+            let x = self.private_key.raw_public_key().expect("raw_public_key");
+            Jwk {
+                alg: self.alg().into(),
+                kty: "OKP".into(),
+                crv: Some("Ed25519".into()),
+                _use: "sig".into(),
+                x: Some(base64url(&x)),
+                y: None,
+                e: None,
+                n: None,
+            }
+        } else {
+            let rsa = self.private_key.rsa().expect("rsa");
+            Jwk {
+                alg: self.alg().into(),
+                kty: "RSA".into(),
+                _use: "sig".into(),
+                e: Some(base64url(&rsa.e().to_vec())),
+                n: Some(base64url(&rsa.n().to_vec())),
+                crv: None,
+                x: None,
+                y: None,
+            }
+        }
+    }
+
+    fn alg(&self) -> &str {
+        match self.key_type {
+            AccountKeyType::P256 => "ES256",
+            AccountKeyType::P384 => "ES384",
+            AccountKeyType::P521 => "ES512",
+            AccountKeyType::Rsa(_) => "RS256",
+            AccountKeyType::Ed25519 => "EdDSA",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum KeySigner {
+    Local(LocalKey),
+    External(Arc<dyn AcmeSigner + Send + Sync>),
+}
 
 #[derive(Clone)]
 pub(crate) struct AcmeKey {
-    private_key: EcKey<pkey::Private>,
+    signer: KeySigner,
     /// set once we contacted the ACME API to figure out the key id
     key_id: Option<String>,
 }
 
 impl AcmeKey {
-    pub(crate) fn new() -> AcmeKey {
-        let pri_key = EcKey::generate(&*EC_GROUP_P256).expect("EcKey");
-        Self::from_key(pri_key)
+    pub(crate) fn new(key_type: AccountKeyType) -> Result<AcmeKey> {
+        let private_key = match key_type {
+            AccountKeyType::Rsa(bits) => {
+                check_rsa_bits(bits)?;
+                let pri_key = Rsa::generate(bits).map_err(Error::KeyGeneration)?;
+                PKey::from_rsa(pri_key).map_err(Error::KeyGeneration)?
+            }
+            AccountKeyType::Ed25519 => PKey::generate_ed25519().expect("generate_ed25519"),
+            _ => {
+                let params = key_type.ec_params().expect("ec_params");
+                let pri_key = EcKey::generate(params.group).expect("EcKey");
+                PKey::from_ec_key(pri_key).expect("from_ec_key")
+            }
+        };
+        Ok(Self::from_key(private_key, key_type))
     }
 
     pub(crate) fn from_pem(pem: &[u8]) -> Result<AcmeKey> {
         let pri_key =
-            EcKey::private_key_from_pem(pem).map_err(|e| format!("Failed to read PEM: {}", e))?;
-        Ok(Self::from_key(pri_key))
+            PKey::private_key_from_pem(pem).map_err(|e| format!("Failed to read PEM: {}", e))?;
+        let key_type = match pri_key.id() {
+            Id::EC => {
+                let ec = pri_key.ec_key().expect("ec_key");
+                match ec.group().curve_name() {
+                    Some(Nid::X9_62_PRIME256V1) => AccountKeyType::P256,
+                    Some(Nid::SECP384R1) => AccountKeyType::P384,
+                    Some(Nid::SECP521R1) => AccountKeyType::P521,
+                    nid => {
+                        return Err(format!("Unsupported account key curve: {:?}", nid).into());
+                    }
+                }
+            }
+            Id::RSA => AccountKeyType::Rsa(pri_key.bits()),
+            Id::ED25519 => AccountKeyType::Ed25519,
+            id => return Err(format!("Unsupported account key type: {:?}", id).into()),
+        };
+        Ok(Self::from_key(pri_key, key_type))
+    }
+
+    pub(crate) fn from_signer(signer: Box<dyn AcmeSigner + Send + Sync>) -> AcmeKey {
+        AcmeKey {
+            signer: KeySigner::External(signer.into()),
+            key_id: None,
+        }
     }
 
-    fn from_key(private_key: EcKey<pkey::Private>) -> AcmeKey {
+    fn from_key(private_key: PKey<pkey::Private>, key_type: AccountKeyType) -> AcmeKey {
         AcmeKey {
-            private_key,
+            signer: KeySigner::Local(LocalKey {
+                private_key,
+                key_type,
+            }),
             key_id: None,
         }
     }
 
-    pub(crate) fn to_pem(&self) -> Vec<u8> {
-        self.private_key
-            .private_key_to_pem()
-            .expect("private_key_to_pem")
+    fn signer(&self) -> &dyn AcmeSigner {
+        match &self.signer {
+            KeySigner::Local(k) => k,
+            KeySigner::External(s) => s.as_ref(),
+        }
+    }
+
+    /// The private key as PEM, `None` for an external signer.
+    pub(crate) fn to_pem(&self) -> Option<Zeroizing<Vec<u8>>> {
+        let key = match &self.signer {
+            KeySigner::Local(k) => k,
+            KeySigner::External(_) => return None,
+        };
+        Some(Zeroizing::new(match key.key_type {
+            AccountKeyType::Rsa(_) => key
+                .private_key
+                .rsa()
+                .expect("rsa")
+                .private_key_to_pem()
+                .expect("private_key_to_pem"),
+            AccountKeyType::Ed25519 => key
+                .private_key
+                .private_key_to_pem_pkcs8()
+                .expect("private_key_to_pem_pkcs8"),
+            _ => key
+                .private_key
+                .ec_key()
+                .expect("ec_key")
+                .private_key_to_pem()
+                .expect("private_key_to_pem"),
+        }))
+    }
+
+    /// The type of key, `None` for an external signer.
+    pub(crate) fn key_type(&self) -> Option<AccountKeyType> {
+        match &self.signer {
+            KeySigner::Local(k) => Some(k.key_type),
+            KeySigner::External(_) => None,
+        }
+    }
+
+    /// The JWS `alg` used when signing with this key.
+    pub(crate) fn alg(&self) -> &str {
+        self.signer().alg()
+    }
+
+    /// Sign the data as required by the JWS `alg` of this key.
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.signer().sign(data)
     }
 
-    pub(crate) fn private_key(&self) -> &EcKey<pkey::Private> {
-        &self.private_key
+    pub(crate) fn public_jwk(&self) -> Jwk {
+        self.signer().public_jwk()
     }
 
     pub(crate) fn key_id(&self) -> &str {
@@ -0,0 +1,53 @@
+//! ACME Renewal Information (ARI), letting the CA suggest when to renew.
+
+use std::time::{Duration, SystemTime};
+
+use crate::api::ApiRenewalInfo;
+use crate::cert::Certificate;
+use crate::req::{req_get, req_handle_error, req_retry_after};
+use crate::util::{parse_rfc3339, read_json};
+use crate::Result;
+
+/// When the CA suggests renewing a certificate, as returned by
+/// [`Account::renewal_info`].
+///
+/// [`Account::renewal_info`]: struct.Account.html#method.renewal_info
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenewalInfo {
+    /// Start of the window in which to renew.
+    pub suggested_window_start: SystemTime,
+    /// End of the window in which to renew.
+    pub suggested_window_end: SystemTime,
+    /// A page explaining the suggested window, typically set when the CA wants an early
+    /// renewal, e.g. because the certificate will be revoked.
+    pub explanation_url: Option<String>,
+    /// How long to wait before asking again, from the `Retry-After` header.
+    pub retry_after: Option<Duration>,
+}
+
+impl RenewalInfo {
+    /// Whether the suggested window has started.
+    pub fn should_renew(&self) -> bool {
+        SystemTime::now() >= self.suggested_window_start
+    }
+}
+
+/// Fetch the renewal information for the certificate from the `renewalInfo` URL.
+pub(crate) fn fetch_renewal_info(base_url: &str, cert: &Certificate) -> Result<RenewalInfo> {
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), cert.ari_cert_id()?);
+    debug!("Get renewal info: {}", url);
+    let res = req_handle_error(req_get(&url))?;
+
+    let retry_after = req_retry_after(&res);
+
+    let api: ApiRenewalInfo = read_json(res)?;
+    let time =
+        |s: &str| parse_rfc3339(s).ok_or_else(|| format!("Bad time in renewal info: {:?}", s));
+
+    Ok(RenewalInfo {
+        suggested_window_start: time(&api.suggestedWindow.start)?,
+        suggested_window_end: time(&api.suggestedWindow.end)?,
+        explanation_url: api.explanationURL,
+        retry_after,
+    })
+}
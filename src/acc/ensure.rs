@@ -0,0 +1,322 @@
+use openssl::pkey::PKey;
+use std::time::Duration;
+
+use super::ident::api_identifiers;
+use super::{Account, Identifier};
+use crate::api::ApiIdentifier;
+use crate::cert::{create_p256_key, public_key_of, same_identifier, Certificate};
+use crate::order::Auth;
+use crate::persist::Persist;
+use crate::{Error, Result};
+
+/// Options for [`Account::ensure_certificate`].
+///
+/// [`Account::ensure_certificate`]: struct.Account.html#method.ensure_certificate
+#[derive(Debug, Clone)]
+pub struct EnsureOptions {
+    renew_days: i64,
+    ari: bool,
+    reuse_key: bool,
+    poll_delay: Duration,
+    timeout: Duration,
+}
+
+impl Default for EnsureOptions {
+    fn default() -> Self {
+        EnsureOptions {
+            renew_days: 30,
+            ari: false,
+            reuse_key: false,
+            poll_delay: Duration::from_secs(5),
+            timeout: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl EnsureOptions {
+    /// The default options: renew with 30 days left, with a new P-256 key, polling every
+    /// 5 seconds for at most 10 minutes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renew when the certificate has fewer [valid days left].
+    ///
+    /// [valid days left]: struct.Certificate.html#method.valid_days_left
+    pub fn with_renew_days(mut self, days: i64) -> Self {
+        self.renew_days = days;
+        self
+    }
+
+    /// Renew when the [renewal information] of the CA says so. The renew days are used
+    /// when the CA doesn't provide renewal information, or getting it fails.
+    ///
+    /// [renewal information]: struct.Account.html#method.renewal_info
+    pub fn with_ari(mut self, ari: bool) -> Self {
+        self.ari = ari;
+        self
+    }
+
+    /// Use the private key of the current certificate for the renewed one, instead of a
+    /// new key.
+    pub fn with_key_reuse(mut self, reuse_key: bool) -> Self {
+        self.reuse_key = reuse_key;
+        self
+    }
+
+    /// Time between polls of the order, unless the ACME API asks for another delay.
+    pub fn with_poll_delay(mut self, delay: Duration) -> Self {
+        self.poll_delay = delay;
+        self
+    }
+
+    /// Longest time to wait for the order to become ready after validating, and again
+    /// for the certificate to be issued.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// What [`Account::ensure_certificate`] did to get the certificate.
+///
+/// [`Account::ensure_certificate`]: struct.Account.html#method.ensure_certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnsureOutcome {
+    /// The saved certificate is still good.
+    Kept(Certificate),
+    /// The saved certificate was replaced by a new one.
+    Renewed(Certificate),
+    /// There was no usable certificate saved, a new one was issued.
+    Issued(Certificate),
+}
+
+impl EnsureOutcome {
+    /// The certificate, whatever happened.
+    pub fn certificate(&self) -> &Certificate {
+        match self {
+            EnsureOutcome::Kept(c) | EnsureOutcome::Renewed(c) | EnsureOutcome::Issued(c) => c,
+        }
+    }
+
+    /// Take the certificate, whatever happened.
+    pub fn into_certificate(self) -> Certificate {
+        match self {
+            EnsureOutcome::Kept(c) | EnsureOutcome::Renewed(c) | EnsureOutcome::Issued(c) => c,
+        }
+    }
+}
+
+impl<P: Persist> Account<P> {
+    /// Make sure there is a good certificate saved for the names, ordering a new one if
+    /// needed.
+    ///
+    /// The saved [certificate] is kept unless it's due for renewal as set in the options,
+    /// or its subject alternative names aren't exactly the requested names. A certificate
+    /// whose private key doesn't match is replaced.
+    ///
+    /// A name that is an IP address is ordered as one, and a name with an `@` as an email
+    /// address, like with [`new_order_identifiers`]. Other names are domain names.
+    ///
+    /// Otherwise a new order is created, [replacing] the current certificate if the names
+    /// are the same. The `validator` gets the pending authorizations, and must validate
+    /// them, such as using [`Challenge::validate`]. It is only called when the ACME API
+    /// wants validations. The certificate is then finalized, downloaded and saved.
+    ///
+    /// [certificate]: struct.Account.html#method.certificate
+    /// [replacing]: struct.Account.html#method.new_order_replacing
+    /// [`new_order_identifiers`]: struct.Account.html#method.new_order_identifiers
+    /// [`Challenge::validate`]: order/struct.Challenge.html#method.validate
+    pub fn ensure_certificate(
+        &self,
+        primary_name: &str,
+        alt_names: &[&str],
+        opts: &EnsureOptions,
+        validator: &dyn Fn(&[Auth<P>]) -> Result<()>,
+    ) -> Result<EnsureOutcome> {
+        let names: Vec<Identifier> = [primary_name]
+            .iter()
+            .chain(alt_names)
+            .map(|s| Identifier::from_name(s))
+            .collect();
+        let wanted = api_identifiers(&names)?;
+        let existing = match self.certificate(primary_name) {
+            Err(Error::KeyCertMismatch { primary_name }) => {
+                warn!("Replace certificate with mismatched key: {}", primary_name);
+                None
+            }
+            res => res?,
+        };
+
+        let same_names = match &existing {
+            Some(cert) => has_names(cert, &wanted),
+            None => false,
+        };
+        if let Some(cert) = existing.as_ref().filter(|_| same_names) {
+            if !self.is_due(cert, opts) {
+                debug!("Keep certificate: {}", primary_name);
+                return Ok(EnsureOutcome::Kept(existing.unwrap()));
+            }
+            debug!("Renew certificate: {}", primary_name);
+        } else if existing.is_some() {
+            debug!("Renew certificate for other names: {}", primary_name);
+        }
+
+        let mut order = match existing.as_ref().filter(|_| same_names) {
+            Some(old) => self.new_order_identifiers_replacing(&names, old)?.0,
+            None => self.new_order_identifiers(&names)?,
+        };
+        if !order.is_validated() {
+            let auths = order.authorizations()?;
+            validator(&auths)?;
+            order.wait_ready_timeout(opts.poll_delay, opts.timeout)?;
+        }
+        let csr = order
+            .confirm_validations()
+            .ok_or_else(|| format!("Order is not ready after validating: {}", order.url()))?
+            .with_timeout(opts.timeout);
+
+        let reused = existing
+            .as_ref()
+            .filter(|c| opts.reuse_key && c.has_private_key())
+            .and_then(|c| PKey::private_key_from_pem(c.private_key().as_bytes()).ok());
+        let (private_key, public_key) = match reused {
+            Some(pkey) => {
+                let public = public_key_of(&pkey);
+                (pkey, public)
+            }
+            None => create_p256_key(),
+        };
+        let delay_millis = opts.poll_delay.as_millis() as u64;
+        let cert = csr
+            .finalize_pkey(private_key, public_key, delay_millis)?
+            .download_and_save_cert()?;
+
+        Ok(match existing {
+            Some(_) => EnsureOutcome::Renewed(cert),
+            None => EnsureOutcome::Issued(cert),
+        })
+    }
+
+    /// Whether the certificate should be renewed, by the renewal information if asked
+    /// for, and else by the days left.
+    fn is_due(&self, cert: &Certificate, opts: &EnsureOptions) -> bool {
+        if opts.ari {
+            match self.renewal_info(cert) {
+                Ok(info) => return info.should_renew(),
+                Err(e) => debug!("No renewal info, use days left: {}", e),
+            }
+        }
+        cert.valid_days_left() < opts.renew_days
+    }
+}
+
+/// Whether the names of the certificate are the requested identifiers, in any order.
+/// Each must be a name of its own type.
+fn has_names(cert: &Certificate, wanted: &[ApiIdentifier]) -> bool {
+    let names = cert.identifiers();
+    let has =
+        |ids: &[ApiIdentifier], id: &ApiIdentifier| ids.iter().any(|i| same_identifier(i, id));
+    wanted.iter().all(|w| has(&names, w)) && names.iter().all(|n| has(wanted, n))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acc::save_certificate;
+    use crate::persist::MemoryPersist;
+    use crate::{Directory, DirectoryUrl};
+    use std::cell::Cell;
+
+    #[test]
+    fn test_ensure_certificate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let dir = Directory::from_url(MemoryPersist::new(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let save = |name: &str| {
+            save_certificate(
+                &acc.inner.persist,
+                &acc.inner.realm,
+                name,
+                &[],
+                Some(crate::test::TEST_CERT_KEY_PEM.as_bytes()),
+                crate::test::TEST_CERT_PEM.as_bytes(),
+            )
+        };
+        let validated = Cell::new(0);
+        let validator = |auths: &[Auth<MemoryPersist>]| {
+            assert_eq!(auths.len(), 1);
+            validated.set(validated.get() + 1);
+            Err(Error::Other("not validated".into()))
+        };
+        let opts = EnsureOptions::new().with_poll_delay(Duration::from_millis(1));
+
+        // nothing saved, the order is valid without validator
+        let outcome = acc.ensure_certificate("valid.example.com", &[], &opts, &validator)?;
+        assert!(matches!(outcome, EnsureOutcome::Issued(_)));
+        assert_eq!(
+            outcome.certificate().certificate(),
+            crate::test::TEST_CERT_PEM
+        );
+
+        // the saved certificate is good
+        save("acmetest.example.com")?;
+        let outcome = acc.ensure_certificate("acmetest.example.com", &[], &opts, &validator)?;
+        assert_eq!(
+            outcome,
+            EnsureOutcome::Kept(acc.certificate("acmetest.example.com")?.unwrap())
+        );
+        assert_eq!(validated.get(), 0);
+
+        // due by days left, or by the renewal info
+        for opts in &[
+            opts.clone().with_renew_days(100_000),
+            opts.clone().with_ari(true),
+        ] {
+            let err = acc
+                .ensure_certificate("acmetest.example.com", &[], opts, &validator)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "not validated");
+        }
+        assert_eq!(validated.get(), 2);
+
+        // saved under a name that isn't in the certificate, with the key reused
+        save("valid.example.com")?;
+        let opts = opts.with_key_reuse(true);
+        let outcome = acc.ensure_certificate("valid.example.com", &[], &opts, &validator)?;
+        assert!(matches!(outcome, EnsureOutcome::Renewed(_)));
+        let cert = outcome.into_certificate();
+        assert_eq!(cert.private_key(), crate::test::TEST_CERT_KEY_PEM);
+        assert!(cert.key_matches_cert());
+        assert_eq!(validated.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_names() -> Result<()> {
+        let wanted = |names: &[&str]| {
+            let ids: Vec<_> = names.iter().map(|s| Identifier::from_name(s)).collect();
+            api_identifiers(&ids)
+        };
+        let names = ["example.com", "10.0.0.5", "2001:db8::1", "user@example.com"];
+        let (key, cert) = crate::test::test_self_signed_names("example.com", &names);
+        let cert = Certificate::new(key, cert);
+        let mixed = [
+            "USER@example.com",
+            "2001:DB8:0:0:0:0:0:1",
+            "Example.COM",
+            "10.0.0.5",
+        ];
+        assert!(has_names(&cert, &wanted(&mixed)?));
+        assert!(!has_names(&cert, &wanted(&names[..3])?));
+        let extra = [&names[..], &["other@example.com"]].concat();
+        assert!(!has_names(&cert, &wanted(&extra)?));
+
+        // a domain name isn't the same as an IP address of the same text
+        let (key, cert) = crate::test::test_self_signed("10.0.0.5");
+        let cert = Certificate::new(key, cert);
+        assert!(!has_names(&cert, &wanted(&["10.0.0.5"])?));
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use zeroize::Zeroize;
+
+use crate::cert::Certificate;
+
+/// Everything needed to move an account between persistence implementations.
+///
+/// Created by [`Account::export`] and read back using [`Directory::import_account`].
+/// The struct is serde serializable, for example to JSON. The account private key is
+/// wiped from memory when the export is dropped.
+///
+/// [`Account::export`]: struct.Account.html#method.export
+/// [`Directory::import_account`]: struct.Directory.html#method.import_account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountExport {
+    /// Identifies the account in the persistence.
+    pub realm: String,
+    /// Contact emails of the account.
+    pub contact_emails: Vec<String>,
+    /// PEM encoded account private key.
+    pub private_key_pem: String,
+    /// The account URL (key id).
+    pub account_url: String,
+    /// Persisted certificates, keyed by primary name.
+    #[serde(default)]
+    pub certificates: BTreeMap<String, Certificate>,
+}
+
+impl Drop for AccountExport {
+    fn drop(&mut self) {
+        self.private_key_pem.zeroize();
+    }
+}
@@ -0,0 +1,324 @@
+//! Earlier certificates kept in the persistence, for rolling back.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use super::store::{
+    find_cert_for_primary_name, read_certificate, remove_entry, save_certificate_as,
+};
+use super::{lookup_name, Account};
+use crate::cert::Certificate;
+use crate::persist::{Persist, PersistExt, PersistKey, PersistKind};
+use crate::{Error, Result};
+
+/// An earlier certificate kept by [`Account::set_cert_history`].
+///
+/// [`Account::set_cert_history`]: struct.Account.html#method.set_cert_history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CertificateMeta {
+    /// Counts up from 1 for each certificate kept under the name.
+    pub generation: u64,
+    /// When the certificate was replaced.
+    pub archived_at: SystemTime,
+    /// Serial number of the certificate, in hex.
+    pub serial_hex: String,
+    /// The names in the certificate.
+    pub sans: Vec<String>,
+    /// When the certificate expires.
+    pub valid_until: SystemTime,
+}
+
+/// Persistence key for the list of kept certificates of a name.
+fn history_index_key<'a>(realm: &str, index_name: &'a str) -> PersistKey<'a> {
+    PersistKey::new(realm, PersistKind::Index, index_name)
+}
+
+fn index_name(name: &str) -> String {
+    format!("history:{}", name)
+}
+
+/// The generation comes first, since the name can have any character.
+fn generation_name(name: &str, generation: u64) -> String {
+    format!("{}:{}", generation, name)
+}
+
+pub(crate) fn read_history<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+) -> Result<Vec<CertificateMeta>> {
+    let index_name = index_name(name);
+    match persist.get_value(&history_index_key(realm, &index_name))? {
+        Some(v) if !v.is_empty() => Ok(serde_json::from_slice(&v)?),
+        _ => Ok(vec![]),
+    }
+}
+
+fn write_history<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+    history: &[CertificateMeta],
+) -> Result<()> {
+    let index_name = index_name(name);
+    let key = history_index_key(realm, &index_name);
+    if history.is_empty() {
+        remove_entry(persist, &key)?;
+        Ok(())
+    } else {
+        persist.put_value(&key, &serde_json::to_vec(history)?)
+    }
+}
+
+fn read_generation<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+    generation: u64,
+) -> Result<Option<Certificate>> {
+    let generation_name = generation_name(name, generation);
+    let key = PersistKey::new(realm, PersistKind::CertificateHistory, &generation_name);
+    match persist.get_value(&key)? {
+        Some(v) if !v.is_empty() => Ok(Some(serde_json::from_slice(&v)?)),
+        _ => Ok(None),
+    }
+}
+
+fn remove_generation<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+    generation: u64,
+) -> Result<()> {
+    let generation_name = generation_name(name, generation);
+    let key = PersistKey::new(realm, PersistKind::CertificateHistory, &generation_name);
+    remove_entry(persist, &key)?;
+    Ok(())
+}
+
+/// Keep the certificate saved under the name, before it's replaced, and remove the
+/// oldest beyond `keep`. Nothing is kept with `keep` 0.
+///
+/// A saved private key that doesn't belong to the certificate isn't kept.
+pub(crate) fn archive_certificate<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+    keep: usize,
+) -> Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let cert = match read_certificate(persist, realm, name)? {
+        Some(c) if !c.is_key_mismatch() => c,
+        _ => return Ok(()),
+    };
+    let mut history = read_history(persist, realm, name)?;
+    let generation = history.last().map(|m| m.generation + 1).unwrap_or(1);
+    let generation_name = generation_name(name, generation);
+    let key = PersistKey::new(realm, PersistKind::CertificateHistory, &generation_name);
+    debug!("Keep certificate: {}", key);
+    persist.put_value(&key, &serde_json::to_vec(&cert)?)?;
+    history.push(CertificateMeta {
+        generation,
+        archived_at: SystemTime::now(),
+        serial_hex: cert.serial_hex(),
+        sans: cert.sans(),
+        valid_until: cert.valid_until(),
+    });
+    prune(persist, realm, name, &mut history, keep)?;
+    write_history(persist, realm, name, &history)
+}
+
+/// Remove the oldest certificates beyond `keep`.
+fn prune<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+    history: &mut Vec<CertificateMeta>,
+    keep: usize,
+) -> Result<()> {
+    while history.len() > keep {
+        let oldest = history.remove(0);
+        debug!("Remove kept certificate {} of {}", oldest.generation, name);
+        remove_generation(persist, realm, name, oldest.generation)?;
+    }
+    Ok(())
+}
+
+/// Remove all kept certificates of the name.
+pub(crate) fn forget_history<P: Persist>(persist: &P, realm: &str, name: &str) -> Result<()> {
+    let mut history = read_history(persist, realm, name)?;
+    if history.is_empty() {
+        return Ok(());
+    }
+    prune(persist, realm, name, &mut history, 0)?;
+    write_history(persist, realm, name, &history)
+}
+
+impl<P: Persist> Account<P> {
+    /// Keep up to `keep` earlier certificates of each name, to [roll back] to.
+    ///
+    /// When a certificate is downloaded and saved, the certificate and private key saved
+    /// before are kept, and the oldest beyond `keep` are removed. The default 0 keeps
+    /// none. The setting is shared with clones of the account.
+    ///
+    /// [roll back]: struct.Account.html#method.rollback_certificate
+    pub fn set_cert_history(&self, keep: usize) {
+        self.inner.cert_history.store(keep, Ordering::SeqCst);
+    }
+
+    /// The earlier certificates kept for the name, oldest first. The name is looked up
+    /// the same way as for [`certificate`].
+    ///
+    /// [`certificate`]: struct.Account.html#method.certificate
+    pub fn certificate_history(&self, primary_name: &str) -> Result<Vec<CertificateMeta>> {
+        let name = self.history_name(primary_name)?;
+        read_history(&self.inner.persist, &self.inner.realm, &name)
+    }
+
+    /// Save a kept certificate as the certificate of the name again.
+    ///
+    /// The certificate it replaces is kept in turn, if [history] is on. The URL the
+    /// replaced certificate was downloaded from is removed, since it isn't the URL of the
+    /// kept certificate.
+    ///
+    /// [history]: struct.Account.html#method.set_cert_history
+    pub fn rollback_certificate(&self, primary_name: &str, generation: u64) -> Result<Certificate> {
+        let realm = &self.inner.realm;
+        let persist = &self.inner.persist;
+        let name = self.history_name(primary_name)?;
+        let cert = read_generation(persist, realm, &name, generation)?.ok_or_else(|| {
+            Error::Validation(format!(
+                "No kept certificate {} of {}",
+                generation, primary_name
+            ))
+        })?;
+        if cert.is_key_mismatch() {
+            return Err(Error::KeyCertMismatch { primary_name: name });
+        }
+        archive_certificate(
+            persist,
+            realm,
+            &name,
+            self.inner.cert_history.load(Ordering::SeqCst),
+        )?;
+        let sans = cert.sans();
+        let domains: Vec<&str> = sans.iter().map(|s| s.as_str()).collect();
+        save_certificate_as(
+            persist,
+            realm,
+            &name,
+            &domains,
+            Some(cert.private_key().as_bytes()),
+            cert.certificate().as_bytes(),
+        )?;
+        remove_entry(
+            persist,
+            &PersistKey::new(realm, PersistKind::CertificateUrl, &name),
+        )?;
+        Ok(cert)
+    }
+
+    /// The name certificates are kept under, a storage name if nothing is kept under
+    /// the primary name.
+    fn history_name(&self, primary_name: &str) -> Result<String> {
+        let realm = &self.inner.realm;
+        let persist = &self.inner.persist;
+        let name = lookup_name(primary_name);
+        if read_history(persist, realm, &name)?.is_empty() {
+            if let Some(storage_name) = find_cert_for_primary_name(persist, realm, &name)? {
+                return Ok(storage_name);
+            }
+        }
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acc::save_certificate;
+    use crate::persist::MemoryPersist;
+    use crate::test::test_self_signed;
+    use crate::{Directory, DirectoryUrl};
+
+    #[test]
+    fn test_cert_history() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let realm = acc.inner.realm.clone();
+        let name = "example.com";
+        let certs: Vec<_> = (0..4).map(|_| test_self_signed(name)).collect();
+        for (i, (key, cert)) in certs.iter().enumerate() {
+            if i > 0 {
+                archive_certificate(&persist, &realm, name, 2)?;
+            }
+            save_certificate(
+                &persist,
+                &realm,
+                name,
+                &[],
+                Some(key.as_bytes()),
+                cert.as_bytes(),
+            )?;
+        }
+        let history = acc.certificate_history("Example.COM")?;
+        let generations: Vec<_> = history.iter().map(|m| m.generation).collect();
+        assert_eq!(generations, vec![2, 3]);
+        let kept = Certificate::new(certs[1].0.clone(), certs[1].1.clone());
+        assert_eq!(history[0].serial_hex, kept.serial_hex());
+        assert_eq!(history[0].sans, vec![name]);
+        assert_eq!(read_generation(&persist, &realm, name, 1)?, None);
+
+        acc.set_cert_history(2);
+        let cert = acc.rollback_certificate(name, 2)?;
+        assert_eq!(cert.certificate(), certs[1].1);
+        assert_eq!(acc.certificate(name)?.unwrap().certificate(), certs[1].1);
+        let history = acc.certificate_history(name)?;
+        let generations: Vec<_> = history.iter().map(|m| m.generation).collect();
+        assert_eq!(generations, vec![3, 4]);
+        let newest = read_generation(&persist, &realm, name, 4)?.unwrap();
+        assert_eq!(newest.certificate(), certs[3].1);
+
+        let err = acc.rollback_certificate(name, 1).unwrap_err();
+        assert!(matches!(err, Error::Validation(_)), "{}", err);
+
+        assert!(acc.forget_certificate(name)?);
+        assert!(acc.certificate_history(name)?.is_empty());
+        assert_eq!(read_generation(&persist, &realm, name, 4)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cert_history_download() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let dir = Directory::from_url(MemoryPersist::new(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let download = || -> Result<Certificate> {
+            let ord = acc.new_order("valid.example.com", &[])?;
+            let (pri_key, pub_key) = crate::test::test_cert_key();
+            ord.confirm_validations()
+                .unwrap()
+                .finalize_pkey(pri_key, pub_key, 1)?
+                .download_and_save_cert()
+        };
+        download()?;
+        download()?;
+        assert!(acc.certificate_history("acmetest.example.com")?.is_empty());
+
+        acc.set_cert_history(1);
+        download()?;
+        download()?;
+        let history = acc.certificate_history("acmetest.example.com")?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].generation, 2);
+        assert_eq!(history[0].sans, vec!["acmetest.example.com"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::net::IpAddr;
+
+use super::mailto;
+use crate::api::ApiIdentifier;
+use crate::{Error, Result};
+
+/// Something to put in a certificate, used when creating an order with
+/// [`Account::new_order_identifiers`].
+///
+/// [`Account::new_order_identifiers`]: struct.Account.html#method.new_order_identifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identifier<'a> {
+    /// A domain name, such as `example.com` or `*.example.com`.
+    ///
+    /// The name is [normalized] before it's sent.
+    ///
+    /// [normalized]: struct.Account.html#method.new_order
+    Dns(&'a str),
+    /// An IPv4 or IPv6 address as specified in [RFC 8738]. Not supported by Let's Encrypt.
+    ///
+    /// [RFC 8738]: https://tools.ietf.org/html/rfc8738
+    Ip(&'a str),
+    /// An email address for S/MIME certificates as specified in [RFC 8823]. The ownership
+    /// is proven using the [email challenge].
+    ///
+    /// [RFC 8823]: https://tools.ietf.org/html/rfc8823
+    /// [email challenge]: order/struct.Auth.html#method.email_challenge
+    Email(&'a str),
+}
+
+impl<'a> Identifier<'a> {
+    /// The identifier for a name: an IP address if it parses as one, an email address if
+    /// it has an `@`, and else a domain name.
+    pub(crate) fn from_name(name: &'a str) -> Identifier<'a> {
+        if name.parse::<IpAddr>().is_ok() {
+            Identifier::Ip(name)
+        } else if name.contains('@') {
+            Identifier::Email(name)
+        } else {
+            Identifier::Dns(name)
+        }
+    }
+
+    /// The identifier as sent to the ACME API.
+    pub(crate) fn to_api(self) -> Result<ApiIdentifier> {
+        Ok(match self {
+            Identifier::Dns(name) => ApiIdentifier {
+                _type: "dns".into(),
+                value: normalize_dns(name)?,
+            },
+            Identifier::Ip(ip) => {
+                let addr: IpAddr = ip
+                    .parse()
+                    .map_err(|_| Error::Validation(format!("Not an IP address: {:?}", ip)))?;
+                // RFC 8738 wants IPv6 in the canonical text form.
+                ApiIdentifier {
+                    _type: "ip".into(),
+                    value: addr.to_string(),
+                }
+            }
+            Identifier::Email(email) => {
+                mailto(email)
+                    .map_err(|_| Error::Validation(format!("Not an email address: {:?}", email)))?;
+                ApiIdentifier {
+                    _type: "email".into(),
+                    value: email.to_string(),
+                }
+            }
+        })
+    }
+}
+
+/// The identifiers as sent to the ACME API, without duplicates.
+///
+/// All invalid identifiers are reported in one error.
+pub(crate) fn api_identifiers(identifiers: &[Identifier]) -> Result<Vec<ApiIdentifier>> {
+    if identifiers.is_empty() {
+        return Err(Error::Validation(
+            "An order needs at least one identifier".into(),
+        ));
+    }
+    let mut result: Vec<ApiIdentifier> = vec![];
+    let mut errors = vec![];
+    for id in identifiers {
+        match id.to_api() {
+            Ok(api) => {
+                let dupe = result
+                    .iter()
+                    .any(|r| r._type == api._type && r.value.eq_ignore_ascii_case(&api.value));
+                if dupe {
+                    debug!("Skip duplicate identifier: {}", api.value);
+                } else {
+                    result.push(api);
+                }
+            }
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::Validation(errors.join(", ")));
+    }
+    Ok(result)
+}
+
+/// Normalize a domain name to how the ACME API and the certificate want it.
+///
+/// ASCII is lowercased and a single trailing dot is removed. Unicode labels are converted
+/// to punycode A-labels, which requires the `idna` feature.
+pub(crate) fn normalize_dns(name: &str) -> Result<String> {
+    let invalid =
+        |reason: &str| Error::Validation(format!("Invalid domain name {:?}: {}", name, reason));
+
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    let (wildcard, domain) = match trimmed.strip_prefix("*.") {
+        Some(d) => ("*.", d),
+        None => ("", trimmed),
+    };
+
+    let ascii = if domain.is_ascii() {
+        domain.to_ascii_lowercase()
+    } else {
+        to_ascii(domain).map_err(|e| invalid(&e))?
+    };
+
+    if ascii.is_empty() {
+        return Err(invalid("empty"));
+    }
+    if ascii.len() > 253 {
+        return Err(invalid("longer than 253 characters"));
+    }
+    for label in ascii.split('.') {
+        if label.is_empty() {
+            return Err(invalid("empty label"));
+        }
+        if label.len() > 63 {
+            return Err(invalid("label longer than 63 characters"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid("label starts or ends with '-'"));
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || *c == '-'))
+        {
+            return Err(invalid(&format!("unexpected character {:?}", c)));
+        }
+    }
+
+    Ok(format!("{}{}", wildcard, ascii))
+}
+
+#[cfg(feature = "idna")]
+fn to_ascii(domain: &str) -> std::result::Result<String, String> {
+    idna::domain_to_ascii(domain).map_err(|_| "not a valid internationalized name".to_string())
+}
+
+#[cfg(not(feature = "idna"))]
+fn to_ascii(_domain: &str) -> std::result::Result<String, String> {
+    Err("non-ASCII names need the idna feature".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_api() -> Result<()> {
+        let id = Identifier::Dns("example.com").to_api()?;
+        assert!(id.is_type_dns());
+        assert_eq!(id.value, "example.com");
+        let id = Identifier::Ip("10.0.0.5").to_api()?;
+        assert!(id.is_type_ip());
+        assert_eq!(id.value, "10.0.0.5");
+        let id = Identifier::Ip("2001:DB8:0:0:0:0:0:1").to_api()?;
+        assert_eq!(id.value, "2001:db8::1");
+        assert!(Identifier::Ip("example.com").to_api().is_err());
+        let id = Identifier::Email("user@example.com").to_api()?;
+        assert!(id.is_type_email());
+        assert_eq!(id.value, "user@example.com");
+        assert!(Identifier::Email("example.com").to_api().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            Identifier::from_name("example.com"),
+            Identifier::Dns("example.com")
+        );
+        assert_eq!(
+            Identifier::from_name("10.0.0.5"),
+            Identifier::Ip("10.0.0.5")
+        );
+        assert_eq!(
+            Identifier::from_name("2001:db8::1"),
+            Identifier::Ip("2001:db8::1")
+        );
+        assert_eq!(
+            Identifier::from_name("user@example.com"),
+            Identifier::Email("user@example.com")
+        );
+    }
+
+    #[test]
+    fn test_api_identifiers() -> Result<()> {
+        let ids = api_identifiers(&[
+            Identifier::Dns("example.com"),
+            Identifier::Dns("Example.COM"),
+            Identifier::Dns("www.example.com"),
+            Identifier::Dns("example.com."),
+        ])?;
+        let values: Vec<_> = ids.iter().map(|i| i.value.as_str()).collect();
+        assert_eq!(values, vec!["example.com", "www.example.com"]);
+
+        assert!(api_identifiers(&[]).is_err());
+        let err = api_identifiers(&[
+            Identifier::Dns("ok.com"),
+            Identifier::Dns(""),
+            Identifier::Dns("a/b.com"),
+            Identifier::Ip("1.2.3"),
+        ])
+        .unwrap_err();
+        assert!(matches!(err, Error::Validation(_)));
+        let err = err.to_string();
+        assert!(err.contains(r#""""#), "{}", err);
+        assert!(err.contains(r#""a/b.com""#), "{}", err);
+        assert!(err.contains(r#""1.2.3""#), "{}", err);
+        assert!(!err.contains("ok.com"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_dns() -> Result<()> {
+        assert_eq!(normalize_dns("Example.COM.")?, "example.com");
+        assert_eq!(normalize_dns("*.Example.com")?, "*.example.com");
+        assert_eq!(normalize_dns("localhost")?, "localhost");
+        for bad in &[
+            "",
+            ".",
+            "example..com",
+            "-a.com",
+            "a_b.com",
+            "a b.com",
+            "*.*.a.com",
+        ] {
+            let err = normalize_dns(bad).unwrap_err().to_string();
+            assert!(err.contains(&format!("{:?}", bad)), "{}", err);
+        }
+        assert!(normalize_dns(&format!("{}.com", "a".repeat(64))).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "idna")]
+    #[test]
+    fn test_normalize_dns_idna() -> Result<()> {
+        assert_eq!(normalize_dns("Bücher.example")?, "xn--bcher-kva.example");
+        assert_eq!(
+            normalize_dns("*.bücher.example.")?,
+            "*.xn--bcher-kva.example"
+        );
+        Ok(())
+    }
+
+    #[cfg(not(feature = "idna"))]
+    #[test]
+    fn test_normalize_dns_no_idna() {
+        let err = normalize_dns("bücher.example").unwrap_err().to_string();
+        assert!(err.contains("idna feature"), "{}", err);
+    }
+}
@@ -1,26 +1,104 @@
 //
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
+use zeroize::Zeroizing;
 
-use crate::api::{ApiAccount, ApiDirectory, ApiIdentifier, ApiOrder, ApiRevocation};
+use crate::api::{
+    ApiAccount, ApiAuth, ApiDirectory, ApiEmptyString, ApiNewAuthz, ApiOrder, ApiOrderList,
+    ApiRevocation,
+};
 use crate::cert::Certificate;
-use crate::order::{NewOrder, Order};
-use crate::persist::{Persist, PersistKey, PersistKind};
-use crate::req::req_expect_header;
-use crate::trans::Transport;
-use crate::util::{base64url, read_json};
-use crate::Result;
+use crate::jwt::{jwk_thumb_json, jwk_thumbprint, KeyChange};
+use crate::order::{create_order, refresh_order, Auth, NewOrder, OrderEvent};
+use crate::persist::{Persist, PersistExt, PersistKey, PersistKind};
+use crate::req::{req_expect_header, req_link};
+use crate::trans::{jws_key_change, Transport};
+use crate::util::{base64url, read_json, rfc3339};
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
 
 mod akey;
+mod ari;
+mod ensure;
+mod export;
+mod history;
+mod ident;
+mod store;
 
 pub(crate) use self::akey::AcmeKey;
+pub use self::akey::{AccountKeyType, AcmeSigner};
+use self::ari::fetch_renewal_info;
+pub use self::ari::RenewalInfo;
+pub use self::ensure::{EnsureOptions, EnsureOutcome};
+pub use self::export::AccountExport;
+pub(crate) use self::history::archive_certificate;
+pub use self::history::CertificateMeta;
+pub use self::ident::Identifier;
+use self::ident::{api_identifiers, normalize_dns};
+use self::store::{cache_order_url, cached_order_url, evict_order_url};
+pub(crate) use self::store::{
+    find_cert_for_domain, find_cert_for_primary_name, forget_certificate, list_cert_names,
+    read_certificate, read_certificate_url, read_private_key, remove_entry, replace_certificate,
+    save_certificate, save_certificate_as, save_certificate_url,
+};
 
 #[derive(Clone)]
 pub(crate) struct AccountInner<P: Persist> {
     pub persist: P,
     pub transport: Transport,
     pub contact_email: String,
+    pub contact_emails: Vec<String>,
+    /// Identifies the account in the persistence.
+    pub realm: String,
     pub api_account: ApiAccount,
     pub api_directory: ApiDirectory,
+    /// How many earlier certificates to keep.
+    pub cert_history: Arc<AtomicUsize>,
+}
+
+/// Realm in the persistence for an account without contact email.
+pub(crate) const NO_CONTACT_REALM: &str = "acme_no_contact";
+
+/// Name in the persistence of the account private key and URL.
+pub(crate) const ACME_ACCOUNT_NAME: &str = "acme_account";
+
+/// Persistence key for the account private key.
+pub(crate) fn acme_key_persist_key(realm: &str) -> PersistKey<'static> {
+    PersistKey::new(realm, PersistKind::AccountPrivateKey, ACME_ACCOUNT_NAME)
+}
+
+/// Persistence key for the account URL, which is the key id used when signing requests.
+pub(crate) fn acme_url_persist_key(realm: &str) -> PersistKey<'static> {
+    PersistKey::new(realm, PersistKind::AccountUrl, ACME_ACCOUNT_NAME)
+}
+
+impl<P: Persist> AccountInner<P> {
+    /// Make a call using the key id.
+    ///
+    /// If the ACME API doesn't know the account, the persisted account URL is cleared so
+    /// the next access calls `newAccount` again.
+    pub(crate) fn call<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> Result<ureq::Response> {
+        let result = self.transport.call(url, body);
+        if let Err(Error::ApiProblem(problem)) = &result {
+            if problem.is_account_does_not_exist() {
+                debug!("Clear persisted account URL: {}", problem);
+                let url_key = acme_url_persist_key(&self.realm);
+                self.persist.put_value(&url_key, &[])?;
+            }
+        }
+        result
+    }
+
+    /// Pass the event to the hook of the directory, if any.
+    pub(crate) fn event(&self, event: OrderEvent) {
+        self.transport.events().fire(event);
+    }
 }
 
 /// Account with an ACME provider.
@@ -28,8 +106,9 @@ pub(crate) struct AccountInner<P: Persist> {
 /// Accounts are created using [`Directory::account`] and consist of a contact
 /// email address and a private key for signing requests to the ACME API.
 ///
-/// acme-lib uses elliptic curve P-256 for accessing the account. This
-/// does not affect which key algorithms that can be used for the
+/// acme-lib by default uses elliptic curve P-256 for accessing the account.
+/// Other [key types] can be chosen using [`Directory::account_with_keytype`].
+/// This does not affect which key algorithms that can be used for the
 /// issued certificates.
 ///
 /// The advantage of using elliptic curve cryptography is that the signed
@@ -37,6 +116,8 @@ pub(crate) struct AccountInner<P: Persist> {
 /// can be derived from the private.
 ///
 /// [`Directory::account`]: struct.Directory.html#method.account
+/// [`Directory::account_with_keytype`]: struct.Directory.html#method.account_with_keytype
+/// [key types]: enum.AccountKeyType.html
 #[derive(Clone)]
 pub struct Account<P: Persist> {
     inner: Arc<AccountInner<P>>,
@@ -46,7 +127,8 @@ impl<P: Persist> Account<P> {
     pub(crate) fn new(
         persist: P,
         transport: Transport,
-        contact_email: &str,
+        realm: &str,
+        contact_emails: Vec<String>,
         api_account: ApiAccount,
         api_directory: ApiDirectory,
     ) -> Self {
@@ -54,25 +136,108 @@ impl<P: Persist> Account<P> {
             inner: Arc::new(AccountInner {
                 persist,
                 transport,
-                contact_email: contact_email.into(),
+                contact_email: contact_emails.first().cloned().unwrap_or_default(),
+                contact_emails,
+                realm: realm.into(),
                 api_account,
                 api_directory,
+                cert_history: Arc::new(AtomicUsize::new(0)),
             }),
         }
     }
 
     /// Private key for this account.
     ///
-    /// The key is an elliptic curve private key.
-    pub fn acme_private_key_pem(&self) -> String {
-        String::from_utf8(self.inner.transport.acme_key().to_pem()).expect("from_utf8")
+    /// The key is an elliptic curve private key, unless another [key type] was used to
+    /// create the account.
+    ///
+    /// Errors for an account using an [external signer], since its key can't be exported.
+    ///
+    /// [key type]: enum.AccountKeyType.html
+    /// [external signer]: trait.AcmeSigner.html
+    pub fn acme_private_key_pem(&self) -> Result<String> {
+        Ok(self.acme_private_key_pem_secret()?.to_string())
     }
 
-    /// Contact email for this account.
+    /// Private key for this account, wiped from memory when dropped.
+    ///
+    /// Works like [`acme_private_key_pem`].
+    ///
+    /// [`acme_private_key_pem`]: struct.Account.html#method.acme_private_key_pem
+    pub fn acme_private_key_pem_secret(&self) -> Result<Zeroizing<String>> {
+        let mut pem = self
+            .inner
+            .transport
+            .acme_key()
+            .to_pem()
+            .ok_or("The account key is held by an external signer and can't be exported")?;
+        let pem = String::from_utf8(std::mem::take(&mut *pem)).expect("from_utf8");
+        Ok(Zeroizing::new(pem))
+    }
+
+    /// The RFC 7638 JWK thumbprint of the account key, base64url encoded without padding.
+    ///
+    /// This is the same thumbprint that is part of the challenge proofs.
+    pub fn jwk_thumbprint(&self) -> String {
+        jwk_thumbprint(self.inner.transport.acme_key())
+    }
+
+    /// The public key of the account as canonical JWK JSON.
+    ///
+    /// The JSON has only the required members of the key type in lexical order and no
+    /// whitespace, which is the input to the [`jwk_thumbprint`].
+    ///
+    /// [`jwk_thumbprint`]: struct.Account.html#method.jwk_thumbprint
+    pub fn public_jwk_json(&self) -> String {
+        jwk_thumb_json(self.inner.transport.acme_key())
+    }
+
+    /// Contact email this account was accessed with, or an empty string for an account
+    /// [without contact].
+    ///
+    /// The contact email also identifies the account in the persistence. This doesn't
+    /// change with [`update_contact`].
+    ///
+    /// [without contact]: struct.Directory.html#method.account_no_contact
+    /// [`update_contact`]: struct.Account.html#method.update_contact
     pub fn contact_email(&self) -> &str {
         &self.inner.contact_email
     }
 
+    /// All contact emails of this account, in the order they were given.
+    pub fn contact_emails(&self) -> &[String] {
+        &self.inner.contact_emails
+    }
+
+    /// Update the contact addresses of this account with the ACME API.
+    ///
+    /// The account and its persisted certificates are still identified by the contact
+    /// email used in [`Directory::account`]. Keep using that email to access the account
+    /// also after updating the contact; using one of the new addresses would create a new
+    /// account under a different identity.
+    ///
+    /// [`Directory::account`]: struct.Directory.html#method.account
+    pub fn update_contact(&mut self, emails: &[&str]) -> Result<()> {
+        let contact = emails
+            .iter()
+            .map(|e| mailto(e))
+            .collect::<Result<Vec<_>>>()?;
+
+        let kid = self.inner.transport.acme_key().key_id();
+        let acc = ApiAccount {
+            contact,
+            ..Default::default()
+        };
+        let res = self.inner.call(kid, &acc)?;
+        let api_account: ApiAccount = read_json(res)?;
+
+        let inner = Arc::make_mut(&mut self.inner);
+        inner.contact_emails = emails.iter().map(|e| e.to_string()).collect();
+        inner.api_account = api_account;
+
+        Ok(())
+    }
+
     /// Get an already issued and [downloaded] certificate.
     ///
     /// Every time a certificate is downloaded, the certificate and corresponding
@@ -82,31 +247,148 @@ impl<P: Persist> Account<P> {
     /// This can form the basis for implemeting automatic renewal of
     /// certificates where the [valid days left] are running low.
     ///
+    /// The name is the primary name, or the [storage name] of a certificate saved under
+    /// another name. When nothing is saved under a primary name, a certificate with the
+    /// primary name saved under a storage name is returned.
+    ///
+    /// Fails with [`Error::KeyCertMismatch`] if the saved private key doesn't belong to
+    /// the certificate.
+    ///
     /// [downloaded]: order/struct.CertOrder.html#method.download_and_save_cert
     /// [valid days left]: struct.Certificate.html#method.valid_days_left
+    /// [storage name]: order/struct.CertOrder.html#method.download_and_save_cert_as
+    /// [`Error::KeyCertMismatch`]: enum.Error.html#variant.KeyCertMismatch
     pub fn certificate(&self, primary_name: &str) -> Result<Option<Certificate>> {
-        // details needed for persistence
-        let realm = &self.inner.contact_email;
+        let (name, cert) = self.find_certificate(primary_name)?;
+        check_key_matches(cert, &name)
+    }
+
+    /// The certificate saved under the name, or else under a storage name for the primary
+    /// name, with the name it's saved under.
+    fn find_certificate(&self, primary_name: &str) -> Result<(String, Option<Certificate>)> {
+        let realm = &self.inner.realm;
         let persist = &self.inner.persist;
+        let name = lookup_name(primary_name);
+        let cert = read_certificate(persist, realm, &name)?;
+        if cert.is_none() {
+            if let Some(storage_name) = find_cert_for_primary_name(persist, realm, &name)? {
+                let cert = read_certificate(persist, realm, &storage_name)?;
+                return Ok((storage_name, cert));
+            }
+        }
+        Ok((name, cert))
+    }
 
-        // read primary key
-        let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, primary_name);
-        debug!("Read private key: {}", pk_key);
-        let private_key = persist
-            .get(&pk_key)?
-            .and_then(|s| String::from_utf8(s).ok());
-
-        // read certificate
-        let pk_crt = PersistKey::new(realm, PersistKind::Certificate, primary_name);
-        debug!("Read certificate: {}", pk_crt);
-        let certificate = persist
-            .get(&pk_crt)?
-            .and_then(|s| String::from_utf8(s).ok());
-
-        Ok(match (private_key, certificate) {
-            (Some(k), Some(c)) => Some(Certificate::new(k, c)),
-            _ => None,
-        })
+    /// Remove the certificate, its private key, the URL it was downloaded from and the
+    /// [kept earlier certificates] from the persistence. The name is looked up the same way as for [`certificate`].
+    ///
+    /// `true` if there was a certificate. This doesn't [revoke] the certificate.
+    ///
+    /// [kept earlier certificates]: struct.Account.html#method.set_cert_history
+    /// [`certificate`]: struct.Account.html#method.certificate
+    /// [revoke]: struct.Account.html#method.revoke_certificate
+    pub fn forget_certificate(&self, primary_name: &str) -> Result<bool> {
+        let (name, _) = self.find_certificate(primary_name)?;
+        forget_certificate(&self.inner.persist, &self.inner.realm, &name)
+    }
+
+    /// Like [`certificate`], without checking that the private key belongs to the
+    /// certificate. For recovering from a [`Error::KeyCertMismatch`]. The name is looked
+    /// up the same way.
+    ///
+    /// [`certificate`]: struct.Account.html#method.certificate
+    /// [`Error::KeyCertMismatch`]: enum.Error.html#variant.KeyCertMismatch
+    pub fn certificate_unchecked(&self, primary_name: &str) -> Result<Option<Certificate>> {
+        Ok(self.find_certificate(primary_name)?.1)
+    }
+
+    /// Download the certificate saved under the primary name again, from the URL it was
+    /// first downloaded from.
+    ///
+    /// The downloaded certificate replaces the saved one, the private key is kept. The
+    /// saved one is [kept] first, like for a new certificate. This helps when a
+    /// certificate was downloaded, but copying it elsewhere failed. Fails with
+    /// [`Error::CertificateGone`] if the ACME API no longer has the certificate.
+    ///
+    /// [kept]: struct.Account.html#method.set_cert_history
+    /// [`Error::CertificateGone`]: enum.Error.html#variant.CertificateGone
+    pub fn redownload_certificate(&self, primary_name: &str) -> Result<Certificate> {
+        let primary_name = lookup_name(primary_name);
+        let realm = &self.inner.realm;
+        let persist = &self.inner.persist;
+        let url = read_certificate_url(persist, realm, &primary_name)?
+            .ok_or_else(|| format!("No certificate URL saved for: {}", primary_name))?;
+
+        let res = match self.inner.call(&url, &ApiEmptyString) {
+            Err(Error::ApiProblem(p)) if p.is_not_found() => {
+                return Err(Error::CertificateGone(url));
+            }
+            r => r?,
+        };
+        let chain = res.into_string()?;
+
+        let private_key = read_private_key(persist, realm, &primary_name)?
+            .and_then(|k| String::from_utf8(k.to_vec()).ok())
+            .unwrap_or_default();
+        let cert = Certificate::new(private_key, chain);
+        archive_certificate(
+            persist,
+            realm,
+            &primary_name,
+            self.inner.cert_history.load(Ordering::SeqCst),
+        )?;
+        replace_certificate(persist, realm, &primary_name, cert.certificate().as_bytes())?;
+        Ok(cert)
+    }
+
+    /// Get an already issued and [downloaded] certificate that is valid for the domain.
+    ///
+    /// Unlike [`certificate`], this finds the certificate by any of its names, not just
+    /// the primary name. Wildcards match like for a TLS client, i.e. `*.example.com`
+    /// matches `foo.example.com`, but not `a.b.example.com`. A certificate with the exact
+    /// domain is preferred over a wildcard match.
+    ///
+    /// [downloaded]: order/struct.CertOrder.html#method.download_and_save_cert
+    /// [`certificate`]: struct.Account.html#method.certificate
+    pub fn certificate_for_domain(&self, domain: &str) -> Result<Option<Certificate>> {
+        let realm = &self.inner.realm;
+        let persist = &self.inner.persist;
+        match find_cert_for_domain(persist, realm, &lookup_name(domain))? {
+            Some(primary_name) => {
+                let cert = read_certificate(persist, realm, &primary_name)?;
+                check_key_matches(cert, &primary_name)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get all already issued and [downloaded] certificates, together with the primary
+    /// name of each.
+    ///
+    /// Like [`certificate`], this reads from the local storage. The certificates are found
+    /// using [`Persist::list`], in order of name, or the index of saved certificates when
+    /// the persistence can't list keys. Certificates whose private key or certificate
+    /// can't be read, or don't match, are skipped with a warning.
+    ///
+    /// [`Persist::list`]: trait.Persist.html#method.list
+    /// [downloaded]: order/struct.CertOrder.html#method.download_and_save_cert
+    /// [`certificate`]: struct.Account.html#method.certificate
+    pub fn certificates(&self) -> Result<Vec<(String, Certificate)>> {
+        let realm = &self.inner.realm;
+        let persist = &self.inner.persist;
+
+        let mut certs = vec![];
+        for primary_name in list_cert_names(persist, realm)? {
+            let cert = read_certificate(persist, realm, &primary_name)
+                .and_then(|c| check_key_matches(c, &primary_name));
+            match cert {
+                Ok(Some(cert)) => certs.push((primary_name, cert)),
+                Ok(None) => warn!("Missing private key or certificate: {}", primary_name),
+                Err(e) => warn!("Failed to read certificate {}: {}", primary_name, e),
+            }
+        }
+
+        Ok(certs)
     }
 
     /// Create a new order to issue a certificate for this account.
@@ -117,64 +399,553 @@ impl<P: Persist> Account<P> {
     /// This library doesn't constrain the number of `alt_names`, but it is limited by the ACME
     /// API provider. Let's Encrypt sets a max of [100 names] per certificate.
     ///
+    /// The names are normalized: lowercased and without a trailing dot. Unicode names such
+    /// as `bücher.example` are converted to punycode (`xn--bcher-kva.example`) when the
+    /// `idna` feature is enabled. The normalized names are used for the certificate and
+    /// in the persistence, but [`certificate`] also finds it by the original name. Names
+    /// that are the same after normalizing are only sent once. Names that aren't valid are
+    /// an [`Error::Validation`] naming each of them, before contacting the ACME API.
+    ///
     /// Every call creates a new order with the ACME API provider, even when the domain
-    /// names supplied are exactly the same.
+    /// names supplied are exactly the same. See [`new_order_or_reuse`] to avoid that.
     ///
     /// [100 names]: https://letsencrypt.org/docs/rate-limits/
+    /// [`new_order_or_reuse`]: struct.Account.html#method.new_order_or_reuse
+    /// [`certificate`]: struct.Account.html#method.certificate
+    /// [`Error::Validation`]: enum.Error.html#variant.Validation
     pub fn new_order(&self, primary_name: &str, alt_names: &[&str]) -> Result<NewOrder<P>> {
+        self.new_order_with_validity(primary_name, alt_names, None, None)
+    }
+
+    /// Create a new order, requesting the validity period of the certificate.
+    ///
+    /// The times are sent as `notBefore`/`notAfter` in the order. Not all ACME API
+    /// providers support this, Let's Encrypt for one rejects orders with these fields.
+    /// A rejection is returned as [`Error::ApiProblem`] as is.
+    ///
+    /// The CA can grant a different window than requested, check `notBefore`/`notAfter`
+    /// in [`api_order`] of the returned order.
+    ///
+    /// [`Error::ApiProblem`]: enum.Error.html#variant.ApiProblem
+    /// [`api_order`]: order/struct.NewOrder.html#method.api_order
+    pub fn new_order_with_validity(
+        &self,
+        primary_name: &str,
+        alt_names: &[&str],
+        not_before: Option<SystemTime>,
+        not_after: Option<SystemTime>,
+    ) -> Result<NewOrder<P>> {
         // construct the identifiers
         let prim_arr = [primary_name];
         let domains = prim_arr.iter().chain(alt_names);
+        let identifiers = domains.map(|s| Identifier::Dns(s)).collect::<Vec<_>>();
+        self.create_order(ApiOrder {
+            identifiers: api_identifiers(&identifiers)?,
+            notBefore: not_before.map(rfc3339).transpose()?,
+            notAfter: not_after.map(rfc3339).transpose()?,
+            ..Default::default()
+        })
+    }
+
+    /// Create a new order for the identifiers, which can mix domain names, IP addresses
+    /// and email addresses.
+    ///
+    /// The first identifier is the primary name, under which the certificate is saved
+    /// in the persistence, also when it's not a domain name. All identifiers become subject
+    /// alternative names of the certificate.
+    ///
+    /// IP and email addresses are not supported by Let's Encrypt, but by some other ACME
+    /// API providers.
+    pub fn new_order_identifiers(&self, identifiers: &[Identifier]) -> Result<NewOrder<P>> {
+        self.create_order(ApiOrder {
+            identifiers: api_identifiers(identifiers)?,
+            ..Default::default()
+        })
+    }
+
+    /// Create a new order to renew a certificate, telling the CA which certificate it
+    /// replaces.
+    ///
+    /// The old certificate is sent as `replaces` using its ARI identifier, the same as
+    /// for [`renewal_info`]. CAs supporting this can exempt the renewal from the rate
+    /// limits on duplicate certificates.
+    ///
+    /// An older CA may reject the unknown field as malformed, in which case the order is
+    /// created once more without it. The returned `bool` is `true` when that happened.
+    ///
+    /// [`renewal_info`]: struct.Account.html#method.renewal_info
+    pub fn new_order_replacing(
+        &self,
+        primary_name: &str,
+        alt_names: &[&str],
+        old_cert: &Certificate,
+    ) -> Result<(NewOrder<P>, bool)> {
+        let domains: Vec<Identifier> = [primary_name]
+            .iter()
+            .chain(alt_names)
+            .map(|s| Identifier::Dns(s))
+            .collect();
+        self.new_order_identifiers_replacing(&domains, old_cert)
+    }
+
+    /// Like [`new_order_replacing`], for identifiers of any type.
+    ///
+    /// [`new_order_replacing`]: struct.Account.html#method.new_order_replacing
+    fn new_order_identifiers_replacing(
+        &self,
+        identifiers: &[Identifier],
+        old_cert: &Certificate,
+    ) -> Result<(NewOrder<P>, bool)> {
         let order = ApiOrder {
-            identifiers: domains
-                .map(|s| ApiIdentifier {
-                    _type: "dns".into(),
-                    value: s.to_string(),
-                })
-                .collect(),
+            identifiers: api_identifiers(identifiers)?,
+            replaces: Some(old_cert.ari_cert_id()?),
+            ..Default::default()
+        };
+
+        match self.create_order(order.clone()) {
+            Err(Error::ApiProblem(p)) if p.is_malformed() => {
+                debug!("Order with replaces rejected, retry without: {}", p);
+                let order = ApiOrder {
+                    replaces: None,
+                    ..order
+                };
+                Ok((self.create_order(order)?, true))
+            }
+            res => Ok((res?, false)),
+        }
+    }
+
+    /// Create a new order for a certificate using one of the [profiles] offered by the
+    /// ACME API provider, such as Let's Encrypt's `shortlived`.
+    ///
+    /// A profile that isn't offered is an [`Error::Validation`] listing the offered ones,
+    /// before contacting the ACME API. If no profiles are offered at all, this fails with
+    /// [`Error::Unsupported`]. The profile granted is in [`api_order`] of the returned order.
+    ///
+    /// [profiles]: struct.Directory.html#method.profiles
+    /// [`Error::Validation`]: enum.Error.html#variant.Validation
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    /// [`api_order`]: order/struct.NewOrder.html#method.api_order
+    pub fn new_order_with_profile(
+        &self,
+        primary_name: &str,
+        alt_names: &[&str],
+        profile: &str,
+    ) -> Result<NewOrder<P>> {
+        let profiles = self
+            .inner
+            .api_directory
+            .meta
+            .as_ref()
+            .and_then(|m| m.profiles.as_ref())
+            .ok_or_else(|| Error::Unsupported("Certificate profiles".into()))?;
+        if !profiles.contains_key(profile) {
+            let mut names: Vec<_> = profiles.keys().map(|k| k.as_str()).collect();
+            names.sort_unstable();
+            return Err(Error::Validation(format!(
+                "Unknown certificate profile {:?}, offered are: {}",
+                profile,
+                names.join(", ")
+            )));
+        }
+
+        let domains: Vec<Identifier> = [primary_name]
+            .iter()
+            .chain(alt_names)
+            .map(|s| Identifier::Dns(s))
+            .collect();
+        self.create_order(ApiOrder {
+            identifiers: api_identifiers(&domains)?,
+            profile: Some(profile.to_string()),
             ..Default::default()
+        })
+    }
+
+    fn create_order(&self, order: ApiOrder) -> Result<NewOrder<P>> {
+        let order = create_order(&self.inner, &order)?;
+        Ok(NewOrder::new(order))
+    }
+
+    /// Create a new order, or reuse a recent one for the same domains.
+    ///
+    /// The URLs of orders created by this call are remembered in the persistence, keyed
+    /// on the set of domains. If there is such an order that is still `pending` or `ready`
+    /// and hasn't expired, it is used instead of creating a new one. This avoids piling
+    /// up orders when retrying, which counts towards the [rate limits]. Orders that can't
+    /// be used anymore are forgotten.
+    ///
+    /// [rate limits]: ../index.html#rate-limits
+    pub fn new_order_or_reuse(
+        &self,
+        primary_name: &str,
+        alt_names: &[&str],
+    ) -> Result<NewOrder<P>> {
+        let inner = &self.inner;
+        let domains: Vec<Identifier> = [primary_name]
+            .iter()
+            .chain(alt_names)
+            .map(|s| Identifier::Dns(s))
+            .collect();
+        let identifiers: Vec<String> = api_identifiers(&domains)?
+            .into_iter()
+            .map(|i| i.value)
+            .collect();
+
+        if let Some(url) = cached_order_url(&inner.persist, &inner.realm, &identifiers)? {
+            let order = match refresh_order(inner, url.clone(), "pending") {
+                Ok(order) => Some(NewOrder::new(order)),
+                // the order is gone.
+                Err(Error::ApiProblem(_)) => None,
+                Err(e) => return Err(e),
+            };
+            match order {
+                Some(order) if is_reusable(&order) => {
+                    debug!("Reuse order: {}", url);
+                    return Ok(order);
+                }
+                _ => evict_order_url(&inner.persist, &inner.realm, &url)?,
+            }
+        }
+
+        let order = self.new_order(primary_name, alt_names)?;
+        cache_order_url(&inner.persist, &inner.realm, &identifiers, order.url())?;
+        Ok(order)
+    }
+
+    /// Continue with an order created earlier, possibly by another process.
+    ///
+    /// The order state is fetched from the ACME API using the URL from [`NewOrder::url`].
+    /// Depending on how far the order has progressed, either provide the
+    /// [`authorizations`], [`confirm_validations`] to finalize, or [`confirm_certificate`]
+    /// to download the issued certificate.
+    ///
+    /// [`NewOrder::url`]: order/struct.NewOrder.html#method.url
+    /// [`authorizations`]: order/struct.NewOrder.html#method.authorizations
+    /// [`confirm_validations`]: order/struct.NewOrder.html#method.confirm_validations
+    /// [`confirm_certificate`]: order/struct.NewOrder.html#method.confirm_certificate
+    pub fn order_from_url(&self, order_url: &str) -> Result<NewOrder<P>> {
+        let order = refresh_order(&self.inner, order_url.to_string(), "pending")?;
+        Ok(NewOrder::new(order))
+    }
+
+    /// URLs of the orders of this account, following the pagination of the list.
+    ///
+    /// The list is fetched from the `orders` URL of the account. If the ACME API
+    /// provider doesn't have such a URL, the list is empty. Let's Encrypt currently
+    /// always returns an empty list. Use [`order_from_url`] to continue with an order.
+    ///
+    /// [`order_from_url`]: struct.Account.html#method.order_from_url
+    pub fn orders(&self) -> Result<Vec<String>> {
+        // an account read from the persistence doesn't know its orders URL.
+        let orders_url = match &self.inner.api_account.orders {
+            Some(url) => Some(url.clone()),
+            None => self.verify()?.orders,
+        };
+
+        let mut result = vec![];
+        let mut next = orders_url;
+        while let Some(url) = next {
+            let res = self.inner.call(&url, &ApiEmptyString)?;
+            next = req_link(&res, "next");
+            let list: ApiOrderList = read_json(res)?;
+            result.extend(list.orders);
+        }
+        Ok(result)
+    }
+
+    /// Authorize a domain before ordering a certificate for it, also called
+    /// pre-authorization.
+    ///
+    /// The authorization is proven using the same challenges as for an order. A later
+    /// order for the domain doesn't need a new authorization, as long as this one is
+    /// still valid.
+    ///
+    /// Only some ACME API providers support this, by providing a `newAuthz` URL in the
+    /// directory. Let's Encrypt doesn't.
+    pub fn new_authorization(&self, domain: &str) -> Result<Auth<P>> {
+        let new_authz_url = match &self.inner.api_directory.newAuthz {
+            Some(url) => url,
+            None => return Err(Error::Unsupported("Pre-authorization".into())),
         };
 
-        let new_order_url = &self.inner.api_directory.newOrder;
+        let new_authz = ApiNewAuthz {
+            identifier: Identifier::Dns(domain).to_api()?,
+        };
 
-        let res = self.inner.transport.call(new_order_url, &order)?;
-        let order_url = req_expect_header(&res, "location")?;
-        let api_order: ApiOrder = read_json(res)?;
+        let res = self.inner.call(new_authz_url, &new_authz)?;
+        let auth_url = req_expect_header(&res, "location")?;
+        let api_auth: ApiAuth = read_json(res)?;
 
-        let order = Order::new(&self.inner, api_order, order_url);
-        Ok(NewOrder { order })
+        Ok(Auth::new(&self.inner, api_auth, &auth_url))
+    }
+
+    /// Ask the CA when to renew the certificate, using ACME Renewal Information (ARI).
+    ///
+    /// The certificate is identified by its authority key identifier and serial number.
+    /// Ask again after [`retry_after`], since the CA may change the suggested window.
+    ///
+    /// Fails with [`Error::Unsupported`] if the ACME API doesn't provide a `renewalInfo`
+    /// URL in the directory.
+    ///
+    /// [`retry_after`]: struct.RenewalInfo.html#structfield.retry_after
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    pub fn renewal_info(&self, cert: &Certificate) -> Result<RenewalInfo> {
+        match &self.inner.api_directory.renewalInfo {
+            Some(url) => fetch_renewal_info(url, cert),
+            None => Err(Error::Unsupported("Renewal information".into())),
+        }
     }
 
-    /// Revoke a certificate for the reason given.
+    /// Revoke a certificate for the reason given, if any.
     ///
     /// This calls the ACME API revoke endpoint, but does not affect the locally persisted
     /// certs, the revoked certificate will still be available using [`certificate`].
+    /// Revoking a certificate that is already revoked succeeds. See [`RevocationReason`]
+    /// for the reasons that are allowed.
     ///
     /// [`certificate`]: struct.Account.html#method.certificate
-    pub fn revoke_certificate(&self, cert: &Certificate, reason: RevocationReason) -> Result<()> {
+    /// [`RevocationReason`]: enum.RevocationReason.html
+    pub fn revoke_certificate(
+        &self,
+        cert: &Certificate,
+        reason: Option<RevocationReason>,
+    ) -> Result<()> {
+        check_revocation_reason(reason)?;
         // convert to base64url of the DER (which is not PEM).
         let certificate = base64url(&cert.certificate_der());
 
         let revoc = ApiRevocation {
             certificate,
-            reason: reason as usize,
+            reason,
         };
 
         let url = &self.inner.api_directory.revokeCert;
-        self.inner.transport.call(url, &revoc)?;
+        match self.inner.call(url, &revoc) {
+            Err(Error::ApiProblem(p)) if p.is_already_revoked() => {
+                debug!("Certificate already revoked: {}", p);
+                Ok(())
+            }
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// Change the private key of this account, also called key rollover.
+    ///
+    /// A new key of the same type as the current one is created and registered with the
+    /// ACME API using the `keyChange` endpoint. On success the new key is persisted in place
+    /// of the old, and the returned account uses the new key for all further calls.
+    ///
+    /// This account, and any orders created from it, keep using the old key, which the ACME
+    /// API no longer accepts.
+    ///
+    /// The key of an account using an [external signer] can't be changed this way.
+    ///
+    /// [external signer]: trait.AcmeSigner.html
+    pub fn change_key(&self) -> Result<Account<P>> {
+        let url = &self.inner.api_directory.keyChange;
+        let old_transport = &self.inner.transport;
+        let key_type = old_transport
+            .acme_key()
+            .key_type()
+            .ok_or("Can't change the key of an external signer")?;
+        let new_key = AcmeKey::new(key_type)?;
+
+        let new_transport = key_change(old_transport, new_key, url)?;
+
+        // save the new key in place of the old
+        let pem_key = acme_key_persist_key(&self.inner.realm);
+        let pem = new_transport.acme_key().to_pem().expect("to_pem");
+        if let Err(e) = self.inner.persist.put_value(&pem_key, &pem) {
+            // The ACME API expects the new key now, but we failed to save it.
+            // Go back to the old key, which is still persisted.
+            warn!(
+                "Failed to persist new account key, revert key change: {}",
+                e
+            );
+            key_change(&new_transport, old_transport.acme_key().clone(), url)?;
+            return Err(e);
+        }
+
+        let mut inner = (*self.inner).clone();
+        inner.transport = new_transport;
+        Ok(Account {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Deactivate this account with the ACME API.
+    ///
+    /// A deactivated account can't be used again. The persisted account key is cleared,
+    /// which means a later [`Directory::account`] for the same contact email creates a
+    /// new account.
+    ///
+    /// [`Directory::account`]: struct.Directory.html#method.account
+    pub fn deactivate(self) -> Result<()> {
+        let kid = self.inner.transport.acme_key().key_id();
+        let acc = ApiAccount {
+            status: Some("deactivated".into()),
+            ..Default::default()
+        };
+        let res = self.inner.call(kid, &acc)?;
+        let api_account: ApiAccount = read_json(res)?;
+
+        if !api_account.is_status_deactivated() {
+            return Err(format!("Account is in status: {:?}", api_account.status).into());
+        }
+
+        let pem_key = acme_key_persist_key(&self.inner.realm);
+        remove_entry(&self.inner.persist, &pem_key)?;
+        let url_key = acme_url_persist_key(&self.inner.realm);
+        remove_entry(&self.inner.persist, &url_key)?;
 
         Ok(())
     }
 
+    /// The realm of the account, without the scope of the directory.
+    fn account_realm(&self) -> &str {
+        if self.inner.contact_email.is_empty() {
+            NO_CONTACT_REALM
+        } else {
+            &self.inner.contact_email
+        }
+    }
+
+    /// Export the account, for importing into another persistence using
+    /// [`Directory::import_account`].
+    ///
+    /// The export contains the account private key, URL and contact emails, but no
+    /// certificates. See [`export_with_certificates`].
+    ///
+    /// [`Directory::import_account`]: struct.Directory.html#method.import_account
+    /// [`export_with_certificates`]: struct.Account.html#method.export_with_certificates
+    pub fn export(&self) -> Result<AccountExport> {
+        self.export_with_certificates(&[])
+    }
+
+    /// Export the account together with the persisted certificates for the given primary
+    /// names.
+    ///
+    /// Names without a persisted certificate are skipped. To export all saved
+    /// certificates, give the names returned by [`certificates`].
+    ///
+    /// [`certificates`]: struct.Account.html#method.certificates
+    pub fn export_with_certificates(&self, primary_names: &[&str]) -> Result<AccountExport> {
+        let mut certificates = BTreeMap::new();
+        for primary_name in primary_names {
+            if let Some(cert) = self.certificate(primary_name)? {
+                certificates.insert(primary_name.to_string(), cert);
+            }
+        }
+
+        Ok(AccountExport {
+            realm: self.account_realm().to_string(),
+            contact_emails: self.inner.contact_emails.clone(),
+            private_key_pem: self.acme_private_key_pem()?,
+            account_url: self.inner.transport.acme_key().key_id().to_string(),
+            certificates,
+        })
+    }
+
+    /// Confirm with the ACME API that this account is still valid.
+    ///
+    /// An account read from the persistence is used without contacting the ACME API.
+    /// This does a "POST-as-GET" against the account URL and returns the current account
+    /// object, or an error if the account isn't valid anymore.
+    pub fn verify(&self) -> Result<ApiAccount> {
+        let kid = self.inner.transport.acme_key().key_id();
+        let res = self.inner.call(kid, &ApiEmptyString)?;
+        let api_account: ApiAccount = read_json(res)?;
+
+        if !api_account.is_status_valid() {
+            return Err(format!("Account is in status: {:?}", api_account.status).into());
+        }
+
+        Ok(api_account)
+    }
+
     /// Access the underlying JSON object for debugging.
+    ///
+    /// For an account read from the persistence, no call is made to the ACME API and this
+    /// is empty. Use [`verify`] to get the current object.
+    ///
+    /// [`verify`]: struct.Account.html#method.verify
     pub fn api_account(&self) -> &ApiAccount {
         &self.inner.api_account
     }
 }
 
+/// The name as saved in the persistence, normalized if it's a domain name.
+pub(crate) fn lookup_name(name: &str) -> String {
+    normalize_dns(name).unwrap_or_else(|_| name.to_string())
+}
+
+/// Fail for a saved certificate whose private key belongs to another certificate.
+///
+/// Certificates saved without a private key, or with contents that can't be read, are
+/// returned as they are.
+fn check_key_matches(cert: Option<Certificate>, primary_name: &str) -> Result<Option<Certificate>> {
+    match cert {
+        Some(c) if c.is_key_mismatch() => Err(Error::KeyCertMismatch {
+            primary_name: primary_name.to_string(),
+        }),
+        c => Ok(c),
+    }
+}
+
+/// Whether an order can still be used to get a certificate.
+fn is_reusable<P: Persist>(order: &NewOrder<P>) -> bool {
+    let api_order = order.api_order();
+    let expired = order
+        .expires()
+        .map(|t| t <= SystemTime::now())
+        .unwrap_or(false);
+    (api_order.is_status_pending() || api_order.is_status_ready()) && !expired
+}
+
+/// Make a contact URI from an email address.
+pub(crate) fn mailto(email: &str) -> Result<String> {
+    let valid = email.contains('@')
+        && !email.starts_with('@')
+        && !email.ends_with('@')
+        && !email.chars().any(|c| c.is_whitespace() || c == ',');
+    if !valid {
+        return Err(format!("Invalid contact email: {:?}", email).into());
+    }
+    Ok(format!("mailto:{}", email))
+}
+
+/// Register `new_key` in place of the key in the `transport`.
+fn key_change(transport: &Transport, new_key: AcmeKey, url: &str) -> Result<Transport> {
+    let old_key = transport.acme_key();
+    let kid = old_key.key_id().to_string();
+
+    // inner JWS signed by the new key, wrapped in an outer signed by the old.
+    let key_change = KeyChange {
+        account: kid.clone(),
+        old_key: old_key.into(),
+    };
+    let inner_jws = jws_key_change(url, &new_key, &key_change)?;
+    transport.call(url, &inner_jws)?;
+
+    let mut new_transport = transport.with_acme_key(new_key);
+    new_transport.set_key_id(kid);
+    Ok(new_transport)
+}
+
 /// Enumeration of reasons for revocation.
 ///
-/// The reason codes are taken from [rfc5280](https://tools.ietf.org/html/rfc5280#section-5.3.1).
+/// The reason codes are taken from [rfc5280](https://tools.ietf.org/html/rfc5280#section-5.3.1),
+/// and serialize to their numeric value.
+///
+/// Only `Unspecified`, `KeyCompromise`, `AffiliationChanged`, `Superseded` and
+/// `CessationOfOperation` apply to a subscriber's certificate, and are the ones Let's
+/// Encrypt accepts. The others are rejected before calling the ACME API: they're about
+/// CA or attribute certificates, suspension, which ACME revocation can't undo, or delta
+/// CRLs.
+///
+/// `KeyCompromise` makes Let's Encrypt block the key, so no certificate is ever issued
+/// for it again, whatever the account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RevocationReason {
     Unspecified = 0,
     KeyCompromise = 1,
@@ -189,8 +960,83 @@ pub enum RevocationReason {
     AACompromise = 10,
 }
 
+impl RevocationReason {
+    /// The numeric CRLReason code.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// The reason of the code, `None` for codes that aren't defined.
+    pub fn from_code(code: u8) -> Option<RevocationReason> {
+        use RevocationReason::*;
+        [
+            Unspecified,
+            KeyCompromise,
+            CACompromise,
+            AffiliationChanged,
+            Superseded,
+            CessationOfOperation,
+            CertificateHold,
+            RemoveFromCRL,
+            PrivilegeWithdrawn,
+            AACompromise,
+        ]
+        .iter()
+        .copied()
+        .find(|r| r.code() == code)
+    }
+
+    /// Whether the reason can be given when revoking a subscriber's certificate.
+    pub fn is_allowed(self) -> bool {
+        matches!(
+            self,
+            RevocationReason::Unspecified
+                | RevocationReason::KeyCompromise
+                | RevocationReason::AffiliationChanged
+                | RevocationReason::Superseded
+                | RevocationReason::CessationOfOperation
+        )
+    }
+}
+
+impl Serialize for RevocationReason {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for RevocationReason {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let code = u8::deserialize(deserializer)?;
+        RevocationReason::from_code(code)
+            .ok_or_else(|| serde::de::Error::custom(format!("Unknown revocation reason: {}", code)))
+    }
+}
+
+/// Fail for a reason that can't be given for a subscriber's certificate.
+pub(crate) fn check_revocation_reason(reason: Option<RevocationReason>) -> Result<()> {
+    match reason {
+        Some(r) if !r.is_allowed() => Err(Error::Validation(format!(
+            "Revocation reason {:?} ({}) is not allowed for a subscriber's certificate",
+            r,
+            r.code()
+        ))),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::{
+        acme_key_persist_key, acme_url_persist_key, save_certificate, save_certificate_as,
+        save_certificate_url,
+    };
+    use crate::api::ApiAccount;
     use crate::persist::*;
     use crate::*;
 
@@ -204,4 +1050,680 @@ mod test {
         let _ = acc.new_order("acmetest.example.com", &[])?;
         Ok(())
     }
+
+    #[test]
+    fn test_new_order_with_validity() -> Result<()> {
+        use std::time::{Duration, UNIX_EPOCH};
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_546_300_800);
+        let not_after = not_before + Duration::from_secs(24 * 60 * 60);
+        let ord = acc.new_order_with_validity(
+            "acmetest.example.com",
+            &[],
+            Some(not_before),
+            Some(not_after),
+        )?;
+        let api_order = ord.api_order();
+        assert_eq!(api_order.notBefore.as_deref(), Some("2019-01-01T00:00:00Z"));
+        assert_eq!(api_order.notAfter.as_deref(), Some("2019-01-02T00:00:00Z"));
+        // not sent unless asked for
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        assert_eq!(ord.api_order().notBefore, None);
+        assert_eq!(ord.api_order().notAfter, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_order_replacing() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let old_cert = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            crate::test::TEST_CERT_PEM.into(),
+        );
+        let (ord, fallback) = acc.new_order_replacing("acmetest.example.com", &[], &old_cert)?;
+        assert!(!fallback);
+        assert_eq!(
+            ord.api_order().replaces.as_deref(),
+            Some("aYhba4dGQEHhs3uEe6CuLN4ByNQ.AIdlQyE")
+        );
+        // a CA that rejects the field
+        let (ord, fallback) = acc.new_order_replacing("noari.example.com", &[], &old_cert)?;
+        assert!(fallback);
+        assert_eq!(ord.api_order().replaces, None);
+        assert_eq!(ord.api_order().domains(), vec!["noari.example.com"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_order_with_profile() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let mut acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order_with_profile("acmetest.example.com", &[], "shortlived")?;
+        assert_eq!(ord.api_order().profile.as_deref(), Some("shortlived"));
+        assert_eq!(
+            acc.new_order("acmetest.example.com", &[])?
+                .api_order()
+                .profile,
+            None
+        );
+
+        let err = acc
+            .new_order_with_profile("acmetest.example.com", &[], "tlsserver")
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Validation(_)));
+        assert!(err.to_string().contains("classic, shortlived"), "{}", err);
+
+        std::sync::Arc::make_mut(&mut acc.inner).api_directory.meta = None;
+        let err = acc
+            .new_order_with_profile("acmetest.example.com", &[], "classic")
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Unsupported(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_order_identifiers() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order_identifiers(&[
+            Identifier::Ip("10.0.0.5"),
+            Identifier::Dns("acmetest.example.com"),
+        ])?;
+        let ids = &ord.api_order().identifiers;
+        assert_eq!(ids.len(), 2);
+        assert!(ids[0].is_type_ip());
+        assert_eq!(ids[0].value, "10.0.0.5");
+        assert!(ids[1].is_type_dns());
+        assert!(acc
+            .new_order_identifiers(&[Identifier::Ip("10.0.0")])
+            .is_err());
+        assert!(acc.new_order_identifiers(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_order_or_reuse() -> Result<()> {
+        use super::store::{cache_order_url, cached_order_url};
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let realm = acc.inner.realm.clone();
+        let ids = vec!["acmetest.example.com".to_string()];
+        let base = server.dir_url.replace("/directory", "");
+
+        // the first order is remembered
+        let ord = acc.new_order_or_reuse("acmetest.example.com", &[])?;
+        let cached = cached_order_url(&persist, &realm, &ids)?;
+        assert_eq!(cached.as_deref(), Some(ord.url()));
+
+        // a ready, not expired order is reused
+        let ready = format!("{}/acme/order/ready", base);
+        cache_order_url(&persist, &realm, &ids, &ready)?;
+        let ord = acc.new_order_or_reuse("acmetest.example.com", &[])?;
+        assert_eq!(ord.url(), ready);
+        assert!(ord.is_validated());
+
+        // expired, issued or gone orders are replaced by a new order
+        for path in &["expired", "issued", "gone"] {
+            let old = format!("{}/acme/order/{}", base, path);
+            cache_order_url(&persist, &realm, &ids, &old)?;
+            let ord = acc.new_order_or_reuse("acmetest.example.com", &[])?;
+            assert!(ord.url().ends_with("/acme/order/YTqpYUthlVfwBncUufE8"));
+            let cached = cached_order_url(&persist, &realm, &ids)?;
+            assert_eq!(cached.as_deref(), Some(ord.url()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_authorization() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let mut acc = dir.account("foo@bar.com")?;
+        let auth = acc.new_authorization("acmetest.example.com")?;
+        assert!(auth.need_challenge());
+        assert!(auth.dns_challenge().need_validate());
+        assert!(acc.new_authorization("bad name").is_err());
+
+        std::sync::Arc::make_mut(&mut acc.inner)
+            .api_directory
+            .newAuthz = None;
+        let err = acc.new_authorization("acmetest.example.com").err().unwrap();
+        assert!(matches!(err, Error::Unsupported(_)));
+        assert!(err.to_string().contains("not supported"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_renewal_info() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let mut acc = dir.account("foo@bar.com")?;
+        let cert = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            crate::test::TEST_CERT_PEM.into(),
+        );
+        let info = acc.renewal_info(&cert)?;
+        assert_eq!(
+            info.suggested_window_start,
+            crate::util::parse_rfc3339("2025-01-02T04:00:00Z").unwrap()
+        );
+        assert_eq!(
+            info.suggested_window_end,
+            crate::util::parse_rfc3339("2025-01-03T04:00:00Z").unwrap()
+        );
+        assert_eq!(
+            info.explanation_url.as_deref(),
+            Some("https://acme.example.com/docs/ari")
+        );
+        assert_eq!(
+            info.retry_after,
+            Some(std::time::Duration::from_secs(21600))
+        );
+        assert!(info.should_renew());
+
+        std::sync::Arc::make_mut(&mut acc.inner)
+            .api_directory
+            .renewalInfo = None;
+        let err = acc.renewal_info(&cert).err().unwrap();
+        assert!(matches!(err, Error::Unsupported(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_orders() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let orders = acc.orders()?;
+        assert_eq!(orders.len(), 2);
+        assert!(orders[0].ends_with("/acme/order/YTqpYUthlVfwBncUufE8"));
+        assert!(orders[1].ends_with("/acme/order/issued"));
+        let ord = acc.order_from_url(&orders[1])?;
+        assert!(ord.is_validated());
+        // the account URL of the test server has no orders, as for a CA without them.
+        let acc = dir.account("foo@bar.com")?;
+        assert!(acc.orders()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deactivate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc1 = dir.account("foo@bar.com")?;
+        let pem1 = acc1.acme_private_key_pem()?;
+        let pk_key = acme_key_persist_key(&acc1.inner.realm);
+        acc1.deactivate()?;
+        assert_eq!(dir.persist().get_value(&pk_key)?, None);
+        // a new account key is created
+        let acc2 = dir.account("foo@bar.com")?;
+        assert!(pem1 != acc2.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_contact() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let mut acc = dir.account("foo@bar.com")?;
+        assert!(acc.update_contact(&["not an email"]).is_err());
+        acc.update_contact(&["ops@bar.com"])?;
+        assert_eq!(acc.api_account().contact, vec!["mailto:ops@bar.com"]);
+        // still the same account in the persistence
+        assert_eq!(acc.contact_email(), "foo@bar.com");
+        assert_eq!(acc.contact_emails(), &["ops@bar.com".to_string()]);
+        let acc2 = dir.account("foo@bar.com")?;
+        assert_eq!(acc.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_account_url() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let kid = acc.inner.transport.acme_key().key_id().to_string();
+        let url_key = acme_url_persist_key(&acc.inner.realm);
+        assert_eq!(persist.get_value(&url_key)?, Some(kid.as_bytes().to_vec()));
+
+        // read from persistence, then verified with the API.
+        let acc = dir.account("foo@bar.com")?;
+        assert_eq!(acc.api_account(), &ApiAccount::default());
+        assert!(acc.verify()?.is_status_valid());
+
+        // an account URL the API doesn't know is cleared.
+        let gone = kid.replace("7728515", "gone");
+        persist.put_value(&url_key, gone.as_bytes())?;
+        let acc = dir.account("foo@bar.com")?;
+        assert!(acc.verify().is_err());
+        assert_eq!(persist.get_value(&url_key)?, Some(vec![]));
+        let acc = dir.account("foo@bar.com")?;
+        assert_eq!(acc.inner.transport.acme_key().key_id(), kid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist1 = MemoryPersist::new();
+        let dir1 = Directory::from_url(persist1.clone(), url)?;
+        let acc1 = dir1.account("foo@bar.com")?;
+        let pk_key = PersistKey::new(&acc1.inner.realm, PersistKind::PrivateKey, "example.com");
+        persist1.put_value(&pk_key, b"KEY HERE")?;
+        let pk_crt = PersistKey::new(&acc1.inner.realm, PersistKind::Certificate, "example.com");
+        persist1.put_value(&pk_crt, b"CERT HERE")?;
+
+        let export = acc1.export_with_certificates(&["example.com", "missing.com"])?;
+        assert_eq!(export.certificates.len(), 1);
+        let json = serde_json::to_string(&export)?;
+        let export: AccountExport = serde_json::from_str(&json)?;
+
+        let persist2 = MemoryPersist::new();
+        let dir2 = Directory::from_url(persist2, DirectoryUrl::Other(&server.dir_url))?;
+        let acc2 = dir2.import_account(export)?;
+        assert_eq!(acc1.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        assert_eq!(acc2.contact_emails(), acc1.contact_emails());
+        assert_eq!(
+            acc1.certificate("example.com")?,
+            acc2.certificate("example.com")?
+        );
+        // found again without the import
+        let acc3 = dir2.account("foo@bar.com")?;
+        assert_eq!(acc1.acme_private_key_pem()?, acc3.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_certificates() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        assert!(acc.certificates()?.is_empty());
+        let realm = &acc.inner.realm;
+
+        save_certificate(&persist, realm, "a.com", &[], Some(b"KEY A"), b"CERT A")?;
+        save_certificate(&persist, realm, "b.com", &[], Some(b"KEY B"), b"CERT B")?;
+        save_certificate(&persist, realm, "a.com", &[], Some(b"KEY A2"), b"CERT A2")?;
+        // a broken entry is skipped
+        save_certificate(&persist, realm, "c.com", &[], Some(b"KEY C"), b"CERT C")?;
+        let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, "c.com");
+        persist.put_value(&pk_key, &[0xff])?;
+
+        let certs = acc.certificates()?;
+        let names: Vec<_> = certs.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["a.com", "b.com"]);
+        assert_eq!(certs[0].1.certificate(), "CERT A2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_forget_certificate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let realm = &acc.inner.realm;
+
+        save_certificate(&persist, realm, "a.com", &[], Some(b"KEY A"), b"CERT A")?;
+        save_certificate_url(&persist, realm, "a.com", "https://ca.example/cert/a")?;
+        save_certificate(&persist, realm, "b.com", &[], Some(b"KEY B"), b"CERT B")?;
+        save_certificate_as(
+            &persist,
+            realm,
+            "c-rsa",
+            &["c.com"],
+            Some(b"KEY C"),
+            b"CERT C",
+        )?;
+
+        assert!(acc.forget_certificate("a.com")?);
+        assert!(!acc.forget_certificate("a.com")?);
+        for kind in &[
+            PersistKind::Certificate,
+            PersistKind::PrivateKey,
+            PersistKind::CertificateUrl,
+        ] {
+            assert_eq!(
+                persist.get_value(&PersistKey::new(realm, *kind, "a.com"))?,
+                None
+            );
+        }
+        // by the primary name of a certificate saved under a storage name
+        assert!(acc.forget_certificate("c.com")?);
+        assert_eq!(
+            super::store::read_cert_index(&persist, realm)?,
+            vec!["b.com"]
+        );
+        assert!(acc.certificate_unchecked("b.com")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_cert_mismatch() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let realm = &acc.inner.realm;
+        let domains = ["acmetest.example.com"];
+        let cert_pem = crate::test::TEST_CERT_PEM.as_bytes();
+        let key_pem = crate::test::TEST_CERT_KEY_PEM.as_bytes();
+        save_certificate(
+            &persist,
+            realm,
+            domains[0],
+            &domains,
+            Some(key_pem),
+            cert_pem,
+        )?;
+        let cert = acc.certificate("acmetest.example.com")?.unwrap();
+        assert!(cert.key_matches_cert());
+
+        let other = crate::create_p256_key()
+            .0
+            .private_key_to_pem_pkcs8()
+            .unwrap();
+        save_certificate(
+            &persist,
+            realm,
+            domains[0],
+            &domains,
+            Some(&other),
+            cert_pem,
+        )?;
+        let err = acc.certificate("acmetest.example.com").unwrap_err();
+        assert!(
+            matches!(&err, Error::KeyCertMismatch { primary_name } if primary_name == domains[0]),
+            "{}",
+            err
+        );
+        assert!(acc.certificate_for_domain("acmetest.example.com").is_err());
+        assert!(acc.certificates()?.is_empty());
+        let cert = acc.certificate_unchecked("acmetest.example.com")?.unwrap();
+        assert!(!cert.key_matches_cert());
+        assert_eq!(cert.private_key().as_bytes(), &other[..]);
+
+        // by the primary name of a certificate saved under a storage name
+        let save_as = |name: &str, primary_name: &str, key: &[u8]| {
+            save_certificate_as(&persist, realm, name, &[primary_name], Some(key), cert_pem)
+        };
+        save_as("good-rsa", "good.example.com", key_pem)?;
+        save_as("bad-rsa", "bad.example.com", &other)?;
+        let good = acc.certificate("good.example.com")?.unwrap();
+        assert_eq!(acc.certificate_unchecked("good.example.com")?, Some(good));
+        let err = acc.certificate("bad.example.com").unwrap_err();
+        assert!(
+            matches!(&err, Error::KeyCertMismatch { primary_name } if primary_name == "bad-rsa"),
+            "{}",
+            err
+        );
+        let cert = acc.certificate_unchecked("bad.example.com")?.unwrap();
+        assert_eq!(cert.private_key().as_bytes(), &other[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_persist_error() -> Result<()> {
+        /// Times out reading certificates.
+        #[derive(Clone)]
+        struct FailingPersist(MemoryPersist);
+
+        impl Persist for FailingPersist {
+            fn put(&self, key: &PersistKey, value: &[u8]) -> PersistResult<()> {
+                self.0.put(key, value)
+            }
+            fn get(&self, key: &PersistKey) -> PersistResult<Option<Vec<u8>>> {
+                if key.kind == PersistKind::Certificate {
+                    let e = std::io::Error::new(std::io::ErrorKind::TimedOut, "no answer");
+                    return Err(e.into());
+                }
+                self.0.get(key)
+            }
+        }
+
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let dir = Directory::from_url(FailingPersist(MemoryPersist::new()), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let err = acc.certificate("acmetest.example.com").unwrap_err();
+        let pk_crt = PersistKey::new(
+            &acc.inner.realm,
+            PersistKind::Certificate,
+            "acmetest.example.com",
+        );
+        assert_eq!(
+            err.to_string(),
+            format!("Persistence failed for {}: no answer", pk_crt)
+        );
+        match &err {
+            Error::Persist { key, source } => {
+                assert_eq!(*key, PersistKeyBuf::from(pk_crt));
+                let io = source.downcast_ref::<std::io::Error>().unwrap();
+                assert_eq!(io.kind(), std::io::ErrorKind::TimedOut);
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "no answer");
+        Ok(())
+    }
+
+    #[test]
+    fn test_redownload_certificate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let err = acc
+            .redownload_certificate("acmetest.example.com")
+            .unwrap_err();
+        assert!(err.to_string().contains("No certificate URL"), "{}", err);
+
+        let ord = acc.new_order("valid.example.com", &[])?;
+        let (pri_key, pub_key) = crate::test::test_cert_key();
+        let ord = ord.confirm_validations().unwrap();
+        let ord = ord.finalize_pkey(pri_key, pub_key, 1)?;
+        let cert_url = ord.certificate_url().to_string();
+        assert!(cert_url.ends_with("/acme/cert/fae41c070f967713109028"));
+        let cert = ord.download_and_save_cert()?;
+
+        // the certificate is replaced, the key is kept
+        let realm = &acc.inner.realm;
+        let pk_crt = PersistKey::new(realm, PersistKind::Certificate, "acmetest.example.com");
+        persist.put_value(&pk_crt, b"")?;
+        let again = acc.redownload_certificate("AcmeTest.example.com")?;
+        assert_eq!(again, cert);
+        assert_eq!(acc.certificate("acmetest.example.com")?.unwrap(), cert);
+        assert!(acc.certificate_history("acmetest.example.com")?.is_empty());
+
+        // the replaced certificate is kept
+        acc.set_cert_history(1);
+        acc.redownload_certificate("acmetest.example.com")?;
+        let history = acc.certificate_history("acmetest.example.com")?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].serial_hex, cert.serial_hex());
+
+        let pk_url = PersistKey::new(realm, PersistKind::CertificateUrl, "acmetest.example.com");
+        assert_eq!(persist.get_value(&pk_url)?.unwrap(), cert_url.as_bytes());
+        let gone = server.dir_url.replace("/directory", "/acme/cert/gone");
+        persist.put_value(&pk_url, gone.as_bytes())?;
+        let err = acc
+            .redownload_certificate("acmetest.example.com")
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::CertificateGone(u) if *u == gone),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_revoke_certificate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let cert = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            crate::test::TEST_CERT_PEM.into(),
+        );
+        acc.revoke_certificate(&cert, Some(RevocationReason::Superseded))?;
+        // already revoked is fine
+        acc.revoke_certificate(&cert, None)?;
+
+        let revocations: Vec<_> = crate::test::revocations(&server)
+            .into_iter()
+            .map(|(_, payload)| payload)
+            .collect();
+        assert_eq!(revocations.len(), 2);
+        let der = crate::util::base64url(&cert.certificate_der());
+        assert_eq!(revocations[0]["certificate"], der.as_str());
+        assert_eq!(revocations[0]["reason"], 4);
+        assert!(revocations[1].get("reason").is_none());
+
+        let err = acc
+            .revoke_certificate(&cert, Some(RevocationReason::CertificateHold))
+            .unwrap_err();
+        assert!(matches!(err, Error::Validation(_)), "{}", err);
+        assert_eq!(crate::test::revocations(&server).len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_revocation_reason() {
+        for code in 0..=10 {
+            match RevocationReason::from_code(code) {
+                Some(r) => {
+                    assert_eq!(r.code(), code);
+                    assert_eq!(serde_json::to_string(&r).unwrap(), code.to_string());
+                    let read: RevocationReason = serde_json::from_str(&code.to_string()).unwrap();
+                    assert_eq!(read, r);
+                }
+                None => assert_eq!(code, 7),
+            }
+        }
+        assert!(serde_json::from_str::<RevocationReason>("7").is_err());
+        let allowed: Vec<_> = (0..=10)
+            .filter_map(RevocationReason::from_code)
+            .filter(|r| r.is_allowed())
+            .map(|r| r.code())
+            .collect();
+        assert_eq!(allowed, vec![0, 1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_normalized_names() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("AcmeTest.Example.com.", &["WWW.example.com"])?;
+        let domains = ord.api_order().domains();
+        assert_eq!(domains, vec!["acmetest.example.com", "www.example.com"]);
+        let err = acc
+            .new_order("example.com", &["bad name.com"])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("\"bad name.com\""));
+
+        let realm = &acc.inner.realm;
+        save_certificate(&persist, realm, domains[0], &domains, Some(b"KEY"), b"CERT")?;
+        assert!(acc.certificate("AcmeTest.Example.com.")?.is_some());
+        assert!(acc.certificate_for_domain("WWW.Example.com.")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_certificate_for_domain() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let realm = &acc.inner.realm;
+        let domains = ["www.example.com", "example.com"];
+        save_certificate(
+            &persist,
+            realm,
+            "www.example.com",
+            &domains,
+            Some(b"KEY"),
+            b"CERT 1",
+        )?;
+        let domains = ["*.example.com"];
+        save_certificate(
+            &persist,
+            realm,
+            "*.example.com",
+            &domains,
+            Some(b"KEY"),
+            b"CERT 2",
+        )?;
+
+        let cert = |d| {
+            acc.certificate_for_domain(d)
+                .map(|c| c.map(|c| c.certificate().to_string()))
+        };
+        assert_eq!(cert("example.com")?.as_deref(), Some("CERT 1"));
+        assert_eq!(cert("WWW.example.com")?.as_deref(), Some("CERT 1"));
+        assert_eq!(cert("foo.example.com")?.as_deref(), Some("CERT 2"));
+        assert_eq!(cert("a.b.example.com")?, None);
+        assert_eq!(cert("example.org")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_key() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc1 = dir.account("foo@bar.com")?;
+        let acc2 = acc1.change_key()?;
+        assert!(acc1.acme_private_key_pem()? != acc2.acme_private_key_pem()?);
+        // the new key is persisted
+        let acc3 = dir.account("foo@bar.com")?;
+        assert_eq!(acc2.acme_private_key_pem()?, acc3.acme_private_key_pem()?);
+        Ok(())
+    }
 }
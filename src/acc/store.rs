@@ -0,0 +1,415 @@
+//! Certificates and orders in the persistence.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::cert::Certificate;
+use crate::persist::{Persist, PersistExt, PersistKey, PersistKind};
+use crate::Result;
+
+/// A saved certificate in the index.
+#[derive(Debug, Serialize, Deserialize)]
+struct CertIndexEntry {
+    /// The name the certificate is saved under, the primary name or a storage name.
+    primary_name: String,
+    /// All domains of the certificate, the primary name first.
+    domains: Vec<String>,
+}
+
+/// Persistence key for the index of certificates saved under a realm.
+fn cert_index_persist_key(realm: &str) -> PersistKey<'static> {
+    PersistKey::new(realm, PersistKind::Index, "certificates")
+}
+
+fn read_index<P: Persist>(persist: &P, realm: &str) -> Result<Vec<CertIndexEntry>> {
+    read_json_list(persist, &cert_index_persist_key(realm))
+}
+
+fn read_json_list<P: Persist, T: DeserializeOwned>(
+    persist: &P,
+    key: &PersistKey,
+) -> Result<Vec<T>> {
+    Ok(match persist.get_value(key)? {
+        Some(v) if !v.is_empty() => serde_json::from_slice(&v)?,
+        _ => vec![],
+    })
+}
+
+/// Primary names of the certificates saved under the realm.
+pub(crate) fn read_cert_index<P: Persist>(persist: &P, realm: &str) -> Result<Vec<String>> {
+    let index = read_index(persist, realm)?;
+    Ok(index.into_iter().map(|e| e.primary_name).collect())
+}
+
+/// Names of the certificates saved under the realm, as [listed] by the persistence.
+/// Falls back on the index for persistence that can't list keys.
+///
+/// [listed]: ../trait.Persist.html#method.list
+pub(crate) fn list_cert_names<P: Persist>(persist: &P, realm: &str) -> Result<Vec<String>> {
+    match persist.list(realm, Some(PersistKind::Certificate)) {
+        Ok(keys) => Ok(keys.into_iter().map(|k| k.key).collect()),
+        Err(e) => {
+            debug!("Fall back on certificate index: {}", e);
+            read_cert_index(persist, realm)
+        }
+    }
+}
+
+/// Name of a certificate saved under the realm whose primary name is the domain, for
+/// certificates saved under another name.
+pub(crate) fn find_cert_for_primary_name<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+) -> Result<Option<String>> {
+    let index = read_index(persist, realm)?;
+    Ok(index
+        .into_iter()
+        .find(|e| {
+            e.domains
+                .first()
+                .map(|d| d.eq_ignore_ascii_case(primary_name))
+                .unwrap_or(false)
+        })
+        .map(|e| e.primary_name))
+}
+
+/// Primary name of a certificate saved under the realm that is valid for the domain.
+///
+/// A certificate with the exact domain is preferred over one with a matching wildcard.
+pub(crate) fn find_cert_for_domain<P: Persist>(
+    persist: &P,
+    realm: &str,
+    domain: &str,
+) -> Result<Option<String>> {
+    let index = read_index(persist, realm)?;
+    let domain = domain.to_ascii_lowercase();
+
+    let exact = index
+        .iter()
+        .find(|e| e.domains.iter().any(|d| d.eq_ignore_ascii_case(&domain)));
+    let found = exact.or_else(|| {
+        index
+            .iter()
+            .find(|e| e.domains.iter().any(|d| wildcard_matches(d, &domain)))
+    });
+
+    Ok(found.map(|e| e.primary_name.clone()))
+}
+
+/// Whether a wildcard such as `*.example.com` matches the domain the way a TLS client
+/// would, i.e. for exactly one extra label.
+fn wildcard_matches(wildcard: &str, domain: &str) -> bool {
+    let suffix = match wildcard.strip_prefix("*.") {
+        Some(s) => s,
+        None => return false,
+    };
+    match domain.split_once('.') {
+        Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+        None => false,
+    }
+}
+
+/// Remove the value under the key. For a persistence that can't delete, an empty value
+/// is stored instead, which is read as no value.
+///
+/// `true` if there was a value.
+pub(crate) fn remove_entry<P: Persist>(persist: &P, key: &PersistKey) -> Result<bool> {
+    match persist.delete(key) {
+        Ok(removed) => Ok(removed),
+        Err(e) => {
+            debug!("Store empty value instead of delete: {}", e);
+            let existed = persist
+                .get_value(key)?
+                .map(|v| !v.is_empty())
+                .unwrap_or(false);
+            persist.put_value(key, &[])?;
+            Ok(existed)
+        }
+    }
+}
+
+/// Remove the private key, certificate, certificate URL and kept earlier certificates
+/// saved under the realm and name, and the name from the index.
+///
+/// `true` if there was a certificate.
+pub(crate) fn forget_certificate<P: Persist>(persist: &P, realm: &str, name: &str) -> Result<bool> {
+    let pk_crt = PersistKey::new(realm, PersistKind::Certificate, name);
+    debug!("Remove certificate: {}", pk_crt);
+    let removed = remove_entry(persist, &pk_crt)?;
+    for kind in &[PersistKind::PrivateKey, PersistKind::CertificateUrl] {
+        remove_entry(persist, &PersistKey::new(realm, *kind, name))?;
+    }
+    super::history::forget_history(persist, realm, name)?;
+
+    let mut index = read_index(persist, realm)?;
+    let len = index.len();
+    index.retain(|e| e.primary_name != name);
+    if index.len() != len {
+        let index_key = cert_index_persist_key(realm);
+        persist.put_value(&index_key, &serde_json::to_vec(&index)?)?;
+    }
+    Ok(removed)
+}
+
+/// Save the private key and certificate under the realm and primary name.
+///
+/// The `domains` are all names in the certificate, used to find it again. Without a
+/// private key, the saved one is kept.
+pub(crate) fn save_certificate<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+    domains: &[&str],
+    private_key_pem: Option<&[u8]>,
+    certificate_pem: &[u8],
+) -> Result<()> {
+    let mut domains = domains.to_vec();
+    if !domains.iter().any(|d| d.eq_ignore_ascii_case(primary_name)) {
+        domains.insert(0, primary_name);
+    }
+    save_certificate_as(
+        persist,
+        realm,
+        primary_name,
+        &domains,
+        private_key_pem,
+        certificate_pem,
+    )
+}
+
+/// Save the private key and certificate under the realm and a storage name, which need
+/// not be a name of the certificate.
+///
+/// The `domains` are all names in the certificate, the primary name first.
+pub(crate) fn save_certificate_as<P: Persist>(
+    persist: &P,
+    realm: &str,
+    storage_name: &str,
+    domains: &[&str],
+    private_key_pem: Option<&[u8]>,
+    certificate_pem: &[u8],
+) -> Result<()> {
+    if let Some(private_key_pem) = private_key_pem {
+        let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, storage_name);
+        debug!("Save private key: {}", pk_key);
+        persist.put_value(&pk_key, private_key_pem)?;
+    }
+
+    let pk_crt = PersistKey::new(realm, PersistKind::Certificate, storage_name);
+    debug!("Save certificate: {}", pk_crt);
+    persist.put_value(&pk_crt, certificate_pem)?;
+
+    let domains: Vec<String> = domains.iter().map(|d| d.to_ascii_lowercase()).collect();
+
+    let mut index = read_index(persist, realm)?;
+    if let Some(entry) = index.iter_mut().find(|e| e.primary_name == storage_name) {
+        entry.domains = domains;
+    } else {
+        index.push(CertIndexEntry {
+            primary_name: storage_name.to_string(),
+            domains,
+        });
+    }
+    let index_key = cert_index_persist_key(realm);
+    persist.put_value(&index_key, &serde_json::to_vec(&index)?)?;
+
+    Ok(())
+}
+
+/// Replace the certificate saved under the realm and name, keeping the private key and
+/// the names to find it by.
+pub(crate) fn replace_certificate<P: Persist>(
+    persist: &P,
+    realm: &str,
+    name: &str,
+    certificate_pem: &[u8],
+) -> Result<()> {
+    let pk_crt = PersistKey::new(realm, PersistKind::Certificate, name);
+    debug!("Replace certificate: {}", pk_crt);
+    persist.put_value(&pk_crt, certificate_pem)
+}
+
+/// Save the URL the certificate under the realm and primary name was downloaded from.
+pub(crate) fn save_certificate_url<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+    url: &str,
+) -> Result<()> {
+    let pk_url = PersistKey::new(realm, PersistKind::CertificateUrl, primary_name);
+    debug!("Save certificate URL: {}", pk_url);
+    persist.put_value(&pk_url, url.as_bytes())
+}
+
+/// Read the URL the certificate under the realm and primary name was downloaded from.
+pub(crate) fn read_certificate_url<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+) -> Result<Option<String>> {
+    let pk_url = PersistKey::new(realm, PersistKind::CertificateUrl, primary_name);
+    debug!("Read certificate URL: {}", pk_url);
+    Ok(persist
+        .get_value(&pk_url)?
+        .and_then(|s| String::from_utf8(s).ok())
+        .filter(|s| !s.is_empty()))
+}
+
+/// Read the private key saved under the realm and primary name.
+pub(crate) fn read_private_key<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, primary_name);
+    debug!("Read private key: {}", pk_key);
+    Ok(persist
+        .get_value(&pk_key)?
+        .filter(|v| !v.is_empty())
+        .map(Zeroizing::new))
+}
+
+/// Read the private key and certificate saved under the realm and primary name.
+pub(crate) fn read_certificate<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+) -> Result<Option<Certificate>> {
+    // read primary key
+    let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, primary_name);
+    debug!("Read private key: {}", pk_key);
+    let private_key = persist
+        .get_value(&pk_key)?
+        .and_then(|s| String::from_utf8(s).ok());
+
+    // read certificate
+    let pk_crt = PersistKey::new(realm, PersistKind::Certificate, primary_name);
+    debug!("Read certificate: {}", pk_crt);
+    let certificate = persist
+        .get_value(&pk_crt)?
+        .and_then(|s| String::from_utf8(s).ok())
+        .filter(|s| !s.is_empty());
+
+    Ok(match (private_key, certificate) {
+        (Some(k), Some(c)) => Some(Certificate::new(k, c)),
+        _ => None,
+    })
+}
+
+/// A recently created order.
+#[derive(Debug, Serialize, Deserialize)]
+struct OrderCacheEntry {
+    /// Sorted identifiers of the order.
+    identifiers: Vec<String>,
+    url: String,
+}
+
+/// Max number of orders to remember. The oldest are dropped first.
+const ORDER_CACHE_SIZE: usize = 16;
+
+/// Persistence key for the recently created orders under a realm.
+fn order_cache_persist_key(realm: &str) -> PersistKey<'static> {
+    PersistKey::new(realm, PersistKind::OrderUrl, "recent")
+}
+
+/// URL of a recently created order for exactly the identifiers (in any order).
+pub(crate) fn cached_order_url<P: Persist>(
+    persist: &P,
+    realm: &str,
+    identifiers: &[String],
+) -> Result<Option<String>> {
+    let identifiers = sorted(identifiers);
+    let cache: Vec<OrderCacheEntry> = read_json_list(persist, &order_cache_persist_key(realm))?;
+    Ok(cache
+        .into_iter()
+        .find(|e| e.identifiers == identifiers)
+        .map(|e| e.url))
+}
+
+/// Remember the order as the most recent one for the identifiers.
+pub(crate) fn cache_order_url<P: Persist>(
+    persist: &P,
+    realm: &str,
+    identifiers: &[String],
+    url: &str,
+) -> Result<()> {
+    let identifiers = sorted(identifiers);
+    let cache_key = order_cache_persist_key(realm);
+    let mut cache: Vec<OrderCacheEntry> = read_json_list(persist, &cache_key)?;
+    cache.retain(|e| e.identifiers != identifiers && e.url != url);
+    cache.push(OrderCacheEntry {
+        identifiers,
+        url: url.to_string(),
+    });
+    if cache.len() > ORDER_CACHE_SIZE {
+        cache.drain(..cache.len() - ORDER_CACHE_SIZE);
+    }
+    persist.put_value(&cache_key, &serde_json::to_vec(&cache)?)
+}
+
+/// Forget the order with the URL.
+pub(crate) fn evict_order_url<P: Persist>(persist: &P, realm: &str, url: &str) -> Result<()> {
+    let cache_key = order_cache_persist_key(realm);
+    let mut cache: Vec<OrderCacheEntry> = read_json_list(persist, &cache_key)?;
+    cache.retain(|e| e.url != url);
+    persist.put_value(&cache_key, &serde_json::to_vec(&cache)?)
+}
+
+fn sorted(identifiers: &[String]) -> Vec<String> {
+    let mut v: Vec<String> = identifiers.iter().map(|i| i.to_ascii_lowercase()).collect();
+    v.sort();
+    v.dedup();
+    v
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_matches() {
+        assert!(wildcard_matches("*.example.com", "foo.example.com"));
+        assert!(wildcard_matches("*.example.com", "FOO.Example.com"));
+        assert!(!wildcard_matches("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_matches("*.example.com", "example.com"));
+        assert!(!wildcard_matches("*.example.com", ".example.com"));
+        assert!(!wildcard_matches("example.com", "example.com"));
+    }
+
+    #[test]
+    fn test_order_cache() -> Result<()> {
+        let persist = crate::persist::MemoryPersist::new();
+        let ids = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        cache_order_url(&persist, "r", &ids(&["b.com", "a.com"]), "u1")?;
+        let found = cached_order_url(&persist, "r", &ids(&["A.com", "b.com"]))?;
+        assert_eq!(found.as_deref(), Some("u1"));
+        assert_eq!(cached_order_url(&persist, "r", &ids(&["a.com"]))?, None);
+        assert_eq!(
+            cached_order_url(&persist, "other", &ids(&["a.com", "b.com"]))?,
+            None
+        );
+        // replaced by a newer order for the same identifiers
+        cache_order_url(&persist, "r", &ids(&["a.com", "b.com"]), "u2")?;
+        let found = cached_order_url(&persist, "r", &ids(&["a.com", "b.com"]))?;
+        assert_eq!(found.as_deref(), Some("u2"));
+        evict_order_url(&persist, "r", "u2")?;
+        assert_eq!(
+            cached_order_url(&persist, "r", &ids(&["a.com", "b.com"]))?,
+            None
+        );
+        // the oldest are dropped
+        for i in 0..=ORDER_CACHE_SIZE {
+            cache_order_url(
+                &persist,
+                "r",
+                &ids(&[&format!("{}.com", i)]),
+                &i.to_string(),
+            )?;
+        }
+        assert_eq!(cached_order_url(&persist, "r", &ids(&["0.com"]))?, None);
+        assert!(cached_order_url(&persist, "r", &ids(&["1.com"]))?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Validating dns challenges using [acme-dns], with the `acme_dns` feature.
+//!
+//! acme-dns is a small DNS server with an HTTP API, only for ACME challenges. Rather
+//! than updating the DNS of the domain, `_acme-challenge.<domain>` is made a `CNAME`
+//! once for the record at the acme-dns server. The [`AcmeDnsProvider`] then updates that
+//! record when validating.
+//!
+//! ```no_run
+//! use acme_lib::acme_dns::{AcmeDnsClient, AcmeDnsProvider};
+//! use acme_lib::order::{DnsValidateOptions, NewOrder};
+//! use acme_lib::persist::Persist;
+//! use acme_lib::Error;
+//!
+//! fn dns_authorize<P: Persist>(ord: &mut NewOrder<P>, persist: P) -> Result<(), Error> {
+//!   let client = AcmeDnsClient::new("https://auth.acme-dns.io");
+//!   let provider = AcmeDnsProvider::new(client, persist);
+//!   let account = provider.account("example.com")?;
+//!   // once: _acme-challenge.example.com.  CNAME  <account.fulldomain>.
+//!   ord.validate_dns(&provider, DnsValidateOptions::new())?;
+//!   Ok(())
+//! }
+//! ```
+//!
+//! [acme-dns]: https://github.com/joohoi/acme-dns
+//! [`AcmeDnsProvider`]: struct.AcmeDnsProvider.html
+use serde::{Deserialize, Serialize};
+
+use crate::order::Dns01Provider;
+use crate::persist::{Persist, PersistExt, PersistKey, PersistKind};
+use crate::req::{req_configure, req_handle_error, req_safe_read_body};
+use crate::Result;
+
+/// The credentials of a record at the acme-dns server, as given when registering.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcmeDnsAccount {
+    /// API user.
+    pub username: String,
+    /// API key.
+    pub password: String,
+    /// The name to `CNAME` the `_acme-challenge` record to.
+    pub fulldomain: String,
+    /// The record to update.
+    pub subdomain: String,
+}
+
+#[derive(Serialize)]
+struct AcmeDnsUpdate<'a> {
+    subdomain: &'a str,
+    txt: &'a str,
+}
+
+/// Calls the HTTP API of an acme-dns server.
+#[derive(Debug, Clone)]
+pub struct AcmeDnsClient {
+    base_url: String,
+}
+
+impl AcmeDnsClient {
+    /// A client for the acme-dns server at the URL, such as `https://auth.acme-dns.io`.
+    pub fn new(base_url: &str) -> Self {
+        AcmeDnsClient {
+            base_url: base_url.trim_end_matches('/').into(),
+        }
+    }
+
+    /// Register a new record.
+    pub fn register(&self) -> Result<AcmeDnsAccount> {
+        let url = format!("{}/register", self.base_url);
+        let mut req = ureq::post(&url);
+        req_configure(&mut req);
+        debug!("Register at acme-dns: {}", url);
+        let res = req_handle_error(req.call())?;
+        Ok(serde_json::from_str(&req_safe_read_body(res))?)
+    }
+
+    /// Set the `TXT` value of the record. Only the two latest values are kept, which is
+    /// enough for a wildcard and its apex domain.
+    pub fn update(&self, account: &AcmeDnsAccount, txt: &str) -> Result<()> {
+        let url = format!("{}/update", self.base_url);
+        let body = serde_json::to_string(&AcmeDnsUpdate {
+            subdomain: &account.subdomain,
+            txt,
+        })?;
+        let mut req = ureq::post(&url);
+        req.set("content-type", "application/json")
+            .set("X-Api-User", &account.username)
+            .set("X-Api-Key", &account.password);
+        req_configure(&mut req);
+        debug!("Update acme-dns record: {}", account.fulldomain);
+        req_handle_error(req.send_string(&body))?;
+        Ok(())
+    }
+}
+
+/// A [`Dns01Provider`] updating records at an acme-dns server.
+///
+/// A record is registered for each domain the first time it's needed, and the
+/// credentials are saved in the persistence. The `_acme-challenge` record of the domain
+/// must be a `CNAME` for the [`fulldomain`] before validating.
+///
+/// [`Dns01Provider`]: ../order/trait.Dns01Provider.html
+/// [`fulldomain`]: struct.AcmeDnsAccount.html#structfield.fulldomain
+pub struct AcmeDnsProvider<P: Persist> {
+    client: AcmeDnsClient,
+    persist: P,
+}
+
+impl<P: Persist> AcmeDnsProvider<P> {
+    /// A provider saving the credentials in the persistence.
+    pub fn new(client: AcmeDnsClient, persist: P) -> Self {
+        AcmeDnsProvider { client, persist }
+    }
+
+    /// The credentials of the record for the domain, registering it unless saved in the
+    /// persistence.
+    ///
+    /// A wildcard uses the record of the domain without the `*.`.
+    pub fn account(&self, domain: &str) -> Result<AcmeDnsAccount> {
+        let domain = domain
+            .strip_prefix("*.")
+            .unwrap_or(domain)
+            .to_ascii_lowercase();
+        let key = PersistKey::new(&self.client.base_url, PersistKind::AcmeDnsAccount, &domain);
+        if let Some(v) = self.persist.get_value(&key)? {
+            return Ok(serde_json::from_slice(&v)?);
+        }
+        let account = self.client.register()?;
+        info!(
+            "Registered at acme-dns, add: _acme-challenge.{}. CNAME {}.",
+            domain, account.fulldomain
+        );
+        self.persist
+            .put_value(&key, &serde_json::to_vec(&account)?)?;
+        Ok(account)
+    }
+}
+
+impl<P: Persist> Dns01Provider for AcmeDnsProvider<P> {
+    fn set_txt(&self, fqdn: &str, value: &str) -> Result<()> {
+        let name = fqdn.strip_suffix('.').unwrap_or(fqdn);
+        let domain = name.strip_prefix("_acme-challenge.").unwrap_or(name);
+        let account = self.account(domain)?;
+        self.client.update(&account, value)
+    }
+
+    fn cleanup_txt(&self, _fqdn: &str, _value: &str) -> Result<()> {
+        // acme-dns can't remove records, old values are replaced by later updates.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+
+    #[test]
+    fn test_acme_dns_provider() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let base = server.dir_url.replace("/directory", "/acme-dns");
+        let persist = MemoryPersist::new();
+        let provider = AcmeDnsProvider::new(AcmeDnsClient::new(&base), persist.clone());
+
+        let registrations = crate::test::acme_dns_registrations(&server);
+        let account = provider.account("example.com")?;
+        assert_eq!(account.username, "user");
+        provider.set_txt("_acme-challenge.example.com.", "value1")?;
+        provider.set_txt("_acme-challenge.example.com.", "value2")?;
+        provider.cleanup_txt("_acme-challenge.example.com.", "value1")?;
+        // the credentials are saved
+        let provider = AcmeDnsProvider::new(AcmeDnsClient::new(&base), persist);
+        assert_eq!(provider.account("*.Example.com")?, account);
+        assert_eq!(
+            crate::test::acme_dns_registrations(&server),
+            registrations + 1
+        );
+
+        let bad = AcmeDnsAccount {
+            password: "wrong".into(),
+            ..account
+        };
+        let err = AcmeDnsClient::new(&base).update(&bad, "value").unwrap_err();
+        assert!(err.to_string().contains("401"), "{}", err);
+        Ok(())
+    }
+}
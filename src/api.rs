@@ -8,6 +8,7 @@ use serde::{
     ser::{SerializeMap, Serializer},
     Deserialize, Serialize,
 };
+use std::collections::HashMap;
 
 /// Serializes to `""`
 pub struct ApiEmptyString;
@@ -40,12 +41,31 @@ pub struct ApiProblem {
     pub detail: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub subproblems: Option<Vec<ApiSubproblem>>,
+    /// The HTTP status of the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
 }
 
 impl ApiProblem {
     pub fn is_bad_nonce(&self) -> bool {
         self._type == "badNonce"
     }
+    pub fn is_account_does_not_exist(&self) -> bool {
+        self._type == "urn:ietf:params:acme:error:accountDoesNotExist"
+    }
+    pub fn is_malformed(&self) -> bool {
+        self._type == "urn:ietf:params:acme:error:malformed"
+    }
+    /// Whether the HTTP status says the resource doesn't exist (anymore).
+    pub fn is_not_found(&self) -> bool {
+        self.status == Some(404) || self.status == Some(410)
+    }
+    pub fn is_already_revoked(&self) -> bool {
+        self._type == "urn:ietf:params:acme:error:alreadyRevoked"
+    }
+    pub fn is_user_action_required(&self) -> bool {
+        self._type == "urn:ietf:params:acme:error:userActionRequired"
+    }
     pub fn is_jwt_verification_error(&self) -> bool {
         self._type == "urn:acme:error:malformed"
             && self
@@ -84,6 +104,8 @@ pub struct ApiDirectory {
     pub revokeCert: String,
     pub keyChange: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub renewalInfo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub meta: Option<ApiDirectoryMeta>,
 }
 
@@ -97,6 +119,9 @@ pub struct ApiDirectoryMeta {
     pub caaIdentities: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub externalAccountRequired: Option<bool>,
+    /// Certificate profiles offered, name to description.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<HashMap<String, String>>,
 }
 
 impl ApiDirectoryMeta {
@@ -118,10 +143,15 @@ impl ApiDirectoryMeta {
 pub struct ApiAccount {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub contact: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub termsOfServiceAgreed: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub externalAccountBinding: Option<ApiJws>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onlyReturnExisting: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub orders: Option<String>,
 }
 
@@ -140,6 +170,14 @@ impl ApiAccount {
     }
 }
 
+/// A JWS in flattened JSON serialization. Used for the external account binding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct ApiJws {
+    pub protected: String,
+    pub payload: String,
+    pub signature: String,
+}
+
 // {
 //   "status": "pending",
 //   "expires": "2019-01-09T08:26:43.570360537Z",
@@ -161,8 +199,16 @@ pub struct ApiOrder {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub expires: Option<String>,
     pub identifiers: Vec<ApiIdentifier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub notBefore: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub notAfter: Option<String>,
+    /// ARI CertID of the certificate this order replaces.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<String>,
+    /// Certificate profile requested, and granted by the CA.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
     pub error: Option<ApiProblem>,
     pub authorizations: Option<Vec<String>>,
     pub finalize: String,
@@ -197,6 +243,18 @@ impl ApiOrder {
     }
 }
 
+// {
+//   "orders": [
+//     "https://example.com/acme/order/TOlocE8rfgo",
+//     "https://example.com/acme/order/4E16bbL5iSw"
+//   ]
+// }
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct ApiOrderList {
+    #[serde(default)]
+    pub orders: Vec<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ApiIdentifier {
     #[serde(rename = "type")]
@@ -208,6 +266,12 @@ impl ApiIdentifier {
     pub fn is_type_dns(&self) -> bool {
         self._type == "dns"
     }
+    pub fn is_type_ip(&self) -> bool {
+        self._type == "ip"
+    }
+    pub fn is_type_email(&self) -> bool {
+        self._type == "email"
+    }
 }
 
 // {
@@ -294,6 +358,13 @@ impl ApiAuth {
     pub fn tls_alpn_challenge(&self) -> Option<&ApiChallenge> {
         self.challenges.iter().find(|c| c._type == "tls-alpn-01")
     }
+    pub fn email_challenge(&self) -> Option<&ApiChallenge> {
+        self.challenges.iter().find(|c| c._type == "email-reply-00")
+    }
+    /// The first challenge with an error, if validating it failed.
+    pub fn failed_challenge(&self) -> Option<&ApiChallenge> {
+        self.challenges.iter().find(|c| c.error.is_some())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -302,9 +373,17 @@ pub struct ApiChallenge {
     #[serde(rename = "type")]
     pub _type: String,
     pub status: String,
+    /// Empty for challenge types without a token.
+    #[serde(default)]
     pub token: String,
     pub validated: Option<String>,
     pub error: Option<ApiProblem>,
+    /// How the ACME API tried to validate the challenge.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validationRecord: Option<Vec<ApiValidationRecord>>,
+    /// Sender of the challenge email for `email-reply-00`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
 }
 
 // {
@@ -328,6 +407,72 @@ impl ApiChallenge {
     }
 }
 
+// {
+//   "url": "http://example.com/.well-known/acme-challenge/MUi-gqeOJdRkSb_YR2eaMxQBqf6al8dgt_dOttSWb0w",
+//   "hostname": "example.com",
+//   "port": "80",
+//   "addressesResolved": ["93.184.216.34"],
+//   "addressUsed": "93.184.216.34"
+// }
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct ApiValidationRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "de_number_or_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub port: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addressesResolved: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addressUsed: Option<String>,
+}
+
+/// Let's Encrypt sends the port as a string, others as a number.
+fn de_number_or_string<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    let v: Option<serde_json::Value> = Option::deserialize(d)?;
+    Ok(v.and_then(|v| match v {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }))
+}
+
+// {
+//   "suggestedWindow": {
+//     "start": "2025-01-02T04:00:00Z",
+//     "end": "2025-01-03T04:00:00Z"
+//   },
+//   "explanationURL": "https://acme.example.com/docs/ari"
+// }
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiRenewalInfo {
+    pub suggestedWindow: ApiSuggestedWindow,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanationURL: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiSuggestedWindow {
+    pub start: String,
+    pub end: String,
+}
+
+/// Update of the status of an authorization, to deactivate it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiAuthStatus {
+    pub status: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiNewAuthz {
+    pub identifier: ApiIdentifier,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ApiFinalize {
     pub csr: String,
@@ -336,7 +481,8 @@ pub struct ApiFinalize {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ApiRevocation {
     pub certificate: String,
-    pub reason: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<crate::RevocationReason>,
 }
 
 #[cfg(test)]
@@ -354,5 +500,4 @@ mod test {
         let x = serde_json::to_string(&ApiEmptyObject).unwrap();
         assert_eq!("{}", x);
     }
-
 }
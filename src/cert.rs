@@ -1,18 +1,34 @@
 use lazy_static::lazy_static;
+use openssl::asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
 use openssl::ec::{Asn1Flag, EcGroup, EcKey};
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::{self, PKey};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{self, Id, PKey, PKeyRef};
 use openssl::rsa::Rsa;
+use openssl::sha::sha256;
 use openssl::stack::Stack;
 use openssl::x509::extension::SubjectAlternativeName;
-use openssl::x509::{X509Req, X509ReqBuilder, X509};
+use openssl::x509::{X509Builder, X509Extension, X509NameBuilder, X509Req, X509ReqBuilder, X509};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::Result;
+use crate::api::ApiIdentifier;
+use crate::util::base64url;
+use crate::{Error, Result};
 
 lazy_static! {
-    pub(crate) static ref EC_GROUP_P256: EcGroup = { ec_group(Nid::X9_62_PRIME256V1) };
-    pub(crate) static ref EC_GROUP_P384: EcGroup = { ec_group(Nid::SECP384R1) };
+    pub(crate) static ref EC_GROUP_P256: EcGroup = ec_group(Nid::X9_62_PRIME256V1);
+    pub(crate) static ref EC_GROUP_P384: EcGroup = ec_group(Nid::SECP384R1);
+    pub(crate) static ref EC_GROUP_P521: EcGroup = ec_group(Nid::SECP521R1);
 }
 
 fn ec_group(nid: Nid) -> EcGroup {
@@ -37,11 +53,107 @@ pub fn create_rsa_key(bits: u32) -> (PKey<pkey::Private>, PKey<pkey::Public>) {
     )
 }
 
+/// Smallest RSA key accepted by [`create_rsa_key_with`].
+///
+/// [`create_rsa_key_with`]: fn.create_rsa_key_with.html
+const MIN_RSA_BITS: u32 = 2048;
+/// Largest RSA key accepted by [`create_rsa_key_with`].
+///
+/// [`create_rsa_key_with`]: fn.create_rsa_key_with.html
+const MAX_RSA_BITS: u32 = 16384;
+
+/// Fail with [`Error::Validation`] unless the RSA key size is between 2048 and 16384 bits.
+///
+/// [`Error::Validation`]: enum.Error.html#variant.Validation
+pub(crate) fn check_rsa_bits(bits: u32) -> Result<()> {
+    if !(MIN_RSA_BITS..=MAX_RSA_BITS).contains(&bits) {
+        return Err(Error::Validation(format!(
+            "RSA key of {} bits, must be between {} and {}",
+            bits, MIN_RSA_BITS, MAX_RSA_BITS
+        )));
+    }
+    Ok(())
+}
+
+/// Make an RSA private/public key pair with the public exponent, typically 65537.
+///
+/// Unlike [`create_rsa_key`], the parameters are checked before generating: the bits
+/// must be between 2048 and 16384, and the exponent odd and at least 65537. Fails with
+/// [`Error::KeyGeneration`] if openssl fails.
+///
+/// [`create_rsa_key`]: fn.create_rsa_key.html
+/// [`Error::KeyGeneration`]: enum.Error.html#variant.KeyGeneration
+pub fn create_rsa_key_with(
+    bits: u32,
+    exponent: u32,
+) -> Result<(PKey<pkey::Private>, PKey<pkey::Public>)> {
+    check_rsa_bits(bits)?;
+    if exponent < 65537 || exponent.is_multiple_of(2) {
+        return Err(Error::Validation(format!(
+            "RSA exponent {}, must be odd and at least 65537",
+            exponent
+        )));
+    }
+    let e = BigNum::from_u32(exponent).map_err(Error::KeyGeneration)?;
+    let pri_key_rsa = Rsa::generate_with_e(bits, &e).map_err(Error::KeyGeneration)?;
+    let n = pri_key_rsa.n().to_owned().map_err(Error::KeyGeneration)?;
+    let e = pri_key_rsa.e().to_owned().map_err(Error::KeyGeneration)?;
+    let pub_key_rsa = Rsa::from_public_components(n, e).map_err(Error::KeyGeneration)?;
+    Ok((
+        PKey::from_rsa(pri_key_rsa).map_err(Error::KeyGeneration)?,
+        PKey::from_rsa(pub_key_rsa).map_err(Error::KeyGeneration)?,
+    ))
+}
+
+/// What kind of key a key is, for logging it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyInfo {
+    algorithm: &'static str,
+    bits: u32,
+}
+
+impl KeyInfo {
+    /// Inspect a private or public key.
+    pub fn of<T: pkey::HasPublic>(pkey: &PKeyRef<T>) -> KeyInfo {
+        let algorithm = match pkey.id() {
+            Id::RSA => "RSA",
+            Id::ED25519 => "Ed25519",
+            Id::EC => match pkey.ec_key().ok().and_then(|ec| ec.group().curve_name()) {
+                Some(Nid::X9_62_PRIME256V1) => "P-256",
+                Some(Nid::SECP384R1) => "P-384",
+                Some(Nid::SECP521R1) => "P-521",
+                _ => "EC",
+            },
+            _ => "unknown",
+        };
+        KeyInfo {
+            algorithm,
+            bits: pkey.bits(),
+        }
+    }
+
+    /// The algorithm, such as `RSA`, the curve name like `P-256` or `Ed25519`.
+    pub fn algorithm(&self) -> &'static str {
+        self.algorithm
+    }
+
+    /// The size of the key in bits.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+}
+
+impl fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} bits)", self.algorithm, self.bits)
+    }
+}
+
 /// Make a P-256 private/public key pair.
 pub fn create_p256_key() -> (PKey<pkey::Private>, PKey<pkey::Public>) {
-    let pri_key_ec = EcKey::generate(&*EC_GROUP_P256).expect("EcKey");
+    let pri_key_ec = EcKey::generate(&EC_GROUP_P256).expect("EcKey");
     let pub_key_ec =
-        EcKey::from_public_key(&*EC_GROUP_P256, pri_key_ec.public_key()).expect("EcKeyPub");
+        EcKey::from_public_key(&EC_GROUP_P256, pri_key_ec.public_key()).expect("EcKeyPub");
 
     (
         PKey::from_ec_key(pri_key_ec).expect("from_ec_key"),
@@ -51,9 +163,21 @@ pub fn create_p256_key() -> (PKey<pkey::Private>, PKey<pkey::Public>) {
 
 /// Make a P-384 private/public key pair.
 pub fn create_p384_key() -> (PKey<pkey::Private>, PKey<pkey::Public>) {
-    let pri_key_ec = EcKey::generate(&*EC_GROUP_P384).expect("EcKey");
+    let pri_key_ec = EcKey::generate(&EC_GROUP_P384).expect("EcKey");
+    let pub_key_ec =
+        EcKey::from_public_key(&EC_GROUP_P384, pri_key_ec.public_key()).expect("EcKeyPub");
+
+    (
+        PKey::from_ec_key(pri_key_ec).expect("from_ec_key"),
+        PKey::from_ec_key(pub_key_ec).expect("from_ec_key_pub"),
+    )
+}
+
+/// Make a P-521 private/public key pair.
+pub fn create_p521_key() -> (PKey<pkey::Private>, PKey<pkey::Public>) {
+    let pri_key_ec = EcKey::generate(&EC_GROUP_P521).expect("EcKey");
     let pub_key_ec =
-        EcKey::from_public_key(&*EC_GROUP_P384, pri_key_ec.public_key()).expect("EcKeyPub");
+        EcKey::from_public_key(&EC_GROUP_P521, pri_key_ec.public_key()).expect("EcKeyPub");
 
     (
         PKey::from_ec_key(pri_key_ec).expect("from_ec_key"),
@@ -61,105 +185,975 @@ pub fn create_p384_key() -> (PKey<pkey::Private>, PKey<pkey::Public>) {
     )
 }
 
+/// Make an Ed25519 private/public key pair.
+///
+/// Needs OpenSSL 1.1.1 or later, like Ed25519 account keys. Not all ACME API providers
+/// issue certificates for Ed25519 keys. Let's Encrypt doesn't.
+pub fn create_ed25519_key() -> (PKey<pkey::Private>, PKey<pkey::Public>) {
+    let pri_key = PKey::generate_ed25519().expect("generate_ed25519");
+    let raw = pri_key.raw_public_key().expect("raw_public_key");
+    let pub_key = PKey::public_key_from_raw_bytes(&raw, Id::ED25519).expect("from_raw_bytes");
+    (pri_key, pub_key)
+}
+
+/// The digest to sign a CSR with, matching the strength of the curve of EC keys.
+///
+/// Ed25519 signs the message as is, which openssl wants as a null digest.
+fn csr_digest(pkey: &PKey<pkey::Private>) -> MessageDigest {
+    if pkey.id() == Id::ED25519 {
+        return MessageDigest::null();
+    }
+    let curve = pkey.ec_key().ok().and_then(|ec| ec.group().curve_name());
+    match curve {
+        Some(Nid::SECP384R1) => MessageDigest::sha384(),
+        Some(Nid::SECP521R1) => MessageDigest::sha512(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// Read a private key from DER, either PKCS#8 or the traditional SEC1 (EC) or PKCS#1
+/// (RSA) encoding.
+pub fn private_key_from_der(der: &[u8]) -> Result<PKey<pkey::Private>> {
+    if let Ok(pkey) = PKey::private_key_from_pkcs8(der) {
+        return Ok(pkey);
+    }
+    if let Ok(ec) = EcKey::private_key_from_der(der) {
+        return Ok(PKey::from_ec_key(ec).expect("from_ec_key"));
+    }
+    if let Ok(rsa) = Rsa::private_key_from_der(der) {
+        return Ok(PKey::from_rsa(rsa).expect("from_rsa"));
+    }
+    Err("Failed to read private key DER, tried PKCS#8, SEC1 and PKCS#1".into())
+}
+
+/// The private key as PKCS#8 DER, wiped from memory when dropped.
+pub fn private_key_to_der(pkey: &PKey<pkey::Private>) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(pkey.private_key_to_pkcs8().expect("private_key_to_pkcs8"))
+}
+
+/// The public key of a private key.
+pub(crate) fn public_key_of(pkey: &PKey<pkey::Private>) -> PKey<pkey::Public> {
+    let der = pkey.public_key_to_der().expect("public_key_to_der");
+    PKey::public_key_from_der(&der).expect("public_key_from_der")
+}
+
+/// OID of the TLS Feature extension of RFC 7633.
+const TLS_FEATURE_OID: &str = "1.3.6.1.5.5.7.1.24";
+
+/// Options for the CSR made when finalizing an order, see
+/// [`CsrOrder::finalize_pkey_with_options`].
+///
+/// [`CsrOrder::finalize_pkey_with_options`]: order/struct.CsrOrder.html#method.finalize_pkey_with_options
+#[derive(Debug, Clone, Default)]
+pub struct CsrOptions {
+    must_staple: bool,
+    /// OID, critical flag and DER value of extra extensions.
+    extensions: Vec<(String, bool, Vec<u8>)>,
+}
+
+impl CsrOptions {
+    /// The default options, a CSR with only the subject alternative names.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the TLS Feature extension with `status_request`, also known as OCSP
+    /// Must-Staple. TLS servers using the certificate must then staple an OCSP response.
+    ///
+    /// Let's Encrypt honors it. ACME API providers that don't support it may reject the
+    /// CSR, and finalizing fails with their error as is.
+    pub fn with_must_staple(mut self, must_staple: bool) -> Self {
+        self.must_staple = must_staple;
+        self
+    }
+
+    /// Request an extra extension, such as one with a private OID required by the ACME
+    /// API provider. The `oid` is in dotted form like `1.3.6.1.4.1.99999.1`, and the
+    /// `der_value` is the DER encoded extension value.
+    ///
+    /// The subject alternative names and the signature are still made by acme-lib, from
+    /// the identifiers of the order. Adding a subject alternative name extension fails
+    /// the finalize call.
+    pub fn add_extension(mut self, oid: &str, critical: bool, der_value: Vec<u8>) -> Self {
+        self.extensions.push((oid.to_string(), critical, der_value));
+        self
+    }
+}
+
 pub(crate) fn create_csr(
     pkey_pri: &PKey<pkey::Private>,
     pkey_pub: &PKey<pkey::Public>,
-    domains: &[&str],
+    identifiers: &[ApiIdentifier],
+    opts: &CsrOptions,
 ) -> Result<X509Req> {
     //
     // the csr builder
     let mut req_bld = X509ReqBuilder::new().expect("X509ReqBuilder");
 
     // set public key in builder
-    req_bld.set_pubkey(&pkey_pub).expect("set_pubkey");
+    req_bld.set_pubkey(pkey_pub).expect("set_pubkey");
+
+    // the primary name first, then the rest by type and name, to not depend on the
+    // order of the alt names.
+    let type_rank = |id: &ApiIdentifier| match &id._type[..] {
+        "dns" => 0,
+        "ip" => 1,
+        "email" => 2,
+        _ => 3,
+    };
+    let mut sorted: Vec<&ApiIdentifier> = identifiers.iter().collect();
+    if sorted.len() > 1 {
+        sorted[1..].sort_by(|a, b| {
+            (type_rank(a), &a.value, &a._type).cmp(&(type_rank(b), &b.value, &b._type))
+        });
+    }
 
-    // set all domains as alt names
+    // set all identifiers as alt names
     let mut stack = Stack::new().expect("Stack::new");
     let ctx = req_bld.x509v3_context(None);
-    let as_lst = domains.iter().map(|&e| format!("DNS:{}", e)).collect::<Vec<_>>().join(", ");
-    let as_lst = as_lst[4..].to_string();
     let mut an = SubjectAlternativeName::new();
-    an.dns(&as_lst);
+    for id in sorted {
+        if id.is_type_dns() {
+            an.dns(&id.value);
+        } else if id.is_type_ip() {
+            an.ip(&id.value);
+        } else if id.is_type_email() {
+            an.email(&id.value);
+        } else {
+            return Err(format!("Unsupported identifier type: {}", id._type).into());
+        }
+    }
     let ext = an.build(&ctx).expect("SubjectAlternativeName::build");
     stack.push(ext).expect("Stack::push");
+
+    if opts.must_staple {
+        // Features ::= SEQUENCE OF INTEGER, with status_request (5).
+        let oid = Asn1Object::from_str(TLS_FEATURE_OID).expect("Asn1Object");
+        let value = Asn1OctetString::new_from_bytes(&[0x30, 0x03, 0x02, 0x01, 0x05])
+            .expect("Asn1OctetString");
+        let ext = X509Extension::new_from_der(&oid, false, &value).expect("X509Extension");
+        stack.push(ext).expect("Stack::push");
+    }
+    for (oid, critical, der) in &opts.extensions {
+        let obj = Asn1Object::from_str(oid)
+            .map_err(|_| Error::Validation(format!("Bad extension OID: {:?}", oid)))?;
+        if obj.nid() == Nid::SUBJECT_ALT_NAME {
+            return Err(Error::Validation(
+                "The subject alternative names are made from the order".into(),
+            ));
+        }
+        let value = Asn1OctetString::new_from_bytes(der).expect("Asn1OctetString");
+        let ext = X509Extension::new_from_der(&obj, *critical, &value).expect("X509Extension");
+        stack.push(ext).expect("Stack::push");
+    }
     req_bld.add_extensions(&stack).expect("add_extensions");
 
     // sign it
     req_bld
-        .sign(pkey_pri, MessageDigest::sha256())
+        .sign(pkey_pri, csr_digest(pkey_pri))
         .expect("csr_sign");
 
     // the csr
     Ok(req_bld.build())
 }
 
-/// Encapsulated certificate and private key.
+/// Read a CSR from DER or PEM.
+pub(crate) fn read_csr(csr: &[u8]) -> Result<X509Req> {
+    if csr.starts_with(b"-----BEGIN") {
+        X509Req::from_pem(csr).map_err(|e| format!("Error reading CSR PEM: {}", e).into())
+    } else {
+        X509Req::from_der(csr).map_err(|e| format!("Error reading CSR DER: {}", e).into())
+    }
+}
+
+/// DER of the subject alternative name extension OID 2.5.29.17.
+const SAN_OID_DER: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+/// The extensions requested in the CSR, as DER of the OID, the critical flag and the
+/// DER of the value.
+pub(crate) fn csr_extensions(csr: &X509Req) -> Vec<(Vec<u8>, bool, Vec<u8>)> {
+    let exts = match csr.extensions() {
+        Ok(exts) => exts,
+        Err(_) => return vec![],
+    };
+    exts.iter()
+        .filter_map(|ext| {
+            let der = ext.to_der().ok()?;
+            // Extension ::= SEQUENCE { extnID, critical BOOLEAN DEFAULT FALSE, extnValue }
+            let (_, seq, _) = der_read(&der, 0x30)?;
+            let (oid_len, _, rest) = der_read(seq, 0x06)?;
+            let oid = seq[..oid_len].to_vec();
+            let (critical, rest) = match der_read(rest, 0x01) {
+                Some((_, v, rest)) => (v.first().map(|b| *b != 0).unwrap_or(false), rest),
+                None => (false, rest),
+            };
+            let (_, value, _) = der_read(rest, 0x04)?;
+            Some((oid, critical, value.to_vec()))
+        })
+        .collect()
+}
+
+/// The DNS names, IP addresses and email addresses in the subject alternative names of
+/// the CSR, as identifiers of their type.
+pub(crate) fn csr_identifiers(csr: &X509Req) -> Vec<ApiIdentifier> {
+    let id = |t: &str, value: String| ApiIdentifier {
+        _type: t.into(),
+        value,
+    };
+    let mut names = vec![];
+    for (oid, _, value) in csr_extensions(csr) {
+        if oid != SAN_OID_DER {
+            continue;
+        }
+        let mut rest = match der_read(&value, 0x30) {
+            Some((_, seq, _)) => seq,
+            None => continue,
+        };
+        while let Some((tag, v, r)) = der_any(rest) {
+            match tag {
+                // rfc822Name [1], dNSName [2] and iPAddress [7]
+                0x81 => names.push(id("email", String::from_utf8_lossy(v).into_owned())),
+                0x82 => names.push(id("dns", String::from_utf8_lossy(v).into_owned())),
+                0x87 => names.extend(ip_to_string(v).map(|ip| id("ip", ip))),
+                _ => {}
+            }
+            rest = r;
+        }
+    }
+    names
+}
+
+/// The values of the [identifiers](fn.csr_identifiers.html) in the CSR.
+#[cfg(test)]
+pub(crate) fn csr_domains(csr: &X509Req) -> Vec<String> {
+    csr_identifiers(csr).into_iter().map(|i| i.value).collect()
+}
+
+/// Whether the identifiers are the same, of the same type.
+pub(crate) fn same_identifier(a: &ApiIdentifier, b: &ApiIdentifier) -> bool {
+    if a._type != b._type {
+        return false;
+    }
+    if a.is_type_ip() {
+        let parse = |v: &str| v.parse::<std::net::IpAddr>().ok();
+        return parse(&a.value).is_some() && parse(&a.value) == parse(&b.value);
+    }
+    a.value.eq_ignore_ascii_case(&b.value)
+}
+
+/// Read a DER element with the tag, giving the length of the whole element, its value
+/// and what follows.
+fn der_read(buf: &[u8], tag: u8) -> Option<(usize, &[u8], &[u8])> {
+    match der_any(buf) {
+        Some((t, v, rest)) if t == tag => Some((buf.len() - rest.len(), v, rest)),
+        _ => None,
+    }
+}
+
+/// Read any DER element, giving its tag, value and what follows.
+pub(crate) fn der_any(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *buf.first()?;
+    let first = *buf.get(1)? as usize;
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = buf
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    let value = buf.get(start..start + len)?;
+    Some((tag, value, &buf[start + len..]))
+}
+
+/// OID of the `acmeIdentifier` extension of RFC 8737.
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+/// The self-signed certificate for a tls-alpn-01 challenge. It is for the identifier
+/// only, and has the SHA-256 digest of the key authorization in the critical
+/// `acmeIdentifier` extension.
+pub(crate) fn create_tls_alpn_cert(
+    pkey: &PKey<pkey::Private>,
+    identifier: &ApiIdentifier,
+    key_authorization: &str,
+) -> Result<X509> {
+    let mut bld = X509Builder::new().expect("X509Builder");
+    bld.set_version(2).expect("set_version");
+
+    let mut serial = BigNum::new().expect("BigNum");
+    serial
+        .rand(64, MsbOption::MAYBE_ZERO, false)
+        .expect("BigNum::rand");
+    let serial = Asn1Integer::from_bn(&serial).expect("Asn1Integer");
+    bld.set_serial_number(&serial).expect("set_serial_number");
+
+    let mut name = X509NameBuilder::new().expect("X509NameBuilder");
+    name.append_entry_by_nid(Nid::COMMONNAME, &identifier.value)
+        .map_err(|e| format!("Bad name {:?}: {}", identifier.value, e))?;
+    let name = name.build();
+    bld.set_subject_name(&name).expect("set_subject_name");
+    bld.set_issuer_name(&name).expect("set_issuer_name");
+
+    let not_before = Asn1Time::days_from_now(0).expect("days_from_now");
+    let not_after = Asn1Time::days_from_now(7).expect("days_from_now");
+    bld.set_not_before(&not_before).expect("set_not_before");
+    bld.set_not_after(&not_after).expect("set_not_after");
+    bld.set_pubkey(pkey).expect("set_pubkey");
+
+    let mut an = SubjectAlternativeName::new();
+    if identifier.is_type_dns() {
+        an.dns(&identifier.value);
+    } else if identifier.is_type_ip() {
+        an.ip(&identifier.value);
+    } else {
+        return Err(format!("Unsupported identifier type: {}", identifier._type).into());
+    }
+    let ext = an
+        .build(&bld.x509v3_context(None, None))
+        .expect("SubjectAlternativeName::build");
+    bld.append_extension(ext).expect("append_extension");
+
+    // the extension value is the DER encoded OCTET STRING of the digest.
+    let mut der = vec![0x04, 0x20];
+    der.extend_from_slice(&sha256(key_authorization.as_bytes()));
+    let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID).expect("Asn1Object");
+    let value = Asn1OctetString::new_from_bytes(&der).expect("Asn1OctetString");
+    let ext = X509Extension::new_from_der(&oid, true, &value).expect("X509Extension");
+    bld.append_extension(ext).expect("append_extension");
+
+    bld.sign(pkey, MessageDigest::sha256()).expect("sign");
+    Ok(bld.build())
+}
+
+/// How the contents of a PKCS#12 bundle are encrypted, see
+/// [`Certificate::to_pkcs12_with`].
+///
+/// [`Certificate::to_pkcs12_with`]: struct.Certificate.html#method.to_pkcs12_with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pkcs12Encryption {
+    /// AES-256-CBC with a SHA-256 MAC. This is the default.
+    Aes256,
+    /// Triple DES with a SHA-1 MAC, for old consumers such as Windows Server 2016 and
+    /// older Java versions.
+    Legacy3Des,
+}
+
+/// The order of the private key and the certificate chain in a PEM bundle, see
+/// [`Certificate::bundle_pem`].
+///
+/// [`Certificate::bundle_pem`]: struct.Certificate.html#method.bundle_pem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleOrder {
+    /// The private key followed by the chain, as wanted by for example HAProxy.
+    KeyFirst,
+    /// The chain followed by the private key.
+    CertFirst,
+}
+
+/// The names and validity of one certificate of a chain, see
+/// [`Certificate::chain_validity`].
+///
+/// [`Certificate::chain_validity`]: struct.Certificate.html#method.chain_validity
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainCertInfo {
+    /// The subject, such as `C=US, O=Let's Encrypt, CN=R3`.
+    pub subject: String,
+    /// The issuer, in the same form as the subject.
+    pub issuer: String,
+    /// Start of the validity, the notBefore.
+    pub not_before: SystemTime,
+    /// End of the validity, the notAfter.
+    pub not_after: SystemTime,
+}
+
+impl ChainCertInfo {
+    /// The number of whole days left until the certificate expires, negative once it
+    /// has expired.
+    pub fn days_left(&self) -> i64 {
+        days_until(self.not_after)
+    }
+}
+
+/// Encapsulated certificate and private key.
+///
+/// The private key is wiped from memory when the certificate is dropped.
+///
+/// The struct is serde serializable as the PEM of the private key and certificate. A
+/// [`redacted`] certificate is serialized without the private key.
+///
+/// [`redacted`]: struct.Certificate.html#method.redacted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "CertificateParts")]
 pub struct Certificate {
+    #[serde(skip_serializing_if = "String::is_empty")]
     private_key: String,
     certificate: String,
+    /// The notAfter of the leaf, parsed once. `None` if the certificate can't be read.
+    #[serde(skip)]
+    not_after: Option<SystemTime>,
+}
+
+/// The serialized form of a `Certificate`. The private key is missing when redacted.
+#[derive(Deserialize)]
+struct CertificateParts {
+    #[serde(default)]
+    private_key: String,
+    certificate: String,
+}
+
+impl From<CertificateParts> for Certificate {
+    fn from(parts: CertificateParts) -> Self {
+        Certificate::new(parts.private_key, parts.certificate)
+    }
+}
+
+fn check_private_key_pem(pem: &str) -> Result<()> {
+    PKey::private_key_from_pem(pem.as_bytes())
+        .map_err(|e| format!("Failed to read private key PEM: {}", e))?;
+    Ok(())
+}
+
+fn check_certificate_pem(pem: &str) -> Result<()> {
+    let chain = X509::stack_from_pem(pem.as_bytes())
+        .map_err(|e| format!("Failed to read certificate PEM: {}", e))?;
+    if chain.is_empty() {
+        return Err("Failed to read certificate PEM: no certificate".into());
+    }
+    Ok(())
 }
 
 impl Certificate {
+    /// A certificate from the PEM of the private key and of the certificate, optionally
+    /// followed by the intermediates.
+    ///
+    /// Fails if either can't be read. The key is not checked to match the certificate,
+    /// see [`key_matches_cert`]. Deserializing doesn't check the PEM.
+    ///
+    /// [`key_matches_cert`]: struct.Certificate.html#method.key_matches_cert
+    pub fn from_parts(private_key_pem: &str, certificate_pem: &str) -> Result<Self> {
+        check_private_key_pem(private_key_pem)?;
+        check_certificate_pem(certificate_pem)?;
+        Ok(Certificate::new(
+            private_key_pem.to_string(),
+            certificate_pem.to_string(),
+        ))
+    }
+
+    /// A certificate from the DER of the private key and certificate, such as from
+    /// [`private_key_der`] and [`certificate_der`]. The private key is PKCS#8, SEC1 or
+    /// PKCS#1.
+    ///
+    /// [`private_key_der`]: struct.Certificate.html#method.private_key_der
+    /// [`certificate_der`]: struct.Certificate.html#method.certificate_der
+    pub fn from_der(private_key_der: &[u8], certificate_der: &[u8]) -> Result<Self> {
+        let pkey = private_key_from_der(private_key_der)?;
+        let x509 = X509::from_der(certificate_der)
+            .map_err(|e| format!("Failed to read certificate DER: {}", e))?;
+        let pkey_pem = Zeroizing::new(pkey.private_key_to_pem_pkcs8().expect("to_pem"));
+        let pkey_pem = String::from_utf8(pkey_pem.to_vec()).expect("from_utf8");
+        let cert_pem = String::from_utf8(x509.to_pem().expect("to_pem")).expect("from_utf8");
+        Ok(Certificate::new(pkey_pem, cert_pem))
+    }
+
     pub(crate) fn new(private_key: String, certificate: String) -> Self {
+        let not_after = X509::from_pem(certificate.as_bytes())
+            .ok()
+            .and_then(|x509| {
+                // Display trait produces this format, which is kinda dumb.
+                // Apr 19 08:48:46 2019 GMT
+                parse_system_time(&x509.not_after().to_string())
+            });
         Certificate {
             private_key,
             certificate,
+            not_after,
         }
     }
 
-    /// The PEM encoded private key.
+    /// Whether the public key of the leaf certificate is the one of the private key.
+    ///
+    /// False if either can't be read.
+    pub fn key_matches_cert(&self) -> bool {
+        let pkey = match PKey::private_key_from_pem(self.private_key.as_bytes()) {
+            Ok(k) => k,
+            Err(_) => return false,
+        };
+        X509::from_pem(self.certificate.as_bytes())
+            .and_then(|x509| x509.public_key())
+            .map(|public| public.public_eq(&pkey))
+            .unwrap_or(false)
+    }
+
+    /// Whether both the private key and the certificate can be read, but don't match.
+    pub(crate) fn is_key_mismatch(&self) -> bool {
+        PKey::private_key_from_pem(self.private_key.as_bytes()).is_ok()
+            && X509::from_pem(self.certificate.as_bytes()).is_ok()
+            && !self.key_matches_cert()
+    }
+
+    /// The PEM encoded private key. Empty if [`redacted`].
+    ///
+    /// [`redacted`]: struct.Certificate.html#method.redacted
     pub fn private_key(&self) -> &str {
         &self.private_key
     }
 
+    /// A copy without the private key, such as for passing the certificate to a process
+    /// that only needs the public parts. It's serialized without the private key.
+    pub fn redacted(&self) -> Certificate {
+        Certificate {
+            private_key: String::new(),
+            certificate: self.certificate.clone(),
+            not_after: self.not_after,
+        }
+    }
+
+    /// Whether the certificate has a private key, which it doesn't if [`redacted`].
+    ///
+    /// [`redacted`]: struct.Certificate.html#method.redacted
+    pub fn has_private_key(&self) -> bool {
+        !self.private_key.is_empty()
+    }
+
     /// The private key as DER.
+    ///
+    /// See [`private_key_der_secret`] for a copy that is wiped from memory when dropped.
+    ///
+    /// [`private_key_der_secret`]: struct.Certificate.html#method.private_key_der_secret
     pub fn private_key_der(&self) -> Vec<u8> {
         let pkey = PKey::private_key_from_pem(self.private_key.as_bytes()).expect("from_pem");
         pkey.private_key_to_der().expect("private_key_to_der")
     }
 
+    /// The private key as DER, wiped from memory when dropped.
+    pub fn private_key_der_secret(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.private_key_der())
+    }
+
     /// The PEM encoded issued certificate.
     pub fn certificate(&self) -> &str {
         &self.certificate
     }
 
-    /// The issued certificate as DER.
+    /// The PEM encoded leaf certificate, without the intermediates.
+    pub fn leaf_pem(&self) -> String {
+        self.chain().into_iter().next().unwrap_or_default()
+    }
+
+    /// The PEM encoded intermediate certificates, in the order delivered by the CA.
+    ///
+    /// Empty if the CA sent only the leaf certificate.
+    pub fn intermediates_pem(&self) -> String {
+        self.chain().into_iter().skip(1).collect()
+    }
+
+    /// The PEM blocks of the chain, the leaf first, in the order delivered by the CA.
+    ///
+    /// Text between the blocks, such as comments, is dropped.
+    pub fn chain(&self) -> Vec<String> {
+        pem_blocks(&self.certificate, Some("CERTIFICATE"))
+    }
+
+    /// The number of certificates in the chain, including the leaf.
+    pub fn chain_len(&self) -> usize {
+        self.chain().len()
+    }
+
+    /// The private key, leaf and intermediate certificates as a PKCS#12 (`.p12`)
+    /// bundle, encrypted with AES.
+    ///
+    /// The `friendly_name` is shown by for example the Windows certificate store. Fails
+    /// if the private key doesn't match the certificate.
+    pub fn to_pkcs12(&self, password: &str, friendly_name: &str) -> Result<Vec<u8>> {
+        self.to_pkcs12_with(password, friendly_name, Pkcs12Encryption::Aes256)
+    }
+
+    /// Like [`to_pkcs12`] with the encryption of the bundle.
+    ///
+    /// [`to_pkcs12`]: struct.Certificate.html#method.to_pkcs12
+    pub fn to_pkcs12_with(
+        &self,
+        password: &str,
+        friendly_name: &str,
+        encryption: Pkcs12Encryption,
+    ) -> Result<Vec<u8>> {
+        let pkey = PKey::private_key_from_pem(self.private_key.as_bytes())
+            .map_err(|e| format!("Failed to read private key: {}", e))?;
+        let mut chain = X509::stack_from_pem(self.certificate.as_bytes())
+            .map_err(|e| format!("Failed to read certificate: {}", e))?
+            .into_iter();
+        let leaf = chain.next().ok_or("No certificate in the chain")?;
+        let cert_key = leaf.public_key().expect("public_key");
+        if !cert_key.public_eq(&pkey) {
+            return Err(Error::Validation(
+                "The private key does not match the certificate".into(),
+            ));
+        }
+        let mut ca = Stack::new().expect("Stack");
+        for x509 in chain {
+            ca.push(x509).expect("push");
+        }
+
+        let (algorithm, mac_md) = match encryption {
+            Pkcs12Encryption::Aes256 => (Nid::AES_256_CBC, MessageDigest::sha256()),
+            Pkcs12Encryption::Legacy3Des => (
+                Nid::PBE_WITHSHA1AND3_KEY_TRIPLEDES_CBC,
+                MessageDigest::sha1(),
+            ),
+        };
+        let pkcs12 = Pkcs12::builder()
+            .name(friendly_name)
+            .pkey(&pkey)
+            .cert(&leaf)
+            .ca(ca)
+            .key_algorithm(algorithm)
+            .cert_algorithm(algorithm)
+            .mac_md(mac_md)
+            .build2(password)
+            .map_err(|e| format!("Failed to create PKCS#12: {}", e))?;
+        Ok(pkcs12.to_der().expect("to_der"))
+    }
+
+    /// The private key and the certificate chain in one PEM string.
+    ///
+    /// The PEM blocks are separated by exactly one newline, and the string ends in one.
+    pub fn bundle_pem(&self, order: BundleOrder) -> String {
+        let key: String = pem_blocks(&self.private_key, None).concat();
+        let chain: String = self.chain().concat();
+        match order {
+            BundleOrder::KeyFirst => key + &chain,
+            BundleOrder::CertFirst => chain + &key,
+        }
+    }
+
+    /// Write the [key first bundle] to the file, replacing it atomically.
+    ///
+    /// The bundle is written to a temporary file next to it, which is renamed. On Unix
+    /// the file gets the permissions of the `mode`, typically `0o600` since the file has
+    /// the private key. Elsewhere the mode is ignored.
+    ///
+    /// [key first bundle]: struct.Certificate.html#method.bundle_pem
+    pub fn write_bundle(&self, path: &Path, mode: u32) -> Result<()> {
+        let bundle = Zeroizing::new(self.bundle_pem(BundleOrder::KeyFirst));
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("Not a file: {}", path.display()))?;
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(file_name);
+        tmp_name.push(".tmp");
+        let tmp = path.with_file_name(tmp_name);
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        let written = options.open(&tmp).and_then(|mut file| {
+            // the mode only applies to new files.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
+            file.write_all(bundle.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&tmp, path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// The issued (leaf) certificate as DER, without the rest of the chain.
+    ///
+    /// See [`chain_der`] for the whole chain.
+    ///
+    /// [`chain_der`]: struct.Certificate.html#method.chain_der
     pub fn certificate_der(&self) -> Vec<u8> {
         let x509 = X509::from_pem(self.certificate.as_bytes()).expect("from_pem");
         x509.to_der().expect("to_der")
     }
 
+    /// The certificate chain as one DER per certificate, the leaf first, in the order
+    /// delivered by the CA.
+    ///
+    /// This is the form wanted by, for example, rustls.
+    pub fn chain_der(&self) -> Vec<Vec<u8>> {
+        X509::stack_from_pem(self.certificate.as_bytes())
+            .expect("stack_from_pem")
+            .iter()
+            .map(|x509| x509.to_der().expect("to_der"))
+            .collect()
+    }
+
+    /// The ARI certificate identifier, the base64url encoded authority key identifier and
+    /// serial number separated by a dot.
+    pub(crate) fn ari_cert_id(&self) -> Result<String> {
+        let x509 = X509::from_pem(self.certificate.as_bytes())
+            .map_err(|e| format!("Failed to read certificate: {}", e))?;
+        let aki = x509
+            .authority_key_id()
+            .ok_or("Certificate has no authority key identifier")?;
+        let serial = x509.serial_number().to_bn().expect("to_bn").to_vec();
+        // the DER encoding of the integer, which is positive.
+        let mut der = vec![];
+        if serial.first().map(|b| b & 0x80 != 0).unwrap_or(true) {
+            der.push(0);
+        }
+        der.extend_from_slice(&serial);
+        Ok(format!("{}.{}", base64url(aki.as_slice()), base64url(&der)))
+    }
+
+    /// The DNS names and IP addresses in the subject alternative names of the leaf
+    /// certificate, in the order of the certificate.
+    ///
+    /// IP addresses are in the canonical text form, such as `2001:db8::1`. Empty if the
+    /// certificate can't be read.
+    pub fn sans(&self) -> Vec<String> {
+        let x509 = match X509::from_pem(self.certificate.as_bytes()) {
+            Ok(x509) => x509,
+            Err(_) => return vec![],
+        };
+        x509.subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|n| {
+                        n.dnsname()
+                            .map(|d| d.to_string())
+                            .or_else(|| n.ipaddress().and_then(ip_to_string))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The serial number of the leaf certificate as lowercase hex, such as `87654321`.
+    pub fn serial_hex(&self) -> String {
+        let x509 = X509::from_pem(self.certificate.as_bytes()).expect("from_pem");
+        let serial = x509.serial_number().to_bn().expect("to_bn");
+        serial.to_hex_str().expect("to_hex_str").to_lowercase()
+    }
+
+    /// The SHA-256 fingerprint of the leaf certificate, the digest of its DER.
+    pub fn fingerprint_sha256(&self) -> [u8; 32] {
+        sha256(&self.certificate_der())
+    }
+
+    /// The common name of the issuer of the leaf certificate.
+    pub fn issuer_cn(&self) -> Option<String> {
+        let x509 = X509::from_pem(self.certificate.as_bytes()).ok()?;
+        let cn = x509.issuer_name().entries_by_nid(Nid::COMMONNAME).next()?;
+        cn.data().to_string().ok()
+    }
+
+    /// The DNS names, IP addresses and email addresses in the subject alternative names
+    /// of the certificate, with their type, empty if the certificate can't be read.
+    pub(crate) fn identifiers(&self) -> Vec<ApiIdentifier> {
+        let x509 = match X509::from_pem(self.certificate.as_bytes()) {
+            Ok(x509) => x509,
+            Err(_) => return vec![],
+        };
+        let id = |t: &str, value: String| ApiIdentifier {
+            _type: t.into(),
+            value,
+        };
+        x509.subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|n| {
+                        n.dnsname()
+                            .map(|d| id("dns", d.to_string()))
+                            .or_else(|| n.ipaddress().and_then(ip_to_string).map(|i| id("ip", i)))
+                            .or_else(|| n.email().map(|e| id("email", e.to_string())))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The DNS names, IP addresses and email addresses in the subject alternative names
+    /// of the certificate, empty if the certificate can't be read.
+    pub(crate) fn domains(&self) -> Vec<String> {
+        let x509 = match X509::from_pem(self.certificate.as_bytes()) {
+            Ok(x509) => x509,
+            Err(_) => return vec![],
+        };
+        x509.subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|n| {
+                        n.dnsname()
+                            .map(|d| d.to_string())
+                            .or_else(|| n.ipaddress().and_then(ip_to_string))
+                            .or_else(|| n.email().map(|e| e.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Inspect the certificate to count the number of (whole) valid days left.
     ///
     /// It's up to the ACME API provider to decide how long an issued certificate is valid.
     /// Let's Encrypt sets the validity to 90 days. This function reports 89 days for newly
-    /// issued cert, since it counts _whole_ days.
+    /// issued cert, since it counts _whole_ days. See [`valid_duration_left`] for short
+    /// lived certificates.
     ///
     /// It is possible to get negative days for an expired certificate.
+    ///
+    /// [`valid_duration_left`]: struct.Certificate.html#method.valid_duration_left
     pub fn valid_days_left(&self) -> i64 {
-        // the cert used in the tests is not valid to load as x509
-        if cfg!(test) {
-            return 89;
-        }
+        days_until(self.valid_until())
+    }
 
-        // load as x509
-        let x509 = X509::from_pem(self.certificate.as_bytes()).expect("from_pem");
+    /// The names and validity of every certificate in the chain, in the order they are
+    /// stored. Certificates that can't be read are left out.
+    pub fn chain_validity(&self) -> Vec<ChainCertInfo> {
+        let chain = match X509::stack_from_pem(self.certificate.as_bytes()) {
+            Ok(chain) => chain,
+            Err(_) => return vec![],
+        };
+        chain
+            .iter()
+            .filter_map(|x509| {
+                Some(ChainCertInfo {
+                    subject: name_to_string(x509.subject_name()),
+                    issuer: name_to_string(x509.issuer_name()),
+                    not_before: parse_system_time(&x509.not_before().to_string())?,
+                    not_after: parse_system_time(&x509.not_after().to_string())?,
+                })
+            })
+            .collect()
+    }
 
-        // convert asn1 time to Tm
-        let not_after = format!("{}", x509.not_after());
-        // Display trait produces this format, which is kinda dumb.
-        // Apr 19 08:48:46 2019 GMT
-        let expires = parse_date(&not_after);
-        let dur = expires - time::now();
+    /// The whole days left until the first certificate of the chain expires, the leaf
+    /// or an intermediate. Negative once one has expired.
+    ///
+    /// An intermediate expiring before the leaf is a reason to renew early, or to pick
+    /// an [alternate chain].
+    ///
+    /// [alternate chain]: order/struct.CertOrder.html#method.download_and_save_cert_preferred
+    pub fn chain_min_days_left(&self) -> i64 {
+        self.chain_validity()
+            .iter()
+            .map(|c| c.days_left())
+            .min()
+            .unwrap_or_else(|| self.valid_days_left())
+    }
+
+    /// The end of the validity of the leaf certificate, its notAfter.
+    ///
+    /// The time is parsed when the certificate is created. Panics if the certificate
+    /// can't be read.
+    pub fn valid_until(&self) -> SystemTime {
+        self.not_after.expect("Certificate with notAfter")
+    }
+
+    /// The time left until the certificate expires, zero if it has expired.
+    pub fn valid_duration_left(&self) -> Duration {
+        self.valid_until()
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
 
-        dur.num_days()
+    /// Whether the certificate has expired.
+    pub fn is_expired(&self) -> bool {
+        self.valid_until() <= SystemTime::now()
     }
 }
 
-fn parse_date(s: &str) -> time::Tm {
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
+/// The PEM blocks with the label, or any label, each ending in a newline.
+fn pem_blocks(pem: &str, label: Option<&str>) -> Vec<String> {
+    let mut blocks = vec![];
+    // the block so far and its end line
+    let mut block: Option<(String, String)> = None;
+    for line in pem.lines().map(str::trim) {
+        if block.is_none() {
+            let begin = line
+                .strip_prefix("-----BEGIN ")
+                .and_then(|l| l.strip_suffix("-----"));
+            if let Some(l) = begin.filter(|l| label.map(|x| x == *l).unwrap_or(true)) {
+                block = Some((String::new(), format!("-----END {}-----", l)));
+            }
+        }
+        if let Some((b, end)) = &mut block {
+            b.push_str(line);
+            b.push('\n');
+            if line == end {
+                blocks.extend(block.take().map(|(b, _)| b));
+            }
+        }
+    }
+    blocks
+}
+
+/// The common name of the issuer of the topmost certificate in a PEM chain, the one
+/// closest to the root. `None` if the chain can't be read or the issuer has no CN.
+pub(crate) fn chain_top_issuer_cn(chain_pem: &str) -> Option<String> {
+    let chain = X509::stack_from_pem(chain_pem.as_bytes()).ok()?;
+    let top = chain.last()?;
+    let cn = top.issuer_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    cn.data().to_string().ok()
+}
+
+/// The whole days from now until the time, negative for a time in the past.
+fn days_until(t: SystemTime) -> i64 {
+    let secs = match t.duration_since(SystemTime::now()) {
+        Ok(left) => left.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    secs / (24 * 60 * 60)
+}
+
+/// Text form of a name, such as `C=US, O=Let's Encrypt, CN=R3`.
+fn name_to_string(name: &openssl::x509::X509NameRef) -> String {
+    name.entries()
+        .map(|e| {
+            let key = e.object().nid().short_name().unwrap_or("?");
+            let value = e.data().to_string().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Text form of an IP address SAN, `None` if neither IPv4 nor IPv6.
+fn ip_to_string(b: &[u8]) -> Option<String> {
+    let addr: IpAddr = match b.len() {
+        4 => <[u8; 4]>::try_from(b).ok()?.into(),
+        16 => <[u8; 16]>::try_from(b).ok()?.into(),
+        _ => return None,
+    };
+    Some(addr.to_string())
+}
+
+fn parse_date(s: &str) -> Option<time::Tm> {
     debug!("Parse date/time: {}", s);
-    time::strptime(s, "%h %e %H:%M:%S %Y %Z").expect("strptime")
+    time::strptime(s, "%h %e %H:%M:%S %Y %Z").ok()
+}
+
+/// An ASN.1 time as displayed by openssl, such as `Apr 19 08:48:46 2019 GMT`.
+pub(crate) fn parse_system_time(s: &str) -> Option<SystemTime> {
+    parse_date(s).map(|tm| UNIX_EPOCH + Duration::from_secs(tm.to_timespec().sec.max(0) as u64))
 }
 
 #[cfg(test)]
@@ -168,7 +1162,465 @@ mod test {
 
     #[test]
     fn test_parse_date() {
-        let x = parse_date("May  3 07:40:15 2019 GMT");
+        let x = parse_date("May  3 07:40:15 2019 GMT").unwrap();
         assert_eq!(time::strftime("%F %T", &x).unwrap(), "2019-05-03 07:40:15");
     }
+
+    #[test]
+    fn test_certificate_serde() -> Result<()> {
+        let key = crate::test::TEST_CERT_KEY_PEM;
+        let pem = crate::test::TEST_CERT_PEM;
+        let cert = Certificate::from_parts(key, pem)?;
+        assert!(cert.has_private_key());
+        assert!(cert.key_matches_cert());
+        assert!(Certificate::from_parts("garbage", pem).is_err());
+        assert!(Certificate::from_parts(key, "garbage").is_err());
+        assert!(Certificate::from_parts(key, "").is_err());
+
+        let json = serde_json::to_string(&cert)?;
+        let read: Certificate = serde_json::from_str(&json)?;
+        assert_eq!(read, cert);
+        assert_eq!(read.valid_until(), cert.valid_until());
+
+        let redacted = cert.redacted();
+        assert!(!redacted.has_private_key());
+        let json = serde_json::to_value(&redacted)?;
+        assert!(json.get("private_key").is_none());
+        assert_eq!(json["certificate"], pem);
+        let read: Certificate = serde_json::from_value(json)?;
+        assert_eq!(read, redacted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_private_key_der() -> Result<()> {
+        let (p256, _) = create_p256_key();
+        let (rsa, _) = create_rsa_key(2048);
+        for pkey in &[p256, rsa] {
+            let traditional = pkey.private_key_to_der().unwrap();
+            for der in &[private_key_to_der(pkey).to_vec(), traditional] {
+                let read = private_key_from_der(der)?;
+                assert!(read.public_eq(pkey));
+            }
+            assert_eq!(
+                public_key_of(pkey).public_key_to_der().unwrap(),
+                pkey.public_key_to_der().unwrap()
+            );
+        }
+        let err = private_key_from_der(b"garbage").unwrap_err().to_string();
+        assert!(err.contains("PKCS#8, SEC1 and PKCS#1"), "{}", err);
+
+        let cert = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            crate::test::TEST_CERT_PEM.into(),
+        );
+        let der = Certificate::from_der(&cert.private_key_der(), &cert.certificate_der())?;
+        assert_eq!(der.certificate_der(), cert.certificate_der());
+        assert_eq!(der.private_key_der(), cert.private_key_der());
+        assert!(Certificate::from_der(&cert.private_key_der(), b"garbage").is_err());
+
+        assert_eq!(cert.chain_der(), vec![cert.certificate_der()]);
+        let intermediate = crate::test::test_intermediate_pem("Test Root");
+        let chain = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            format!("{}{}", crate::test::TEST_CERT_PEM, intermediate),
+        );
+        assert_eq!(chain.certificate_der(), cert.certificate_der());
+        let intermediate = X509::from_pem(intermediate.as_bytes()).unwrap();
+        assert_eq!(
+            chain.chain_der(),
+            vec![cert.certificate_der(), intermediate.to_der().unwrap()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_pem() {
+        let leaf = crate::test::TEST_CERT_PEM;
+        let cert = Certificate::new(String::new(), leaf.into());
+        assert_eq!(cert.leaf_pem(), leaf);
+        assert_eq!(cert.intermediates_pem(), "");
+        assert_eq!(cert.chain_len(), 1);
+
+        let first = crate::test::test_intermediate_pem("Test Root");
+        let second = crate::test::test_intermediate_pem("Other Root");
+        let bundle = format!(
+            "# leaf\r\n{}\n# intermediates\n{}{}garbage\n",
+            leaf.replace('\n', "\r\n"),
+            first,
+            second
+        );
+        let cert = Certificate::new(String::new(), bundle);
+        assert_eq!(
+            cert.chain(),
+            vec![leaf.to_string(), first.clone(), second.clone()]
+        );
+        assert_eq!(cert.leaf_pem(), leaf);
+        assert_eq!(cert.intermediates_pem(), format!("{}{}", first, second));
+        assert_eq!(cert.chain_len(), 3);
+    }
+
+    #[test]
+    fn test_to_pkcs12() -> Result<()> {
+        let intermediate = crate::test::test_intermediate_pem("Test Root");
+        let cert = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            format!("{}{}", crate::test::TEST_CERT_PEM, intermediate),
+        );
+        for encryption in &[Pkcs12Encryption::Aes256, Pkcs12Encryption::Legacy3Des] {
+            let der = cert.to_pkcs12_with("secret", "acmetest", *encryption)?;
+            let pkcs12 = Pkcs12::from_der(&der).unwrap();
+            assert!(pkcs12.parse2("wrong").is_err());
+            let parsed = pkcs12.parse2("secret").unwrap();
+            let pkey = parsed.pkey.unwrap();
+            assert_eq!(pkey.private_key_to_der().unwrap(), cert.private_key_der());
+            assert_eq!(
+                parsed.cert.unwrap().to_der().unwrap(),
+                cert.certificate_der()
+            );
+            let ca: Vec<_> = parsed
+                .ca
+                .unwrap()
+                .iter()
+                .map(|c| c.to_der().unwrap())
+                .collect();
+            assert_eq!(ca, cert.chain_der()[1..].to_vec());
+        }
+
+        let (other, _) = create_p256_key();
+        let other_pem = String::from_utf8(other.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let mismatch = Certificate::new(other_pem, crate::test::TEST_CERT_PEM.into());
+        let err = mismatch.to_pkcs12("secret", "acmetest").unwrap_err();
+        assert!(matches!(err, Error::Validation(_)), "{}", err);
+        Ok(())
+    }
+
+    /// A self-signed certificate valid until the offset in seconds from now.
+    fn cert_expiring_in(secs: i64) -> Certificate {
+        let (pkey, _) = create_p256_key();
+        let now = time::get_time().sec;
+        let mut b = X509Builder::new().unwrap();
+        b.set_pubkey(&pkey).unwrap();
+        b.set_not_before(&Asn1Time::from_unix(now - 7200).unwrap())
+            .unwrap();
+        b.set_not_after(&Asn1Time::from_unix(now + secs).unwrap())
+            .unwrap();
+        b.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let pem = String::from_utf8(b.build().to_pem().unwrap()).unwrap();
+        Certificate::new(String::new(), pem)
+    }
+
+    #[test]
+    fn test_valid_until() {
+        let cert = cert_expiring_in(90);
+        assert!(!cert.is_expired());
+        let left = cert.valid_duration_left();
+        assert!(left > Duration::from_secs(80) && left <= Duration::from_secs(90));
+        assert_eq!(cert.valid_days_left(), 0);
+        let until = cert.valid_until().duration_since(UNIX_EPOCH).unwrap();
+        assert!((until.as_secs() as i64 - time::get_time().sec - 90).abs() <= 10);
+
+        let cert = cert_expiring_in(-3600);
+        assert!(cert.is_expired());
+        assert_eq!(cert.valid_duration_left(), Duration::from_secs(0));
+        assert_eq!(cert.valid_days_left(), 0);
+        assert_eq!(cert_expiring_in(-2 * 86400 - 60).valid_days_left(), -2);
+        assert_eq!(cert_expiring_in(89 * 86400 + 60).valid_days_left(), 89);
+
+        // kept when serialized
+        let json = serde_json::to_string(&cert).unwrap();
+        let read: Certificate = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.valid_until(), cert.valid_until());
+        assert_eq!(read, cert);
+    }
+
+    #[test]
+    fn test_chain_validity() {
+        let leaf = crate::test::TEST_CERT_PEM;
+        let intermediate = crate::test::test_intermediate_pem("Test Root");
+
+        let cert = Certificate::new(String::new(), leaf.into());
+        let chain = cert.chain_validity();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].subject, "CN=acmetest.example.com");
+        assert_eq!(chain[0].issuer, "CN=Test CA");
+        assert_eq!(chain[0].not_after, cert.valid_until());
+        assert!(chain[0].not_before < chain[0].not_after);
+        assert_eq!(cert.chain_min_days_left(), cert.valid_days_left());
+
+        // the intermediate expires first, also when stored before the leaf
+        for pem in &[
+            format!("{}{}", leaf, intermediate),
+            format!("{}{}", intermediate, leaf),
+        ] {
+            let cert = Certificate::new(String::new(), pem.clone());
+            let chain = cert.chain_validity();
+            assert_eq!(chain.len(), 2);
+            let ca = chain.iter().find(|c| c.subject == "CN=Test CA").unwrap();
+            assert_eq!(ca.issuer, "CN=Test Root");
+            assert_eq!(cert.chain_min_days_left(), 364);
+        }
+    }
+
+    #[test]
+    fn test_cert_details() {
+        let cert = Certificate::new(String::new(), crate::test::TEST_CERT_PEM.into());
+        assert_eq!(cert.sans(), vec!["acmetest.example.com"]);
+        assert_eq!(cert.serial_hex(), "87654321");
+        let hex: String = cert
+            .fingerprint_sha256()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        assert_eq!(
+            hex,
+            "2F3B06885235C73FEFB484CAA82E77F06D86D836E9C761B76A5CAA540C29C436"
+        );
+        assert_eq!(cert.issuer_cn().as_deref(), Some("Test CA"));
+
+        let (pkey, _) = create_p256_key();
+        let mut b = X509Builder::new().unwrap();
+        b.set_version(2).unwrap();
+        b.set_pubkey(&pkey).unwrap();
+        b.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        b.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let mut san = SubjectAlternativeName::new();
+        san.dns("example.com")
+            .ip("2001:DB8:0::1")
+            .ip("10.0.0.1")
+            .email("a@example.com");
+        let san = san.build(&b.x509v3_context(None, None)).unwrap();
+        b.append_extension(san).unwrap();
+        b.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let pem = String::from_utf8(b.build().to_pem().unwrap()).unwrap();
+        let cert = Certificate::new(String::new(), pem);
+        assert_eq!(cert.sans(), vec!["example.com", "2001:db8::1", "10.0.0.1"]);
+        assert_eq!(cert.issuer_cn(), None);
+    }
+
+    #[test]
+    fn test_bundle_pem() -> Result<()> {
+        let key = crate::test::TEST_CERT_KEY_PEM;
+        let leaf = crate::test::TEST_CERT_PEM;
+        let intermediate = crate::test::test_intermediate_pem("Test Root");
+        let cert = Certificate::new(
+            format!("\n{}\n\n", key.replace('\n', "\r\n")),
+            format!("{}\n\n{}", leaf.trim_end(), intermediate),
+        );
+        let chain = format!("{}{}", leaf, intermediate);
+        assert_eq!(
+            cert.bundle_pem(BundleOrder::KeyFirst),
+            format!("{}{}", key, chain)
+        );
+        assert_eq!(
+            cert.bundle_pem(BundleOrder::CertFirst),
+            format!("{}{}", chain, key)
+        );
+
+        let dir = crate::test::temp_dir("bundle");
+        let path = dir.join("bundle.pem");
+        fs::write(&path, "old")?;
+        cert.write_bundle(&path, 0o600)?;
+        assert_eq!(fs::read_to_string(&path)?, format!("{}{}", key, chain));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+        assert!(cert
+            .write_bundle(&dir.join("missing/bundle.pem"), 0o600)
+            .is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_ari_cert_id() -> Result<()> {
+        let cert = Certificate::new(
+            crate::test::TEST_CERT_KEY_PEM.into(),
+            crate::test::TEST_CERT_PEM.into(),
+        );
+        assert_eq!(cert.ari_cert_id()?, "aYhba4dGQEHhs3uEe6CuLN4ByNQ.AIdlQyE");
+        let broken = Certificate::new("".into(), "CERT HERE".into());
+        assert!(broken.ari_cert_id().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_tls_alpn_cert() -> Result<()> {
+        let pkey = PKey::private_key_from_pem(crate::test::TEST_CERT_KEY_PEM.as_bytes())
+            .expect("from_pem");
+        let id = ApiIdentifier {
+            _type: "dns".into(),
+            value: "acmetest.example.com".into(),
+        };
+        let key_auth = "WCdRWkCy4THTD_j5IH4ISAzr59lFIg5wzYmKxuOJ1lU.thumbprint";
+        let x509 = create_tls_alpn_cert(&pkey, &id, key_auth)?;
+        let cert = Certificate::new(
+            "".into(),
+            String::from_utf8(x509.to_pem().unwrap()).unwrap(),
+        );
+        assert_eq!(cert.domains(), vec!["acmetest.example.com"]);
+
+        // OID 1.3.6.1.5.5.7.1.31, critical, OCTET STRING of the sha256 digest
+        let mut ext = vec![
+            0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f, 0x01, 0x01, 0xff, 0x04,
+            0x22, 0x04, 0x20,
+        ];
+        ext.extend_from_slice(&[
+            0xd0, 0xd5, 0x54, 0x2f, 0xfe, 0x7e, 0x27, 0xd7, 0xaf, 0x52, 0x25, 0x2b, 0x1e, 0x0c,
+            0x14, 0x2c, 0xf5, 0xa7, 0x99, 0x0f, 0x0e, 0x31, 0x5c, 0x9a, 0x5e, 0x20, 0xa5, 0x48,
+            0xba, 0x71, 0x08, 0xc5,
+        ]);
+        let der = x509.to_der().unwrap();
+        assert!(der.windows(ext.len()).any(|w| w == &ext[..]));
+
+        let id = ApiIdentifier {
+            _type: "email".into(),
+            value: "a@example.com".into(),
+        };
+        assert!(create_tls_alpn_cert(&pkey, &id, key_auth).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_csr() -> Result<()> {
+        let (pri, public) = create_p256_key();
+        let ids = [
+            ApiIdentifier {
+                _type: "dns".into(),
+                value: "example.com".into(),
+            },
+            ApiIdentifier {
+                _type: "dns".into(),
+                value: "www.example.com".into(),
+            },
+            ApiIdentifier {
+                _type: "ip".into(),
+                value: "10.0.0.5".into(),
+            },
+            ApiIdentifier {
+                _type: "email".into(),
+                value: "user@example.com".into(),
+            },
+        ];
+        let csr = create_csr(&pri, &public, &ids, &CsrOptions::new())?;
+        let text = String::from_utf8(csr.to_text().unwrap()).unwrap();
+        assert!(text.contains(
+            "DNS:example.com, DNS:www.example.com, IP Address:10.0.0.5, email:user@example.com"
+        ));
+        let unknown = ApiIdentifier {
+            _type: "unknown".into(),
+            value: "x".into(),
+        };
+        assert!(create_csr(&pri, &public, &[unknown], &CsrOptions::new()).is_err());
+        assert_eq!(csr_extensions(&csr).len(), 1);
+        assert_eq!(csr_identifiers(&csr), ids.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_rsa_key_with() -> Result<()> {
+        let (pri, public) = create_rsa_key_with(2048, 65537)?;
+        let info = KeyInfo::of(&pri);
+        assert_eq!((info.algorithm(), info.bits()), ("RSA", 2048));
+        assert_eq!(KeyInfo::of(&public), info);
+        assert_eq!(
+            pri.rsa().unwrap().e().to_dec_str().unwrap().to_string(),
+            "65537"
+        );
+        for (bits, e) in &[
+            (512, 65537),
+            (1024, 65537),
+            (2048, 3),
+            (2048, 65538),
+            (32768, 65537),
+        ] {
+            let err = create_rsa_key_with(*bits, *e).unwrap_err();
+            assert!(matches!(err, Error::Validation(_)), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_info() {
+        let info = |k: (PKey<pkey::Private>, PKey<pkey::Public>)| KeyInfo::of(&k.0).to_string();
+        assert_eq!(info(create_p256_key()), "P-256 (256 bits)");
+        assert_eq!(info(create_p384_key()), "P-384 (384 bits)");
+        assert_eq!(info(create_p521_key()), "P-521 (521 bits)");
+        assert_eq!(info(create_ed25519_key()), "Ed25519 (256 bits)");
+    }
+
+    #[test]
+    fn test_create_csr_curves() -> Result<()> {
+        let ids = [ApiIdentifier {
+            _type: "dns".into(),
+            value: "example.com".into(),
+        }];
+        let keys = [
+            (create_p256_key(), "ecdsa-with-SHA256"),
+            (create_p384_key(), "ecdsa-with-SHA384"),
+            (create_p521_key(), "ecdsa-with-SHA512"),
+            (create_rsa_key(2048), "sha256WithRSAEncryption"),
+            (create_ed25519_key(), "ED25519"),
+        ];
+        for ((pri, public), alg) in &keys {
+            let csr = create_csr(pri, public, &ids, &CsrOptions::new())?;
+            let text = String::from_utf8(csr.to_text().unwrap()).unwrap();
+            assert!(text.contains(alg), "{}", text);
+            assert!(csr.verify(public).unwrap());
+            assert_eq!(csr_domains(&csr), vec!["example.com"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_csr_must_staple() -> Result<()> {
+        let (pri, public) = create_p256_key();
+        let ids = [ApiIdentifier {
+            _type: "dns".into(),
+            value: "example.com".into(),
+        }];
+        let opts = CsrOptions::new().with_must_staple(true);
+        let csr = create_csr(&pri, &public, &ids, &opts)?;
+        assert_eq!(csr_domains(&csr), vec!["example.com"]);
+        let tls_feature = (
+            vec![0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18],
+            false,
+            vec![0x30, 0x03, 0x02, 0x01, 0x05],
+        );
+        assert!(csr_extensions(&csr).contains(&tls_feature));
+        assert!(csr.verify(&public).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_csr_extension() -> Result<()> {
+        let (pri, public) = create_p256_key();
+        let ids = [ApiIdentifier {
+            _type: "dns".into(),
+            value: "example.com".into(),
+        }];
+        // a UTF8String with a workload identity under a private OID
+        let mut value = vec![0x0c, 0x0b];
+        value.extend_from_slice(b"workload-42");
+        let opts = CsrOptions::new().add_extension("1.3.6.1.4.1.55555.1", true, value.clone());
+        let csr = create_csr(&pri, &public, &ids, &opts)?;
+        assert_eq!(csr_domains(&csr), vec!["example.com"]);
+        // 1.3.6.1.4.1.55555.1
+        let oid = vec![
+            0x06, 0x09, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb2, 0x03, 0x01,
+        ];
+        assert!(csr_extensions(&csr).contains(&(oid, true, value)));
+
+        let bad = CsrOptions::new().add_extension("not an oid", false, vec![0x05, 0x00]);
+        assert!(create_csr(&pri, &public, &ids, &bad).is_err());
+        let san = CsrOptions::new().add_extension("2.5.29.17", false, vec![0x30, 0x00]);
+        assert!(create_csr(&pri, &public, &ids, &san).is_err());
+        Ok(())
+    }
 }
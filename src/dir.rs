@@ -1,13 +1,20 @@
 //
+use std::collections::HashMap;
 use std::sync::Arc;
+use zeroize::Zeroizing;
 
-use crate::acc::AcmeKey;
-use crate::api::{ApiAccount, ApiDirectory};
-use crate::persist::{Persist, PersistKey, PersistKind};
+use crate::acc::{
+    acme_key_persist_key, acme_url_persist_key, check_revocation_reason, mailto, save_certificate,
+    AccountKeyType, AcmeKey, NO_CONTACT_REALM,
+};
+use crate::api::{ApiAccount, ApiDirectory, ApiRevocation};
+use crate::jwt::eab_jws;
+use crate::order::{EventHook, OrderEvent};
+use crate::persist::{Persist, PersistExt, PersistKey, PersistKeyBuf};
 use crate::req::{req_expect_header, req_get, req_handle_error};
 use crate::trans::{NoncePool, Transport};
-use crate::util::read_json;
-use crate::{Account, Result};
+use crate::util::{base64url, base64url_decode, read_json};
+use crate::{Account, AccountExport, AcmeSigner, Certificate, Error, Result, RevocationReason};
 
 const LETSENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
 const LETSENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
@@ -35,28 +42,154 @@ impl<'a> DirectoryUrl<'a> {
     }
 }
 
+/// What to use when accessing an account.
+struct AccountParams<'a> {
+    /// Identifies the account in the persistence.
+    realm: &'a str,
+    /// Contact emails, may be empty.
+    contact_emails: &'a [&'a str],
+    /// Key type in case we need to create a new key.
+    key_type: AccountKeyType,
+    /// External account binding as (kid, base64url hmac key).
+    eab: Option<(&'a str, &'a str)>,
+    /// Externally managed key, not read from or written to the persistence.
+    acme_key: Option<AcmeKey>,
+    /// Whether to agree to the terms of service.
+    tos_agreed: bool,
+}
+
 /// Entry point for accessing an ACME API.
 #[derive(Clone)]
 pub struct Directory<P: Persist> {
     persist: P,
     nonce_pool: Arc<NoncePool>,
     api_directory: ApiDirectory,
+    /// Separates accounts of this directory from other directories in the persistence.
+    /// `None` for the legacy behavior of only using the contact email.
+    realm_scope: Option<String>,
+    events: EventHook,
 }
 
 impl<P: Persist> Directory<P> {
     /// Create a directory over a persistence implementation and directory url.
+    ///
+    /// Accounts and certificates are persisted separately for each directory url, so the
+    /// same contact email can be used with both [`LetsEncrypt`] and [`LetsEncryptStaging`]
+    /// without them overwriting each other.
+    ///
+    /// Older versions persisted by the contact email only. When an account has nothing
+    /// persisted for the directory, but an account key persisted that way, the key and
+    /// everything else persisted under the contact email is copied for the directory. The
+    /// originals are left for other directories. Without [`Persist::list`], only the
+    /// account key and URL are copied.
+    ///
+    /// [`LetsEncrypt`]: enum.DirectoryUrl.html#variant.LetsEncrypt
+    /// [`LetsEncryptStaging`]: enum.DirectoryUrl.html#variant.LetsEncryptStaging
+    /// [`Persist::list`]: trait.Persist.html#method.list
     pub fn from_url(persist: P, url: DirectoryUrl) -> Result<Directory<P>> {
         let dir_url = url.to_url();
-        let res = req_handle_error(req_get(&dir_url))?;
+        let res = req_handle_error(req_get(dir_url))?;
         let api_directory: ApiDirectory = read_json(res)?;
         let nonce_pool = Arc::new(NoncePool::new(&api_directory.newNonce));
         Ok(Directory {
             persist,
             nonce_pool,
             api_directory,
+            realm_scope: Some(dir_url.to_string()),
+            events: EventHook::default(),
+        })
+    }
+
+    /// Use an explicit realm to separate the accounts and certificates of this directory
+    /// from other directories in the persistence, instead of the directory url.
+    ///
+    /// This keeps the persisted state when the directory url changes.
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        self.realm_scope = Some(realm.to_string());
+        self
+    }
+
+    /// Identify accounts and certificates in the persistence by the contact email only.
+    ///
+    /// This was the behavior before the persistence was separated per directory. Use it to
+    /// keep using a persistence written by an older version of this library in place,
+    /// instead of a copy. The same contact email used with different directories shares
+    /// the persisted state.
+    pub fn with_legacy_realm(mut self) -> Self {
+        self.realm_scope = None;
+        self
+    }
+
+    /// Call the hook with the progress of orders of accounts accessed through this
+    /// directory, e.g. to show it in a UI.
+    ///
+    /// The hook is called on the thread doing the order, and should return quickly. A
+    /// panic in the hook is caught and logged, it doesn't affect the order. Note that the
+    /// panic message is still printed by the panic handler.
+    pub fn with_event_hook<F: Fn(OrderEvent) + Send + Sync + 'static>(mut self, hook: F) -> Self {
+        self.events = EventHook::new(hook);
+        self
+    }
+
+    /// URL of the terms of service of the ACME API provider, if any.
+    ///
+    /// [`account`] and the other ways of accessing an account implicitly agree to the terms
+    /// of service. Use [`account_with_tos`] to show the terms first.
+    ///
+    /// [`account`]: struct.Directory.html#method.account
+    /// [`account_with_tos`]: struct.Directory.html#method.account_with_tos
+    pub fn terms_of_service(&self) -> Option<String> {
+        self.api_directory
+            .meta
+            .as_ref()
+            .and_then(|m| m.termsOfService.clone())
+    }
+
+    /// Certificate profiles offered by the ACME API provider, mapping the name of each
+    /// profile to a description, if any.
+    ///
+    /// Select a profile using [`Account::new_order_with_profile`].
+    ///
+    /// [`Account::new_order_with_profile`]: struct.Account.html#method.new_order_with_profile
+    pub fn profiles(&self) -> Option<HashMap<String, String>> {
+        self.api_directory
+            .meta
+            .as_ref()
+            .and_then(|m| m.profiles.clone())
+    }
+
+    /// Access an account identified by a contact email, explicitly agreeing or not to the
+    /// [terms of service].
+    ///
+    /// Some ACME API providers require showing the terms of service before agreeing. If the
+    /// ACME API requires agreeing when `tos_agreed` is false, this fails with
+    /// [`Error::TermsOfService`] carrying the URL of the terms, after which the caller can
+    /// ask for agreement and retry.
+    ///
+    /// Works like [`account`] otherwise.
+    ///
+    /// [terms of service]: struct.Directory.html#method.terms_of_service
+    /// [`Error::TermsOfService`]: enum.Error.html#variant.TermsOfService
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_with_tos(&self, contact_email: &str, tos_agreed: bool) -> Result<Account<P>> {
+        self.account_with_params(AccountParams {
+            realm: contact_email,
+            contact_emails: &[contact_email],
+            key_type: AccountKeyType::P256,
+            eab: None,
+            acme_key: None,
+            tos_agreed,
         })
     }
 
+    /// The realm for an account in the persistence.
+    pub(crate) fn realm(&self, account_realm: &str) -> String {
+        match &self.realm_scope {
+            Some(scope) => format!("{} {}", scope, account_realm),
+            None => account_realm.to_string(),
+        }
+    }
+
     /// Access an account identified by a contact email.
     ///
     /// If a persisted private key exists for the contact email, it will be read
@@ -65,61 +198,472 @@ impl<P: Persist> Directory<P> {
     /// If one doesn't exist, it is created and the corresponding public key is
     /// uploaded to the ACME API thus creating the account.
     ///
-    /// Either way the `newAccount` API endpoint is called and thereby ensures the
-    /// account is active and working.
+    /// The account URL returned by the `newAccount` API endpoint is persisted alongside
+    /// the key. When both are persisted, the account is used without any API call. Use
+    /// [`Account::verify`] to confirm such an account is still active and working.
+    ///
+    /// [`Account::verify`]: struct.Account.html#method.verify
     pub fn account(&self, contact_email: &str) -> Result<Account<P>> {
-        // key in persistence for acme account private key
-        let pem_key = PersistKey::new(&contact_email, PersistKind::AccountPrivateKey, "acme_account");
+        self.account_with_keytype(contact_email, AccountKeyType::P256)
+    }
+
+    /// Access an account identified by a contact email, creating any new account key
+    /// using the given key type.
+    ///
+    /// Works like [`account`], but the `key_type` decides the algorithm for a newly created
+    /// account key. If a persisted private key exists for the contact email, it is used
+    /// regardless of `key_type`.
+    ///
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_with_keytype(
+        &self,
+        contact_email: &str,
+        key_type: AccountKeyType,
+    ) -> Result<Account<P>> {
+        self.account_with_params(AccountParams {
+            realm: contact_email,
+            contact_emails: &[contact_email],
+            key_type,
+            eab: None,
+            acme_key: None,
+            tos_agreed: true,
+        })
+    }
+
+    /// Access an account identified by a contact email, using an external account binding
+    /// (EAB) when creating the account.
+    ///
+    /// Some ACME API providers, such as ZeroSSL and Google Trust Services, require new
+    /// accounts to be bound to an existing account with the provider. The provider hands
+    /// out a key id `eab_kid` and a base64url encoded HMAC key `eab_hmac_key`.
+    ///
+    /// Works like [`account`] otherwise.
+    ///
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_with_eab(
+        &self,
+        contact_email: &str,
+        eab_kid: &str,
+        eab_hmac_key: &str,
+    ) -> Result<Account<P>> {
+        self.account_with_params(AccountParams {
+            realm: contact_email,
+            contact_emails: &[contact_email],
+            key_type: AccountKeyType::P256,
+            eab: Some((eab_kid, eab_hmac_key)),
+            acme_key: None,
+            tos_agreed: true,
+        })
+    }
+
+    /// Access an account with several contact emails.
+    ///
+    /// All addresses are sent as contacts to the ACME API, in the given order. The
+    /// first address identifies the account in the persistence, i.e. use the same first
+    /// address on the next run to find the account and its certificates again.
+    ///
+    /// Works like [`account`] otherwise.
+    ///
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_with_contacts(&self, contact_emails: &[&str]) -> Result<Account<P>> {
+        let realm = *contact_emails
+            .first()
+            .ok_or("At least one contact email is required")?;
+        self.account_with_params(AccountParams {
+            realm,
+            contact_emails,
+            key_type: AccountKeyType::P256,
+            eab: None,
+            acme_key: None,
+            tos_agreed: true,
+        })
+    }
+
+    /// Access an account without any contact email.
+    ///
+    /// Some ACME API providers reject `mailto:` contacts, and RFC 8555 doesn't require
+    /// any. Without a contact, there is no email to identify the account in the
+    /// persistence. Instead a fixed realm is used, which means a persistence holds at most
+    /// one account without contact. Use separate persistences for several such accounts.
+    ///
+    /// Works like [`account`] otherwise.
+    ///
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_no_contact(&self) -> Result<Account<P>> {
+        self.account_with_params(AccountParams {
+            realm: NO_CONTACT_REALM,
+            contact_emails: &[],
+            key_type: AccountKeyType::P256,
+            eab: None,
+            acme_key: None,
+            tos_agreed: true,
+        })
+    }
+
+    /// Access an account identified by a contact email, using an externally managed
+    /// account private key.
+    ///
+    /// The `private_key_pem` is a PEM encoded EC (P-256, P-384 or P-521), RSA or Ed25519
+    /// private key. The key is used to register (or fetch) the account, but is never read
+    /// from or written to the persistence. Certificates are still persisted under the
+    /// contact email.
+    ///
+    /// Works like [`account`] otherwise.
+    ///
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_with_key(
+        &self,
+        contact_email: &str,
+        private_key_pem: &str,
+    ) -> Result<Account<P>> {
+        let acme_key = AcmeKey::from_pem(private_key_pem.as_bytes()).map_err(|e| {
+            format!(
+                "{}. Expected a PEM encoded EC (P-256, P-384 or P-521), RSA or Ed25519 \
+                 private key",
+                e
+            )
+        })?;
+        self.account_with_params(AccountParams {
+            realm: contact_email,
+            contact_emails: &[contact_email],
+            // not used with a given key
+            key_type: AccountKeyType::P256,
+            eab: None,
+            acme_key: Some(acme_key),
+            tos_agreed: true,
+        })
+    }
+
+    /// Access an account identified by a contact email, signing requests using an
+    /// external signer.
+    ///
+    /// This keeps the account key out of the process, for example in an HSM or a cloud KMS.
+    /// The key is never read from or written to the persistence, and
+    /// [`Account::acme_private_key_pem`] errors since the key can't be exported.
+    ///
+    /// Works like [`account`] otherwise.
+    ///
+    /// [`Account::acme_private_key_pem`]: struct.Account.html#method.acme_private_key_pem
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn account_with_signer(
+        &self,
+        contact_email: &str,
+        signer: Box<dyn AcmeSigner + Send + Sync>,
+    ) -> Result<Account<P>> {
+        self.account_with_params(AccountParams {
+            realm: contact_email,
+            contact_emails: &[contact_email],
+            // not used with a given key
+            key_type: AccountKeyType::P256,
+            eab: None,
+            acme_key: Some(AcmeKey::from_signer(signer)),
+            tos_agreed: true,
+        })
+    }
+
+    /// Copy what an older version persisted under the account realm alone to the realm,
+    /// if it persisted an account key.
+    fn copy_legacy_realm(&self, account_realm: &str, realm: &str) -> Result<()> {
+        let legacy_key = acme_key_persist_key(account_realm);
+        if realm == account_realm || self.persist().get_value(&legacy_key)?.is_none() {
+            return Ok(());
+        }
+        let keys: Vec<PersistKeyBuf> = match self.persist().list(account_realm, None) {
+            Ok(keys) => keys,
+            Err(e) => {
+                debug!("Copy only the account of the legacy realm: {}", e);
+                vec![
+                    legacy_key.into(),
+                    acme_url_persist_key(account_realm).into(),
+                ]
+            }
+        };
+        debug!(
+            "Copy {} keys of the legacy realm: {}",
+            keys.len(),
+            account_realm
+        );
+        for key in &keys {
+            if let Some(value) = self.persist().get_value(&key.as_key())? {
+                let copy = PersistKey::new(realm, key.kind, &key.key);
+                self.persist().put_value(&copy, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn account_with_params(&self, params: AccountParams) -> Result<Account<P>> {
+        let realm = &self.realm(params.realm);
+
+        let contact = params
+            .contact_emails
+            .iter()
+            .map(|e| mailto(e))
+            .collect::<Result<Vec<_>>>()?;
 
-        // Get the key from a saved PEM, or from creating a new
+        // keys in persistence for acme account private key and url
+        let pem_key = acme_key_persist_key(realm);
+        let url_key = acme_url_persist_key(realm);
+
+        // Get the key from the params, a saved PEM, or from creating a new
         let mut is_new = false;
-        let pem = self.persist().get(&pem_key)?;
-        let acme_key = if let Some(pem) = pem {
+        let external = params.acme_key.is_some();
+        let pem = if external {
+            None
+        } else {
+            if self.persist().get_value(&pem_key)?.is_none() {
+                self.copy_legacy_realm(params.realm, realm)?;
+            }
+            // an empty value is left behind by a deactivated account.
+            self.persist()
+                .get_value(&pem_key)?
+                .filter(|p| !p.is_empty())
+                .map(Zeroizing::new)
+        };
+        let acme_key = if let Some(acme_key) = params.acme_key {
+            debug!("Use external acme account key");
+            acme_key
+        } else if let Some(pem) = pem {
             // we got a persisted private key. read it.
             debug!("Read persisted acme account key");
-            AcmeKey::from_pem(&pem)?
+            let mut acme_key = AcmeKey::from_pem(&pem)?;
+
+            // with a persisted account URL, we don't need to call newAccount.
+            let kid = self
+                .persist()
+                .get_value(&url_key)?
+                .filter(|u| !u.is_empty())
+                .and_then(|u| String::from_utf8(u).ok());
+            if let Some(kid) = kid {
+                debug!("Read persisted account URL: {}", kid);
+                acme_key.set_key_id(kid);
+                let transport = Transport::new(&self.nonce_pool, acme_key, &self.events);
+                return Ok(Account::new(
+                    self.persist.clone(),
+                    transport,
+                    realm,
+                    to_strings(params.contact_emails),
+                    ApiAccount::default(),
+                    self.api_directory.clone(),
+                ));
+            }
+
+            acme_key
         } else {
             // create a new key (and new account)
             debug!("Create new acme account key");
             is_new = true;
-            AcmeKey::new()
+            AcmeKey::new(params.key_type)?
+        };
+
+        let external_account_required = self
+            .api_directory
+            .meta
+            .as_ref()
+            .map(|m| m.externalAccountRequired())
+            .unwrap_or(false);
+
+        if is_new && external_account_required && params.eab.is_none() {
+            return Err(
+                "The ACME API provider requires an external account binding \
+                        for new accounts, use Directory::account_with_eab"
+                    .into(),
+            );
+        }
+
+        let new_account_url = &self.api_directory.newAccount;
+
+        let eab = if let Some((eab_kid, eab_hmac_key)) = params.eab {
+            let hmac_key = Zeroizing::new(base64url_decode(eab_hmac_key)?);
+            Some(eab_jws(&acme_key, eab_kid, &hmac_key, new_account_url)?)
+        } else {
+            None
         };
 
         // Prepare making a call to newAccount. This is fine to do both for
         // new keys and existing. For existing the spec says to return a 200
         // with the Location header set to the key id (kid).
         let acc = ApiAccount {
-            contact: vec![format!("mailto:{}", contact_email)],
-            termsOfServiceAgreed: Some(true),
+            contact,
+            termsOfServiceAgreed: if params.tos_agreed { Some(true) } else { None },
+            externalAccountBinding: eab,
             ..Default::default()
         };
 
-        let mut transport = Transport::new(&self.nonce_pool, acme_key);
-        let res = transport.call_jwk(&self.api_directory.newAccount, &acc)?;
+        let mut transport = Transport::new(&self.nonce_pool, acme_key, &self.events);
+        let res = transport.call_jwk(new_account_url, &acc)?;
         let kid = req_expect_header(&res, "location")?;
         debug!("Key id is: {}", kid);
         let api_account: ApiAccount = read_json(res)?;
 
         // fill in the server returned key id
-        transport.set_key_id(kid);
+        transport.set_key_id(kid.clone());
 
         // If we did create a new key, save it back to the persistence.
         if is_new {
             debug!("Persist acme account key");
-            let pem = transport.acme_key().to_pem();
-            self.persist().put(&pem_key, &pem)?;
+            let pem = transport.acme_key().to_pem().expect("to_pem");
+            self.persist().put_value(&pem_key, &pem)?;
+        }
+
+        // An external key can change between runs, so its URL isn't kept.
+        if !external {
+            debug!("Persist account URL");
+            self.persist().put_value(&url_key, kid.as_bytes())?;
         }
 
         // The finished account
         Ok(Account::new(
             self.persist.clone(),
             transport,
-            contact_email,
+            realm,
+            to_strings(params.contact_emails),
             api_account,
             self.api_directory.clone(),
         ))
     }
 
+    /// Import an account previously [exported] from another persistence.
+    ///
+    /// The account private key, URL and any certificates are written to this directory's
+    /// persistence, after which the account is accessed the same way as with [`account`].
+    /// No call is made to the ACME API.
+    ///
+    /// [exported]: struct.Account.html#method.export
+    /// [`account`]: struct.Directory.html#method.account
+    pub fn import_account(&self, export: AccountExport) -> Result<Account<P>> {
+        let realm = &self.realm(&export.realm);
+
+        // check the key before writing anything.
+        AcmeKey::from_pem(export.private_key_pem.as_bytes())?;
+
+        let pem_key = acme_key_persist_key(realm);
+        self.persist()
+            .put_value(&pem_key, export.private_key_pem.as_bytes())?;
+        let url_key = acme_url_persist_key(realm);
+        self.persist()
+            .put_value(&url_key, export.account_url.as_bytes())?;
+
+        for (primary_name, cert) in &export.certificates {
+            let domains = cert.domains();
+            let domains: Vec<&str> = domains.iter().map(|d| d.as_str()).collect();
+            save_certificate(
+                self.persist(),
+                realm,
+                primary_name,
+                &domains,
+                Some(cert.private_key().as_bytes()),
+                cert.certificate().as_bytes(),
+            )?;
+        }
+
+        let contact_emails: Vec<&str> = export.contact_emails.iter().map(|e| e.as_str()).collect();
+        self.account_with_params(AccountParams {
+            realm: &export.realm,
+            contact_emails: &contact_emails,
+            key_type: AccountKeyType::P256,
+            eab: None,
+            acme_key: None,
+            tos_agreed: true,
+        })
+    }
+
+    /// Look up an existing account for a private key, without creating any new account.
+    ///
+    /// The `acme_key_pem` is the PEM of an account private key, such as from
+    /// [`Account::acme_private_key_pem`]. Returns `None` if the ACME API has no account
+    /// for the key.
+    ///
+    /// The account is identified in the persistence by the first contact email the ACME
+    /// API returns for it, or like an [account without contact] if it has no contact
+    /// email.
+    /// Neither the key nor the account is written to the persistence.
+    ///
+    /// [`Account::acme_private_key_pem`]: struct.Account.html#method.acme_private_key_pem
+    /// [account without contact]: struct.Directory.html#method.account_no_contact
+    pub fn existing_account(&self, acme_key_pem: &str) -> Result<Option<Account<P>>> {
+        let acme_key = AcmeKey::from_pem(acme_key_pem.as_bytes())?;
+
+        let acc = ApiAccount {
+            onlyReturnExisting: Some(true),
+            ..Default::default()
+        };
+
+        let mut transport = Transport::new(&self.nonce_pool, acme_key, &self.events);
+        let res = match transport.call_jwk(&self.api_directory.newAccount, &acc) {
+            Ok(res) => res,
+            Err(Error::ApiProblem(p)) if p.is_account_does_not_exist() => {
+                debug!("No existing account for key: {}", p);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let kid = req_expect_header(&res, "location")?;
+        debug!("Key id is: {}", kid);
+        let api_account: ApiAccount = read_json(res)?;
+
+        transport.set_key_id(kid);
+
+        let contact_emails: Vec<String> = api_account
+            .contact
+            .iter()
+            .filter_map(|c| c.strip_prefix("mailto:"))
+            .map(|e| e.to_string())
+            .collect();
+        let realm = self.realm(
+            contact_emails
+                .first()
+                .map(|e| e.as_str())
+                .unwrap_or(NO_CONTACT_REALM),
+        );
+
+        Ok(Some(Account::new(
+            self.persist.clone(),
+            transport,
+            &realm,
+            contact_emails,
+            api_account,
+            self.api_directory.clone(),
+        )))
+    }
+
+    /// Revoke a certificate using its private key instead of an account key.
+    ///
+    /// For when the account key is lost, but the private key of the certificate isn't,
+    /// or has leaked. The request is signed with the certificate key, which may be EC or
+    /// RSA. No account is needed. Revoking a certificate that is already revoked succeeds.
+    /// See [`RevocationReason`] for the reasons that are allowed.
+    ///
+    /// [`RevocationReason`]: enum.RevocationReason.html
+    pub fn revoke_certificate_with_key(
+        &self,
+        cert_pem: &str,
+        cert_private_key_pem: &str,
+        reason: Option<RevocationReason>,
+    ) -> Result<()> {
+        let cert = Certificate::new(cert_private_key_pem.into(), cert_pem.into());
+        if !cert.key_matches_cert() {
+            return Err(Error::Validation(
+                "The private key does not match the certificate".into(),
+            ));
+        }
+        check_revocation_reason(reason)?;
+        let cert_key = AcmeKey::from_pem(cert_private_key_pem.as_bytes())?;
+        let transport = Transport::new(&self.nonce_pool, cert_key, &self.events);
+
+        let revoc = ApiRevocation {
+            certificate: base64url(&cert.certificate_der()),
+            reason,
+        };
+        match transport.call_jwk(&self.api_directory.revokeCert, &revoc) {
+            Err(Error::ApiProblem(p)) if p.is_already_revoked() => {
+                debug!("Certificate already revoked: {}", p);
+                Ok(())
+            }
+            r => r.map(|_| ()),
+        }
+    }
+
     /// Access the underlying JSON object for debugging.
     pub fn api_directory(&self) -> &ApiDirectory {
         &self.api_directory
@@ -130,6 +674,10 @@ impl<P: Persist> Directory<P> {
     }
 }
 
+fn to_strings(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -143,6 +691,17 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let dir = Directory::from_url(MemoryPersist::new(), url)?;
+        let profiles = dir.profiles().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles["shortlived"], "Short-lived certificates");
+        Ok(())
+    }
+
     #[test]
     fn test_create_acount() -> Result<()> {
         let server = crate::test::with_directory_server();
@@ -153,6 +712,190 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_realm_per_directory() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let persist = MemoryPersist::new();
+        let url = || DirectoryUrl::Other(&server.dir_url);
+        let prod = Directory::from_url(persist.clone(), url())?.with_realm("prod");
+        let staging = Directory::from_url(persist.clone(), url())?.with_realm("staging");
+        let acc1 = prod.account("foo@bar.com")?;
+        let acc2 = staging.account("foo@bar.com")?;
+        assert_ne!(acc1.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        let acc3 = prod.account("foo@bar.com")?;
+        assert_eq!(acc1.acme_private_key_pem()?, acc3.acme_private_key_pem()?);
+
+        // a store written before the realm had the directory.
+        let pem_key = acme_key_persist_key("foo@bar.com");
+        persist.put_value(&pem_key, crate::test::TEST_ACCOUNT_KEY_PEM.as_bytes())?;
+        let legacy = Directory::from_url(persist, url())?.with_legacy_realm();
+        let acc4 = legacy.account("foo@bar.com")?;
+        assert_eq!(
+            acc4.acme_private_key_pem()?,
+            crate::test::TEST_ACCOUNT_KEY_PEM
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_legacy_realm() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let persist = MemoryPersist::new();
+        let url = || DirectoryUrl::Other(&server.dir_url);
+        // a store written before the realm had the directory.
+        let pem = crate::test::TEST_ACCOUNT_KEY_PEM;
+        persist.put_value(&acme_key_persist_key("foo@bar.com"), pem.as_bytes())?;
+        save_certificate(
+            &persist,
+            "foo@bar.com",
+            "example.com",
+            &[],
+            Some(crate::test::TEST_CERT_KEY_PEM.as_bytes()),
+            crate::test::TEST_CERT_PEM.as_bytes(),
+        )?;
+
+        let dir = Directory::from_url(persist.clone(), url())?;
+        let acc = dir.account("foo@bar.com")?;
+        assert_eq!(acc.acme_private_key_pem()?, pem);
+        let cert = acc.certificate_unchecked("example.com")?.unwrap();
+        assert_eq!(cert.certificate(), crate::test::TEST_CERT_PEM);
+
+        // the originals are left, and not copied over what the directory has
+        let other = Directory::from_url(persist.clone(), url())?.with_realm("other");
+        assert_eq!(other.account("foo@bar.com")?.acme_private_key_pem()?, pem);
+        persist.put_value(&acme_key_persist_key("foo@bar.com"), b"changed")?;
+        assert_eq!(dir.account("foo@bar.com")?.acme_private_key_pem()?, pem);
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_with_tos() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let tos = dir.terms_of_service().expect("terms of service");
+        assert!(tos.ends_with("/terms"));
+        match dir.account_with_tos("foo@bar.com", false) {
+            Err(Error::TermsOfService(url)) => assert_eq!(url, tos),
+            _ => panic!("Expected Error::TermsOfService"),
+        }
+        let _ = dir.account_with_tos("foo@bar.com", true)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_with_contacts() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        assert!(dir.account_with_contacts(&[]).is_err());
+        assert!(dir.account_with_contacts(&["a@x.com", "b x"]).is_err());
+        let acc = dir.account_with_contacts(&["a@x.com", "b@x.com"])?;
+        assert_eq!(acc.contact_email(), "a@x.com");
+        assert_eq!(acc.contact_emails(), &["a@x.com", "b@x.com"]);
+        // first address identifies the account
+        let acc2 = dir.account("a@x.com")?;
+        assert_eq!(acc.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_no_contact() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account_no_contact()?;
+        assert_eq!(acc.contact_email(), "");
+        assert!(acc.contact_emails().is_empty());
+        let acc2 = dir.account_no_contact()?;
+        assert_eq!(acc.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        // not mixed up with an account with contact
+        let acc3 = dir.account("foo@bar.com")?;
+        assert_ne!(acc.acme_private_key_pem()?, acc3.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_with_key() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let pem = crate::test::TEST_ACCOUNT_KEY_PEM;
+        let acc = dir.account_with_key("foo@bar.com", pem)?;
+        assert_eq!(acc.acme_private_key_pem()?, pem);
+        assert_eq!(*acc.acme_private_key_pem_secret()?, pem);
+        // nothing persisted
+        let pem_key = acme_key_persist_key(&dir.realm("foo@bar.com"));
+        assert!(dir.persist().get_value(&pem_key)?.is_none());
+        let url_key = acme_url_persist_key(&dir.realm("foo@bar.com"));
+        assert!(dir.persist().get_value(&url_key)?.is_none());
+
+        let err = dir
+            .account_with_key("foo@bar.com", "not a pem")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Expected a PEM encoded"));
+        Ok(())
+    }
+
+    struct TestSigner(AcmeKey);
+
+    impl AcmeSigner for TestSigner {
+        fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+            self.0.sign(data)
+        }
+        fn public_jwk(&self) -> crate::Jwk {
+            self.0.public_jwk()
+        }
+        fn alg(&self) -> &str {
+            self.0.alg()
+        }
+    }
+
+    #[test]
+    fn test_account_with_signer() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let signer = TestSigner(AcmeKey::new(AccountKeyType::P384)?);
+        let acc = dir.account_with_signer("foo@bar.com", Box::new(signer))?;
+        assert!(acc.acme_private_key_pem().is_err());
+        assert!(acc.change_key().is_err());
+        let _ = acc.new_order("acmetest.example.com", &[])?;
+        // nothing persisted
+        let pem_key = acme_key_persist_key(&dir.realm("foo@bar.com"));
+        assert!(dir.persist().get_value(&pem_key)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_account() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.existing_account(crate::test::TEST_ACCOUNT_KEY_PEM)?;
+        let acc = acc.expect("existing account");
+        assert_eq!(acc.contact_email(), "foo@bar.com");
+        assert_eq!(
+            acc.acme_private_key_pem()?,
+            crate::test::TEST_ACCOUNT_KEY_PEM
+        );
+        // nothing persisted
+        let pem_key = acme_key_persist_key(&dir.realm("foo@bar.com"));
+        assert!(dir.persist().get_value(&pem_key)?.is_none());
+
+        let other = AcmeKey::new(AccountKeyType::P256)?;
+        let pem = String::from_utf8(other.to_pem().unwrap().to_vec()).unwrap();
+        assert!(dir.existing_account(&pem)?.is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_persisted_acount() -> Result<()> {
         let server = crate::test::with_directory_server();
@@ -162,8 +905,73 @@ mod test {
         let acc1 = dir.account("foo@bar.com")?;
         let acc2 = dir.account("foo@bar.com")?;
         let acc3 = dir.account("karlfoo@bar.com")?;
-        assert_eq!(acc1.acme_private_key_pem(), acc2.acme_private_key_pem());
-        assert!(acc1.acme_private_key_pem() != acc3.acme_private_key_pem());
+        assert_eq!(acc1.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        assert!(acc1.acme_private_key_pem()? != acc3.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_requires_eab() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let dir_url = format!("{}-eab", server.dir_url);
+        let url = DirectoryUrl::Other(&dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let err = dir.account("foo@bar.com").err().unwrap();
+        assert!(err.to_string().contains("external account binding"));
+        let hmac_key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY";
+        let _ = dir.account_with_eab("foo@bar.com", "kid-1", hmac_key)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_rsa_acount() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        for bits in &[512, 100_000] {
+            let err = dir
+                .account_with_keytype("foo@bar.com", AccountKeyType::Rsa(*bits))
+                .err()
+                .unwrap();
+            assert!(matches!(err, Error::Validation(_)), "{}", err);
+        }
+        let acc1 = dir.account_with_keytype("foo@bar.com", AccountKeyType::Rsa(2048))?;
+        assert!(acc1
+            .acme_private_key_pem()?
+            .contains("BEGIN RSA PRIVATE KEY"));
+        // the persisted key is RSA, also when asking for another key type.
+        let acc2 = dir.account("foo@bar.com")?;
+        assert_eq!(acc1.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_p384_acount() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc1 = dir.account_with_keytype("foo@bar.com", AccountKeyType::P384)?;
+        let acc2 = dir.account("foo@bar.com")?;
+        assert_eq!(acc1.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        let key = AcmeKey::from_pem(acc2.acme_private_key_pem()?.as_bytes())?;
+        assert_eq!(key.key_type(), Some(AccountKeyType::P384));
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_ed25519_acount() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc1 = dir.account_with_keytype("foo@bar.com", AccountKeyType::Ed25519)?;
+        let acc2 = dir.account("foo@bar.com")?;
+        assert_eq!(acc1.acme_private_key_pem()?, acc2.acme_private_key_pem()?);
+        let key = AcmeKey::from_pem(acc2.acme_private_key_pem()?.as_bytes())?;
+        assert_eq!(key.key_type(), Some(AccountKeyType::Ed25519));
         Ok(())
     }
 
@@ -214,4 +1022,54 @@ mod test {
     //     Ok(())
     // }
 
+    #[test]
+    fn test_revoke_certificate_with_key() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let dir = Directory::from_url(MemoryPersist::new(), url)?;
+
+        // the EC key of the test certificate
+        let cert_pem = crate::test::TEST_CERT_PEM;
+        let key_pem = crate::test::TEST_CERT_KEY_PEM;
+        dir.revoke_certificate_with_key(cert_pem, key_pem, Some(RevocationReason::KeyCompromise))?;
+
+        // a self-signed RSA certificate
+        let (rsa, _) = crate::create_rsa_key(2048);
+        let mut b = openssl::x509::X509Builder::new().unwrap();
+        b.set_version(2).unwrap();
+        b.set_pubkey(&rsa).unwrap();
+        b.set_not_before(&openssl::asn1::Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        b.set_not_after(&openssl::asn1::Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        b.sign(&rsa, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        let rsa_cert_pem = String::from_utf8(b.build().to_pem().unwrap()).unwrap();
+        let rsa_key_pem = String::from_utf8(rsa.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        dir.revoke_certificate_with_key(&rsa_cert_pem, &rsa_key_pem, None)?;
+        // already revoked is fine
+        dir.revoke_certificate_with_key(&rsa_cert_pem, &rsa_key_pem, None)?;
+
+        let revocations = crate::test::revocations(&server);
+        assert_eq!(revocations.len(), 3);
+        let (header, payload) = &revocations[0];
+        assert_eq!(header["alg"], "ES256");
+        assert_eq!(header["jwk"]["kty"], "EC");
+        assert!(header.get("kid").is_none());
+        assert_eq!(payload["reason"], 1);
+        let (header, payload) = &revocations[1];
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(header["jwk"]["kty"], "RSA");
+        let n = rsa.rsa().unwrap().n().to_vec();
+        assert_eq!(header["jwk"]["n"], base64url(&n).as_str());
+        assert!(payload.get("reason").is_none());
+
+        // the key must be the one of the certificate
+        let err = dir
+            .revoke_certificate_with_key(cert_pem, &rsa_key_pem, None)
+            .unwrap_err();
+        assert!(matches!(err, Error::Validation(_)), "{}", err);
+        assert_eq!(crate::test::revocations(&server).len(), 3);
+        Ok(())
+    }
 }
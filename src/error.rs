@@ -1,8 +1,10 @@
 //
 use std::fmt;
 use std::io;
+use std::time::SystemTime;
 
-use crate::api::ApiProblem;
+use crate::api::{ApiProblem, ApiValidationRecord};
+use crate::persist::{PersistError, PersistKey, PersistKeyBuf};
 
 /// acme-lib result.
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -14,6 +16,74 @@ pub enum Error {
     ApiProblem(ApiProblem),
     /// An API call failed.
     Call(String),
+    /// The ACME API requires agreeing to the terms of service at the given URL.
+    TermsOfService(String),
+    /// Invalid input, such as a malformed domain name, found before calling the API.
+    Validation(String),
+    /// The ACME API provider doesn't support the named feature.
+    Unsupported(String),
+    /// Validating a challenge failed, with the reason given by the ACME API.
+    ChallengeFailed {
+        /// The identifier of the authorization.
+        domain: String,
+        /// Why the validation failed.
+        problem: Box<ApiProblem>,
+        /// How the ACME API tried to validate, such as the addresses it connected to.
+        validation_record: Vec<ApiValidationRecord>,
+    },
+    /// Polling an ACME API object gave up, because it didn't leave the status before the
+    /// timeout.
+    Timeout {
+        /// URL of the polled object, or the record name of a DNS propagation check.
+        url: String,
+        /// How many times the object was polled.
+        polls: usize,
+        /// The last status of the object.
+        status: String,
+    },
+    /// The saved private key doesn't belong to the saved certificate, for example after a
+    /// crash between saving the two.
+    ///
+    /// See [`Account::certificate_unchecked`] to read them anyway.
+    ///
+    /// [`Account::certificate_unchecked`]: struct.Account.html#method.certificate_unchecked
+    KeyCertMismatch {
+        /// Primary name the certificate is saved under.
+        primary_name: String,
+    },
+    /// A challenge isn't answered as expected, found by a self check before the ACME
+    /// API validates it.
+    SelfCheckFailed(String),
+    /// Waiting was cancelled using a [`CancelToken`].
+    ///
+    /// [`CancelToken`]: order/struct.CancelToken.html
+    Cancelled,
+    /// Validating several authorizations failed for the identifiers, with the reasons.
+    AuthorizationsFailed(Vec<(String, Error)>),
+    /// The order has expired and can't be used anymore. Create a new order.
+    OrderExpired {
+        /// URL of the expired order.
+        order_url: String,
+        /// When the order expired.
+        expired_at: SystemTime,
+    },
+    /// The certificate can't be downloaded from the URL anymore. Create a new order to
+    /// get a new certificate.
+    CertificateGone(String),
+    /// The revocation status of the certificate can't be checked using OCSP, because the
+    /// chain has no issuer or the certificate has no OCSP responder.
+    NotOcspCheckable(String),
+    /// Reading or writing the persistence failed.
+    Persist {
+        /// The key read or written.
+        key: PersistKeyBuf,
+        /// The error of the [`Persist`] implementation.
+        ///
+        /// [`Persist`]: persist/trait.Persist.html
+        source: PersistError,
+    },
+    /// Generating a key failed in openssl.
+    KeyGeneration(openssl::error::ErrorStack),
     /// Base64 decoding failed.
     Base64Decode(base64::DecodeError),
     /// JSON serialization/deserialization error.
@@ -24,13 +94,98 @@ pub enum Error {
     /// `From<String>` and `From<&str>` and it becomes `Other`.
     Other(String),
 }
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Persist { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    pub(crate) fn persist(key: &PersistKey, source: PersistError) -> Self {
+        Error::Persist {
+            key: (*key).into(),
+            source,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::ApiProblem(a) => write!(f, "{}", a),
             Error::Call(s) => write!(f, "{}", s),
+            Error::TermsOfService(s) => write!(f, "Terms of service must be agreed: {}", s),
+            Error::Validation(s) => write!(f, "{}", s),
+            Error::Unsupported(s) => write!(f, "{} is not supported by this ACME API", s),
+            Error::ChallengeFailed {
+                domain,
+                problem,
+                validation_record,
+            } => {
+                write!(f, "Validation of {} failed: {}", domain, problem)?;
+                for r in validation_record {
+                    if let Some(hostname) = &r.hostname {
+                        write!(f, " ({}", hostname)?;
+                        if let Some(port) = &r.port {
+                            write!(f, ":{}", port)?;
+                        }
+                        if let Some(addrs) = &r.addressesResolved {
+                            write!(f, " resolved to {}", addrs.join(", "))?;
+                        }
+                        write!(f, ")")?;
+                    }
+                }
+                Ok(())
+            }
+            Error::Timeout { url, polls, status } => write!(
+                f,
+                "Timed out after {} polls, last status {}: {}",
+                polls, status, url
+            ),
+            Error::NotOcspCheckable(s) => {
+                write!(f, "Certificate can't be checked using OCSP: {}", s)
+            }
+            Error::KeyCertMismatch { primary_name } => write!(
+                f,
+                "Private key does not match the certificate: {}",
+                primary_name
+            ),
+            Error::SelfCheckFailed(s) => write!(f, "Self check failed: {}", s),
+            Error::Cancelled => write!(f, "Cancelled"),
+            Error::AuthorizationsFailed(failed) => {
+                write!(f, "Validation failed for")?;
+                for (i, (domain, e)) in failed.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ";" };
+                    match e {
+                        Error::ChallengeFailed { problem, .. } => {
+                            write!(f, "{} {}: {}", sep, domain, problem)?
+                        }
+                        e => write!(f, "{} {}: {}", sep, domain, e)?,
+                    }
+                }
+                Ok(())
+            }
+            Error::OrderExpired {
+                order_url,
+                expired_at,
+            } => {
+                let at = crate::util::rfc3339(*expired_at).unwrap_or_default();
+                write!(f, "Order expired at {}: {}", at, order_url)
+            }
+            Error::CertificateGone(url) => {
+                write!(
+                    f,
+                    "Certificate is no longer available, a new order is needed: {}",
+                    url
+                )
+            }
+            Error::Persist { key, source } => {
+                write!(f, "Persistence failed for {}: {}", key.as_key(), source)
+            }
+            Error::KeyGeneration(e) => write!(f, "Key generation failed: {}", e),
             Error::Base64Decode(e) => write!(f, "{}", e),
             Error::Json(e) => write!(f, "{}", e),
             Error::Io(e) => write!(f, "{}", e),
@@ -45,6 +200,12 @@ impl From<ApiProblem> for Error {
     }
 }
 
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Base64Decode(e)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Error::Json(e)
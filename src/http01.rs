@@ -0,0 +1,267 @@
+//! A small web server answering http challenges, with the `http01` feature.
+//!
+//! The ACME API validates an [http challenge] by fetching
+//! `http://<domain>/.well-known/acme-challenge/<token>` on port 80. The
+//! [`Http01Responder`] serves exactly the registered tokens, anything else is `404`.
+//!
+//! ```no_run
+//! use acme_lib::http01::Http01Responder;
+//! use acme_lib::persist::Persist;
+//! use acme_lib::order::Auth;
+//! use acme_lib::Error;
+//!
+//! fn web_authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
+//!   let responder = Http01Responder::start("0.0.0.0:80")?;
+//!   let challenge = auth.http_challenge().unwrap();
+//!   responder.add_challenge(&challenge);
+//!   challenge.validate(5000)?;
+//!   Ok(())
+//! }
+//! ```
+//!
+//! [http challenge]: ../order/struct.Auth.html#method.http_challenge
+//! [`Http01Responder`]: struct.Http01Responder.html
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::order::{Challenge, Http};
+use crate::persist::Persist;
+use crate::Result;
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Max size of the request line and headers.
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+/// Slow clients are dropped after this.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Key authorizations by token.
+type Tokens = Arc<Mutex<HashMap<String, String>>>;
+
+/// Serves the proofs of http challenges, until dropped.
+///
+/// A minimal HTTP/1.1 server in a background thread, closing each connection after
+/// the response. Proofs are served as `text/plain`.
+pub struct Http01Responder {
+    addr: SocketAddr,
+    tokens: Tokens,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Http01Responder {
+    /// Listen on the address, typically `0.0.0.0:80` since that's where the ACME API
+    /// connects.
+    pub fn start<A: ToSocketAddrs>(addr: A) -> Result<Http01Responder> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        debug!("Serve http challenges on: {}", addr);
+        let tokens = Tokens::default();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let tokens = tokens.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let tokens = tokens.clone();
+                            thread::spawn(move || {
+                                if let Err(e) = serve(stream, &tokens) {
+                                    debug!("Failed to serve http challenge: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => debug!("Failed to accept connection: {}", e),
+                    }
+                }
+            })
+        };
+
+        Ok(Http01Responder {
+            addr,
+            tokens,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The address listened on, such as to find the port when started on port `0`.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Serve the key authorization for the token.
+    pub fn add(&self, token: &str, key_authorization: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.insert(token.into(), key_authorization.into());
+    }
+
+    /// Serve the [`http_proof`] of the challenge.
+    ///
+    /// [`http_proof`]: ../order/struct.Challenge.html#method.http_proof
+    pub fn add_challenge<P: Persist>(&self, challenge: &Challenge<P, Http>) {
+        self.add(challenge.http_token(), &challenge.http_proof());
+    }
+
+    /// Stop serving the token, once the challenge is validated.
+    pub fn remove(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+}
+
+impl Drop for Http01Responder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake up the accept loop to see it's stopped.
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if TcpStream::connect_timeout(&wake, IO_TIMEOUT).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, tokens: &Mutex<HashMap<String, String>>) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_HEAD);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers don't matter.
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path, version) = (parts.next(), parts.next(), parts.next());
+    let (status, body) = match (method, path, version) {
+        (Some("GET"), Some(path), Some(v)) | (Some("HEAD"), Some(path), Some(v))
+            if v.starts_with("HTTP/1.") =>
+        {
+            let key_auth = path
+                .strip_prefix(CHALLENGE_PATH)
+                .and_then(|token| tokens.lock().unwrap().get(token).cloned());
+            match key_auth {
+                Some(key_auth) => ("200 OK", key_auth),
+                None => ("404 Not Found", String::new()),
+            }
+        }
+        (Some(_), Some(_), Some(v)) if v.starts_with("HTTP/1.") => {
+            ("405 Method Not Allowed", String::new())
+        }
+        _ => ("400 Bad Request", String::new()),
+    };
+    debug!("Http challenge {:?}: {}", request_line.trim_end(), status);
+
+    let mut res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    if status.starts_with("405") {
+        res.push_str("Allow: GET, HEAD\r\n");
+    }
+    res.push_str("\r\n");
+    if method != Some("HEAD") {
+        res.push_str(&body);
+    }
+    stream.write_all(res.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::*;
+    use crate::*;
+
+    fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        res
+    }
+
+    #[test]
+    fn test_responder() -> Result<()> {
+        let responder = Http01Responder::start("127.0.0.1:0")?;
+        let addr = responder.local_addr();
+        responder.add("tok", "tok.thumb");
+        let req = |path: &str| format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+
+        let res = get(addr, &req("/.well-known/acme-challenge/tok"));
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+        assert!(res.contains("Content-Type: text/plain\r\n"), "{}", res);
+        assert!(res.ends_with("\r\n\r\ntok.thumb"), "{}", res);
+
+        let res = get(addr, &req("/.well-known/acme-challenge/other"));
+        assert!(res.starts_with("HTTP/1.1 404 "), "{}", res);
+        let res = get(addr, &req("/"));
+        assert!(res.starts_with("HTTP/1.1 404 "), "{}", res);
+        let res = get(
+            addr,
+            "POST /.well-known/acme-challenge/tok HTTP/1.1\r\n\r\n",
+        );
+        assert!(res.starts_with("HTTP/1.1 405 "), "{}", res);
+        let res = get(addr, "nonsense\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 400 "), "{}", res);
+
+        responder.remove("tok");
+        let res = get(addr, &req("/.well-known/acme-challenge/tok"));
+        assert!(res.starts_with("HTTP/1.1 404 "), "{}", res);
+
+        drop(responder);
+        assert!(TcpStream::connect(addr).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_with_responder() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+
+        let responder = Http01Responder::start("127.0.0.1:0")?;
+        // the mock server fetches the proof from the port in the name.
+        let port = responder.local_addr().port();
+        let challenge = |name: &str| -> Result<Challenge<MemoryPersist, Http>> {
+            let ord = acc.new_order(&format!("http01-{}.{}", port, name), &[])?;
+            let auth = ord.authorizations()?.remove(0);
+            Ok(auth.http_challenge().unwrap())
+        };
+
+        let chall = challenge("missing.localhost")?;
+        let err = chall.validate(1).err().unwrap();
+        assert!(err.to_string().contains("404"), "{}", err);
+
+        let chall = challenge("added.localhost")?;
+        responder.add_challenge(&chall);
+        chall.validate(1)?;
+        Ok(())
+    }
+}
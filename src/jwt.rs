@@ -1,14 +1,20 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
 use serde::{Deserialize, Serialize};
 
 use crate::acc::AcmeKey;
-use crate::cert::EC_GROUP_P256;
+use crate::api::ApiJws;
 use crate::util::base64url;
+use crate::Result;
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub(crate) struct JwsProtected {
     alg: String,
     url: String,
-    nonce: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     jwk: Option<Jwk>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -18,61 +24,82 @@ pub(crate) struct JwsProtected {
 impl JwsProtected {
     pub(crate) fn new_jwk(jwk: Jwk, url: &str, nonce: String) -> Self {
         JwsProtected {
-            alg: "ES256".into(),
+            alg: jwk.alg.clone(),
             url: url.into(),
-            nonce,
+            nonce: Some(nonce),
             jwk: Some(jwk),
             ..Default::default()
         }
     }
-    pub(crate) fn new_kid(kid: &str, url: &str, nonce: String) -> Self {
+    /// The inner JWS of a key change has no nonce.
+    pub(crate) fn new_jwk_without_nonce(jwk: Jwk, url: &str) -> Self {
         JwsProtected {
-            alg: "ES256".into(),
+            alg: jwk.alg.clone(),
             url: url.into(),
-            nonce,
+            jwk: Some(jwk),
+            ..Default::default()
+        }
+    }
+    pub(crate) fn new_kid(alg: &str, kid: &str, url: &str, nonce: String) -> Self {
+        JwsProtected {
+            alg: alg.into(),
+            url: url.into(),
+            nonce: Some(nonce),
             kid: Some(kid.into()),
             ..Default::default()
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub(crate) struct Jwk {
-    alg: String,
-    crv: String,
-    kty: String,
+/// A public key as JSON Web Key (RFC 7517).
+///
+/// The values are base64url encoded without padding. Members that don't apply to
+/// the key type are `None`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    /// Algorithm, such as `ES256`.
+    pub alg: String,
+    /// Curve for `EC` and `OKP` keys, such as `P-256`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    /// RSA public exponent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    /// Key type, such as `EC`.
+    pub kty: String,
+    /// RSA modulus.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    /// Public key use, `sig`.
     #[serde(rename = "use")]
-    _use: String,
-    x: String,
-    y: String,
+    pub _use: String,
+    /// X coordinate for `EC` keys, the public key for `OKP` keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    /// Y coordinate for `EC` keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 // LEXICAL ORDER OF FIELDS MATTER!
 pub(crate) struct JwkThumb {
-    crv: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
     kty: String,
-    x: String,
-    y: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y: Option<String>,
 }
 
 impl From<&AcmeKey> for Jwk {
     fn from(a: &AcmeKey) -> Self {
-        let mut ctx = openssl::bn::BigNumContext::new().expect("BigNumContext");
-        let mut x = openssl::bn::BigNum::new().expect("BigNum");
-        let mut y = openssl::bn::BigNum::new().expect("BigNum");
-        a.private_key()
-            .public_key()
-            .affine_coordinates_gfp(&*EC_GROUP_P256, &mut x, &mut y, &mut ctx)
-            .expect("affine_coordinates_gfp");
-        Jwk {
-            alg: "ES256".into(),
-            kty: "EC".into(),
-            crv: "P-256".into(),
-            _use: "sig".into(),
-            x: base64url(&x.to_vec()),
-            y: base64url(&y.to_vec()),
-        }
+        a.public_jwk()
     }
 }
 
@@ -80,13 +107,37 @@ impl From<&Jwk> for JwkThumb {
     fn from(a: &Jwk) -> Self {
         JwkThumb {
             crv: a.crv.clone(),
+            e: a.e.clone(),
             kty: a.kty.clone(),
+            n: a.n.clone(),
             x: a.x.clone(),
             y: a.y.clone(),
         }
     }
 }
 
+/// The JWK of the key with only the required members in lexical order, as used for
+/// the RFC 7638 thumbprint.
+pub(crate) fn jwk_thumb_json(key: &AcmeKey) -> String {
+    let jwk: Jwk = key.into();
+    let jwk_thumb: JwkThumb = (&jwk).into();
+    serde_json::to_string(&jwk_thumb).expect("jwk_thumb")
+}
+
+/// The base64url encoded RFC 7638 JWK thumbprint of the key.
+pub(crate) fn jwk_thumbprint(key: &AcmeKey) -> String {
+    let jwk_json = jwk_thumb_json(key);
+    base64url(&sha256(jwk_json.as_bytes()))
+}
+
+/// Payload of the inner JWS when changing the account key.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct KeyChange {
+    pub account: String,
+    pub old_key: Jwk,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Jws {
     protected: String,
@@ -103,3 +154,93 @@ impl Jws {
         }
     }
 }
+
+/// Protected header of an external account binding. Notice there is no nonce.
+#[derive(Debug, Serialize)]
+struct EabProtected<'a> {
+    alg: &'a str,
+    kid: &'a str,
+    url: &'a str,
+}
+
+/// Make the external account binding for a newAccount request. It's a JWS
+/// over the account JWK, signed using the HMAC key provided by the CA.
+pub(crate) fn eab_jws(key: &AcmeKey, eab_kid: &str, hmac_key: &[u8], url: &str) -> Result<ApiJws> {
+    let protected = EabProtected {
+        alg: "HS256",
+        kid: eab_kid,
+        url,
+    };
+    let protected = base64url(serde_json::to_string(&protected)?.as_bytes());
+    let jwk: Jwk = key.into();
+    let payload = base64url(serde_json::to_string(&jwk)?.as_bytes());
+
+    let to_sign = format!("{}.{}", protected, payload);
+    let hmac = PKey::hmac(hmac_key).map_err(|e| format!("Invalid EAB HMAC key: {}", e))?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &hmac).expect("Signer");
+    signer.update(to_sign.as_bytes()).expect("Signer::update");
+    let signature = base64url(&signer.sign_to_vec().expect("sign_to_vec"));
+
+    Ok(ApiJws {
+        protected,
+        payload,
+        signature,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acc::AccountKeyType;
+
+    #[test]
+    fn test_eab_jws() -> Result<()> {
+        let key = AcmeKey::from_pem(crate::test::TEST_ACCOUNT_KEY_PEM.as_bytes())?;
+        let hmac_key = b"0123456789abcdef0123456789abcdef";
+        let url = "https://example.com/acme/new-acct";
+        let eab = eab_jws(&key, "kid-1", hmac_key, url)?;
+        // {"alg":"HS256","kid":"kid-1","url":"https://example.com/acme/new-acct"}
+        assert_eq!(
+            eab.protected,
+            "eyJhbGciOiJIUzI1NiIsImtpZCI6ImtpZC0xIiwidXJsIjoiaHR0cHM6Ly9leGFtcGxlLmNvbS9hY21l\
+             L25ldy1hY2N0In0"
+        );
+        // the account JWK
+        assert_eq!(
+            eab.payload,
+            "eyJhbGciOiJFUzI1NiIsImNydiI6IlAtMjU2Iiwia3R5IjoiRUMiLCJ1c2UiOiJzaWciLCJ4IjoiVVlk\
+             SkZJTEZ3OVVlbVZMdWpRVFBYTG1GQ0FPcUItejVDWXQyRm5OX01sNCIsInkiOiJobTQwY3VYNm1nT1Jt\
+             eUZnSmw4MWEyQ2VGOWc2Ykt5Sk1ndlZpNWZhek1VIn0"
+        );
+        assert_eq!(eab.signature, "NxI-G7z4Bg-Ohr5J-cp0bn4aNv94UXII7BhcWSBELsw");
+        Ok(())
+    }
+
+    #[test]
+    fn test_jwk_thumbprint() -> Result<()> {
+        let key = AcmeKey::from_pem(crate::test::TEST_ACCOUNT_KEY_PEM.as_bytes())?;
+        assert_eq!(
+            jwk_thumb_json(&key),
+            r#"{"crv":"P-256","kty":"EC","x":"UYdJFILFw9UemVLujQTPXLmFCAOqB-z5CYt2FnN_Ml4","y":"hm40cuX6mgORmyFgJl81a2CeF9g6bKyJMgvVi5fazMU"}"#
+        );
+        assert_eq!(
+            jwk_thumbprint(&key),
+            "3K9DdBmExiW3Om_6KFKrOGbyfcYw0h2vioV1S-Yi4GA"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ed25519_thumb_member_order() {
+        let key = AcmeKey::new(AccountKeyType::Ed25519).unwrap();
+        let jwk: Jwk = (&key).into();
+        let thumb: JwkThumb = (&jwk).into();
+        let json = serde_json::to_string(&thumb).unwrap();
+        // RFC 8037 requires exactly the members crv, kty and x in lexical order.
+        let x = jwk.x.unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, x)
+        );
+    }
+}
@@ -62,7 +62,9 @@
 //!     // certificate for:
 //!     //
 //!     // http://mydomain.io/.well-known/acme-challenge/<token>
-//!     let chall = auths[0].http_challenge();
+//!     //
+//!     // Wildcard domains can't use HTTP, only DNS.
+//!     let chall = auths[0].http_challenge().ok_or("No http challenge")?;
 //!
 //!     // The token is the filename.
 //!     let token = chall.http_token();
@@ -113,12 +115,16 @@
 //!
 //! Most website TLS certificates tries to prove ownership/control over the domain they
 //! are issued for. For ACME, this means proving you control either a web server answering
-//! HTTP requests to the domain, or the DNS server answering name lookups against the domain.
+//! HTTP requests to the domain, the TLS server on port 443 of the domain, or the DNS server
+//! answering name lookups against the domain.
 //!
 //! To use this library, there are points in the flow where you would need to modify either
 //! the web server or DNS server before progressing to get the certificate.
 //!
-//! See [`http_challenge`] and [`dns_challenge`].
+//! See [`http_challenge`], [`tls_alpn_challenge`] and [`dns_challenge`]. With the `http01`
+//! feature, the [`http01`] module has a small web server for the http challenges.
+//! With the `dns_check` feature, [`wait_for_dns_propagation`] checks that the dns
+//! challenge record is served before validating it.
 //!
 //! ### Multiple domains
 //!
@@ -127,7 +133,10 @@
 //! domain. See [`authorizations`].
 //!
 //! [`http_challenge`]: struct.Auth.html#method.http_challenge
+//! [`tls_alpn_challenge`]: order/struct.Auth.html#method.tls_alpn_challenge
 //! [`dns_challenge`]: struct.Auth.html#method.dns_challenge
+//! [`wait_for_dns_propagation`]: order/struct.Challenge.html#method.wait_for_dns_propagation
+//! [`http01`]: http01/index.html
 //! [`authorizations`]: order/struct.NewOrder.html#method.authorizations
 //!
 //! ## Rate limits
@@ -170,14 +179,31 @@ mod req;
 mod trans;
 mod util;
 
+#[cfg(feature = "acme_dns")]
+pub mod acme_dns;
 pub mod api;
+#[cfg(feature = "http01")]
+pub mod http01;
+#[cfg(feature = "ocsp")]
+mod ocsp;
 pub mod order;
 pub mod persist;
 
 #[cfg(test)]
 mod test;
 
-pub use crate::acc::{Account, RevocationReason};
-pub use crate::cert::{create_p256_key, create_p384_key, create_rsa_key, Certificate};
+pub use crate::acc::{
+    Account, AccountExport, AccountKeyType, AcmeSigner, CertificateMeta, EnsureOptions,
+    EnsureOutcome, Identifier, RenewalInfo, RevocationReason,
+};
+pub use crate::cert::{
+    create_ed25519_key, create_p256_key, create_p384_key, create_p521_key, create_rsa_key,
+    create_rsa_key_with, private_key_from_der, private_key_to_der, BundleOrder, Certificate,
+    ChainCertInfo, CsrOptions, KeyInfo, Pkcs12Encryption,
+};
 pub use crate::dir::{Directory, DirectoryUrl};
 pub use crate::error::{Error, Result};
+pub use crate::jwt::Jwk;
+#[cfg(feature = "ocsp")]
+pub use crate::ocsp::OcspStatus;
+pub use zeroize::Zeroizing;
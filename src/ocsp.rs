@@ -0,0 +1,186 @@
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509VerifyResult, X509};
+use std::io::Read;
+use std::time::SystemTime;
+
+use crate::cert::parse_system_time;
+use crate::req::{req_handle_error, req_post_ocsp};
+use crate::{Certificate, Error, Result, RevocationReason};
+
+/// Largest OCSP response read.
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// How far the clocks of the responder and us may be apart, in seconds.
+const MAX_CLOCK_SKEW: u32 = 5 * 60;
+
+/// Revocation status of a certificate, as answered by the OCSP responder of the CA.
+///
+/// See [`Certificate::ocsp_status`].
+///
+/// [`Certificate::ocsp_status`]: struct.Certificate.html#method.ocsp_status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcspStatus {
+    /// The certificate is not revoked.
+    Good,
+    /// The certificate is revoked, and should be renewed right away.
+    Revoked {
+        /// When the certificate was revoked.
+        at: SystemTime,
+        /// Why the certificate was revoked, if the responder said.
+        reason: Option<RevocationReason>,
+    },
+    /// The responder doesn't know the certificate.
+    Unknown,
+}
+
+impl Certificate {
+    /// Ask the OCSP responder of the CA whether the certificate is revoked.
+    ///
+    /// The responder URL is taken from the authority information access extension of the
+    /// leaf certificate, and the issuer is the first intermediate of the chain. The response
+    /// must be signed by the issuer, or by a responder the issuer delegated to.
+    ///
+    /// Fails with [`Error::NotOcspCheckable`] if the chain has no issuer or the leaf has no
+    /// OCSP responder. Requires the `ocsp` feature.
+    ///
+    /// [`Error::NotOcspCheckable`]: enum.Error.html#variant.NotOcspCheckable
+    pub fn ocsp_status(&self) -> Result<OcspStatus> {
+        let chain = X509::stack_from_pem(self.certificate().as_bytes())
+            .map_err(|e| format!("Failed to read certificate PEM: {}", e))?;
+        let leaf = chain.first().ok_or("No certificate in PEM")?;
+        let issuer = match chain.get(1) {
+            Some(i) if i.issued(leaf) == X509VerifyResult::OK => i,
+            Some(_) => {
+                return Err(Error::NotOcspCheckable(
+                    "second certificate of the chain is not the issuer".into(),
+                ))
+            }
+            None => return Err(Error::NotOcspCheckable("no issuer in the chain".into())),
+        };
+        let url = leaf
+            .ocsp_responders()
+            .ok()
+            .and_then(|r| r.iter().next().map(|u| u.to_string()))
+            .ok_or_else(|| Error::NotOcspCheckable("no OCSP responder".into()))?;
+
+        let cert_id = || {
+            OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)
+                .map_err(|e| Error::Other(format!("Failed to create OCSP cert id: {}", e)))
+        };
+        let mut req = OcspRequest::new().expect("OcspRequest");
+        req.add_id(cert_id()?).expect("add_id");
+        let der = req.to_der().expect("to_der");
+
+        debug!("Check OCSP status: {}", url);
+        let res = req_handle_error(req_post_ocsp(&url, &der))?;
+        let mut body = vec![];
+        res.into_reader()
+            .take(MAX_RESPONSE_LEN)
+            .read_to_end(&mut body)?;
+
+        let response = OcspResponse::from_der(&body)
+            .map_err(|e| format!("Failed to read OCSP response from {}: {}", url, e))?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(Error::Call(format!(
+                "OCSP responder {} answered status {}",
+                url,
+                response.status().as_raw()
+            )));
+        }
+        let basic = response
+            .basic()
+            .map_err(|e| format!("Failed to read OCSP response from {}: {}", url, e))?;
+
+        // The issuer is trusted as is, without the rest of the chain.
+        let mut certs = Stack::new().expect("Stack");
+        certs.push(issuer.to_owned()).expect("push");
+        let mut store = X509StoreBuilder::new().expect("X509StoreBuilder");
+        store.add_cert(issuer.to_owned()).expect("add_cert");
+        store
+            .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+            .expect("set_flags");
+        let store = store.build();
+        basic
+            .verify(&certs, &store, OcspFlag::empty())
+            .map_err(|e| format!("Invalid OCSP response from {}: {}", url, e))?;
+
+        let id = cert_id()?;
+        let status = basic.find_status(&id).ok_or_else(|| {
+            format!(
+                "OCSP response from {} has no status for the certificate",
+                url
+            )
+        })?;
+        status
+            .check_validity(MAX_CLOCK_SKEW, None)
+            .map_err(|e| format!("Outdated OCSP response from {}: {}", url, e))?;
+
+        Ok(match status.status {
+            OcspCertStatus::GOOD => OcspStatus::Good,
+            OcspCertStatus::REVOKED => {
+                let at = status
+                    .revocation_time
+                    .and_then(|t| parse_system_time(&t.to_string()))
+                    .ok_or_else(|| format!("OCSP response from {} has no revocation time", url))?;
+                let raw = status.reason.as_raw();
+                let reason = if (0..=u8::MAX as i32).contains(&raw) {
+                    RevocationReason::from_code(raw as u8)
+                } else {
+                    None
+                };
+                OcspStatus::Revoked { at, reason }
+            }
+            _ => OcspStatus::Unknown,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ocsp_status() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let status = |s: &str| crate::test::test_ocsp_certificate(&server, s).ocsp_status();
+
+        assert_eq!(status("good")?, OcspStatus::Good);
+        assert_eq!(status("unknown")?, OcspStatus::Unknown);
+        match status("revoked")? {
+            OcspStatus::Revoked { at, reason } => {
+                assert_eq!(reason, Some(RevocationReason::KeyCompromise));
+                let secs = at.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+                assert_eq!(secs, 1_767_268_800);
+            }
+            s => panic!("Expected revoked: {:?}", s),
+        }
+        let err = status("badsig").unwrap_err().to_string();
+        assert!(err.contains("Invalid OCSP response"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ocsp_not_checkable() {
+        let server = crate::test::with_directory_server();
+        let cert = crate::test::test_ocsp_certificate(&server, "good");
+        let leaf = Certificate::new(cert.private_key().into(), cert.leaf_pem());
+        let err = leaf.ocsp_status().unwrap_err();
+        assert!(matches!(err, Error::NotOcspCheckable(_)), "{}", err);
+
+        // The mock certificate has no OCSP responder.
+        let chain = format!(
+            "{}{}",
+            crate::test::TEST_CERT_PEM,
+            crate::test::test_intermediate_pem("Test Root")
+        );
+        let cert = Certificate::new(crate::test::TEST_CERT_KEY_PEM.into(), chain);
+        let err = cert.ocsp_status().unwrap_err();
+        assert!(matches!(err, Error::NotOcspCheckable(_)), "{}", err);
+    }
+}
@@ -1,16 +1,31 @@
 //
 use openssl::sha::sha256;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
-use std::thread;
 use std::time::Duration;
 
 use crate::acc::AccountInner;
 use crate::acc::AcmeKey;
-use crate::api::{ApiAuth, ApiChallenge, ApiEmptyObject, ApiEmptyString};
+use crate::api::{
+    ApiAuth, ApiAuthStatus, ApiChallenge, ApiEmptyObject, ApiEmptyString, ApiIdentifier, ApiProblem,
+};
+use crate::cert::{create_p256_key, create_tls_alpn_cert, Certificate};
 use crate::jwt::*;
+#[cfg(feature = "dns_check")]
+use crate::order::propagation::wait_for_propagation;
+use crate::order::selfcheck::{check_http, check_tls_alpn};
+use crate::order::webroot::write_token_file;
+#[cfg(feature = "dns_check")]
+use crate::order::DnsPropagationOptions;
+use crate::order::{
+    invalid_problem, CancelToken, ChallengeStatus, HttpSelfCheckOptions, OrderEvent, Poller,
+    TokenGuard,
+};
 use crate::persist::Persist;
+use crate::req::{poll_delay, req_retry_after};
 use crate::util::{base64url, read_json};
-use crate::Result;
+use crate::{Error, Result};
 
 /// An authorization ([ownership proof]) for a domain name.
 ///
@@ -25,13 +40,17 @@ use crate::Result;
 /// * In a text file served using [HTTP] from a web server of the domain being authorized.
 /// * A `TXT` [DNS] record under the domain being authorized.
 ///
+/// For email identifiers, the authorization is instead provided by replying to an [email].
+///
 /// [ownership proof]: ../index.html#domain-ownership
 /// [HTTP]: #method.http_challenge
 /// [DNS]: #method.dns_challenge
+/// [email]: #method.email_challenge
 pub struct Auth<P: Persist> {
     inner: Arc<AccountInner<P>>,
     api_auth: ApiAuth,
     auth_url: String,
+    cancel: Option<CancelToken>,
 }
 
 impl<P: Persist> Auth<P> {
@@ -40,12 +59,38 @@ impl<P: Persist> Auth<P> {
             inner: inner.clone(),
             api_auth,
             auth_url: auth_url.into(),
+            cancel: None,
         }
     }
 
+    /// Stop waiting for the authorization when the token is cancelled, failing with
+    /// [`Error::Cancelled`]. The challenges of the authorization use the same token.
+    ///
+    /// The authorizations of a [`NewOrder::with_cancel`] already have its token.
+    ///
+    /// [`Error::Cancelled`]: ../enum.Error.html#variant.Cancelled
+    /// [`NewOrder::with_cancel`]: struct.NewOrder.html#method.with_cancel
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
     /// Domain name for this authorization.
+    ///
+    /// For a wildcard such as `*.example.com`, this is the domain without the `*.`
+    /// prefix, which is where the DNS challenge record goes.
     pub fn domain_name(&self) -> &str {
-        &self.api_auth.identifier.value
+        let value = &self.api_auth.identifier.value;
+        value.strip_prefix("*.").unwrap_or(value)
+    }
+
+    /// Whether this authorization is for a wildcard domain, such as `*.example.com`.
+    ///
+    /// Wildcard domains can only be authorized using the [DNS challenge].
+    ///
+    /// [DNS challenge]: #method.dns_challenge
+    pub fn is_wildcard(&self) -> bool {
+        self.api_auth.wildcard() || self.api_auth.identifier.value.starts_with("*.")
     }
 
     /// Whether we actually need to do the authorization. This might not be needed if we have
@@ -56,6 +101,9 @@ impl<P: Persist> Auth<P> {
 
     /// Get the http challenge.
     ///
+    /// `None` for a [wildcard] domain, which can't be authorized over HTTP, or if the
+    /// ACME API provider doesn't offer an http challenge.
+    ///
     /// The http challenge must be placed so it is accessible under:
     ///
     /// ```text
@@ -72,7 +120,7 @@ impl<P: Persist> Auth<P> {
     /// use std::io::Write;
     ///
     /// fn web_authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
-    ///   let challenge = auth.http_challenge();
+    ///   let challenge = auth.http_challenge().ok_or("No http challenge")?;
     ///   // Assuming our web server's root is under /var/www
     ///   let path = {
     ///     let token = challenge.http_token();
@@ -84,11 +132,35 @@ impl<P: Persist> Auth<P> {
     ///   Ok(())
     /// }
     /// ```
-    pub fn http_challenge(&self) -> Challenge<P, Http> {
+    ///
+    /// [wildcard]: #method.is_wildcard
+    pub fn http_challenge(&self) -> Option<Challenge<P, Http>> {
+        if self.is_wildcard() {
+            return None;
+        }
         self.api_auth
             .http_challenge()
-            .map(|c| Challenge::new(&self.inner, c.clone(), &self.auth_url))
-            .expect("http-challenge")
+            .map(|c| Challenge::new(self, c.clone()))
+    }
+
+    /// Get the tls-alpn challenge ([RFC 8737]), `None` if the ACME API doesn't offer it.
+    ///
+    /// The proof is a self-signed certificate, which a TLS server on port 443 of the
+    /// domain presents when the client asks for the [`ACME_TLS_ALPN_PROTOCOL`] with the
+    /// domain as SNI. Other TLS connections are served as usual.
+    ///
+    /// Not possible for a [wildcard] authorization, which is always `None`.
+    ///
+    /// [RFC 8737]: https://tools.ietf.org/html/rfc8737
+    /// [`ACME_TLS_ALPN_PROTOCOL`]: constant.ACME_TLS_ALPN_PROTOCOL.html
+    /// [wildcard]: #method.is_wildcard
+    pub fn tls_alpn_challenge(&self) -> Option<Challenge<P, TlsAlpn>> {
+        if self.is_wildcard() {
+            return None;
+        }
+        self.api_auth
+            .tls_alpn_challenge()
+            .map(|c| Challenge::new(self, c.clone()))
     }
 
     /// Get the dns challenge.
@@ -108,8 +180,8 @@ impl<P: Persist> Auth<P> {
     ///
     /// fn dns_authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
     ///   let challenge = auth.dns_challenge();
-    ///   let record = format!("_acme-challenge.{}.", auth.domain_name());
-    ///   // route_53_set_record(&record, "TXT", challenge.dns_proof());
+    ///   let record = challenge.dns_record();
+    ///   // route_53_set_record(&record.name, "TXT", &record.value);
     ///   challenge.validate(5000)?;
     ///   Ok(())
     /// }
@@ -119,13 +191,168 @@ impl<P: Persist> Auth<P> {
     pub fn dns_challenge(&self) -> Challenge<P, Dns> {
         self.api_auth
             .dns_challenge()
-            .map(|c| Challenge::new(&self.inner, c.clone(), &self.auth_url))
+            .map(|c| Challenge::new(self, c.clone()))
             .expect("dns-challenge")
     }
 
+    /// Get the email challenge, for an email identifier ([RFC 8823]).
+    ///
+    /// The ACME API provider sends an email to the address being authorized, with a
+    /// subject of `ACME: <token-part1>`. The reply must have the subject `Re: ACME:
+    /// <token-part1>` and contain the proof between the lines
+    /// `-----BEGIN ACME RESPONSE-----` and `-----END ACME RESPONSE-----`.
+    ///
+    /// ```no_run
+    /// use acme_lib::persist::Persist;
+    /// use acme_lib::order::Auth;
+    /// use acme_lib::Error;
+    ///
+    /// fn email_authorize<P: Persist>(auth: &Auth<P>, token_part1: &str) -> Result<(), Error> {
+    ///   let challenge = auth.email_challenge().expect("an email identifier");
+    ///   let proof = challenge.email_proof(token_part1);
+    ///   // send_reply(challenge.email_from(), token_part1, &proof);
+    ///   challenge.validate(5000)?;
+    ///   Ok(())
+    /// }
+    /// ```
+    ///
+    /// `None` unless offered, which it is only for an email identifier.
+    ///
+    /// [RFC 8823]: https://tools.ietf.org/html/rfc8823
+    pub fn email_challenge(&self) -> Option<Challenge<P, Email>> {
+        self.api_auth
+            .email_challenge()
+            .map(|c| Challenge::new(self, c.clone()))
+    }
+
+    /// The types of the challenges offered, such as `http-01`, including types this
+    /// library doesn't know.
+    pub fn challenge_types(&self) -> Vec<&str> {
+        self.api_auth
+            .challenges
+            .iter()
+            .map(|c| c._type.as_str())
+            .collect()
+    }
+
+    /// Get the challenge of the type, such as `dns-01`, `None` if it isn't offered.
+    ///
+    /// Like the typed accessors, http and tls-alpn challenges are `None` for a
+    /// [wildcard] authorization.
+    ///
+    /// [wildcard]: #method.is_wildcard
+    pub fn challenge(&self, type_: &str) -> Option<AnyChallenge<P>> {
+        let api_challenge = self
+            .api_auth
+            .challenges
+            .iter()
+            .find(|c| c._type == type_)?
+            .clone();
+        Some(match ChallengeType::from(type_) {
+            ChallengeType::Http01 | ChallengeType::TlsAlpn01 if self.is_wildcard() => return None,
+            ChallengeType::Http01 => AnyChallenge::Http(Challenge::new(self, api_challenge)),
+            ChallengeType::Dns01 => AnyChallenge::Dns(Challenge::new(self, api_challenge)),
+            ChallengeType::TlsAlpn01 => AnyChallenge::TlsAlpn(Challenge::new(self, api_challenge)),
+            ChallengeType::EmailReply00 => AnyChallenge::Email(Challenge::new(self, api_challenge)),
+            ChallengeType::Other(_) => AnyChallenge::Other(Challenge::new(self, api_challenge)),
+        })
+    }
+
+    /// The first challenge offered of the types in order of preference.
+    ///
+    /// ```no_run
+    /// use acme_lib::persist::Persist;
+    /// use acme_lib::order::{AnyChallenge, Auth, ChallengeType};
+    /// use acme_lib::Error;
+    ///
+    /// fn authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
+    ///   let preference = [ChallengeType::Http01, ChallengeType::Dns01];
+    ///   match auth.preferred_challenge(&preference) {
+    ///     Some(AnyChallenge::Http(challenge)) => {
+    ///       // serve challenge.http_proof()
+    ///       challenge.validate(5000)
+    ///     }
+    ///     Some(AnyChallenge::Dns(challenge)) => {
+    ///       // create challenge.dns_record()
+    ///       challenge.validate(5000)
+    ///     }
+    ///     _ => Err("No usable challenge".into()),
+    ///   }
+    /// }
+    /// ```
+    pub fn preferred_challenge(&self, preference: &[ChallengeType]) -> Option<AnyChallenge<P>> {
+        preference.iter().find_map(|t| self.challenge(t.as_str()))
+    }
+
+    /// Deactivate the authorization, to give up the proven ownership of the domain.
+    ///
+    /// Later orders for the domain need a new authorization. If the authorization
+    /// belongs to an order that isn't finalized, the ACME API provider decides whether
+    /// the order is still usable. Typically it becomes `invalid`, which shows when the
+    /// order is [refreshed].
+    ///
+    /// [refreshed]: struct.NewOrder.html#method.refresh
+    pub fn deactivate(self) -> Result<()> {
+        let status = ApiAuthStatus {
+            status: "deactivated".into(),
+        };
+        let res = self.inner.call(&self.auth_url, &status)?;
+        let api_auth: ApiAuth = read_json(res)?;
+
+        if !api_auth.is_status_deactivated() {
+            return Err(format!("Authorization is in status: {:?}", api_auth.status).into());
+        }
+
+        Ok(())
+    }
+
+    /// The URL of the authorization.
+    pub fn url(&self) -> &str {
+        &self.auth_url
+    }
+
+    /// Refresh the authorization state against the ACME API.
+    ///
+    /// The specification calls this a "POST-as-GET" against the authorization URL.
+    pub fn refresh(&mut self) -> Result<()> {
+        let res = self.inner.call(&self.auth_url, &ApiEmptyString)?;
+        self.api_auth = read_json(res)?;
+        Ok(())
+    }
+
+    /// Poll the authorization until it has left the `pending` status, for when the
+    /// challenge is validated elsewhere.
+    ///
+    /// The `delay` is the time between polls, unless the ACME API asks for another delay
+    /// using `Retry-After`. Fails with [`Error::Timeout`] if the authorization is still
+    /// `pending` after the `timeout`, and with [`Error::ChallengeFailed`] unless it
+    /// became `valid`.
+    ///
+    /// [`Error::Timeout`]: ../enum.Error.html#variant.Timeout
+    /// [`Error::ChallengeFailed`]: ../enum.Error.html#variant.ChallengeFailed
+    pub fn wait_done_timeout(&mut self, delay: Duration, timeout: Duration) -> Result<()> {
+        let mut poller = Poller::new(&self.auth_url, Some(timeout), self.cancel.as_ref());
+        self.api_auth = wait_for_auth_status(&self.inner, &self.auth_url, delay, &mut poller)?;
+        check_auth_valid(&self.api_auth, None)
+    }
+
+    /// Why validating a challenge of this authorization failed, if it did.
+    ///
+    /// The authorization isn't updated when a challenge is validated, [`refresh`] it
+    /// first.
+    ///
+    /// [`refresh`]: #method.refresh
+    pub fn error(&self) -> Option<&ApiProblem> {
+        self.api_auth
+            .failed_challenge()
+            .and_then(|c| c.error.as_ref())
+    }
+
     /// Access the underlying JSON object for debugging. We don't
     /// refresh the authorization when the corresponding challenge is validated,
-    /// so there will be no changes to see here.
+    /// so there will be no changes to see here unless [`refresh`] is called.
+    ///
+    /// [`refresh`]: #method.refresh
     pub fn api_auth(&self) -> &ApiAuth {
         &self.api_auth
     }
@@ -139,13 +366,104 @@ pub struct Http;
 #[doc(hidden)]
 pub struct Dns;
 
-/// A DNS or HTTP challenge as obtained from the [`Auth`].
+/// Marker type for email challenges.
+#[doc(hidden)]
+pub struct Email;
+
+/// Marker type for tls-alpn challenges.
+#[doc(hidden)]
+pub struct TlsAlpn;
+
+/// Marker type for challenges of types this library doesn't know.
+#[doc(hidden)]
+pub struct Unknown;
+
+/// Types of challenges, for picking one using [`Auth::preferred_challenge`].
+///
+/// [`Auth::preferred_challenge`]: struct.Auth.html#method.preferred_challenge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChallengeType {
+    /// `http-01`
+    Http01,
+    /// `dns-01`
+    Dns01,
+    /// `tls-alpn-01`
+    TlsAlpn01,
+    /// `email-reply-00`
+    EmailReply00,
+    /// Any other type, by name.
+    Other(String),
+}
+
+impl ChallengeType {
+    /// The type as named by the ACME API, such as `http-01`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+            ChallengeType::EmailReply00 => "email-reply-00",
+            ChallengeType::Other(s) => s,
+        }
+    }
+}
+
+impl From<&str> for ChallengeType {
+    fn from(s: &str) -> Self {
+        match s {
+            "http-01" => ChallengeType::Http01,
+            "dns-01" => ChallengeType::Dns01,
+            "tls-alpn-01" => ChallengeType::TlsAlpn01,
+            "email-reply-00" => ChallengeType::EmailReply00,
+            _ => ChallengeType::Other(s.to_string()),
+        }
+    }
+}
+
+/// A challenge of any type, from [`Auth::challenge`].
+///
+/// [`Auth::challenge`]: struct.Auth.html#method.challenge
+pub enum AnyChallenge<P: Persist> {
+    /// An http challenge.
+    Http(Challenge<P, Http>),
+    /// A dns challenge.
+    Dns(Challenge<P, Dns>),
+    /// A tls-alpn challenge.
+    TlsAlpn(Challenge<P, TlsAlpn>),
+    /// An email challenge.
+    Email(Challenge<P, Email>),
+    /// A challenge of a type this library doesn't know. It can still be validated, once
+    /// the caller has done what it asks.
+    Other(Challenge<P, Unknown>),
+}
+
+impl<P: Persist> AnyChallenge<P> {
+    /// The type of the challenge.
+    pub fn challenge_type(&self) -> ChallengeType {
+        let api_challenge = match self {
+            AnyChallenge::Http(c) => c.api_challenge(),
+            AnyChallenge::Dns(c) => c.api_challenge(),
+            AnyChallenge::TlsAlpn(c) => c.api_challenge(),
+            AnyChallenge::Email(c) => c.api_challenge(),
+            AnyChallenge::Other(c) => c.api_challenge(),
+        };
+        ChallengeType::from(api_challenge._type.as_str())
+    }
+}
+
+/// The ALPN protocol of TLS connections validating a tls-alpn challenge.
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+/// A DNS, HTTP, TLS-ALPN or email challenge as obtained from the [`Auth`].
 ///
 /// [`Auth`]: struct.Auth.html
 pub struct Challenge<P: Persist, A> {
     inner: Arc<AccountInner<P>>,
     api_challenge: ApiChallenge,
     auth_url: String,
+    /// The identifier of the authorization.
+    identifier: ApiIdentifier,
+    cancel: Option<CancelToken>,
     _ph: std::marker::PhantomData<A>,
 }
 
@@ -165,6 +483,43 @@ impl<P: Persist> Challenge<P, Http> {
         let acme_key = self.inner.transport.acme_key();
         key_authorization(&self.api_challenge.token, acme_key, false)
     }
+
+    /// Write the [`http_proof`] to `<webroot>/.well-known/acme-challenge/<token>`, for
+    /// a web server already serving the webroot.
+    ///
+    /// Missing directories are created. The file is readable by anyone, and deleted when
+    /// the returned guard is dropped. Fails rather than following a symlink below the
+    /// webroot.
+    ///
+    /// [`http_proof`]: #method.http_proof
+    pub fn write_http_token(&self, webroot: &Path) -> Result<TokenGuard> {
+        write_token_file(webroot, self.http_token(), &self.http_proof())
+    }
+
+    /// Fetch `http://<domain>/.well-known/acme-challenge/<token>` like the ACME API
+    /// would, and check that it answers the [`http_proof`]. Catches a misconfigured web
+    /// server before using up a validation attempt.
+    ///
+    /// Redirects are followed, and trailing whitespace in the body is ignored. Fails with
+    /// [`Error::SelfCheckFailed`] describing the answer.
+    ///
+    /// [`http_proof`]: #method.http_proof
+    /// [`Error::SelfCheckFailed`]: ../enum.Error.html#variant.SelfCheckFailed
+    pub fn self_check_http(&self) -> Result<()> {
+        self.self_check_http_with(&HttpSelfCheckOptions::new())
+    }
+
+    /// Like [`self_check_http`], with options such as the address to connect to.
+    ///
+    /// [`self_check_http`]: #method.self_check_http
+    pub fn self_check_http_with(&self, opts: &HttpSelfCheckOptions) -> Result<()> {
+        let host = if self.identifier.is_type_ip() && self.identifier.value.contains(':') {
+            format!("[{}]", self.identifier.value)
+        } else {
+            self.identifier.value.clone()
+        };
+        check_http(&host, self.http_token(), &self.http_proof(), opts)
+    }
 }
 
 impl<P: Persist> Challenge<P, Dns> {
@@ -177,18 +532,209 @@ impl<P: Persist> Challenge<P, Dns> {
         let acme_key = self.inner.transport.acme_key();
         key_authorization(&self.api_challenge.token, acme_key, true)
     }
+
+    /// The `TXT` record to create, with the full record name and the [proof].
+    ///
+    /// For a wildcard such as `*.example.com`, the record is under the domain without
+    /// the `*.` label, `_acme-challenge.example.com.`.
+    ///
+    /// [proof]: #method.dns_proof
+    pub fn dns_record(&self) -> DnsTxtRecord {
+        DnsTxtRecord {
+            name: format!("_acme-challenge.{}.", self.dns_domain()),
+            value: self.dns_proof(),
+        }
+    }
+
+    /// Wait until the authoritative name servers of the zone serve the [record], before
+    /// triggering the validation. A CNAME at the record name is followed to the zone it
+    /// delegates to.
+    ///
+    /// Fails with [`Error::Timeout`] when too few servers serve the record in time. A
+    /// failed DNS query counts as not served yet, and its error is returned if the last
+    /// check before the timeout failed. Only the IPv4 addresses of the name servers are
+    /// queried.
+    ///
+    /// Requires the `dns_check` feature.
+    ///
+    /// [record]: #method.dns_record
+    /// [`Error::Timeout`]: ../enum.Error.html#variant.Timeout
+    #[cfg(feature = "dns_check")]
+    pub fn wait_for_dns_propagation(&self, opts: &DnsPropagationOptions) -> Result<()> {
+        let record = self.dns_record();
+        wait_for_propagation(&record.name, &record.value, opts)
+    }
+
+    /// The `TXT` record to create when the validation is delegated to another zone.
+    ///
+    /// The [`dns_record`] name must then be a `CNAME` pointing to the name of this record,
+    /// which is `_acme-challenge.<domain>.<target_zone>.`, such as
+    /// `_acme-challenge.example.com.acme.example.net.` for the `acme.example.net` zone.
+    ///
+    /// [`dns_record`]: #method.dns_record
+    pub fn dns_record_with_cname_target(&self, target_zone: &str) -> DnsTxtRecord {
+        let zone = target_zone.trim_matches('.');
+        DnsTxtRecord {
+            name: format!("_acme-challenge.{}.{}.", self.dns_domain(), zone),
+            value: self.dns_proof(),
+        }
+    }
+
+    /// The domain the record is for, without a wildcard label or trailing dot.
+    fn dns_domain(&self) -> &str {
+        let domain = &self.identifier.value;
+        let domain = domain.strip_prefix("*.").unwrap_or(domain);
+        domain.strip_suffix('.').unwrap_or(domain)
+    }
+}
+
+/// A DNS `TXT` record for a dns challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsTxtRecord {
+    /// The fully qualified record name, with a trailing dot, such as
+    /// `_acme-challenge.example.com.`.
+    pub name: String,
+    /// The text of the record.
+    pub value: String,
+}
+
+impl<P: Persist> Challenge<P, TlsAlpn> {
+    /// The key authorization, of which the SHA-256 digest is in the certificate.
+    pub fn tls_alpn_key_authorization(&self) -> String {
+        self.key_authorization()
+    }
+
+    /// The `proof` is a new self-signed certificate with a P-256 key, for the domain only.
+    /// It has the digest of the key authorization in the critical `acmeIdentifier`
+    /// extension.
+    ///
+    /// The certificate and key are available as PEM and DER, to load into a TLS server.
+    pub fn tls_alpn_proof(&self) -> Result<Certificate> {
+        let (pkey, _) = create_p256_key();
+        let key_auth = self.tls_alpn_key_authorization();
+        let x509 = create_tls_alpn_cert(&pkey, &self.identifier, &key_auth)?;
+        let pkey_pem = pkey
+            .private_key_to_pem_pkcs8()
+            .expect("private_key_to_pem_pkcs8");
+        let cert_pem = x509.to_pem().expect("to_pem");
+        Ok(Certificate::new(
+            String::from_utf8(pkey_pem).expect("from_utf8"),
+            String::from_utf8(cert_pem).expect("from_utf8"),
+        ))
+    }
+
+    /// Connect to the TLS server like the ACME API would, with the
+    /// [`ACME_TLS_ALPN_PROTOCOL`] and the identifier as SNI, and check that it presents
+    /// the [proof]. The address defaults to port 443 of the identifier.
+    ///
+    /// Only the client side is checked, so any TLS server works. Fails with
+    /// [`Error::SelfCheckFailed`] naming the negotiated protocol and the names in the
+    /// certificate.
+    ///
+    /// [`ACME_TLS_ALPN_PROTOCOL`]: constant.ACME_TLS_ALPN_PROTOCOL.html
+    /// [proof]: #method.tls_alpn_proof
+    /// [`Error::SelfCheckFailed`]: ../enum.Error.html#variant.SelfCheckFailed
+    pub fn self_check_tls_alpn(&self, addr: Option<SocketAddr>) -> Result<()> {
+        check_tls_alpn(&self.identifier, addr, &self.tls_alpn_key_authorization())
+    }
+}
+
+impl<P: Persist> Challenge<P, Email> {
+    /// The address the challenge email is sent from, and the reply should go to.
+    pub fn email_from(&self) -> Option<&str> {
+        self.api_challenge.from.as_deref()
+    }
+
+    /// The second half of the token. The first half is in the subject of the
+    /// challenge email.
+    pub fn email_token_part2(&self) -> &str {
+        &self.api_challenge.token
+    }
+
+    /// The key authorization for both halves of the token.
+    pub fn email_key_authorization(&self, token_part1: &str) -> String {
+        let token = format!("{}{}", token_part1, self.api_challenge.token);
+        let acme_key = self.inner.transport.acme_key();
+        key_authorization(&token, acme_key, false)
+    }
+
+    /// The `proof` to put in the reply to the challenge email, given the first half of
+    /// the token from the subject of the challenge email.
+    pub fn email_proof(&self, token_part1: &str) -> String {
+        let token = format!("{}{}", token_part1, self.api_challenge.token);
+        let acme_key = self.inner.transport.acme_key();
+        key_authorization(&token, acme_key, true)
+    }
 }
 
 impl<P: Persist, A> Challenge<P, A> {
-    fn new(inner: &Arc<AccountInner<P>>, api_challenge: ApiChallenge, auth_url: &str) -> Self {
+    fn new(auth: &Auth<P>, api_challenge: ApiChallenge) -> Self {
         Challenge {
-            inner: inner.clone(),
+            inner: auth.inner.clone(),
             api_challenge,
-            auth_url: auth_url.into(),
+            auth_url: auth.auth_url.clone(),
+            identifier: auth.api_auth.identifier.clone(),
+            cancel: auth.cancel.clone(),
             _ph: std::marker::PhantomData,
         }
     }
 
+    /// The `token` of the challenge, as given by the ACME API.
+    ///
+    /// For an email challenge, this is only the second half of the token.
+    pub fn token(&self) -> &str {
+        &self.api_challenge.token
+    }
+
+    /// The key authorization, `<token>.<thumbprint of the account key>`, which is what
+    /// the ACME API checks in some encoding depending on the type of challenge.
+    ///
+    /// This is the [`http_proof`] as is, and the [`dns_proof`] is its base64url SHA-256
+    /// digest. For an email challenge, use [`email_key_authorization`] instead.
+    ///
+    /// [`http_proof`]: #method.http_proof
+    /// [`dns_proof`]: #method.dns_proof
+    /// [`email_key_authorization`]: #method.email_key_authorization
+    pub fn key_authorization(&self) -> String {
+        let acme_key = self.inner.transport.acme_key();
+        key_authorization(&self.api_challenge.token, acme_key, false)
+    }
+
+    /// The URL of the challenge, which [`validate`] calls.
+    ///
+    /// [`validate`]: #method.validate
+    pub fn url(&self) -> &str {
+        &self.api_challenge.url
+    }
+
+    /// Why validating the challenge failed, if it did in an earlier attempt.
+    pub fn error(&self) -> Option<&ApiProblem> {
+        self.api_challenge.error.as_ref()
+    }
+
+    /// Refresh the challenge once and tell its status, without waiting.
+    ///
+    /// The specification calls this a "POST-as-GET" against the challenge URL. Use it
+    /// after telling the ACME API to [`validate`], to drive the polling loop from the
+    /// outside, such as an async runtime that sleeps between polls without blocking a
+    /// thread.
+    ///
+    /// [`validate`]: #method.validate
+    pub fn poll(&mut self) -> Result<ChallengeStatus> {
+        let res = self.inner.call(&self.api_challenge.url, &ApiEmptyString)?;
+        let retry_after = req_retry_after(&res);
+        self.api_challenge = read_json(res)?;
+        let chall = &self.api_challenge;
+        Ok(match chall.status.as_str() {
+            "processing" => ChallengeStatus::Processing { retry_after },
+            "valid" => ChallengeStatus::Valid,
+            "invalid" => {
+                ChallengeStatus::Invalid(invalid_problem(chall.error.as_ref(), &chall.url))
+            }
+            _ => ChallengeStatus::Pending,
+        })
+    }
+
     /// Check whether this challlenge really need validation. It might already been
     /// done in a previous order for the same account.
     pub fn need_validate(&self) -> bool {
@@ -199,30 +745,45 @@ impl<P: Persist, A> Challenge<P, A> {
     ///
     /// The user must first update the DNS record or HTTP web server depending
     /// on the type challenge being validated.
-    pub fn validate(self, delay_millis: u64) -> Result<()> {
+    ///
+    /// If the authorization becomes invalid, this fails with [`Error::ChallengeFailed`]
+    /// carrying the reason given by the ACME API.
+    ///
+    /// The challenge isn't consumed, validate it again after fixing a failure. If the
+    /// authorization has become `invalid`, see [`NewOrder::replace_invalid_auths`].
+    ///
+    /// [`Error::ChallengeFailed`]: ../enum.Error.html#variant.ChallengeFailed
+    /// [`NewOrder::replace_invalid_auths`]: struct.NewOrder.html#method.replace_invalid_auths
+    pub fn validate(&self, delay_millis: u64) -> Result<()> {
+        self.validate_until(Duration::from_millis(delay_millis), None)
+    }
+
+    /// Like [`validate`], but gives up with [`Error::Timeout`] if the authorization is
+    /// still `pending` after the `timeout`.
+    ///
+    /// [`validate`]: #method.validate
+    /// [`Error::Timeout`]: ../enum.Error.html#variant.Timeout
+    pub fn validate_timeout(&self, delay: Duration, timeout: Duration) -> Result<()> {
+        self.validate_until(delay, Some(timeout))
+    }
+
+    pub(crate) fn validate_until(&self, delay: Duration, timeout: Option<Duration>) -> Result<()> {
+        let mut poller = Poller::new(&self.auth_url, timeout, self.cancel.as_ref());
+        poller.check_cancelled()?;
         let url_chall = &self.api_challenge.url;
-        let res = self.inner.transport.call(url_chall, &ApiEmptyObject)?;
+        self.inner.event(OrderEvent::AuthValidating {
+            domain: self.identifier.value.clone(),
+            challenge_type: self.api_challenge._type.clone(),
+        });
+        let res = self.inner.call(url_chall, &ApiEmptyObject)?;
         let _: ApiChallenge = read_json(res)?;
 
-        let auth = wait_for_auth_status(&self.inner, &self.auth_url, delay_millis)?;
-
-        if !auth.is_status_valid() {
-            let error = auth
-                .challenges
-                .iter()
-                .filter_map(|c| c.error.as_ref())
-                .nth(0);
-            let reason = if let Some(error) = error {
-                format!(
-                    "Failed: {}",
-                    error.detail.clone().unwrap_or_else(|| error._type.clone())
-                )
-            } else {
-                "Validation failed and no error found".into()
-            };
-            return Err(reason.into());
-        }
+        let auth = wait_for_auth_status(&self.inner, &self.auth_url, delay, &mut poller)?;
+        check_auth_valid(&auth, Some(url_chall))?;
 
+        self.inner.event(OrderEvent::AuthValid {
+            domain: self.identifier.value.clone(),
+        });
         Ok(())
     }
 
@@ -233,11 +794,7 @@ impl<P: Persist, A> Challenge<P, A> {
 }
 
 fn key_authorization(token: &str, key: &AcmeKey, extra_sha256: bool) -> String {
-    let jwk: Jwk = key.into();
-    let jwk_thumb: JwkThumb = (&jwk).into();
-    let jwk_json = serde_json::to_string(&jwk_thumb).expect("jwk_thumb");
-    let digest = base64url(&sha256(jwk_json.as_bytes()));
-    let key_auth = format!("{}.{}", token, digest);
+    let key_auth = format!("{}.{}", token, jwk_thumbprint(key));
     if extra_sha256 {
         base64url(&sha256(key_auth.as_bytes()))
     } else {
@@ -245,24 +802,58 @@ fn key_authorization(token: &str, key: &AcmeKey, extra_sha256: bool) -> String {
     }
 }
 
+/// Fail with the reason unless the authorization is valid, preferring the error of the
+/// challenge with the URL.
+fn check_auth_valid(auth: &ApiAuth, challenge_url: Option<&str>) -> Result<()> {
+    if auth.is_status_valid() {
+        return Ok(());
+    }
+    let failed = auth
+        .challenges
+        .iter()
+        .find(|c| Some(c.url.as_str()) == challenge_url && c.error.is_some())
+        .or_else(|| auth.failed_challenge());
+    Err(match failed {
+        Some(c) => Error::ChallengeFailed {
+            domain: auth.identifier.value.clone(),
+            problem: Box::new(c.error.clone().expect("error")),
+            validation_record: c.validationRecord.clone().unwrap_or_default(),
+        },
+        None => format!(
+            "Validation failed and no error found, authorization is: {:?}",
+            auth.status
+        )
+        .into(),
+    })
+}
+
 fn wait_for_auth_status<P: Persist>(
     inner: &Arc<AccountInner<P>>,
     auth_url: &str,
-    delay_millis: u64,
+    delay: Duration,
+    poller: &mut Poller,
 ) -> Result<ApiAuth> {
     let auth = loop {
-        let res = inner.transport.call(auth_url, &ApiEmptyString)?;
+        poller.check_cancelled()?;
+        let res = inner.call(auth_url, &ApiEmptyString)?;
+        let retry_after = req_retry_after(&res);
         let auth: ApiAuth = read_json(res)?;
         if !auth.is_status_pending() {
             break auth;
         }
-        thread::sleep(Duration::from_millis(delay_millis));
+        let delay = poller.delay("pending", poll_delay(retry_after, delay))?;
+        debug!(
+            "Authorization is pending, poll again in {:?}: {}",
+            delay, auth_url
+        );
+        poller.sleep(delay)?;
     };
     Ok(auth)
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use crate::persist::*;
     use crate::*;
 
@@ -278,7 +869,7 @@ mod test {
         assert!(authz.len() == 1);
         let auth = &authz[0];
         {
-            let http = auth.http_challenge();
+            let http = auth.http_challenge().unwrap();
             assert!(http.need_validate());
         }
         {
@@ -287,4 +878,308 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_deactivate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let mut authz = ord.authorizations()?;
+        let auth = authz.remove(0);
+        assert!(auth
+            .url()
+            .ends_with("/acme/authz/YTqpYUthlVfwBncUufE8IRWLMSRqcSs"));
+        auth.deactivate()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wildcard() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("*.example.com", &["example.com"])?;
+        let authz = ord.authorizations()?;
+        assert_eq!(authz.len(), 2);
+        let wildcard = &authz[0];
+        assert!(wildcard.is_wildcard());
+        assert_eq!(wildcard.domain_name(), "example.com");
+        assert!(wildcard.http_challenge().is_none());
+        assert!(wildcard.tls_alpn_challenge().is_none());
+        assert!(wildcard.email_challenge().is_none());
+        assert!(wildcard.dns_challenge().need_validate());
+        let record = wildcard.dns_challenge().dns_record();
+        assert_eq!(record.name, "_acme-challenge.example.com.");
+        assert_eq!(record.value, wildcard.dns_challenge().dns_proof());
+        let plain = &authz[1];
+        assert!(!plain.is_wildcard());
+        assert!(plain.http_challenge().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_proof_thumbprint() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let pem = crate::test::TEST_ACCOUNT_KEY_PEM;
+        let acc = dir.account_with_key("foo@bar.com", pem)?;
+        let thumbprint = "3K9DdBmExiW3Om_6KFKrOGbyfcYw0h2vioV1S-Yi4GA";
+        assert_eq!(acc.jwk_thumbprint(), thumbprint);
+        assert!(acc
+            .public_jwk_json()
+            .starts_with(r#"{"crv":"P-256","kty":"EC","x":"#));
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let authz = ord.authorizations()?;
+        let http = authz[0].http_challenge().unwrap();
+        assert!(http.http_proof().ends_with(&format!(".{}", thumbprint)));
+        assert_eq!(http.token(), http.http_token());
+        assert_eq!(http.key_authorization(), http.http_proof());
+        assert!(http.url().ends_with("/216789597"));
+        let webroot = crate::test::temp_dir("http-proof");
+        let file = http.write_http_token(&webroot)?;
+        assert!(file.path().ends_with(http.http_token()));
+        assert_eq!(std::fs::read_to_string(file.path())?, http.http_proof());
+        drop(file);
+        std::fs::remove_dir_all(&webroot)?;
+        let dns = authz[0].dns_challenge();
+        assert_eq!(
+            dns.dns_proof(),
+            base64url(&sha256(dns.key_authorization().as_bytes()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_failed() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let mut auth = ord.authorizations()?.remove(0);
+        assert!(auth.error().is_none());
+        // pretend the authorization is for another domain
+        auth.auth_url = server.dir_url.replace("/directory", "/acme/authz/invalid");
+        auth.refresh()?;
+        assert_eq!(auth.api_auth().identifier.value, "invalid.example.com");
+        let error = auth.error().expect("error");
+        assert_eq!(error._type, "urn:ietf:params:acme:error:unauthorized");
+
+        let http = auth.http_challenge().unwrap();
+        assert!(http.error().is_some());
+        let err = http.validate(1).err().unwrap();
+        let msg = err.to_string();
+        assert!(msg.contains("Invalid response from http://"), "{}", msg);
+        assert!(msg.contains("invalid.example.com:80"), "{}", msg);
+        assert!(msg.contains("192.0.2.1, 2001:db8::1"), "{}", msg);
+        match err {
+            Error::ChallengeFailed {
+                domain,
+                problem,
+                validation_record,
+            } => {
+                assert_eq!(domain, "invalid.example.com");
+                assert!(problem.detail.unwrap().ends_with(": 403"));
+                assert_eq!(validation_record[0].port.as_deref(), Some("80"));
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_timeout() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let mut auth = ord.authorizations()?.remove(0);
+        let delay = Duration::from_millis(1);
+        let timeout = Duration::from_millis(50);
+
+        let http = auth.http_challenge().unwrap();
+        match http.validate_timeout(delay, timeout).err().unwrap() {
+            Error::Timeout { url, polls, status } => {
+                assert_eq!(url, auth.url());
+                assert!(polls >= 1);
+                assert_eq!(status, "pending");
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = auth.wait_done_timeout(delay, timeout).err().unwrap();
+        assert!(matches!(err, Error::Timeout { .. }), "{}", err);
+
+        auth.auth_url = server.dir_url.replace("/directory", "/acme/authz/valid");
+        auth.wait_done_timeout(delay, timeout)?;
+        assert!(auth.api_auth().is_status_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cancel() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let cancel = CancelToken::new();
+        let ord = ord.with_cancel(cancel.clone());
+        let auth = ord.authorizations()?.remove(0);
+        let http = auth.http_challenge().unwrap();
+
+        let start = std::time::Instant::now();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+        let err = http.validate(2000);
+        assert!(matches!(err, Err(Error::Cancelled)), "{:?}", err);
+        assert!(start.elapsed() < Duration::from_millis(2000));
+        t.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let mut auth = ord.authorizations()?.remove(0);
+
+        let mut http = auth.http_challenge().unwrap();
+        let status = http.poll()?;
+        assert_eq!(status, ChallengeStatus::Processing { retry_after: None });
+
+        auth.auth_url = server.dir_url.replace("/directory", "/acme/authz/valid");
+        auth.refresh()?;
+        let mut http = auth.http_challenge().unwrap();
+        assert_eq!(http.poll()?, ChallengeStatus::Valid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dns_record() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let authz = ord.authorizations()?;
+        let dns = authz[0].dns_challenge();
+        let record = dns.dns_record();
+        assert_eq!(record.name, "_acme-challenge.acmetest.algesten.se.");
+        assert_eq!(record.value, dns.dns_proof());
+        let record = dns.dns_record_with_cname_target(".acme.example.net.");
+        assert_eq!(
+            record.name,
+            "_acme-challenge.acmetest.algesten.se.acme.example.net."
+        );
+        assert_eq!(record.value, dns.dns_proof());
+        Ok(())
+    }
+
+    #[test]
+    fn test_challenge_types() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let authz = ord.authorizations()?;
+        let auth = &authz[0];
+        assert_eq!(
+            auth.challenge_types(),
+            vec![
+                "http-01",
+                "tls-alpn-01",
+                "dns-01",
+                "email-reply-00",
+                "future-01"
+            ]
+        );
+        assert!(matches!(
+            auth.challenge("dns-01"),
+            Some(AnyChallenge::Dns(_))
+        ));
+        match auth.challenge("future-01") {
+            Some(c @ AnyChallenge::Other(_)) => {
+                assert_eq!(c.challenge_type(), ChallengeType::Other("future-01".into()))
+            }
+            _ => panic!("expected future-01"),
+        }
+        assert!(auth.challenge("nope-01").is_none());
+
+        let preference = [
+            ChallengeType::Other("nope-01".into()),
+            ChallengeType::TlsAlpn01,
+            ChallengeType::Http01,
+        ];
+        let preferred = auth.preferred_challenge(&preference).unwrap();
+        assert_eq!(preferred.challenge_type(), ChallengeType::TlsAlpn01);
+        assert!(auth.preferred_challenge(&[]).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_alpn_challenge() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let pem = crate::test::TEST_ACCOUNT_KEY_PEM;
+        let acc = dir.account_with_key("foo@bar.com", pem)?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let authz = ord.authorizations()?;
+        let tls_alpn = authz[0].tls_alpn_challenge().unwrap();
+        assert!(tls_alpn.need_validate());
+        assert_eq!(
+            tls_alpn.tls_alpn_key_authorization(),
+            "WCdRWkCy4THTD_j5IH4ISAzr59lFIg5wzYmKxuOJ1lU.3K9DdBmExiW3Om_6KFKrOGbyfcYw0h2vioV1S-Yi4GA"
+        );
+        let proof = tls_alpn.tls_alpn_proof()?;
+        assert_eq!(proof.domains(), vec![authz[0].domain_name()]);
+        assert!(!proof.private_key_der().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_challenge() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let pem = crate::test::TEST_ACCOUNT_KEY_PEM;
+        let acc = dir.account_with_key("foo@bar.com", pem)?;
+        let ord = acc.new_order_identifiers(&[Identifier::Email("user@example.com")])?;
+        let authz = ord.authorizations()?;
+        let email = authz[0].email_challenge().unwrap();
+        assert!(email.need_validate());
+        assert_eq!(email.email_from(), Some("acme-challenge@example.com"));
+        assert_eq!(email.email_token_part2(), "DGyRejmCefe7v4NfDGDKfA");
+        let key_auth = email.email_key_authorization("LgYemJLy3F1LDkiJrdIGbEzyFJyOyf6v");
+        assert_eq!(
+            key_auth,
+            "LgYemJLy3F1LDkiJrdIGbEzyFJyOyf6vDGyRejmCefe7v4NfDGDKfA.\
+             3K9DdBmExiW3Om_6KFKrOGbyfcYw0h2vioV1S-Yi4GA"
+        );
+        let proof = email.email_proof("LgYemJLy3F1LDkiJrdIGbEzyFJyOyf6v");
+        assert_eq!(proof, base64url(&sha256(key_auth.as_bytes())));
+        Ok(())
+    }
 }
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Cancels waiting for the ACME API, such as when the operator gives up on an order or
+/// the process is shutting down.
+///
+/// Clones share the same state, keep one and hand another to the order using
+/// [`NewOrder::with_cancel`]. Once cancelled, the waiting calls fail with
+/// [`Error::Cancelled`].
+///
+/// [`NewOrder::with_cancel`]: struct.NewOrder.html#method.with_cancel
+/// [`Error::Cancelled`]: ../enum.Error.html#variant.Cancelled
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// A new token that isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel, from any thread.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [`cancel`] was called.
+    ///
+    /// [`cancel`]: #method.cancel
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        CancelToken(flag)
+    }
+}
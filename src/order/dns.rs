@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+#[cfg(feature = "dns_check")]
+use crate::order::DnsPropagationOptions;
+use crate::Result;
+
+/// Creates and removes the `TXT` records of dns challenges, for
+/// [`NewOrder::validate_dns`] to drive the whole validation.
+///
+/// Implement it for the API of a DNS provider.
+///
+/// [`NewOrder::validate_dns`]: struct.NewOrder.html#method.validate_dns
+pub trait Dns01Provider {
+    /// Create a `TXT` record. The `fqdn` has a trailing dot, such as
+    /// `_acme-challenge.example.com.`.
+    ///
+    /// A wildcard and its apex domain have records with the same name. Add the value to
+    /// the existing ones, rather than replacing them.
+    fn set_txt(&self, fqdn: &str, value: &str) -> Result<()>;
+
+    /// Remove the `TXT` record with the value, once the challenge is done.
+    fn cleanup_txt(&self, fqdn: &str, value: &str) -> Result<()>;
+}
+
+/// Options of [`NewOrder::validate_dns`].
+///
+/// [`NewOrder::validate_dns`]: struct.NewOrder.html#method.validate_dns
+#[derive(Debug, Clone)]
+pub struct DnsValidateOptions {
+    pub(crate) propagation_delay: Duration,
+    pub(crate) poll_delay: Duration,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) set_all_first: bool,
+    #[cfg(feature = "dns_check")]
+    pub(crate) propagation_check: Option<DnsPropagationOptions>,
+}
+
+impl Default for DnsValidateOptions {
+    fn default() -> Self {
+        DnsValidateOptions {
+            propagation_delay: Duration::from_secs(30),
+            poll_delay: Duration::from_secs(5),
+            timeout: None,
+            set_all_first: true,
+            #[cfg(feature = "dns_check")]
+            propagation_check: None,
+        }
+    }
+}
+
+impl DnsValidateOptions {
+    /// The default options: wait 30 seconds for records to propagate, poll every 5
+    /// seconds, without a timeout, and set all records first.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait after setting records, before the ACME API validates them.
+    pub fn with_propagation_delay(mut self, delay: Duration) -> Self {
+        self.propagation_delay = delay;
+        self
+    }
+
+    /// The time between polls of an authorization being validated.
+    pub fn with_poll_delay(mut self, delay: Duration) -> Self {
+        self.poll_delay = delay;
+        self
+    }
+
+    /// Give up validating a record after the timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether all records are set before validating any of them, the default.
+    ///
+    /// Otherwise each record is set, validated and removed in turn. That doesn't work
+    /// when two authorizations, such as for a wildcard and its apex domain, have
+    /// records with the same name. The ACME API might see the record of the other one.
+    pub fn with_set_all_first(mut self, set_all_first: bool) -> Self {
+        self.set_all_first = set_all_first;
+        self
+    }
+
+    /// Before validating a record, wait until the authoritative name servers serve it.
+    /// The check runs after the propagation delay, which can then be short.
+    ///
+    /// Requires the `dns_check` feature.
+    #[cfg(feature = "dns_check")]
+    pub fn with_propagation_check(mut self, check: DnsPropagationOptions) -> Self {
+        self.propagation_check = Some(check);
+        self
+    }
+}
@@ -0,0 +1,86 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Progress of an order, passed to the hook set using [`Directory::with_event_hook`].
+///
+/// [`Directory::with_event_hook`]: ../struct.Directory.html#method.with_event_hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderEvent {
+    /// A new order was created with the ACME API.
+    OrderCreated {
+        /// The URL of the order.
+        url: String,
+    },
+    /// A challenge was submitted for validation by the ACME API.
+    AuthValidating {
+        /// The identifier being authorized, such as `example.com`.
+        domain: String,
+        /// The type of challenge, such as `http-01`.
+        challenge_type: String,
+    },
+    /// The authorization is valid, after validating a challenge.
+    AuthValid {
+        /// The identifier authorized.
+        domain: String,
+    },
+    /// The CSR was submitted to finalize the order.
+    Finalizing,
+    /// The certificate isn't issued yet, the order is polled again after the delay.
+    Processing {
+        /// The time until the next poll.
+        retry_in: Duration,
+    },
+    /// The certificate was downloaded and saved in the persistence.
+    CertificateDownloaded {
+        /// The subject alternative names of the certificate.
+        sans: Vec<String>,
+    },
+    /// A request to the ACME API is retried, such as for a `badNonce` error.
+    Retrying {
+        /// The URL of the request.
+        url: String,
+        /// The error that caused the retry.
+        reason: String,
+    },
+}
+
+/// The hook to pass events to, if any.
+#[derive(Clone, Default)]
+pub(crate) struct EventHook(Option<Arc<dyn Fn(OrderEvent) + Send + Sync>>);
+
+impl EventHook {
+    pub(crate) fn new<F: Fn(OrderEvent) + Send + Sync + 'static>(hook: F) -> Self {
+        EventHook(Some(Arc::new(hook)))
+    }
+
+    /// Pass the event to the hook. A panic in the hook is logged, not propagated.
+    pub(crate) fn fire(&self, event: OrderEvent) {
+        if let Some(hook) = &self.0 {
+            trace!("Event: {:?}", event);
+            if catch_unwind(AssertUnwindSafe(|| hook(event))).is_err() {
+                warn!("Event hook panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_fire() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen2 = seen.clone();
+        let hook = EventHook::new(move |e| seen2.lock().unwrap().push(e));
+        hook.fire(OrderEvent::Finalizing);
+        assert_eq!(*seen.lock().unwrap(), vec![OrderEvent::Finalizing]);
+
+        // doesn't break the caller
+        let hook = EventHook::new(|_| panic!("hook"));
+        hook.fire(OrderEvent::Finalizing);
+        EventHook::default().fire(OrderEvent::Finalizing);
+    }
+}
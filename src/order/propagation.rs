@@ -0,0 +1,628 @@
+//! Checking that a dns challenge record is served by the authoritative name servers,
+//! using a minimal DNS client over UDP.
+use openssl::rand::rand_bytes;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+/// Recursion desired.
+const FLAG_RD: u16 = 0x0100;
+/// Truncated.
+const FLAG_TC: u16 = 0x0200;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Max CNAMEs followed from the record name.
+const MAX_CNAMES: usize = 8;
+
+/// Options of [`Challenge::wait_for_dns_propagation`].
+///
+/// [`Challenge::wait_for_dns_propagation`]: struct.Challenge.html#method.wait_for_dns_propagation
+#[derive(Debug, Clone)]
+pub struct DnsPropagationOptions {
+    timeout: Duration,
+    poll_delay: Duration,
+    quorum: Option<usize>,
+    resolver: Option<SocketAddr>,
+    query_timeout: Duration,
+    /// Port of the authoritative name servers, other than 53 in tests.
+    pub(crate) port: u16,
+}
+
+impl Default for DnsPropagationOptions {
+    fn default() -> Self {
+        DnsPropagationOptions {
+            timeout: Duration::from_secs(5 * 60),
+            poll_delay: Duration::from_secs(5),
+            quorum: None,
+            resolver: None,
+            query_timeout: Duration::from_secs(3),
+            port: 53,
+        }
+    }
+}
+
+impl DnsPropagationOptions {
+    /// The default options: check every 5 seconds for up to 5 minutes, until all
+    /// authoritative name servers serve the record.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up after the timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The time between checks.
+    pub fn with_poll_delay(mut self, delay: Duration) -> Self {
+        self.poll_delay = delay;
+        self
+    }
+
+    /// Done once this many authoritative name servers serve the record, rather than all.
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        self.quorum = Some(quorum);
+        self
+    }
+
+    /// The recursive resolver used to find the authoritative name servers. By default the
+    /// first `nameserver` of `/etc/resolv.conf`.
+    pub fn with_resolver(mut self, resolver: SocketAddr) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// How long to wait for the answer of single DNS query.
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+}
+
+/// Wait until the `TXT` record with the name has the value at the authoritative name
+/// servers. CNAMEs are followed.
+///
+/// A failed query counts as not served yet. The error of the last poll is returned when
+/// it failed at the deadline.
+pub(crate) fn wait_for_propagation(
+    name: &str,
+    value: &str,
+    opts: &DnsPropagationOptions,
+) -> Result<()> {
+    let client = Client::new(opts)?;
+    let name = normalize(name);
+    let deadline = Instant::now() + opts.timeout;
+    let mut polls = 0;
+    loop {
+        polls += 1;
+        let result = client.served(&name, value);
+        match &result {
+            Ok((served, total)) => {
+                let quorum = opts.quorum.unwrap_or(*total).clamp(1, (*total).max(1));
+                debug!(
+                    "TXT record {} served by {} of {} name servers",
+                    name, served, total
+                );
+                if *served >= quorum {
+                    return Ok(());
+                }
+            }
+            Err(e) => debug!("Checking TXT record {} failed: {}", name, e),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            let (served, total) = result?;
+            return Err(Error::Timeout {
+                url: name,
+                polls,
+                status: format!("served by {} of {} name servers", served, total),
+            });
+        }
+        thread::sleep(opts.poll_delay.min(deadline - now));
+    }
+}
+
+struct Client {
+    resolver: SocketAddr,
+    port: u16,
+    timeout: Duration,
+}
+
+impl Client {
+    fn new(opts: &DnsPropagationOptions) -> Result<Self> {
+        let resolver = match opts.resolver {
+            Some(r) => r,
+            None => system_resolver()?,
+        };
+        Ok(Client {
+            resolver,
+            port: opts.port,
+            timeout: opts.query_timeout,
+        })
+    }
+
+    /// How many of the authoritative name servers serve the value, of how many.
+    fn served(&self, name: &str, value: &str) -> Result<(usize, usize)> {
+        let mut name = name.to_string();
+        for _ in 0..MAX_CNAMES {
+            let servers = self.authoritative_servers(&name)?;
+            let mut cname = None;
+            let mut served = 0;
+            for server in &servers {
+                let res = match query(*server, &name, TYPE_TXT, false, self.timeout) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        debug!("TXT query to {} failed: {}", server, e);
+                        continue;
+                    }
+                };
+                let mut found = false;
+                for r in res.answers.iter().filter(|r| r.name == name) {
+                    match &r.data {
+                        RData::Cname(target) => cname = Some(target.clone()),
+                        RData::Txt(txt) if txt == value => found = true,
+                        _ => {}
+                    }
+                }
+                if found {
+                    served += 1;
+                }
+            }
+            match cname {
+                Some(target) => {
+                    debug!("Follow CNAME {} to {}", name, target);
+                    name = target;
+                }
+                None => return Ok((served, servers.len())),
+            }
+        }
+        Err(format!("Too many CNAMEs for: {}", name).into())
+    }
+
+    /// The addresses of the name servers of the zone of the name. Only IPv4 addresses
+    /// are looked up.
+    fn authoritative_servers(&self, name: &str) -> Result<Vec<SocketAddr>> {
+        let labels: Vec<_> = name.trim_end_matches('.').split('.').collect();
+        for i in 0..labels.len() {
+            let zone = format!("{}.", labels[i..].join("."));
+            let res = query(self.resolver, &zone, TYPE_NS, true, self.timeout)?;
+            let hosts: Vec<_> = res
+                .answers
+                .iter()
+                .filter(|r| r.name == zone)
+                .filter_map(|r| match &r.data {
+                    RData::Ns(host) => Some(host.clone()),
+                    _ => None,
+                })
+                .collect();
+            if hosts.is_empty() {
+                continue;
+            }
+            let mut addrs = vec![];
+            for host in hosts {
+                let glue = addresses(&res.additional, &host);
+                let ips = if glue.is_empty() {
+                    match query(self.resolver, &host, TYPE_A, true, self.timeout) {
+                        Ok(res) => addresses(&res.answers, &host),
+                        Err(e) => {
+                            debug!("Address query for {} failed: {}", host, e);
+                            continue;
+                        }
+                    }
+                } else {
+                    glue
+                };
+                addrs.extend(
+                    ips.into_iter()
+                        .map(|ip| SocketAddr::new(ip.into(), self.port)),
+                );
+            }
+            if addrs.is_empty() {
+                return Err(format!("No addresses of the name servers of: {}", zone).into());
+            }
+            return Ok(addrs);
+        }
+        Err(format!("No name servers found for: {}", name).into())
+    }
+}
+
+fn addresses(records: &[Record], host: &str) -> Vec<Ipv4Addr> {
+    records
+        .iter()
+        .filter(|r| r.name == host)
+        .filter_map(|r| match r.data {
+            RData::A(ip) => Some(ip),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The first `nameserver` of `/etc/resolv.conf`.
+fn system_resolver() -> Result<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf")?;
+    conf.lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+        .ok_or_else(|| "No nameserver in /etc/resolv.conf".into())
+}
+
+/// Lowercase with a trailing dot.
+fn normalize(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    if name.ends_with('.') {
+        name
+    } else {
+        format!("{}.", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RData {
+    A(Ipv4Addr),
+    Ns(String),
+    Cname(String),
+    Txt(String),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    data: RData,
+}
+
+#[derive(Debug, Default)]
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<(String, u16)>,
+    answers: Vec<Record>,
+    authority: Vec<Record>,
+    additional: Vec<Record>,
+}
+
+fn query(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+    recursion: bool,
+    timeout: Duration,
+) -> Result<Message> {
+    let mut id = [0; 2];
+    rand_bytes(&mut id).expect("rand_bytes");
+    let msg = Message {
+        id: u16::from_be_bytes(id),
+        flags: if recursion { FLAG_RD } else { 0 },
+        questions: vec![(name.to_string(), qtype)],
+        ..Default::default()
+    };
+    let bind: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+    socket.send(&encode(&msg))?;
+
+    let mut buf = [0; 4096];
+    loop {
+        let n = socket.recv(&mut buf)?;
+        let res = match parse(&buf[..n]) {
+            Ok(res) if res.id == msg.id => res,
+            // not the answer to our query.
+            _ => continue,
+        };
+        if res.flags & FLAG_TC != 0 {
+            return Err(format!("Truncated DNS answer from {} for: {}", server, name).into());
+        }
+        let rcode = res.flags & 0xf;
+        if rcode != 0 && rcode != RCODE_NXDOMAIN {
+            return Err(format!("DNS error {} from {} for: {}", rcode, server, name).into());
+        }
+        return Ok(res);
+    }
+}
+
+fn encode(msg: &Message) -> Vec<u8> {
+    let mut buf = vec![];
+    let sections = [&msg.answers, &msg.authority, &msg.additional];
+    buf.extend_from_slice(&msg.id.to_be_bytes());
+    buf.extend_from_slice(&msg.flags.to_be_bytes());
+    buf.extend_from_slice(&(msg.questions.len() as u16).to_be_bytes());
+    for records in &sections {
+        buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    }
+    for (name, qtype) in &msg.questions {
+        encode_name(&mut buf, name);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for r in sections.iter().flat_map(|s| s.iter()) {
+        let (rtype, rdata) = match &r.data {
+            RData::A(ip) => (TYPE_A, ip.octets().to_vec()),
+            RData::Ns(host) => (TYPE_NS, name_bytes(host)),
+            RData::Cname(host) => (TYPE_CNAME, name_bytes(host)),
+            RData::Txt(txt) => {
+                let mut v = vec![];
+                for chunk in txt.as_bytes().chunks(255) {
+                    v.push(chunk.len() as u8);
+                    v.extend_from_slice(chunk);
+                }
+                (TYPE_TXT, v)
+            }
+            RData::Other => continue,
+        };
+        encode_name(&mut buf, &r.name);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&60u32.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
+    }
+    buf
+}
+
+fn name_bytes(name: &str) -> Vec<u8> {
+    let mut buf = vec![];
+    encode_name(&mut buf, name);
+    buf
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn parse(buf: &[u8]) -> Result<Message> {
+    let mut r = Reader { buf, pos: 0 };
+    let id = r.u16()?;
+    let flags = r.u16()?;
+    let counts = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+    let mut msg = Message {
+        id,
+        flags,
+        ..Default::default()
+    };
+    for _ in 0..counts[0] {
+        let name = r.name()?;
+        let qtype = r.u16()?;
+        r.u16()?;
+        msg.questions.push((name, qtype));
+    }
+    for (i, count) in counts.iter().enumerate().skip(1) {
+        for _ in 0..*count {
+            let record = r.record()?;
+            match i {
+                1 => msg.answers.push(record),
+                2 => msg.authority.push(record),
+                _ => msg.additional.push(record),
+            }
+        }
+    }
+    Ok(msg)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or("Short DNS message")?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A possibly compressed name, lowercased with a trailing dot.
+    fn name(&mut self) -> Result<String> {
+        let mut labels = vec![];
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // limits the pointers followed, to not loop forever.
+        for _ in 0..128 {
+            let len = *self.buf.get(pos).ok_or("Short DNS message")? as usize;
+            if len & 0xc0 == 0xc0 {
+                let low = *self.buf.get(pos + 1).ok_or("Short DNS message")? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                }
+                jumped = true;
+                pos = ((len & 0x3f) << 8) | low;
+            } else if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Ok(normalize(&labels.join(".")));
+            } else {
+                let label = self
+                    .buf
+                    .get(pos + 1..pos + 1 + len)
+                    .ok_or("Short DNS message")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+        Err("Invalid name in DNS message".into())
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let _class = self.u16()?;
+        let _ttl = self.bytes(4)?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let b = self.bytes(4)?;
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_NS => RData::Ns(self.name()?),
+            TYPE_CNAME => RData::Cname(self.name()?),
+            TYPE_TXT => {
+                let mut txt = vec![];
+                while self.pos < end {
+                    let n = self.bytes(1)?[0] as usize;
+                    txt.extend_from_slice(self.bytes(n)?);
+                }
+                RData::Txt(String::from_utf8_lossy(&txt).into_owned())
+            }
+            _ => RData::Other,
+        };
+        self.pos = end;
+        Ok(Record { name, data })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(name: &str, data: RData) -> Record {
+        Record {
+            name: name.into(),
+            data,
+        }
+    }
+
+    /// A name server that is both the resolver and authoritative for all zones.
+    fn dns_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let localhost = RData::A(Ipv4Addr::LOCALHOST);
+        thread::spawn(move || {
+            let mut flaky = 0;
+            let mut buf = [0; 512];
+            while let Ok((n, from)) = socket.recv_from(&mut buf) {
+                let req = parse(&buf[..n]).unwrap();
+                let (name, qtype) = req.questions[0].clone();
+                let mut res = Message {
+                    id: req.id,
+                    flags: 0x8000,
+                    questions: req.questions.clone(),
+                    ..Default::default()
+                };
+                match (name.as_str(), qtype) {
+                    // fails the first queries
+                    ("flaky.test.", TYPE_NS) if flaky < 2 => {
+                        flaky += 1;
+                        res.flags |= 2;
+                    }
+                    ("flaky.test.", TYPE_NS) => {
+                        let ns = RData::Ns("ns1.example.com.".into());
+                        res.answers.push(record(&name, ns));
+                        res.additional
+                            .push(record("ns1.example.com.", localhost.clone()));
+                    }
+                    ("_acme-challenge.flaky.test.", TYPE_TXT) => {
+                        res.answers
+                            .push(record(&name, RData::Txt("expected".into())));
+                    }
+                    ("example.com.", TYPE_NS) => {
+                        let ns = RData::Ns("ns1.example.com.".into());
+                        res.answers.push(record(&name, ns));
+                        res.additional
+                            .push(record("ns1.example.com.", localhost.clone()));
+                    }
+                    ("delegated.test.", TYPE_NS) => {
+                        let ns = RData::Ns("ns.delegated.test.".into());
+                        res.answers.push(record(&name, ns));
+                    }
+                    ("ns.delegated.test.", TYPE_A) => {
+                        res.answers.push(record(&name, localhost.clone()));
+                    }
+                    ("_acme-challenge.example.com.", TYPE_TXT) => {
+                        let cname = RData::Cname("_acme-challenge.delegated.test.".into());
+                        res.answers.push(record(&name, cname));
+                    }
+                    ("_acme-challenge.delegated.test.", TYPE_TXT) => {
+                        res.answers.push(record(&name, RData::Txt("old".into())));
+                        res.answers
+                            .push(record(&name, RData::Txt("expected".into())));
+                    }
+                    ("_acme-challenge.stale.example.com.", TYPE_TXT) => {
+                        res.answers.push(record(&name, RData::Txt("old".into())));
+                    }
+                    _ => {}
+                }
+                socket.send_to(&encode(&res), from).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_compressed_name() -> Result<()> {
+        // example.com. followed by www pointing back at it.
+        let buf = b"\x07example\x03com\x00\x03www\xc0\x00";
+        let mut r = Reader { buf, pos: 0 };
+        assert_eq!(r.name()?, "example.com.");
+        assert_eq!(r.name()?, "www.example.com.");
+        assert_eq!(r.pos, buf.len());
+        let mut r = Reader {
+            buf: b"\xc0\x00",
+            pos: 0,
+        };
+        assert!(r.name().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_propagation() -> Result<()> {
+        let server = dns_server();
+        let mut opts = DnsPropagationOptions::new()
+            .with_resolver(server)
+            .with_poll_delay(Duration::from_millis(10))
+            .with_timeout(Duration::from_millis(100));
+        opts.port = server.port();
+
+        // through the CNAME
+        wait_for_propagation("_acme-challenge.Example.com", "expected", &opts)?;
+
+        match wait_for_propagation("_acme-challenge.stale.example.com.", "expected", &opts) {
+            Err(Error::Timeout { url, polls, status }) => {
+                assert_eq!(url, "_acme-challenge.stale.example.com.");
+                assert!(polls > 1);
+                assert_eq!(status, "served by 0 of 1 name servers");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let err = wait_for_propagation("_acme-challenge.example.org.", "expected", &opts);
+        let err = err.unwrap_err().to_string();
+        assert!(err.contains("No name servers found"), "{}", err);
+
+        // SERVFAIL from the resolver is polled again
+        wait_for_propagation("_acme-challenge.flaky.test.", "expected", &opts)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+//! Checking that a challenge is answered correctly, before the ACME API validates it.
+use openssl::sha::sha256;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::convert::TryFrom;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::api::ApiIdentifier;
+use crate::order::ACME_TLS_ALPN_PROTOCOL;
+use crate::{Error, Result};
+
+/// Like Let's Encrypt.
+const MAX_REDIRECTS: usize = 10;
+/// How much of an unexpected body is shown in the error.
+const BODY_PREFIX_LEN: usize = 64;
+/// Bodies are read up to this size.
+const MAX_BODY_LEN: u64 = 8192;
+/// Timeout of the tls-alpn connection.
+const TLS_TIMEOUT: Duration = Duration::from_secs(10);
+/// DER of the `acmeIdentifier` extension OID 1.3.6.1.5.5.7.1.31.
+const ACME_IDENTIFIER_OID_DER: &[u8] =
+    &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+
+/// Options of [`Challenge::self_check_http_with`].
+///
+/// [`Challenge::self_check_http_with`]: struct.Challenge.html#method.self_check_http_with
+#[derive(Debug, Clone)]
+pub struct HttpSelfCheckOptions {
+    max_redirects: usize,
+    address: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Default for HttpSelfCheckOptions {
+    fn default() -> Self {
+        HttpSelfCheckOptions {
+            max_redirects: MAX_REDIRECTS,
+            address: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl HttpSelfCheckOptions {
+    /// The default options: follow up to 10 redirects, with a timeout of 10 seconds per
+    /// request.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many redirects are followed.
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Connect to the address rather than resolving the domain, for when the public
+    /// address differs from what the domain resolves to here. It's also used for plain
+    /// http redirects to the same domain.
+    pub fn with_address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Timeout of connecting and reading each request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Fetch the token from the host (a domain or IP address) and check that the body is
+/// the key authorization.
+pub(crate) fn check_http(
+    host: &str,
+    token: &str,
+    key_auth: &str,
+    opts: &HttpSelfCheckOptions,
+) -> Result<()> {
+    let mut url = format!("http://{}/.well-known/acme-challenge/{}", host, token);
+    for _ in 0..=opts.max_redirects {
+        let (scheme, authority, path) = split_url(&url)?;
+        let mut req = match opts.address {
+            Some(addr) if scheme == "http" && authority.eq_ignore_ascii_case(host) => {
+                let mut req = ureq::get(&format!("http://{}{}", addr, path));
+                req.set("Host", host);
+                req
+            }
+            _ => ureq::get(&url),
+        };
+        let millis = opts.timeout.as_millis() as u64;
+        req.redirects(0)
+            .timeout_connect(millis)
+            .timeout_read(millis);
+        debug!("Self check: {}", url);
+        let res = req.call();
+        if let Some(e) = res.synthetic_error() {
+            return Err(Error::SelfCheckFailed(format!("{}: {}", url, e)));
+        }
+        let status = res.status();
+        if (300..400).contains(&status) {
+            if let Some(location) = res.header("location") {
+                url = join_url(&url, location);
+                continue;
+            }
+        }
+        let mut body = String::new();
+        let read = res
+            .into_reader()
+            .take(MAX_BODY_LEN)
+            .read_to_string(&mut body);
+        if status == 200 && read.is_ok() && body.trim_end() == key_auth {
+            return Ok(());
+        }
+        let prefix: String = body.chars().take(BODY_PREFIX_LEN).collect();
+        return Err(Error::SelfCheckFailed(format!(
+            "{} answered status {} with body {:?}, expected {:?}",
+            url, status, prefix, key_auth
+        )));
+    }
+    Err(Error::SelfCheckFailed(format!(
+        "More than {} redirects, last to: {}",
+        opts.max_redirects, url
+    )))
+}
+
+/// Scheme, authority and path (with the query) of an absolute URL.
+fn split_url(url: &str) -> Result<(&str, &str, &str)> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| Error::SelfCheckFailed(format!("Bad redirect URL: {}", url)))?;
+    let at = rest.find('/').unwrap_or(rest.len());
+    let path = if at == rest.len() { "/" } else { &rest[at..] };
+    Ok((scheme, &rest[..at], path))
+}
+
+/// The redirect `location` relative to the URL.
+fn join_url(url: &str, location: &str) -> String {
+    if location.contains("://") {
+        return location.to_string();
+    }
+    let (scheme, authority, path) = split_url(url).expect("absolute url");
+    if let Some(rest) = location.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let path = path.split('?').next().unwrap_or(path);
+        let dir = &path[..=path.rfind('/').unwrap_or(0)];
+        format!("{}://{}{}{}", scheme, authority, dir, location)
+    }
+}
+
+/// Connect like the ACME API validating a tls-alpn challenge, and check the certificate
+/// presented. Connects to port 443 of the identifier unless given an address.
+pub(crate) fn check_tls_alpn(
+    identifier: &ApiIdentifier,
+    addr: Option<SocketAddr>,
+    key_auth: &str,
+) -> Result<()> {
+    let failed = |s: String| Error::SelfCheckFailed(s);
+    let addr = match addr {
+        Some(addr) => addr,
+        None => (identifier.value.as_str(), 443)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| failed(format!("No address for: {}", identifier.value)))?,
+    };
+    let stream = TcpStream::connect_timeout(&addr, TLS_TIMEOUT)?;
+    stream.set_read_timeout(Some(TLS_TIMEOUT))?;
+    stream.set_write_timeout(Some(TLS_TIMEOUT))?;
+
+    // the certificate is self-signed, only the extension matters.
+    let mut bld = SslConnector::builder(SslMethod::tls_client()).expect("SslConnector");
+    bld.set_verify(SslVerifyMode::NONE);
+    let mut protos = vec![ACME_TLS_ALPN_PROTOCOL.len() as u8];
+    protos.extend_from_slice(ACME_TLS_ALPN_PROTOCOL);
+    bld.set_alpn_protos(&protos).expect("set_alpn_protos");
+    let mut config = bld.build().configure().expect("configure");
+    config.set_verify_hostname(false);
+
+    let sni = server_name(identifier);
+    let tls = config
+        .connect(&sni, stream)
+        .map_err(|e| failed(format!("TLS handshake with {} failed: {}", addr, e)))?;
+    let ssl = tls.ssl();
+    let alpn = ssl
+        .selected_alpn_protocol()
+        .map(|p| String::from_utf8_lossy(p).into_owned());
+    let cert = ssl
+        .peer_certificate()
+        .ok_or_else(|| failed(format!("{} presented no certificate", addr)))?;
+    let sans: Vec<String> = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|n| match (n.dnsname(), n.ipaddress()) {
+                    (Some(dns), _) => Some(dns.to_string()),
+                    (_, Some(ip)) => ip_from_bytes(ip).map(|ip| ip.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut expected = vec![0x04, 0x20];
+    expected.extend_from_slice(&sha256(key_auth.as_bytes()));
+    let der = cert.to_der().expect("to_der");
+    let reason = if alpn.as_deref().map(str::as_bytes) != Some(ACME_TLS_ALPN_PROTOCOL) {
+        "without the acme-tls/1 protocol"
+    } else if !sans
+        .iter()
+        .any(|n| n.eq_ignore_ascii_case(&identifier.value))
+    {
+        "without the identifier"
+    } else if !has_acme_identifier(&der, &expected) {
+        "without the expected acmeIdentifier"
+    } else {
+        return Ok(());
+    };
+    Err(failed(format!(
+        "{} negotiated ALPN {:?} and presented a certificate for {:?}, {}",
+        addr, alpn, sans, reason
+    )))
+}
+
+/// The SNI of the connection. IP addresses use the reverse mapping name of RFC 8738.
+fn server_name(identifier: &ApiIdentifier) -> String {
+    match identifier.value.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let mut labels = vec![];
+            for b in ip.octets().iter().rev() {
+                labels.push(format!("{:x}.{:x}", b & 0xf, b >> 4));
+            }
+            format!("{}.ip6.arpa", labels.join("."))
+        }
+        Err(_) => identifier.value.clone(),
+    }
+}
+
+fn ip_from_bytes(b: &[u8]) -> Option<IpAddr> {
+    match b.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(b).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(b).ok()?)),
+        _ => None,
+    }
+}
+
+/// Whether the certificate DER has the `acmeIdentifier` extension with the value, the
+/// DER of the digest as an OCTET STRING.
+fn has_acme_identifier(der: &[u8], expected: &[u8]) -> bool {
+    let at = match der
+        .windows(ACME_IDENTIFIER_OID_DER.len())
+        .position(|w| w == ACME_IDENTIFIER_OID_DER)
+    {
+        Some(at) => at + ACME_IDENTIFIER_OID_DER.len(),
+        None => return false,
+    };
+    let mut rest = &der[at..];
+    // the critical flag
+    if rest.starts_with(&[0x01, 0x01]) && rest.len() > 3 {
+        rest = &rest[3..];
+    }
+    // the extnValue OCTET STRING wraps the value.
+    rest.len() > 2
+        && rest[0] == 0x04
+        && rest[1] as usize == expected.len()
+        && rest[2..].starts_with(expected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::cert::{create_p256_key, create_tls_alpn_cert};
+
+    /// Answers requests by path with `(status, location, body)`.
+    fn http_server(routes: Vec<(&'static str, u16, &'static str, &'static str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut line).unwrap();
+                let mut host = String::new();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(h) = header.strip_prefix("Host: ") {
+                        host = h.trim().to_string();
+                    }
+                }
+                let path = line.split(' ').nth(1).unwrap_or("");
+                let (status, location, body) = routes
+                    .iter()
+                    .find(|r| r.0 == path && host == "example.com")
+                    .map(|r| (r.1, r.2, r.3))
+                    .unwrap_or((404, "", "not found"));
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nLocation: {}\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    status,
+                    location,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_join_url() {
+        let url = "http://a.com/.well-known/acme-challenge/tok";
+        assert_eq!(join_url(url, "https://b.com/x"), "https://b.com/x");
+        assert_eq!(join_url(url, "//b.com/x"), "http://b.com/x");
+        assert_eq!(join_url(url, "/x?y"), "http://a.com/x?y");
+        assert_eq!(
+            join_url(url, "other"),
+            "http://a.com/.well-known/acme-challenge/other"
+        );
+    }
+
+    #[test]
+    fn test_check_http() {
+        let addr = http_server(vec![
+            ("/.well-known/acme-challenge/tok", 301, "/moved", ""),
+            ("/moved", 200, "", "tok.key\r\n"),
+            ("/.well-known/acme-challenge/loop", 302, "loop", ""),
+            (
+                "/.well-known/acme-challenge/bad",
+                200,
+                "",
+                "<html>stripped</html>",
+            ),
+        ]);
+        let opts = HttpSelfCheckOptions::new().with_address(addr);
+
+        check_http("example.com", "tok", "tok.key", &opts).unwrap();
+
+        let err = check_http("example.com", "bad", "bad.key", &opts).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("status 200"), "{}", err);
+        assert!(err.contains("<html>stripped</html>"), "{}", err);
+
+        let err = check_http("example.com", "missing", "missing.key", &opts).unwrap_err();
+        assert!(err.to_string().contains("status 404"), "{}", err);
+
+        let err = check_http("example.com", "loop", "loop.key", &opts).unwrap_err();
+        assert!(
+            err.to_string().contains("More than 10 redirects"),
+            "{}",
+            err
+        );
+    }
+
+    /// A TLS server presenting a certificate for the identifier and key authorization,
+    /// selecting the protocol if offered.
+    fn tls_server(identifier: &ApiIdentifier, key_auth: &str, alpn: &'static [u8]) -> SocketAddr {
+        use openssl::ssl::{AlpnError, SslAcceptor};
+        let (pkey, _) = create_p256_key();
+        let cert = create_tls_alpn_cert(&pkey, identifier, key_auth).unwrap();
+        let mut bld = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        bld.set_private_key(&pkey).unwrap();
+        bld.set_certificate(&cert).unwrap();
+        bld.set_alpn_select_callback(move |_, client| {
+            openssl::ssl::select_next_proto(alpn, client).ok_or(AlpnError::NOACK)
+        });
+        let acceptor = bld.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = acceptor.accept(stream.unwrap());
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_check_tls_alpn() {
+        let id = ApiIdentifier {
+            _type: "dns".into(),
+            value: "example.com".into(),
+        };
+        let addr = tls_server(&id, "tok.key", b"\x0aacme-tls/1");
+        check_tls_alpn(&id, Some(addr), "tok.key").unwrap();
+
+        let err = check_tls_alpn(&id, Some(addr), "other.key").unwrap_err();
+        let err = err.to_string();
+        assert!(
+            err.contains("without the expected acmeIdentifier"),
+            "{}",
+            err
+        );
+        assert!(err.contains(r#"["example.com"]"#), "{}", err);
+
+        let other = ApiIdentifier {
+            _type: "dns".into(),
+            value: "other.com".into(),
+        };
+        let err = check_tls_alpn(&other, Some(addr), "tok.key").unwrap_err();
+        assert!(
+            err.to_string().contains("without the identifier"),
+            "{}",
+            err
+        );
+
+        let addr = tls_server(&id, "tok.key", b"\x08http/1.1");
+        let err = check_tls_alpn(&id, Some(addr), "tok.key").unwrap_err();
+        assert!(err.to_string().contains("ALPN None"), "{}", err);
+    }
+
+    #[test]
+    fn test_server_name() {
+        let id = |t: &str, v: &str| ApiIdentifier {
+            _type: t.into(),
+            value: v.into(),
+        };
+        assert_eq!(server_name(&id("dns", "example.com")), "example.com");
+        assert_eq!(
+            server_name(&id("ip", "192.0.2.1")),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert!(server_name(&id("ip", "2001:db8::1")).starts_with(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        ));
+    }
+}
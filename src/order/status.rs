@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use crate::api::ApiProblem;
+
+/// The status of a challenge, as found by [`Challenge::poll`].
+///
+/// [`Challenge::poll`]: struct.Challenge.html#method.poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChallengeStatus {
+    /// The challenge is waiting to be validated.
+    Pending,
+    /// The ACME API is validating the challenge.
+    Processing {
+        /// How long the ACME API asks to wait before polling again, if it did.
+        retry_after: Option<Duration>,
+    },
+    /// The challenge is validated.
+    Valid,
+    /// Validating the challenge failed.
+    Invalid(ApiProblem),
+}
+
+/// The status of an order, as found by [`NewOrder::poll`].
+///
+/// [`NewOrder::poll`]: struct.NewOrder.html#method.poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderStatus {
+    /// Some authorizations of the order are not yet valid.
+    Pending,
+    /// All authorizations are valid, the order can be finalized.
+    Ready,
+    /// The order is finalized and the certificate is being issued.
+    Processing {
+        /// How long the ACME API asks to wait before polling again, if it did.
+        retry_after: Option<Duration>,
+    },
+    /// The certificate is issued.
+    Valid,
+    /// The order can't be used anymore, for example if an authorization failed.
+    Invalid(ApiProblem),
+}
+
+/// The problem of an invalid object, or a generic one if the ACME API didn't give any.
+pub(crate) fn invalid_problem(error: Option<&ApiProblem>, url: &str) -> ApiProblem {
+    error.cloned().unwrap_or_else(|| ApiProblem {
+        _type: "about:blank".into(),
+        detail: Some(format!("Invalid without a reason: {}", url)),
+        subproblems: None,
+        status: None,
+    })
+}
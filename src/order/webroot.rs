@@ -0,0 +1,134 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// The directories from the webroot to the http challenge files.
+const CHALLENGE_DIRS: &[&str] = &[".well-known", "acme-challenge"];
+
+/// An http challenge file written by [`Challenge::write_http_token`], deleted when
+/// the guard is dropped.
+///
+/// The directories are left in place.
+///
+/// [`Challenge::write_http_token`]: struct.Challenge.html#method.write_http_token
+#[derive(Debug)]
+pub struct TokenGuard {
+    path: Option<PathBuf>,
+}
+
+impl TokenGuard {
+    /// Path of the file.
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().expect("path")
+    }
+
+    /// Keep the file when dropping the guard, returning its path.
+    pub fn persist(mut self) -> PathBuf {
+        self.path.take().expect("path")
+    }
+}
+
+impl Drop for TokenGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            debug!("Remove http challenge file: {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Write the file `<webroot>/.well-known/acme-challenge/<token>`, readable by anyone.
+///
+/// Symlinks below the webroot are refused, so the file can't end up outside of it.
+pub(crate) fn write_token_file(webroot: &Path, token: &str, content: &str) -> Result<TokenGuard> {
+    if token.is_empty() || token.contains(['/', '\\']) || token == ".." {
+        return Err(format!("Unexpected http challenge token: {:?}", token).into());
+    }
+    let mut path = webroot.to_path_buf();
+    for dir in CHALLENGE_DIRS {
+        path.push(dir);
+        match fs::symlink_metadata(&path) {
+            Ok(m) if m.file_type().is_dir() => {}
+            Ok(_) => return Err(format!("Not a directory: {}", path.display()).into()),
+            Err(_) => {
+                debug!("Create directory: {}", path.display());
+                fs::create_dir(&path)?;
+                set_mode(&path, 0o755)?;
+            }
+        }
+    }
+    path.push(token);
+    if let Ok(m) = fs::symlink_metadata(&path) {
+        if !m.file_type().is_file() {
+            return Err(format!("Not a file: {}", path.display()).into());
+        }
+    }
+
+    debug!("Write http challenge file: {}", path.display());
+    let mut file = fs::File::create(&path)?;
+    file.write_all(content.as_bytes())?;
+    set_mode(&path, 0o644)?;
+    Ok(TokenGuard { path: Some(path) })
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::temp_dir;
+
+    #[test]
+    fn test_write_token_file() -> Result<()> {
+        let webroot = temp_dir("webroot");
+        let guard = write_token_file(&webroot, "tok", "tok.thumb")?;
+        let path = webroot.join(".well-known/acme-challenge/tok");
+        assert_eq!(guard.path(), path);
+        assert_eq!(fs::read_to_string(&path)?, "tok.thumb");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o644);
+        }
+        drop(guard);
+        assert!(!path.exists());
+
+        // the directories exist now
+        let guard = write_token_file(&webroot, "tok2", "tok2.thumb")?;
+        let path = guard.persist();
+        assert!(path.exists());
+
+        assert!(write_token_file(&webroot, "../tok", "x").is_err());
+        fs::remove_dir_all(&webroot)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_token_file_symlink() -> Result<()> {
+        let webroot = temp_dir("webroot-symlink");
+        let outside = temp_dir("outside");
+        std::os::unix::fs::symlink(&outside, webroot.join(".well-known"))?;
+        let err = write_token_file(&webroot, "tok", "tok.thumb").unwrap_err();
+        assert!(err.to_string().contains("Not a directory"), "{}", err);
+        assert_eq!(fs::read_dir(&outside)?.count(), 0);
+        fs::remove_dir_all(&webroot)?;
+        fs::remove_dir_all(&outside)?;
+        Ok(())
+    }
+}
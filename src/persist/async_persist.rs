@@ -0,0 +1,321 @@
+//! Async persistence, and the adapter to use it from the blocking API.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use super::PersistResult;
+use super::{Persist, PersistKey, PersistKeyBuf, PersistKind};
+
+/// The future returned by the methods of [`AsyncPersist`].
+///
+/// [`AsyncPersist`]: trait.AsyncPersist.html
+pub type PersistFuture<'a, T> = Pin<Box<dyn Future<Output = PersistResult<T>> + Send + 'a>>;
+
+/// A future run by [`BlockingPersist`] for each call to the persistence.
+///
+/// [`BlockingPersist`]: struct.BlockingPersist.html
+pub type PersistJob = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Async variant of [`Persist`], for storage only reachable through an async client.
+///
+/// The blocking API of acme-lib uses it through a [`BlockingPersist`]. A [`Persist`] is
+/// used as an `AsyncPersist` by wrapping it in an [`AsyncWrapper`].
+///
+/// [`Persist`]: trait.Persist.html
+/// [`BlockingPersist`]: struct.BlockingPersist.html
+/// [`AsyncWrapper`]: struct.AsyncWrapper.html
+pub trait AsyncPersist: Send + Sync + 'static {
+    /// Store the given bytes under the given key.
+    fn put<'a>(&'a self, key: &'a PersistKey<'a>, value: &'a [u8]) -> PersistFuture<'a, ()>;
+    /// Read the bytes stored under the given key.
+    ///
+    /// `None` if the value doesn't exist.
+    fn get<'a>(&'a self, key: &'a PersistKey<'a>) -> PersistFuture<'a, Option<Vec<u8>>>;
+    /// Remove the value stored under the given key, `false` if there was none.
+    ///
+    /// Like [`Persist::delete`], the default implementation fails.
+    ///
+    /// [`Persist::delete`]: trait.Persist.html#method.delete
+    fn delete<'a>(&'a self, key: &'a PersistKey<'a>) -> PersistFuture<'a, bool> {
+        let err = format!("Delete is not supported by this persistence: {}", key);
+        Box::pin(async move { Err(err.into()) })
+    }
+    /// The keys stored under the realm, of the kind or of all kinds.
+    ///
+    /// Like [`Persist::list`], the default implementation fails.
+    ///
+    /// [`Persist::list`]: trait.Persist.html#method.list
+    fn list<'a>(
+        &'a self,
+        realm: &'a str,
+        kind: Option<PersistKind>,
+    ) -> PersistFuture<'a, Vec<PersistKeyBuf>> {
+        let _ = (realm, kind);
+        Box::pin(async { Err("List is not supported by this persistence".into()) })
+    }
+}
+
+/// Use a [`Persist`], such as `MemoryPersist` or `FilePersist`, as an [`AsyncPersist`].
+///
+/// The work is done when the future is created. For `FilePersist` that means blocking
+/// file io.
+///
+/// [`Persist`]: trait.Persist.html
+/// [`AsyncPersist`]: trait.AsyncPersist.html
+#[derive(Clone, Debug)]
+pub struct AsyncWrapper<P>(pub P);
+
+impl<P: Persist + Sync + 'static> AsyncPersist for AsyncWrapper<P> {
+    fn put<'a>(&'a self, key: &'a PersistKey<'a>, value: &'a [u8]) -> PersistFuture<'a, ()> {
+        let res = self.0.put(key, value);
+        Box::pin(async { res })
+    }
+    fn get<'a>(&'a self, key: &'a PersistKey<'a>) -> PersistFuture<'a, Option<Vec<u8>>> {
+        let res = self.0.get(key);
+        Box::pin(async { res })
+    }
+    fn delete<'a>(&'a self, key: &'a PersistKey<'a>) -> PersistFuture<'a, bool> {
+        let res = self.0.delete(key);
+        Box::pin(async { res })
+    }
+    fn list<'a>(
+        &'a self,
+        realm: &'a str,
+        kind: Option<PersistKind>,
+    ) -> PersistFuture<'a, Vec<PersistKeyBuf>> {
+        let res = self.0.list(realm, kind);
+        Box::pin(async { res })
+    }
+}
+
+/// Runs an [`AsyncPersist`] for the blocking API.
+///
+/// Each call creates a [`PersistJob`] and waits for it to complete. By default the jobs
+/// run on a dedicated thread, which is enough for futures that don't need a runtime.
+/// Clients tied to a runtime, like most tokio database clients, need the jobs spawned on
+/// that runtime using [`with_spawner`].
+///
+/// The blocking calls must not be made on a thread the runtime needs to make progress,
+/// such as the only thread of a tokio current thread runtime. Use `spawn_blocking` to call
+/// acme-lib from async code.
+///
+/// ```no_run
+/// # fn main() -> acme_lib::Result<()> {
+/// use acme_lib::persist::{AsyncWrapper, BlockingPersist, MemoryPersist};
+/// use acme_lib::{Directory, DirectoryUrl};
+///
+/// // Any AsyncPersist, spawned using something like `move |job| { handle.spawn(job); }`.
+/// let async_persist = AsyncWrapper(MemoryPersist::new());
+/// let persist = BlockingPersist::with_spawner(async_persist, |job| {
+///     std::thread::spawn(move || run_job(job));
+/// });
+/// let dir = Directory::from_url(persist, DirectoryUrl::LetsEncryptStaging)?;
+/// # Ok(())
+/// # }
+/// # fn run_job(_job: acme_lib::persist::PersistJob) {}
+/// ```
+///
+/// [`AsyncPersist`]: trait.AsyncPersist.html
+/// [`PersistJob`]: type.PersistJob.html
+/// [`with_spawner`]: struct.BlockingPersist.html#method.with_spawner
+pub struct BlockingPersist<A> {
+    persist: Arc<A>,
+    spawner: Arc<dyn Fn(PersistJob) + Send + Sync>,
+}
+
+impl<A> Clone for BlockingPersist<A> {
+    fn clone(&self) -> Self {
+        BlockingPersist {
+            persist: self.persist.clone(),
+            spawner: self.spawner.clone(),
+        }
+    }
+}
+
+impl<A: AsyncPersist> BlockingPersist<A> {
+    /// Run the persistence on a dedicated thread, which stops when the last clone is dropped.
+    pub fn new(persist: A) -> Self {
+        let (tx, rx) = mpsc::channel::<PersistJob>();
+        thread::Builder::new()
+            .name("acme-lib-persist".into())
+            .spawn(move || {
+                for job in rx {
+                    block_on(job);
+                }
+            })
+            .expect("Failed to start persistence thread");
+        let tx = Mutex::new(tx);
+        Self::with_spawner(persist, move |job| {
+            // The thread only stops when the sender is dropped.
+            let _ = tx.lock().unwrap().send(job);
+        })
+    }
+
+    /// Run the persistence using the spawner, such as `tokio::runtime::Handle::spawn`.
+    ///
+    /// The spawner must run the job to completion.
+    pub fn with_spawner<S>(persist: A, spawner: S) -> Self
+    where
+        S: Fn(PersistJob) + Send + Sync + 'static,
+    {
+        BlockingPersist {
+            persist: Arc::new(persist),
+            spawner: Arc::new(spawner),
+        }
+    }
+
+    /// The wrapped async persistence.
+    pub fn inner(&self) -> &A {
+        &self.persist
+    }
+
+    /// Spawn a job calling the persistence and wait for the result.
+    fn run<T, F>(&self, call: F) -> PersistResult<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a A) -> PersistFuture<'a, T> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let persist = self.persist.clone();
+        (self.spawner)(Box::pin(async move {
+            let res = call(&persist).await;
+            let _ = tx.send(res);
+        }));
+        rx.recv()
+            .map_err(|_| "Async persistence dropped the call without a result".to_string())?
+    }
+}
+
+impl<A: AsyncPersist> Persist for BlockingPersist<A> {
+    fn put(&self, key: &PersistKey, value: &[u8]) -> PersistResult<()> {
+        let key = PersistKeyBuf::from(*key);
+        let value = value.to_vec();
+        self.run(move |p| Box::pin(async move { p.put(&key.as_key(), &value).await }))
+    }
+    fn get(&self, key: &PersistKey) -> PersistResult<Option<Vec<u8>>> {
+        let key = PersistKeyBuf::from(*key);
+        self.run(move |p| Box::pin(async move { p.get(&key.as_key()).await }))
+    }
+    fn delete(&self, key: &PersistKey) -> PersistResult<bool> {
+        let key = PersistKeyBuf::from(*key);
+        self.run(move |p| Box::pin(async move { p.delete(&key.as_key()).await }))
+    }
+    fn list(&self, realm: &str, kind: Option<PersistKind>) -> PersistResult<Vec<PersistKeyBuf>> {
+        let realm = realm.to_string();
+        self.run(move |p| Box::pin(async move { p.list(&realm, kind).await }))
+    }
+}
+
+/// Wakes the thread polling a future.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Poll the future on the current thread until it completes.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+    use crate::{Directory, DirectoryUrl};
+
+    /// Yields once before completing, like a future waiting on io.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// An async persistence that only implements `put` and `get`.
+    struct SlowPersist(MemoryPersist);
+
+    impl AsyncPersist for SlowPersist {
+        fn put<'a>(&'a self, key: &'a PersistKey<'a>, value: &'a [u8]) -> PersistFuture<'a, ()> {
+            Box::pin(async move {
+                YieldOnce(false).await;
+                Persist::put(&self.0, key, value)
+            })
+        }
+        fn get<'a>(&'a self, key: &'a PersistKey<'a>) -> PersistFuture<'a, Option<Vec<u8>>> {
+            Box::pin(async move {
+                YieldOnce(false).await;
+                Persist::get(&self.0, key)
+            })
+        }
+    }
+
+    #[test]
+    fn test_blocking_persist() -> PersistResult<()> {
+        let key = PersistKey::new("realm", PersistKind::Certificate, "example.com");
+        let spawned = BlockingPersist::with_spawner(SlowPersist(MemoryPersist::new()), |job| {
+            thread::spawn(move || block_on(job));
+        });
+        for persist in &[
+            BlockingPersist::new(SlowPersist(MemoryPersist::new())),
+            spawned,
+        ] {
+            persist.put(&key, b"cert")?;
+            assert_eq!(persist.get(&key)?, Some(b"cert".to_vec()));
+            let err = persist.delete(&key).unwrap_err().to_string();
+            assert!(err.contains("not supported"), "{}", err);
+            assert!(persist.list("realm", None).is_err());
+        }
+
+        // A wrapped Persist does the work when the future is created.
+        let memory = MemoryPersist::new();
+        let persist = BlockingPersist::new(AsyncWrapper(memory.clone()));
+        persist.put(&key, b"cert")?;
+        assert_eq!(memory.get(&key)?, Some(b"cert".to_vec()));
+        assert_eq!(persist.list("realm", None)?.len(), 1);
+        assert!(persist.delete(&key)?);
+        assert_eq!(block_on(persist.inner().get(&key))?, None);
+
+        let dropped = BlockingPersist::with_spawner(AsyncWrapper(MemoryPersist::new()), drop);
+        assert!(dropped.get(&key).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocking_persist_account() -> PersistResult<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let memory = MemoryPersist::new();
+        let persist = BlockingPersist::new(SlowPersist(memory.clone()));
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let again = dir.account("foo@bar.com")?;
+        assert_eq!(acc.acme_private_key_pem()?, again.acme_private_key_pem()?);
+        // Saved in the wrapped persistence.
+        let dir = Directory::from_url(memory, DirectoryUrl::Other(&server.dir_url))?;
+        let same = dir.account("foo@bar.com")?;
+        assert_eq!(acc.acme_private_key_pem()?, same.acme_private_key_pem()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use super::{
+    create_dir, write_atomic, FilePersist, Persist, PersistKey, PersistKeyBuf, PersistKind,
+    PersistResult,
+};
+
+const PRIVKEY: &str = "privkey";
+const CERT: &str = "cert";
+const CHAIN: &str = "chain";
+const FULLCHAIN: &str = "fullchain";
+const ITEMS: [&str; 4] = [PRIVKEY, CERT, CHAIN, FULLCHAIN];
+
+/// File persistence in the layout of certbot, such as under `/etc/letsencrypt`.
+///
+/// Certificates and their private keys are kept like certbot keeps its lineages:
+///
+/// * `live/<name>/privkey.pem` is the private key.
+/// * `live/<name>/fullchain.pem` is the certificate with its chain, and `cert.pem` and
+///   `chain.pem` are the certificate and the chain apart.
+/// * The files in `live/<name>` are symlinks to numbered versions in `archive/<name>`,
+///   a new version for each write.
+///
+/// The name is the primary name of the certificate, which means certificates already
+/// issued by certbot are found by [`Account::certificate`]. Certbot has no realms, so
+/// certificates are shared between all accounts using the persistence. Certbot's
+/// `renewal/<name>.conf` is neither read nor written.
+///
+/// Everything else, such as the account key, is saved like [`FilePersist`] does in
+/// `accounts/acme-lib`. Accounts created by certbot are not read.
+///
+/// Only available on Unix, since it relies on symlinks.
+///
+/// [`Account::certificate`]: ../struct.Account.html#method.certificate
+/// [`FilePersist`]: struct.FilePersist.html
+#[derive(Clone)]
+pub struct CertbotLayoutPersist {
+    root: PathBuf,
+    accounts: FilePersist,
+    accounts_dir: PathBuf,
+}
+
+impl CertbotLayoutPersist {
+    /// Create a persistence in the certbot config directory `root`, like `/etc/letsencrypt`.
+    ///
+    /// Directories are created when written to.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        let root = root.as_ref().to_path_buf();
+        let accounts_dir = root.join("accounts").join("acme-lib");
+        CertbotLayoutPersist {
+            accounts: FilePersist::new(&accounts_dir),
+            accounts_dir,
+            root,
+        }
+    }
+
+    fn lineage(&self, name: &str) -> PersistResult<Lineage> {
+        let valid = !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', '\0']);
+        if !valid {
+            return Err(format!("Not usable as certbot lineage name: {:?}", name).into());
+        }
+        Ok(Lineage {
+            name: name.to_string(),
+            live: self.root.join("live").join(name),
+            archive: self.root.join("archive").join(name),
+        })
+    }
+
+    fn list_lineages(&self, realm: &str, kind: PersistKind) -> PersistResult<Vec<PersistKeyBuf>> {
+        let item = if kind == PersistKind::PrivateKey {
+            PRIVKEY
+        } else {
+            FULLCHAIN
+        };
+        let realm = PersistKey::hash_realm(realm);
+        let entries = match fs::read_dir(self.root.join("live")) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut keys = vec![];
+        for entry in entries {
+            let entry = entry?;
+            if !entry.path().join(format!("{}.pem", item)).exists() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                keys.push(PersistKeyBuf {
+                    realm,
+                    kind,
+                    key: name.to_string(),
+                });
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// The directories of a certificate.
+struct Lineage {
+    name: String,
+    live: PathBuf,
+    archive: PathBuf,
+}
+
+impl Lineage {
+    fn live_file(&self, item: &str) -> PathBuf {
+        self.live.join(format!("{}.pem", item))
+    }
+
+    fn archive_file(&self, item: &str, version: u32) -> PathBuf {
+        self.archive.join(format!("{}{}.pem", item, version))
+    }
+
+    fn read(&self, item: &str) -> PersistResult<Option<Vec<u8>>> {
+        match fs::read(self.live_file(item)) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The version the live file links to.
+    fn current_version(&self, item: &str) -> Option<u32> {
+        let target = fs::read_link(self.live_file(item)).ok()?;
+        let file_name = target.file_name()?.to_str()?;
+        parse_version(file_name, item)
+    }
+
+    /// The version after the highest in the archive.
+    fn next_version(&self) -> PersistResult<u32> {
+        let entries = match fs::read_dir(&self.archive) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(1),
+            Err(e) => return Err(e.into()),
+        };
+        let mut max = 0;
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_str().unwrap_or("");
+            for item in &ITEMS {
+                if let Some(v) = parse_version(file_name, item) {
+                    max = max.max(v);
+                }
+            }
+        }
+        Ok(max + 1)
+    }
+
+    /// Write a new version of the item and point the live file to it.
+    fn write(&self, item: &str, version: u32, value: &[u8]) -> PersistResult<()> {
+        for dir in &[&self.live, &self.archive] {
+            create_dir(dir)?;
+        }
+        write_atomic(&self.archive_file(item, version), value, item == PRIVKEY)?;
+        // Relative like certbot, so the tree can be moved.
+        let target = Path::new("../../archive")
+            .join(&self.name)
+            .join(format!("{}{}.pem", item, version));
+        let tmp = self.live.join(format!(".{}.pem.tmp", item));
+        let _ = fs::remove_file(&tmp);
+        symlink(target, &tmp)?;
+        fs::rename(&tmp, self.live_file(item))?;
+        Ok(())
+    }
+
+    fn put_private_key(&self, value: &[u8]) -> PersistResult<()> {
+        self.write(PRIVKEY, self.next_version()?, value)
+    }
+
+    fn put_certificate(&self, value: &[u8]) -> PersistResult<()> {
+        let (cert, chain) = split_chain(value);
+        // The private key is usually written just before with a new version.
+        let key_version = self.current_version(PRIVKEY);
+        let version = match key_version {
+            Some(v) if !self.archive_file(FULLCHAIN, v).exists() => v,
+            _ => self.next_version()?,
+        };
+        if let Some(v) = key_version.filter(|v| *v != version) {
+            // Reused key, which certbot copies to the new version.
+            let key = fs::read(self.archive_file(PRIVKEY, v))?;
+            self.write(PRIVKEY, version, &key)?;
+        }
+        self.write(CERT, version, cert)?;
+        self.write(CHAIN, version, chain)?;
+        self.write(FULLCHAIN, version, value)?;
+        Ok(())
+    }
+
+    /// Remove the live files, and the lineage once none are left.
+    fn delete(&self, items: &[&str]) -> PersistResult<bool> {
+        let mut removed = false;
+        for item in items {
+            match fs::remove_file(self.live_file(item)) {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let in_use = ITEMS
+            .iter()
+            .any(|i| fs::symlink_metadata(self.live_file(i)).is_ok());
+        if removed && !in_use {
+            let _ = fs::remove_dir_all(&self.live);
+            let _ = fs::remove_dir_all(&self.archive);
+        }
+        Ok(removed)
+    }
+}
+
+/// The version of an archive file name like `privkey3.pem`.
+fn parse_version(file_name: &str, item: &str) -> Option<u32> {
+    let version = file_name.strip_prefix(item)?.strip_suffix(".pem")?;
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    version.parse().ok()
+}
+
+/// Split a PEM chain into the first certificate and the rest.
+fn split_chain(pem: &[u8]) -> (&[u8], &[u8]) {
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let end = match pem.windows(END.len()).position(|w| w == END) {
+        Some(pos) => pos + END.len(),
+        None => return (pem, &[]),
+    };
+    // the line breaks after the certificate are part of it
+    let mut split = end;
+    while split < pem.len() && (pem[split] == b'\r' || pem[split] == b'\n') {
+        split += 1;
+    }
+    pem.split_at(split)
+}
+
+impl Persist for CertbotLayoutPersist {
+    fn put(&self, key: &PersistKey, value: &[u8]) -> PersistResult<()> {
+        match key.kind {
+            PersistKind::PrivateKey => self.lineage(key.key)?.put_private_key(value),
+            PersistKind::Certificate => self.lineage(key.key)?.put_certificate(value),
+            _ => self.accounts.put(key, value),
+        }
+    }
+    fn get(&self, key: &PersistKey) -> PersistResult<Option<Vec<u8>>> {
+        match key.kind {
+            PersistKind::PrivateKey => self.lineage(key.key)?.read(PRIVKEY),
+            PersistKind::Certificate => self.lineage(key.key)?.read(FULLCHAIN),
+            _ => self.accounts.get(key),
+        }
+    }
+    fn delete(&self, key: &PersistKey) -> PersistResult<bool> {
+        match key.kind {
+            PersistKind::PrivateKey => self.lineage(key.key)?.delete(&[PRIVKEY]),
+            PersistKind::Certificate => self.lineage(key.key)?.delete(&[CERT, CHAIN, FULLCHAIN]),
+            _ => self.accounts.delete(key),
+        }
+    }
+    fn list(&self, realm: &str, kind: Option<PersistKind>) -> PersistResult<Vec<PersistKeyBuf>> {
+        let mut keys = vec![];
+        for k in &[PersistKind::Certificate, PersistKind::PrivateKey] {
+            if kind.map(|kind| kind == *k).unwrap_or(true) {
+                keys.extend(self.list_lineages(realm, *k)?);
+            }
+        }
+        let other = kind
+            .map(|k| k != PersistKind::Certificate && k != PersistKind::PrivateKey)
+            .unwrap_or(true);
+        if other && self.accounts_dir.exists() {
+            keys.extend(self.accounts.list(realm, kind)?.into_iter().filter(|k| {
+                k.kind != PersistKind::Certificate && k.kind != PersistKind::PrivateKey
+            }));
+        }
+        super::sort_keys(&mut keys);
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{test_intermediate_pem, TEST_CERT_KEY_PEM, TEST_CERT_PEM};
+    use crate::{Directory, DirectoryUrl};
+
+    /// A lineage like certbot leaves it after a renewal.
+    fn certbot_dir(name: &str, fullchain: &str) -> PersistResult<PathBuf> {
+        let root = crate::test::temp_dir("persist-certbot");
+        let archive = root.join("archive").join(name);
+        let live = root.join("live").join(name);
+        fs::create_dir_all(&archive)?;
+        fs::create_dir_all(&live)?;
+        let (cert, chain) = split_chain(fullchain.as_bytes());
+        for v in 1..=2 {
+            fs::write(archive.join(format!("privkey{}.pem", v)), TEST_CERT_KEY_PEM)?;
+            fs::write(archive.join(format!("cert{}.pem", v)), cert)?;
+            fs::write(archive.join(format!("chain{}.pem", v)), chain)?;
+            fs::write(archive.join(format!("fullchain{}.pem", v)), fullchain)?;
+        }
+        for item in &ITEMS {
+            let target = format!("../../archive/{}/{}2.pem", name, item);
+            symlink(target, live.join(format!("{}.pem", item)))?;
+        }
+        fs::write(live.join("README"), "certbot")?;
+        Ok(root)
+    }
+
+    #[test]
+    fn test_certbot_read() -> PersistResult<()> {
+        let fullchain = format!("{}{}", TEST_CERT_PEM, test_intermediate_pem("Test CA"));
+        let root = certbot_dir("acmetest.example.com", &fullchain)?;
+        let server = crate::test::with_directory_server();
+        let persist = CertbotLayoutPersist::new(&root);
+        let dir = Directory::from_url(persist.clone(), DirectoryUrl::Other(&server.dir_url))?;
+        let acc = dir.account("foo@bar.com")?;
+        let cert = acc
+            .certificate("acmetest.example.com")?
+            .expect("certificate");
+        assert_eq!(cert.certificate(), fullchain);
+        assert_eq!(cert.private_key(), TEST_CERT_KEY_PEM);
+        let names: Vec<_> = acc.certificates()?.into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec!["acmetest.example.com"]);
+        // the account is kept apart
+        assert!(root.join("accounts").join("acme-lib").is_dir());
+        assert_eq!(
+            persist.list("realm", Some(PersistKind::AccountUrl))?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_certbot_write() -> PersistResult<()> {
+        let name = "acmetest.example.com";
+        let chain = test_intermediate_pem("Test CA");
+        let fullchain = format!("{}{}", TEST_CERT_PEM, chain);
+        let root = certbot_dir(name, &fullchain)?;
+        let persist = CertbotLayoutPersist::new(&root);
+        let lineage = persist.lineage(name)?;
+        let pk_key = PersistKey::new("realm", PersistKind::PrivateKey, name);
+        let pk_crt = PersistKey::new("realm", PersistKind::Certificate, name);
+
+        persist.put(&pk_key, b"new key")?;
+        persist.put(&pk_crt, fullchain.as_bytes())?;
+        for item in &ITEMS {
+            assert_eq!(lineage.current_version(item), Some(3), "{}", item);
+        }
+        assert_eq!(persist.get(&pk_key)?, Some(b"new key".to_vec()));
+        assert_eq!(lineage.read(CERT)?, Some(TEST_CERT_PEM.as_bytes().to_vec()));
+        assert_eq!(lineage.read(CHAIN)?, Some(chain.into_bytes()));
+        let link = fs::read_link(lineage.live_file(PRIVKEY))?;
+        assert_eq!(
+            link,
+            Path::new("../../archive/acmetest.example.com/privkey3.pem")
+        );
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(lineage.archive_file(PRIVKEY, 3))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // a renewal reusing the key
+        persist.put(&pk_crt, fullchain.as_bytes())?;
+        assert_eq!(lineage.current_version(PRIVKEY), Some(4));
+        assert_eq!(lineage.current_version(FULLCHAIN), Some(4));
+        assert_eq!(persist.get(&pk_key)?, Some(b"new key".to_vec()));
+
+        // a new lineage
+        let other = PersistKey::new("realm", PersistKind::Certificate, "*.example.com");
+        persist.put(&other, TEST_CERT_PEM.as_bytes())?;
+        assert_eq!(
+            persist.lineage("*.example.com")?.current_version(CERT),
+            Some(1)
+        );
+        let names: Vec<_> = persist.list("any", Some(PersistKind::Certificate))?;
+        let names: Vec<_> = names.iter().map(|k| k.key.as_str()).collect();
+        assert_eq!(names, vec!["*.example.com", name]);
+
+        assert!(persist.delete(&pk_crt)?);
+        assert_eq!(persist.get(&pk_crt)?, None);
+        assert!(lineage.live.exists());
+        assert!(persist.delete(&pk_key)?);
+        assert!(!lineage.live.exists());
+        assert!(!lineage.archive.exists());
+        assert!(!persist.delete(&pk_key)?);
+
+        let bad = PersistKey::new("realm", PersistKind::Certificate, "../etc");
+        assert!(persist.put(&bad, b"x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_chain() {
+        let chain = format!("{}{}", TEST_CERT_PEM, TEST_CERT_PEM);
+        let (cert, rest) = split_chain(chain.as_bytes());
+        assert_eq!(cert, TEST_CERT_PEM.as_bytes());
+        assert_eq!(rest, TEST_CERT_PEM.as_bytes());
+        let (cert, rest) = split_chain(TEST_CERT_PEM.as_bytes());
+        assert_eq!(cert, TEST_CERT_PEM.as_bytes());
+        assert!(rest.is_empty());
+        assert_eq!(parse_version("privkey12.pem", PRIVKEY), Some(12));
+        assert_eq!(parse_version("privkey.pem", PRIVKEY), None);
+        assert_eq!(parse_version("fullchain1.pem", CHAIN), None);
+    }
+}
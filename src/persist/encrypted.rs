@@ -0,0 +1,247 @@
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use zeroize::Zeroizing;
+
+use super::{Persist, PersistError, PersistKey, PersistKeyBuf, PersistKind, PersistResult};
+
+/// Start of every encrypted value.
+const MAGIC: &[u8] = b"ACMELIBENC";
+/// AES-256-GCM with a random 96 bit nonce and a 128 bit tag.
+const VERSION_AES_256_GCM: u8 = 1;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Encrypts the values of another persistence using AES-256-GCM.
+///
+/// Each value is written as a header with the format version, followed by a random
+/// nonce, the encrypted value and the authentication tag. The persistence key is
+/// authenticated too, so a value copied to another key can't be read.
+///
+/// Reading a value encrypted with another key, or a corrupted value, fails. By default
+/// so does reading a value that isn't encrypted. Use [`with_migration`] to read those and
+/// encrypt them on the first read.
+///
+/// ```
+/// use acme_lib::persist::{EncryptedPersist, MemoryPersist};
+///
+/// // in practice the key comes from a secret manager
+/// let key = [7; 32];
+/// let persist = EncryptedPersist::new(MemoryPersist::new(), key);
+/// ```
+///
+/// [`with_migration`]: struct.EncryptedPersist.html#method.with_migration
+#[derive(Clone)]
+pub struct EncryptedPersist<P> {
+    inner: P,
+    key: Zeroizing<[u8; 32]>,
+    migrate: bool,
+}
+
+impl<P: Persist> EncryptedPersist<P> {
+    /// Encrypt the values of `inner` using the 256 bit key.
+    pub fn new(inner: P, key: [u8; 32]) -> Self {
+        EncryptedPersist {
+            inner,
+            key: Zeroizing::new(key),
+            migrate: false,
+        }
+    }
+
+    /// Read values that aren't encrypted, and write them back encrypted.
+    ///
+    /// This is for moving an existing persistence to encryption. Turn it off once all
+    /// values are encrypted, since anyone able to write to the inner persistence could
+    /// otherwise make this read a value of their choosing.
+    pub fn with_migration(mut self) -> Self {
+        self.migrate = true;
+        self
+    }
+
+    /// The wrapped persistence.
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn encrypt(&self, key: &PersistKey, value: &[u8]) -> PersistResult<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(|e| format!("Failed to create nonce: {}", e))?;
+        let mut out = header(VERSION_AES_256_GCM);
+        let aad = aad(&out, key);
+        let mut tag = [0; TAG_LEN];
+        let encrypted = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key[..],
+            Some(&nonce),
+            &aad,
+            value,
+            &mut tag,
+        )
+        .map_err(|e| format!("Failed to encrypt {}: {}", key, e))?;
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&encrypted);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    fn decrypt(&self, key: &PersistKey, value: &[u8]) -> PersistResult<Vec<u8>> {
+        let version = value[MAGIC.len()];
+        if version != VERSION_AES_256_GCM {
+            return Err(format!("Unsupported encryption version {} for {}", version, key).into());
+        }
+        let header_len = MAGIC.len() + 1;
+        let corrupted =
+            || PersistError::from(format!("Failed to decrypt {}: wrong key or corrupted", key));
+        if value.len() < header_len + NONCE_LEN + TAG_LEN {
+            return Err(corrupted());
+        }
+        let (header, rest) = value.split_at(header_len);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (encrypted, tag) = rest.split_at(rest.len() - TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key[..],
+            Some(nonce),
+            &aad(header, key),
+            encrypted,
+            tag,
+        )
+        .map_err(|_| corrupted())
+    }
+}
+
+fn header(version: u8) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(version);
+    header
+}
+
+/// The header and the persistence key, authenticated along with the value.
+fn aad(header: &[u8], key: &PersistKey) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(key.to_string().as_bytes());
+    aad
+}
+
+impl<P: Persist> Persist for EncryptedPersist<P> {
+    fn put(&self, key: &PersistKey, value: &[u8]) -> PersistResult<()> {
+        let encrypted = self.encrypt(key, value)?;
+        self.inner.put(key, &encrypted)
+    }
+    fn get(&self, key: &PersistKey) -> PersistResult<Option<Vec<u8>>> {
+        let value = match self.inner.get(key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        // Empty values are how removed values are stored without delete.
+        if value.is_empty() {
+            return Ok(Some(value));
+        }
+        if value.len() > MAGIC.len() && value.starts_with(MAGIC) {
+            return self.decrypt(key, &value).map(Some);
+        }
+        if !self.migrate {
+            return Err(format!("Value is not encrypted: {}", key).into());
+        }
+        debug!("Encrypt value read as plain text: {}", key);
+        self.put(key, &value)?;
+        Ok(Some(value))
+    }
+    fn delete(&self, key: &PersistKey) -> PersistResult<bool> {
+        self.inner.delete(key)
+    }
+    fn list(&self, realm: &str, kind: Option<PersistKind>) -> PersistResult<Vec<PersistKeyBuf>> {
+        self.inner.list(realm, kind)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+    use crate::{Directory, DirectoryUrl};
+
+    #[test]
+    fn test_encrypted_persist() -> PersistResult<()> {
+        let inner = MemoryPersist::new();
+        let persist = EncryptedPersist::new(inner.clone(), [1; 32]);
+        let key = PersistKey::new("realm", PersistKind::PrivateKey, "example.com");
+        persist.put(&key, b"secret")?;
+        assert_eq!(persist.get(&key)?, Some(b"secret".to_vec()));
+
+        let stored = inner.get(&key)?.unwrap();
+        assert!(stored.starts_with(b"ACMELIBENC\x01"));
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+        // a new nonce for every write
+        persist.put(&key, b"secret")?;
+        assert_ne!(inner.get(&key)?.unwrap(), stored);
+
+        let other = PersistKey::new("realm", PersistKind::PrivateKey, "nothing.com");
+        assert_eq!(persist.get(&other)?, None);
+
+        let wrong_key = EncryptedPersist::new(inner.clone(), [2; 32]);
+        let err = wrong_key.get(&key).unwrap_err().to_string();
+        assert!(err.contains("wrong key or corrupted"), "{}", err);
+
+        // a value copied to another key
+        inner.put(&other, &stored)?;
+        assert!(persist.get(&other).is_err());
+
+        let mut corrupted = stored.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        inner.put(&key, &corrupted)?;
+        assert!(persist.get(&key).is_err());
+        inner.put(&key, &stored[..20])?;
+        assert!(persist.get(&key).is_err());
+
+        let mut future = stored;
+        future[10] = 2;
+        inner.put(&key, &future)?;
+        let err = persist.get(&key).unwrap_err().to_string();
+        assert!(err.contains("Unsupported encryption version 2"), "{}", err);
+
+        assert!(persist.delete(&key)?);
+        assert_eq!(inner.get(&key)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_persist_migration() -> PersistResult<()> {
+        let inner = MemoryPersist::new();
+        let key = PersistKey::new("realm", PersistKind::PrivateKey, "example.com");
+        inner.put(&key, b"plain")?;
+        inner.put(
+            &PersistKey::new("realm", PersistKind::Certificate, "x"),
+            b"",
+        )?;
+
+        let persist = EncryptedPersist::new(inner.clone(), [1; 32]);
+        let err = persist.get(&key).unwrap_err().to_string();
+        assert!(err.contains("not encrypted"), "{}", err);
+        let removed = PersistKey::new("realm", PersistKind::Certificate, "x");
+        assert_eq!(persist.get(&removed)?, Some(vec![]));
+        assert_eq!(inner.get(&key)?, Some(b"plain".to_vec()));
+
+        let migrating = persist.clone().with_migration();
+        assert_eq!(migrating.get(&key)?, Some(b"plain".to_vec()));
+        assert!(inner.get(&key)?.unwrap().starts_with(MAGIC));
+        assert_eq!(persist.get(&key)?, Some(b"plain".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_persist_account() -> PersistResult<()> {
+        let server = crate::test::with_directory_server();
+        let inner = MemoryPersist::new();
+        let persist = EncryptedPersist::new(inner.clone(), [3; 32]);
+        let dir = Directory::from_url(persist, DirectoryUrl::Other(&server.dir_url))?;
+        let acc = dir.account("foo@bar.com")?;
+        let again = dir.account("foo@bar.com")?;
+        assert_eq!(acc.acme_private_key_pem()?, again.acme_private_key_pem()?);
+
+        // The plain inner persistence can't read the account key.
+        let dir = Directory::from_url(inner, DirectoryUrl::Other(&server.dir_url))?;
+        assert!(dir.account("foo@bar.com").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,598 @@
+use openssl::ssl::{SslConnector, SslMethod};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Persist, PersistKey, PersistKeyBuf, PersistKind, PersistResult};
+use crate::Result;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Persistence in Redis.
+///
+/// Each value is saved as a binary string under `<prefix>:<realm>:<kind>:<name>`, where
+/// the realm is the [opaque hash] of the realm and the kind is a short name like `crt`.
+/// [`list`] uses `SCAN`, so it's fine to share the database with other data.
+///
+/// The connection is made when created, and made again if it fails while in use. Clones
+/// share the connection.
+///
+/// ```no_run
+/// use acme_lib::persist::RedisPersist;
+///
+/// # fn main() -> acme_lib::Result<()> {
+/// let persist = RedisPersist::new("redis://:secret@localhost:6379/0", "acme")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [opaque hash]: struct.PersistKey.html#method.hash_realm
+/// [`list`]: trait.Persist.html#method.list
+#[derive(Clone)]
+pub struct RedisPersist {
+    url: RedisUrl,
+    prefix: String,
+    conn: Arc<Mutex<Option<Connection>>>,
+}
+
+impl RedisPersist {
+    /// Connect to the Redis server at the URL, and save the keys under the prefix.
+    ///
+    /// The URL is like `redis://[[user]:password@]host[:port][/db]`. Use `rediss://` for
+    /// TLS.
+    pub fn new(url: &str, prefix: &str) -> Result<Self> {
+        let url = RedisUrl::parse(url)?;
+        let conn = Connection::open(&url)?;
+        Ok(RedisPersist {
+            url,
+            prefix: prefix.to_string(),
+            conn: Arc::new(Mutex::new(Some(conn))),
+        })
+    }
+
+    /// The Redis key of the persistence key.
+    fn redis_key(&self, key: &PersistKey) -> Vec<u8> {
+        let mut k = format!("{}:{}:{}:", self.prefix, key.realm, key.kind.name()).into_bytes();
+        k.extend_from_slice(key.key.as_bytes());
+        k
+    }
+
+    /// Read a Redis key back, `None` for keys of others.
+    fn persist_key(&self, redis_key: &[u8]) -> Option<PersistKeyBuf> {
+        let rest = redis_key.strip_prefix(self.prefix.as_bytes())?;
+        let rest = std::str::from_utf8(rest.strip_prefix(b":")?).ok()?;
+        let mut parts = rest.splitn(3, ':');
+        Some(PersistKeyBuf {
+            realm: parts.next()?.parse().ok()?,
+            kind: PersistKind::from_name(parts.next()?)?,
+            key: parts.next()?.to_string(),
+        })
+    }
+
+    /// Send the command, connecting again once if the connection fails. A failed
+    /// connection is an `io::Error` of the kind it failed with.
+    fn call(&self, args: &[&[u8]]) -> PersistResult<Reply> {
+        let mut lock = self.conn.lock().unwrap();
+        for attempt in 0..2 {
+            if lock.is_none() {
+                *lock = Some(Connection::open(&self.url)?);
+            }
+            let conn = lock.as_mut().unwrap();
+            match conn.call(args) {
+                Ok(reply) => return Ok(reply),
+                Err(e) if attempt == 0 => {
+                    debug!("Reconnect to redis {} after: {}", self.url.addr, e);
+                    *lock = None;
+                }
+                Err(e) => {
+                    *lock = None;
+                    let context = format!("Redis {}: {}", self.url.addr, e);
+                    return Err(io::Error::new(e.kind(), context).into());
+                }
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Persist for RedisPersist {
+    fn put(&self, key: &PersistKey, value: &[u8]) -> PersistResult<()> {
+        let k = self.redis_key(key);
+        Ok(self.call(&[b"SET", &k, value])?.ok()?)
+    }
+    fn get(&self, key: &PersistKey) -> PersistResult<Option<Vec<u8>>> {
+        let k = self.redis_key(key);
+        match self.call(&[b"GET", &k])?.checked()? {
+            Reply::Bulk(v) => Ok(v),
+            r => Err(format!("Unexpected redis reply to GET: {:?}", r).into()),
+        }
+    }
+    fn delete(&self, key: &PersistKey) -> PersistResult<bool> {
+        let k = self.redis_key(key);
+        match self.call(&[b"DEL", &k])?.checked()? {
+            Reply::Int(n) => Ok(n > 0),
+            r => Err(format!("Unexpected redis reply to DEL: {:?}", r).into()),
+        }
+    }
+    fn list(&self, realm: &str, kind: Option<PersistKind>) -> PersistResult<Vec<PersistKeyBuf>> {
+        let realm = PersistKey::hash_realm(realm);
+        let kind_pattern = kind
+            .map(|k| glob_escape(k.name()))
+            .unwrap_or_else(|| "*".into());
+        let pattern = format!("{}:{}:{}:*", glob_escape(&self.prefix), realm, kind_pattern);
+        let mut cursor = b"0".to_vec();
+        let mut keys = vec![];
+        loop {
+            let reply = self.call(&[
+                b"SCAN",
+                &cursor,
+                b"MATCH",
+                pattern.as_bytes(),
+                b"COUNT",
+                b"100",
+            ])?;
+            let (next, found) = match reply.checked()? {
+                Reply::Array(mut a) if a.len() == 2 => match (a.remove(0), a.remove(0)) {
+                    (Reply::Bulk(Some(next)), Reply::Array(found)) => (next, found),
+                    r => return Err(format!("Unexpected redis reply to SCAN: {:?}", r).into()),
+                },
+                r => return Err(format!("Unexpected redis reply to SCAN: {:?}", r).into()),
+            };
+            for k in found {
+                if let Reply::Bulk(Some(k)) = k {
+                    // SCAN can return a key more than once.
+                    match self.persist_key(&k) {
+                        Some(k) if k.realm == realm && !keys.contains(&k) => keys.push(k),
+                        _ => {}
+                    }
+                }
+            }
+            if next == b"0" {
+                break;
+            }
+            cursor = next;
+        }
+        super::sort_keys(&mut keys);
+        Ok(keys)
+    }
+}
+
+/// Escape the glob characters of `SCAN MATCH`.
+fn glob_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The parts of a `redis://` URL.
+#[derive(Clone, Debug)]
+struct RedisUrl {
+    tls: bool,
+    host: String,
+    addr: String,
+    username: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+impl RedisUrl {
+    fn parse(url: &str) -> Result<RedisUrl> {
+        let invalid = |reason: &str| format!("Invalid redis URL: {}", reason);
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("redis://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("rediss://") {
+            (true, rest)
+        } else {
+            return Err(invalid("not redis:// or rediss://").into());
+        };
+        let (auth, rest) = match rest.rfind('@') {
+            Some(i) => (Some(&rest[..i]), &rest[i + 1..]),
+            None => (None, rest),
+        };
+        let (host_port, db) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        let db = if db.is_empty() {
+            0
+        } else {
+            db.parse().map_err(|_| invalid("db is not a number"))?
+        };
+        let (host, port) = match host_port.rfind(':') {
+            Some(i) if !host_port[i..].contains(']') => (
+                &host_port[..i],
+                host_port[i + 1..]
+                    .parse::<u16>()
+                    .map_err(|_| invalid("port is not a number"))?,
+            ),
+            _ => (host_port, 6379),
+        };
+        if host.is_empty() {
+            return Err(invalid("no host").into());
+        }
+        let (username, password) = match auth {
+            Some(auth) => match auth.find(':') {
+                Some(i) => (
+                    Some(percent_decode(&auth[..i])?).filter(|u| !u.is_empty()),
+                    Some(percent_decode(&auth[i + 1..])?),
+                ),
+                None => (Some(percent_decode(auth)?), None),
+            },
+            None => (None, None),
+        };
+        Ok(RedisUrl {
+            tls,
+            addr: format!("{}:{}", host, port),
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            username,
+            password,
+            db,
+        })
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2]).unwrap_or("");
+            if let Ok(v) = u8::from_str_radix(hex, 16) {
+                bytes.push(v);
+                rest = &tail[2..];
+                continue;
+            }
+        }
+        bytes.push(b);
+        rest = tail;
+    }
+    String::from_utf8(bytes).map_err(|_| "Invalid redis URL: not UTF-8".to_string().into())
+}
+
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
+
+/// A connection speaking the Redis protocol (RESP).
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Connection {
+    fn open(url: &RedisUrl) -> Result<Connection> {
+        let context = |e: &dyn std::fmt::Display| format!("Redis {}: {}", url.addr, e);
+        let tcp = TcpStream::connect(&url.addr).map_err(|e| context(&e))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let stream: Box<dyn Stream> = if url.tls {
+            let connector = SslConnector::builder(SslMethod::tls())
+                .map_err(|e| context(&e))?
+                .build();
+            Box::new(connector.connect(&url.host, tcp).map_err(|e| context(&e))?)
+        } else {
+            Box::new(tcp)
+        };
+        let mut conn = Connection {
+            stream: BufReader::new(stream),
+        };
+        let mut setup = || -> Result<()> {
+            match (&url.username, &url.password) {
+                (Some(u), Some(p)) => conn.call(&[b"AUTH", u.as_bytes(), p.as_bytes()])?.ok()?,
+                (None, Some(p)) => conn.call(&[b"AUTH", p.as_bytes()])?.ok()?,
+                _ => {}
+            }
+            if url.db != 0 {
+                let db = url.db.to_string();
+                conn.call(&[b"SELECT", db.as_bytes()])?.ok()?;
+            }
+            Ok(())
+        };
+        setup().map_err(|e| context(&e))?;
+        Ok(conn)
+    }
+
+    fn call(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut cmd = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            cmd.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            cmd.extend_from_slice(arg);
+            cmd.extend_from_slice(b"\r\n");
+        }
+        let stream = self.stream.get_mut();
+        stream.write_all(&cmd)?;
+        stream.flush()?;
+        read_reply(&mut self.stream)
+    }
+}
+
+/// A reply in the Redis protocol.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    /// The reply, unless it's an error.
+    fn checked(self) -> Result<Reply> {
+        match self {
+            Reply::Error(e) => Err(format!("Redis error: {}", e).into()),
+            r => Ok(r),
+        }
+    }
+
+    /// Expect the `OK` status.
+    fn ok(self) -> Result<()> {
+        match self.checked()? {
+            Reply::Status(ref s) if s == "OK" => Ok(()),
+            r => Err(format!("Unexpected redis reply: {:?}", r).into()),
+        }
+    }
+}
+
+/// Longest bulk string read, the default `proto-max-bulk-len` of Redis.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+fn read_reply<R: BufRead>(r: &mut R) -> io::Result<Reply> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut line = vec![];
+    r.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid("connection closed"));
+    }
+    line.truncate(line.len() - 2);
+    if line.is_empty() {
+        return Err(invalid("empty reply"));
+    }
+    let text = String::from_utf8_lossy(&line[1..]).to_string();
+    let number = || text.parse::<i64>().map_err(|_| invalid("not a number"));
+    Ok(match line.first() {
+        Some(b'+') => Reply::Status(text),
+        Some(b'-') => Reply::Error(text),
+        Some(b':') => Reply::Int(number()?),
+        Some(b'$') => match number()? {
+            -1 => Reply::Bulk(None),
+            n if !(0..=MAX_BULK_LEN).contains(&n) => return Err(invalid("bad bulk length")),
+            n => {
+                let mut v = vec![0; n as usize + 2];
+                r.read_exact(&mut v)?;
+                if !v.ends_with(b"\r\n") {
+                    return Err(invalid("bulk string not ended"));
+                }
+                v.truncate(n as usize);
+                Reply::Bulk(Some(v))
+            }
+        },
+        Some(b'*') => match number()? {
+            -1 => Reply::Bulk(None),
+            n if n < 0 => return Err(invalid("bad array length")),
+            n => {
+                // grows as the elements are read, not by the length sent
+                let mut items = vec![];
+                for _ in 0..n {
+                    items.push(read_reply(r)?);
+                }
+                Reply::Array(items)
+            }
+        },
+        _ => return Err(invalid("unknown reply type")),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+    use std::thread;
+
+    type MockData = Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>;
+
+    /// Answers the commands used by `RedisPersist`, like a Redis server would.
+    fn mock_redis() -> (String, MockData) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let data = Arc::new(Mutex::new(BTreeMap::<Vec<u8>, Vec<u8>>::new()));
+        let shared = data.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let data = shared.clone();
+                thread::spawn(move || {
+                    let mut r = BufReader::new(stream.try_clone().unwrap());
+                    let mut w = stream;
+                    while let Ok(Reply::Array(args)) = read_reply(&mut r) {
+                        let args: Vec<Vec<u8>> = args
+                            .into_iter()
+                            .map(|a| match a {
+                                Reply::Bulk(Some(a)) => a,
+                                _ => vec![],
+                            })
+                            .collect();
+                        let bulk = |v: &[u8]| {
+                            let mut out = format!("${}\r\n", v.len()).into_bytes();
+                            out.extend_from_slice(v);
+                            out.extend_from_slice(b"\r\n");
+                            out
+                        };
+                        let mut data = data.lock().unwrap();
+                        let out = match &args[0][..] {
+                            b"AUTH" if args.last().unwrap() == b"secret" => b"+OK\r\n".to_vec(),
+                            b"AUTH" => b"-WRONGPASS invalid password\r\n".to_vec(),
+                            b"SELECT" => b"+OK\r\n".to_vec(),
+                            // a broken server
+                            b"GET" if args[1].ends_with(b":broken") => b"\r\n".to_vec(),
+                            b"SET" => {
+                                data.insert(args[1].clone(), args[2].clone());
+                                b"+OK\r\n".to_vec()
+                            }
+                            b"GET" => match data.get(&args[1]) {
+                                Some(v) => bulk(v),
+                                None => b"$-1\r\n".to_vec(),
+                            },
+                            b"DEL" => format!(":{}\r\n", data.remove(&args[1]).is_some() as u8)
+                                .into_bytes(),
+                            b"SCAN" => {
+                                // only the prefix before the first glob is matched
+                                let pattern = String::from_utf8(args[3].clone()).unwrap();
+                                let prefix = pattern.split('*').next().unwrap().replace('\\', "");
+                                let found: Vec<_> = data
+                                    .keys()
+                                    .filter(|k| k.starts_with(prefix.as_bytes()))
+                                    .collect();
+                                let mut out = b"*2\r\n$1\r\n0\r\n".to_vec();
+                                out.extend_from_slice(format!("*{}\r\n", found.len()).as_bytes());
+                                for k in found {
+                                    out.extend_from_slice(&bulk(k));
+                                }
+                                out
+                            }
+                            _ => b"-ERR unknown command\r\n".to_vec(),
+                        };
+                        if w.write_all(&out).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (format!("redis://:secret@127.0.0.1:{}/2", port), data)
+    }
+
+    fn check_persist(persist: &RedisPersist) -> PersistResult<()> {
+        let key = PersistKey::new("realm", PersistKind::Certificate, "*.example.com");
+        let other = PersistKey::new("realm", PersistKind::PrivateKey, "*.example.com");
+        assert_eq!(persist.get(&key)?, None);
+        persist.put(&key, b"cert\r\n\xff")?;
+        persist.put(&other, b"key")?;
+        persist.put(
+            &PersistKey::new("realm2", PersistKind::PrivateKey, "a"),
+            b"a",
+        )?;
+        assert_eq!(persist.get(&key)?, Some(b"cert\r\n\xff".to_vec()));
+        let keys = persist.list("realm", None)?;
+        assert_eq!(keys, vec![key.into(), other.into()]);
+        let keys = persist.list("realm", Some(PersistKind::PrivateKey))?;
+        assert_eq!(keys, vec![other.into()]);
+        assert!(persist.delete(&key)?);
+        assert!(!persist.delete(&key)?);
+        assert_eq!(persist.get(&key)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redis_persist() -> PersistResult<()> {
+        let (url, data) = mock_redis();
+        let persist = RedisPersist::new(&url, "acme:test")?;
+        check_persist(&persist)?;
+
+        let key = PersistKey::new("realm", PersistKind::PrivateKey, "*.example.com");
+        let redis_key = format!("acme:test:{}:key:*.example.com", key.realm);
+        assert!(data.lock().unwrap().contains_key(redis_key.as_bytes()));
+        assert_eq!(persist.persist_key(redis_key.as_bytes()), Some(key.into()));
+        assert_eq!(persist.persist_key(b"acme:other:1:key:a"), None);
+        assert_eq!(persist.persist_key(b"acme:test:1:nope:a"), None);
+
+        // A dropped connection is made again.
+        let tcp = TcpStream::connect(persist.url.addr.as_str())?;
+        tcp.shutdown(std::net::Shutdown::Both)?;
+        *persist.conn.lock().unwrap() = Some(Connection {
+            stream: BufReader::new(Box::new(tcp)),
+        });
+        assert_eq!(persist.get(&key)?, Some(b"key".to_vec()));
+
+        let broken = PersistKey::new("realm", PersistKind::PrivateKey, "broken");
+        let err = persist.get(&broken).unwrap_err();
+        let io = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("empty reply"), "{}", err);
+        assert_eq!(persist.get(&key)?, Some(b"key".to_vec()));
+
+        let wrong = url.replace("secret", "wrong");
+        let err = RedisPersist::new(&wrong, "acme").err().unwrap().to_string();
+        assert!(err.contains("WRONGPASS"), "{}", err);
+        let err = RedisPersist::new("redis://127.0.0.1:1", "acme")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("Redis 127.0.0.1:1:"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let read = |b: &[u8]| read_reply(&mut &b[..]);
+        assert_eq!(read(b"+OK\r\n").unwrap(), Reply::Status("OK".into()));
+        assert_eq!(
+            read(b"$3\r\nabc\r\n").unwrap(),
+            Reply::Bulk(Some(b"abc".to_vec()))
+        );
+        assert_eq!(read(b"$-1\r\n").unwrap(), Reply::Bulk(None));
+        let bad = [
+            &b"\r\n"[..],
+            b"",
+            b"+OK",
+            b":x\r\n",
+            b"?\r\n",
+            b"$-2\r\n",
+            b"$536870913\r\n",
+            b"$3\r\nabcd\r\n",
+            b"*-2\r\n",
+        ];
+        for bad in &bad {
+            let err = read(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_redis_url() -> PersistResult<()> {
+        let url = RedisUrl::parse("redis://localhost")?;
+        assert_eq!(url.addr, "localhost:6379");
+        assert_eq!((url.tls, url.db), (false, 0));
+        assert_eq!((url.username, url.password), (None, None));
+        let url = RedisUrl::parse("rediss://user:p%40ss@[::1]:7000/3")?;
+        assert_eq!(url.addr, "[::1]:7000");
+        assert_eq!(url.host, "::1");
+        assert_eq!((url.tls, url.db), (true, 3));
+        assert_eq!(url.username.as_deref(), Some("user"));
+        assert_eq!(url.password.as_deref(), Some("p@ss"));
+        let url = RedisUrl::parse("redis://:secret@host:1/")?;
+        assert_eq!(
+            (url.username, url.password.as_deref()),
+            (None, Some("secret"))
+        );
+        for bad in &[
+            "http://host",
+            "redis://",
+            "redis://host:port",
+            "redis://host/db",
+        ] {
+            assert!(RedisUrl::parse(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(glob_escape("a*b?[c]"), r"a\*b\?\[c\]");
+        Ok(())
+    }
+
+    /// Against a real Redis, when `ACME_LIB_TEST_REDIS_URL` is set.
+    #[test]
+    fn test_redis_server() -> PersistResult<()> {
+        let url = match std::env::var("ACME_LIB_TEST_REDIS_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
+        };
+        let prefix = format!("acme-lib-test-{}", std::process::id());
+        check_persist(&RedisPersist::new(&url, &prefix)?)
+    }
+}
@@ -1,4 +1,7 @@
+use std::time::{Duration, SystemTime};
+
 use crate::api::ApiProblem;
+use crate::util::parse_http_date;
 
 pub(crate) type ReqResult<T> = ::std::result::Result<T, ApiProblem>;
 
@@ -24,7 +27,63 @@ pub(crate) fn req_post(url: &str, body: &str) -> ureq::Response {
     req.send_string(body)
 }
 
-fn req_configure(req: &mut ureq::Request) {
+#[cfg(feature = "ocsp")]
+pub(crate) fn req_post_ocsp(url: &str, der: &[u8]) -> ureq::Response {
+    let mut req = ureq::post(url);
+    req.set("content-type", "application/ocsp-request");
+    req_configure(&mut req);
+    trace!("{:?} ({} bytes)", req, der.len());
+    req.send_bytes(der)
+}
+
+/// Longest time to wait between polls, whatever the `Retry-After` says.
+pub(crate) const MAX_POLL_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// The delay of a `Retry-After` header, given as either seconds or an HTTP date.
+pub(crate) fn req_retry_after(res: &ureq::Response) -> Option<Duration> {
+    let value = res.header("retry-after")?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = parse_http_date(value)?;
+    // a date in the past means now.
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// How long to wait before polling again. The `Retry-After` of the response if there
+/// was one, but at most `MAX_POLL_DELAY`, otherwise the default.
+pub(crate) fn poll_delay(retry_after: Option<Duration>, default: Duration) -> Duration {
+    match retry_after {
+        Some(d) => d.min(MAX_POLL_DELAY),
+        None => default,
+    }
+}
+
+/// Find the URL of a `Link` header with the given relation, such as
+/// `<https://example.com/tos>;rel="terms-of-service"`.
+pub(crate) fn req_link(res: &ureq::Response, rel: &str) -> Option<String> {
+    req_links(res, rel).into_iter().next()
+}
+
+/// The URLs of all `Link` headers with the given relation, in the order sent.
+pub(crate) fn req_links(res: &ureq::Response, rel: &str) -> Vec<String> {
+    let rel = format!("rel=\"{}\"", rel);
+    res.all("link")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .filter(|l| l.split(';').skip(1).any(|p| p.trim() == rel))
+        .filter_map(|l| {
+            let url = l.split(';').next()?.trim();
+            Some(
+                url.trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string(),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn req_configure(req: &mut ureq::Request) {
     req.timeout_connect(30_000);
     req.timeout_read(30_000);
     req.timeout_write(30_000);
@@ -36,17 +95,18 @@ pub(crate) fn req_handle_error(res: ureq::Response) -> ReqResult<ureq::Response>
         return Ok(res);
     }
 
-    let problem = if res.content_type() == "application/problem+json" {
+    let status = res.status();
+    let mut problem = if res.content_type() == "application/problem+json" {
         // if we were sent a problem+json, deserialize it
         let body = req_safe_read_body(res);
         serde_json::from_str(&body).unwrap_or_else(|e| ApiProblem {
             _type: "problemJsonFail".into(),
             detail: Some(format!(
                 "Failed to deserialize application/problem+json ({}) body: {}",
-                e.to_string(),
-                body
+                e, body
             )),
             subproblems: None,
+            status: None,
         })
     } else {
         // some other problem
@@ -57,8 +117,12 @@ pub(crate) fn req_handle_error(res: ureq::Response) -> ReqResult<ureq::Response>
             _type: "httpReqError".into(),
             detail: Some(detail),
             subproblems: None,
+            status: None,
         }
     };
+    if problem.status.is_none() {
+        problem.status = Some(status);
+    }
 
     Err(problem)
 }
@@ -70,6 +134,7 @@ pub(crate) fn req_expect_header(res: &ureq::Response, name: &str) -> ReqResult<S
             _type: format!("Missing header: {}", name),
             detail: None,
             subproblems: None,
+            status: None,
         })
 }
 
@@ -82,3 +147,47 @@ pub(crate) fn req_safe_read_body(res: ureq::Response) -> String {
     read.read_to_string(&mut res_body).ok();
     res_body
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_req_retry_after() {
+        let server = crate::test::with_directory_server();
+        let base = server.dir_url.replace("/directory", "/retry-after");
+        let retry_after = |path: &str| req_retry_after(&req_get(&format!("{}/{}", base, path)));
+
+        assert_eq!(retry_after("seconds"), Some(Duration::from_secs(120)));
+        let d = retry_after("date").unwrap();
+        assert!(d > Duration::from_secs(3500) && d <= Duration::from_secs(3600));
+        assert_eq!(retry_after("past"), Some(Duration::from_secs(0)));
+        assert_eq!(retry_after("bogus"), None);
+        assert_eq!(retry_after("none"), None);
+    }
+
+    #[test]
+    fn test_req_links() {
+        let server = crate::test::with_directory_server();
+        let res = ureq::post(&format!(
+            "{}/acme/cert/fae41c070f967713109028",
+            server.dir_url.replace("/directory", "")
+        ))
+        .call();
+        let alternate = req_links(&res, "alternate");
+        assert_eq!(alternate.len(), 1);
+        assert!(alternate[0].ends_with("/1"), "{:?}", alternate);
+        assert!(req_link(&res, "index").unwrap().ends_with("/directory"));
+        assert!(req_links(&res, "up").is_empty());
+    }
+
+    #[test]
+    fn test_poll_delay() {
+        let default = Duration::from_millis(500);
+        assert_eq!(poll_delay(None, default), default);
+        let d = Duration::from_secs(3);
+        assert_eq!(poll_delay(Some(d), default), d);
+        let d = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(poll_delay(Some(d), default), MAX_POLL_DELAY);
+    }
+}
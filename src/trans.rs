@@ -1,14 +1,14 @@
-use openssl::ecdsa::EcdsaSig;
-use openssl::sha::sha256;
 use serde::Serialize;
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 use crate::acc::AcmeKey;
+use crate::api::ApiProblem;
 use crate::jwt::*;
-use crate::req::{req_expect_header, req_handle_error, req_head, req_post};
+use crate::order::{EventHook, OrderEvent};
+use crate::req::{req_expect_header, req_handle_error, req_head, req_link, req_post};
 use crate::util::base64url;
-use crate::Result;
+use crate::{Error, Result};
 
 /// JWS payload and nonce handling for requests to the API.
 ///
@@ -22,16 +22,23 @@ use crate::Result;
 pub(crate) struct Transport {
     acme_key: AcmeKey,
     nonce_pool: Arc<NoncePool>,
+    events: EventHook,
 }
 
 impl Transport {
-    pub fn new(nonce_pool: &Arc<NoncePool>, acme_key: AcmeKey) -> Self {
+    pub fn new(nonce_pool: &Arc<NoncePool>, acme_key: AcmeKey, events: &EventHook) -> Self {
         Transport {
-            acme_key: acme_key,
+            acme_key,
             nonce_pool: nonce_pool.clone(),
+            events: events.clone(),
         }
     }
 
+    /// The hook for order events.
+    pub fn events(&self) -> &EventHook {
+        &self.events
+    }
+
     /// Update the key id once it is known (part of setting up the transport).
     pub fn set_key_id(&mut self, kid: String) {
         self.acme_key.set_key_id(kid);
@@ -42,6 +49,11 @@ impl Transport {
         &self.acme_key
     }
 
+    /// A transport sharing the nonce pool with this, but using another key.
+    pub fn with_acme_key(&self, acme_key: AcmeKey) -> Transport {
+        Transport::new(&self.nonce_pool, acme_key, &self.events)
+    }
+
     /// Make call using the full jwk. Only for the first newAccount request.
     pub fn call_jwk<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<ureq::Response> {
         self.do_call(url, body, jws_with_jwk)
@@ -76,6 +88,12 @@ impl Transport {
             // a nonce in the response.
             self.nonce_pool.extract_nonce(&response);
 
+            let tos_link = if response.ok() {
+                None
+            } else {
+                req_link(&response, "terms-of-service")
+            };
+
             // Turn errors into ApiProblem.
             let result = req_handle_error(response);
 
@@ -83,18 +101,32 @@ impl Transport {
                 if problem.is_bad_nonce() {
                     // retry the request with a new nonce.
                     debug!("Retrying on bad nonce");
+                    self.retrying(url, problem);
                     continue;
                 }
                 // it seems we sometimes make bad JWTs. Why?!
                 if problem.is_jwt_verification_error() {
                     debug!("Retrying on: {}", problem);
+                    self.retrying(url, problem);
                     continue;
                 }
+                if problem.is_user_action_required() {
+                    if let Some(url) = tos_link {
+                        return Err(Error::TermsOfService(url));
+                    }
+                }
             }
 
             return Ok(result?);
         }
     }
+
+    fn retrying(&self, url: &str, problem: &ApiProblem) {
+        self.events.fire(OrderEvent::Retrying {
+            url: url.to_string(),
+            reason: problem.to_string(),
+        });
+    }
 }
 
 /// Shared pool of nonces.
@@ -143,7 +175,7 @@ fn jws_with_kid<T: Serialize + ?Sized>(
     key: &AcmeKey,
     payload: &T,
 ) -> Result<String> {
-    let protected = JwsProtected::new_kid(key.key_id(), url, nonce);
+    let protected = JwsProtected::new_kid(key.alg(), key.key_id(), url, nonce);
     jws_with(protected, key, payload)
 }
 
@@ -158,11 +190,28 @@ fn jws_with_jwk<T: Serialize + ?Sized>(
     jws_with(protected, key, payload)
 }
 
+/// Make the inner JWS for an account key change. It is signed by the new key
+/// and uses the full jwk, but no nonce.
+pub(crate) fn jws_key_change(url: &str, new_key: &AcmeKey, payload: &KeyChange) -> Result<Jws> {
+    let jwk: Jwk = new_key.into();
+    let protected = JwsProtected::new_jwk_without_nonce(jwk, url);
+    jws_object(protected, new_key, payload)
+}
+
 fn jws_with<T: Serialize + ?Sized>(
     protected: JwsProtected,
     key: &AcmeKey,
     payload: &T,
 ) -> Result<String> {
+    let jws = jws_object(protected, key, payload)?;
+    Ok(serde_json::to_string(&jws)?)
+}
+
+fn jws_object<T: Serialize + ?Sized>(
+    protected: JwsProtected,
+    key: &AcmeKey,
+    payload: &T,
+) -> Result<Jws> {
     let protected = {
         let pro_json = serde_json::to_string(&protected)?;
         base64url(pro_json.as_bytes())
@@ -179,17 +228,84 @@ fn jws_with<T: Serialize + ?Sized>(
     };
 
     let to_sign = format!("{}.{}", protected, payload);
-    let digest = sha256(to_sign.as_bytes());
-    let sig = EcdsaSig::sign(&digest, key.private_key()).expect("EcdsaSig::sign");
-    let r = sig.r().to_vec();
-    let s = sig.s().to_vec();
+    let signature = base64url(&key.sign(to_sign.as_bytes())?);
 
-    let mut v = Vec::with_capacity(r.len() + s.len());
-    v.extend_from_slice(&r);
-    v.extend_from_slice(&s);
-    let signature = base64url(&v);
+    Ok(Jws::new(protected, payload, signature))
+}
 
-    let jws = Jws::new(protected, payload, signature);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acc::AccountKeyType;
+    use crate::api::ApiEmptyObject;
+    use openssl::bn::BigNum;
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::hash::hash;
+    use openssl::pkey::{PKey, Private};
 
-    Ok(serde_json::to_string(&jws)?)
+    fn base64url_decode(s: &str) -> Vec<u8> {
+        base64::decode_config(s, base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    fn private_key(key: &AcmeKey) -> PKey<Private> {
+        PKey::private_key_from_pem(&key.to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_jws_ec_key_types() -> Result<()> {
+        let types = [
+            (AccountKeyType::P256, "ES256", "P-256"),
+            (AccountKeyType::P384, "ES384", "P-384"),
+            (AccountKeyType::P521, "ES512", "P-521"),
+        ];
+        for (key_type, alg, crv) in types.iter() {
+            let key = AcmeKey::new(*key_type)?;
+            let url = "https://example.com/acme/new-acct";
+            let body = jws_with_jwk(url, "nonce".into(), &key, &ApiEmptyObject)?;
+            let jws: serde_json::Value = serde_json::from_str(&body)?;
+            let protected = jws["protected"].as_str().unwrap();
+            let payload = jws["payload"].as_str().unwrap();
+
+            let header: serde_json::Value = serde_json::from_slice(&base64url_decode(protected))?;
+            assert_eq!(header["alg"], *alg);
+            assert_eq!(header["jwk"]["crv"], *crv);
+
+            // the signature must verify with the key.
+            let params = key_type.ec_params().unwrap();
+            let sig = base64url_decode(jws["signature"].as_str().unwrap());
+            assert_eq!(sig.len(), params.size * 2);
+            let r = BigNum::from_slice(&sig[..params.size]).unwrap();
+            let s = BigNum::from_slice(&sig[params.size..]).unwrap();
+            let sig = EcdsaSig::from_private_components(r, s).unwrap();
+            let to_sign = format!("{}.{}", protected, payload);
+            let digest = hash(params.digest, to_sign.as_bytes()).unwrap();
+            let ec_key = private_key(&key).ec_key().unwrap();
+            assert!(sig.verify(&digest, &ec_key).unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_jws_ed25519() -> Result<()> {
+        use openssl::sign::Verifier;
+
+        let key = AcmeKey::new(AccountKeyType::Ed25519)?;
+        let url = "https://example.com/acme/new-acct";
+        let body = jws_with_jwk(url, "nonce".into(), &key, &ApiEmptyObject)?;
+        let jws: serde_json::Value = serde_json::from_str(&body)?;
+        let protected = jws["protected"].as_str().unwrap();
+        let payload = jws["payload"].as_str().unwrap();
+
+        let header: serde_json::Value = serde_json::from_slice(&base64url_decode(protected))?;
+        assert_eq!(header["alg"], "EdDSA");
+        assert_eq!(header["jwk"]["kty"], "OKP");
+        assert_eq!(header["jwk"]["crv"], "Ed25519");
+
+        let sig = base64url_decode(jws["signature"].as_str().unwrap());
+        let to_sign = format!("{}.{}", protected, payload);
+        let pkey = private_key(&key);
+        let mut verifier = Verifier::new_without_digest(&pkey).unwrap();
+        assert!(verifier.verify_oneshot(&sig, to_sign.as_bytes()).unwrap());
+        Ok(())
+    }
 }
@@ -1,20 +1,109 @@
 use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::req::req_safe_read_body;
 use crate::Result;
 
 lazy_static! {
     static ref BASE64_CONFIG: base64::Config =
-        { base64::Config::new(base64::CharacterSet::UrlSafe, false) };
+        base64::Config::new(base64::CharacterSet::UrlSafe, false);
 }
 
 pub(crate) fn base64url<T: ?Sized + AsRef<[u8]>>(input: &T) -> String {
     base64::encode_config(input, *BASE64_CONFIG)
 }
 
+pub(crate) fn base64url_decode<T: ?Sized + AsRef<[u8]>>(input: &T) -> Result<Vec<u8>> {
+    // be lenient with trailing padding.
+    let input = input.as_ref();
+    let end = input
+        .iter()
+        .rposition(|b| *b != b'=')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    Ok(base64::decode_config(&input[..end], *BASE64_CONFIG)?)
+}
+
 pub(crate) fn read_json<T: DeserializeOwned>(res: ureq::Response) -> Result<T> {
     let res_body = req_safe_read_body(res);
     debug!("{}", res_body);
     Ok(serde_json::from_str(&res_body)?)
 }
+
+/// Format the time as RFC 3339 in UTC, such as `2019-01-09T08:26:43Z`.
+pub(crate) fn rfc3339(t: SystemTime) -> Result<String> {
+    let since = t
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "Time before unix epoch")?;
+    let spec = time::Timespec::new(since.as_secs() as i64, 0);
+    Ok(time::at_utc(spec).rfc3339().to_string())
+}
+
+/// Parse a RFC 3339 time such as `2019-01-09T08:26:43.570360537Z`, ignoring fractions
+/// of seconds. `None` if it can't be parsed.
+pub(crate) fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    if !s.is_char_boundary(19) {
+        return None;
+    }
+    let (date, rest) = s.split_at(19);
+    let tm = time::strptime(date, "%Y-%m-%dT%H:%M:%S").ok()?;
+    let rest = match rest.strip_prefix('.') {
+        Some(r) => r.trim_start_matches(|c: char| c.is_ascii_digit()),
+        None => rest,
+    };
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.chars().next()? {
+                '+' => 1,
+                '-' => -1,
+                _ => return None,
+            };
+            let (hours, mins) = rest[1..].split_once(':')?;
+            let hours: i64 = hours.parse().ok()?;
+            let mins: i64 = mins.parse().ok()?;
+            sign * (hours * 60 + mins) * 60
+        }
+    };
+    let secs = tm.to_timespec().sec - offset;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Parse an HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT`. `None` if it can't be
+/// parsed.
+pub(crate) fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let tm = time::strptime(s.trim(), "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    let secs = tm.to_timespec().sec;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_547_022_403);
+        assert_eq!(parse_rfc3339("2019-01-09T08:26:43Z"), Some(t));
+        assert_eq!(parse_rfc3339("2019-01-09T08:26:43.570360537Z"), Some(t));
+        assert_eq!(parse_rfc3339("2019-01-09T10:26:43+02:00"), Some(t));
+        assert_eq!(parse_rfc3339(&rfc3339(t).unwrap()), Some(t));
+        assert_eq!(parse_rfc3339("2019-01-09"), None);
+        assert_eq!(parse_rfc3339("2019-01-09T08:26:43"), None);
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(t));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("120"), None);
+    }
+}